@@ -11,8 +11,17 @@ mod size_spec;
 #[path = "src/job_count.rs"]
 #[allow(dead_code)]
 mod job_count;
+#[path = "src/timestamp.rs"]
+#[allow(dead_code)]
+mod timestamp;
+#[path = "src/duration_spec.rs"]
+#[allow(dead_code)]
+mod duration_spec;
+#[path = "src/length_range.rs"]
+#[allow(dead_code)]
+mod length_range;
 
-use size_spec::SizeSpec;
+use size_spec::{AutoSizeSpec, SizeSpec, Units};
 
 fn main() -> std::io::Result<()> {
 	let out_dir = std::path::PathBuf::from(std::env::var_os("OUT_DIR").ok_or(std::io::ErrorKind::NotFound)?);
@@ -0,0 +1,78 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+/// Returns the CPUs the current thread may run on
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Vec<usize> {
+	// SAFETY: `cpu_set_t` is a plain bitmask, all zeroes is a valid (empty) set
+	let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+	// SAFETY: `set` is a valid `cpu_set_t` of the given size
+	if unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) } != 0 {
+		return Vec::new();
+	}
+	(0..libc::CPU_SETSIZE as usize)
+		// SAFETY: `cpu` is below `CPU_SETSIZE`
+		.filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+		.collect()
+}
+
+/// Pins the current thread to one of the CPUs it may run on
+///
+/// Worker `index` gets the `index`-th allowed CPU, wrapping around if there are
+/// more workers than CPUs, so workers are spread across the available cores.
+/// Returns `false` without changing anything if affinity isn't supported.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(index: usize) -> bool {
+	let cpus = allowed_cpus();
+	if cpus.is_empty() {
+		return false;
+	}
+	let cpu = cpus[index % cpus.len()];
+	// SAFETY: see `allowed_cpus`
+	let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+	// SAFETY: `cpu` is below `CPU_SETSIZE`, `set` is a valid `cpu_set_t` of the given size
+	let result = unsafe {
+		libc::CPU_SET(cpu, &mut set);
+		libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set)
+	};
+	if result != 0 {
+		debug!(
+			"Couldn't pin thread to CPU {}: {}",
+			cpu,
+			std::io::Error::last_os_error()
+		);
+		return false;
+	}
+	trace!("Pinned thread to CPU {}", cpu);
+	true
+}
+
+/// Pins the current thread to one of the CPUs it may run on
+///
+/// Thread affinity isn't supported on this platform, so this does nothing.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_index: usize) -> bool {
+	false
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_pin_current_thread() {
+		let cpus = allowed_cpus();
+		assert!(!cpus.is_empty());
+		let expected = cpus[1 % cpus.len()];
+		// Pin a separate thread to keep the test thread's affinity
+		let count = cpus.len();
+		let pinned = std::thread::spawn(move || {
+			assert!(pin_current_thread(count + 1));
+			allowed_cpus()
+		})
+		.join()
+		.unwrap();
+		assert_eq!(pinned, [expected]);
+	}
+}
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: LGPL-3.0-or-later
 //
 use libc::c_int;
-use std::io;
+use std::io::{self, BufRead, Read};
 use std::mem::size_of;
 use std::ops::Add;
 use std::time::{Duration, SystemTime};
@@ -28,6 +28,23 @@ impl TryFrom<u32> for Format {
 	}
 }
 
+impl Format {
+	/// Size of the format identifier at the start of each header file
+	const ID_SIZE: usize = size_of::<u32>();
+
+	/// Minimum size of a header file in this format
+	///
+	/// This is the size of the fixed prefix read by `parse`.
+	#[inline]
+	pub const fn size_of_header(self) -> usize {
+		Self::ID_SIZE
+			+ match self {
+				Self::Vary => 8,
+				Self::Disk => size_of::<c_int>() + size_of::<usize>() * 2 + 8 * 2,
+			}
+	}
+}
+
 /// Basic Apache cache header file information
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Header {
@@ -49,20 +66,47 @@ impl From<FormatError> for io::Error {
 	}
 }
 
+/// Error type for when a header file is shorter than its format requires.
+///
+/// Will usually be wrapped in a `io::Error` of type `InvalidData`.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("truncated apache cache header: expected at least {expected} bytes, got {actual}")]
+pub struct TruncatedError {
+	pub expected: usize,
+	pub actual: u64,
+}
+
+impl From<TruncatedError> for io::Error {
+	#[inline]
+	fn from(error: TruncatedError) -> Self {
+		io::Error::new(io::ErrorKind::InvalidData, error)
+	}
+}
+
 /// Read the format and expiration time from an Apache cache header file
-pub fn parse(mut f: impl io::Read) -> Result<Header, io::Error> {
-	let mut buffer = [0u8; 4];
+///
+/// If the file length `len` is known, it is checked against the size required
+/// by the format, failing with a `TruncatedError` for too short files.
+pub fn parse(mut f: impl io::Read, len: Option<u64>) -> Result<Header, io::Error> {
+	let check_len = |expected: usize| match len {
+		Some(actual) if actual < expected as u64 => Err(TruncatedError { expected, actual }),
+		_ => Ok(()),
+	};
+
+	check_len(Format::ID_SIZE)?;
+	let mut buffer = [0u8; Format::ID_SIZE];
 	f.read_exact(&mut buffer)?;
 	let format = Format::try_from(u32::from_ne_bytes(buffer))?;
+	check_len(format.size_of_header())?;
 
 	let microseconds = match format {
 		Format::Disk => {
-			let mut buffer = [0u8; size_of::<c_int>() + size_of::<usize>() * 2 + 8 * 2];
+			let mut buffer = [0u8; Format::Disk.size_of_header() - Format::ID_SIZE];
 			f.read_exact(&mut buffer)?;
 			u64::from_ne_bytes(buffer[buffer.len() - 8..].try_into().unwrap())
 		}
 		Format::Vary => {
-			let mut buffer = [0u8; 8];
+			let mut buffer = [0u8; Format::Vary.size_of_header() - Format::ID_SIZE];
 			f.read_exact(&mut buffer)?;
 			u64::from_ne_bytes(buffer)
 		}
@@ -74,6 +118,71 @@ pub fn parse(mut f: impl io::Read) -> Result<Header, io::Error> {
 	})
 }
 
+/// Size of Apache's `disk_cache_info_t` on 64-bit platforms, followed by the key
+const DISK_INFO_SIZE: usize = 120;
+/// Offset of the key length in `disk_cache_info_t`
+const DISK_NAME_LEN_OFFSET: usize = Format::ID_SIZE + size_of::<c_int>();
+/// Maximum size of the stored response headers read by `read_response_headers`
+const MAX_RESPONSE_HEADERS_SIZE: u64 = 64 * 1024;
+
+/// Reads the stored response headers from an Apache cache header file
+///
+/// In the disk format, the fixed `disk_cache_info_t` is followed by the key
+/// (the URL) and the response headers as `Name: value` lines up to an empty
+/// line. Files in the vary format don't store any headers.
+pub fn read_response_headers(mut f: impl BufRead) -> Result<Vec<(String, String)>, io::Error> {
+	let mut info = [0u8; DISK_INFO_SIZE];
+	f.read_exact(&mut info[..Format::ID_SIZE])?;
+	let format = Format::try_from(u32::from_ne_bytes(
+		info[..Format::ID_SIZE].try_into().unwrap(),
+	))?;
+	if format == Format::Vary {
+		return Ok(Vec::new());
+	}
+	f.read_exact(&mut info[Format::ID_SIZE..])?;
+	let name_len = usize::from_ne_bytes(
+		info[DISK_NAME_LEN_OFFSET..DISK_NAME_LEN_OFFSET + size_of::<usize>()]
+			.try_into()
+			.unwrap(),
+	);
+	io::copy(&mut (&mut f).take(name_len as u64), &mut io::sink())?;
+
+	let mut headers = Vec::new();
+	let mut lines = f.take(MAX_RESPONSE_HEADERS_SIZE).split(b'\n');
+	while let Some(line) = lines.next().transpose()? {
+		let line = String::from_utf8_lossy(&line);
+		let line = line.trim_end_matches('\r');
+		if line.is_empty() {
+			break;
+		}
+		if let Some((name, value)) = line.split_once(':') {
+			headers.push((name.to_owned(), value.trim().to_owned()));
+		}
+	}
+	Ok(headers)
+}
+
+/// Checks if stored response headers contain a validator (`ETag` or `Last-Modified`)
+///
+/// Entries with validators can be revalidated with a conditional request
+/// instead of being fetched again.
+pub fn has_validators(headers: &[(String, String)]) -> bool {
+	headers.iter().any(|(name, _)| {
+		name.eq_ignore_ascii_case("ETag") || name.eq_ignore_ascii_case("Last-Modified")
+	})
+}
+
+/// Reads up to `len` bytes from the start of a header file and formats them as hex
+pub fn hex_prefix(f: impl io::Read, len: usize) -> Result<String, io::Error> {
+	let mut buffer = Vec::with_capacity(len);
+	f.take(len as u64).read_to_end(&mut buffer)?;
+	Ok(buffer
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect::<Vec<_>>()
+		.join(" "))
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -88,7 +197,7 @@ mod tests {
 
 	#[test]
 	fn test_invalid_data() {
-		let error = parse([255u8, 255, 255, 255, 0, 0, 0, 0].as_ref()).unwrap_err();
+		let error = parse([255u8, 255, 255, 255, 0, 0, 0, 0].as_ref(), None).unwrap_err();
 
 		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
 		let inner_error: FormatError = *error.get_ref().unwrap().downcast_ref().unwrap();
@@ -98,10 +207,38 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_truncated_header() {
+		let data = std::fs::read("testcases/disk.header").unwrap();
+		assert!(data.len() >= Format::Disk.size_of_header());
+		let truncated = &data[..20];
+
+		let error = parse(truncated, Some(truncated.len() as u64)).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+		let inner_error: TruncatedError = *error.get_ref().unwrap().downcast_ref().unwrap();
+		assert_eq!(
+			inner_error,
+			TruncatedError {
+				expected: Format::Disk.size_of_header(),
+				actual: 20
+			}
+		);
+
+		let error = parse(truncated, None).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+	}
+
+	#[test]
+	fn test_hex_prefix() {
+		let data = [255u8, 0, 16, 1, 2, 3];
+		assert_eq!(hex_prefix(data.as_ref(), 4).unwrap(), "ff 00 10 01");
+		assert_eq!(hex_prefix(data.as_ref(), 64).unwrap(), "ff 00 10 01 02 03");
+	}
+
 	#[test]
 	fn test_vary_header() {
 		let file = File::open("testcases/vary.header").unwrap();
-		let header = parse(file).unwrap();
+		let header = parse(file, None).unwrap();
 
 		assert_eq!(header.format, Format::Vary);
 		assert_eq!(
@@ -114,10 +251,33 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_read_response_headers() {
+		let file = io::BufReader::new(File::open("testcases/disk.header").unwrap());
+		let headers = read_response_headers(file).unwrap();
+		assert_eq!(headers.len(), 8);
+		assert_eq!(
+			headers[0],
+			(
+				"Last-Modified".to_owned(),
+				"Fri, 21 May 2021 14:15:38 GMT".to_owned()
+			)
+		);
+		assert_eq!(
+			headers[7],
+			("Content-Type".to_owned(), "text/plain".to_owned())
+		);
+		assert!(has_validators(&headers));
+		assert!(!has_validators(&headers[2..]));
+
+		let file = io::BufReader::new(File::open("testcases/vary.header").unwrap());
+		assert!(read_response_headers(file).unwrap().is_empty());
+	}
+
 	#[test]
 	fn test_disk_header() {
 		let file = File::open("testcases/disk.header").unwrap();
-		let header = parse(file).unwrap();
+		let header = parse(file, None).unwrap();
 
 		assert_eq!(header.format, Format::Disk);
 		assert_eq!(
@@ -0,0 +1,66 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::fs::ReadDir;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{CacheFileInfo, CacheSuffixes};
+
+/// Iterator over all cache entries below a directory
+///
+/// Only reads the directory tree and the header files, nothing is deleted.
+#[derive(Debug)]
+pub struct CacheEntries {
+	stack: Vec<ReadDir>,
+	suffixes: Arc<CacheSuffixes>,
+}
+
+/// Returns an iterator over all cache entries below `path`
+pub fn iter_cache_entries(path: &Path) -> Result<CacheEntries, io::Error> {
+	iter_cache_entries_with_suffixes(path, &CacheSuffixes::shared_default())
+}
+
+/// Returns an iterator over all cache entries below `path` named with `suffixes`
+pub fn iter_cache_entries_with_suffixes(
+	path: &Path,
+	suffixes: &Arc<CacheSuffixes>,
+) -> Result<CacheEntries, io::Error> {
+	Ok(CacheEntries {
+		stack: vec![path.read_dir()?],
+		suffixes: Arc::clone(suffixes),
+	})
+}
+
+impl Iterator for CacheEntries {
+	type Item = Result<CacheFileInfo, io::Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some(dir) = self.stack.last_mut() {
+			let item = match dir.next() {
+				Some(Ok(item)) => item,
+				Some(Err(e)) => return Some(Err(e)),
+				None => {
+					self.stack.pop();
+					continue;
+				}
+			};
+
+			if let Some(name) = item.file_name().to_str() {
+				if name.ends_with(&self.suffixes.header) {
+					return Some(CacheFileInfo::with_options(&item, None, &self.suffixes));
+				}
+			}
+
+			// Recurse into directories (including vary directories)
+			if matches!(item.file_type(), Ok(t) if t.is_dir()) {
+				match item.path().read_dir() {
+					Ok(dir) => self.stack.push(dir),
+					Err(e) => return Some(Err(e)),
+				}
+			}
+		}
+		None
+	}
+}
@@ -2,48 +2,237 @@
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
 use std::cmp::{max, Eq, Ord, Ordering, PartialEq, PartialOrd};
-use std::fs::{DirEntry, OpenOptions};
-use std::io::Error as IOError;
-use std::os::unix::fs::OpenOptionsExt;
+use std::ffi::{OsStr, OsString};
+use std::fs::{symlink_metadata, DirEntry, File, Metadata, OpenOptions};
+use std::io::{BufReader, Error as IOError, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, SystemTime};
 
 use crate::apache_cache;
-use crate::CACHE_DATA_SUFFIX;
-use crate::CACHE_HEADER_VDIR_EXTENSION;
+use crate::syscall_timing::{timed, Syscall};
+use crate::{CACHE_DATA_SUFFIX, CACHE_HEADER_SUFFIX, CACHE_VDIR_SUFFIX};
 
-/// Basic information about a cache file entry
+/// Shared instance of the default suffixes
+static DEFAULT_SUFFIXES: LazyLock<Arc<CacheSuffixes>> =
+	LazyLock::new(|| Arc::new(CacheSuffixes::default()));
+
+/// File name suffixes of the parts of a cache entry
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheSuffixes {
+	/// Suffix of header files
+	pub header: String,
+	/// Suffix of data files, replacing the header suffix
+	pub data: String,
+	/// Suffix of vary directories, appended to the header file name
+	pub vary: String,
+}
+
+impl Default for CacheSuffixes {
+	fn default() -> Self {
+		Self {
+			header: CACHE_HEADER_SUFFIX.to_owned(),
+			data: CACHE_DATA_SUFFIX.to_owned(),
+			vary: CACHE_VDIR_SUFFIX.to_owned(),
+		}
+	}
+}
+
+impl CacheSuffixes {
+	/// Returns the shared instance of the default suffixes
+	pub fn shared_default() -> Arc<Self> {
+		Arc::clone(&DEFAULT_SUFFIXES)
+	}
+}
+
+/// Opens a header file for reading without updating its access time
+fn open_header(path: &Path) -> Result<File, IOError> {
+	let mut options = OpenOptions::new();
+	options.read(true);
+	// Never read through symbolic links, they can point anywhere
+	options.custom_flags(libc::O_NOATIME | libc::O_NOCTTY | libc::O_CLOEXEC | libc::O_NOFOLLOW);
+	timed(Syscall::Open, || options.open(path))
+}
+
+/// Difference between file size and allocated size above which a warning is logged
+const ALLOCATION_WARN_THRESHOLD: u64 = 1024 * 1024;
+
+/// Returns the disk space allocated to a file in bytes
+///
+/// Logs a warning if it differs significantly from the file size, which
+/// indicates preallocated or sparse files.
+fn allocated_size(path: &Path, metadata: &Metadata) -> u64 {
+	let allocated = metadata.blocks() * 512;
+	if allocated.abs_diff(metadata.len()) > ALLOCATION_WARN_THRESHOLD {
+		warn!(
+			path=?path,
+			"Allocated size of {:?} ({} bytes) differs from its file size ({} bytes), the file is preallocated or sparse",
+			path, allocated, metadata.len()
+		);
+	}
+	allocated
+}
+
+/// Returns the disk space allocated to a file in bytes, zero if it doesn't exist
+fn file_size(path: &Path) -> u64 {
+	timed(Syscall::Metadata, || symlink_metadata(path))
+		.map_or(0, |metadata| allocated_size(path, &metadata))
+}
+
+/// Basic information about a cache file entry
+#[derive(Debug, Clone)]
 pub struct CacheFileInfo {
 	header_path: PathBuf,
 	header_info: apache_cache::Header,
 	modified: SystemTime,
 	accessed: SystemTime,
+	fallback_ttl: Option<Duration>,
+	suffixes: Arc<CacheSuffixes>,
+	/// Result of `header_size`, known from the scan or read on first use
+	header_size: OnceLock<u64>,
+	/// Result of `data_size`, read on first use
+	data_size: OnceLock<u64>,
 }
 
+impl PartialEq for CacheFileInfo {
+	/// Compares everything but whether the sizes were read already
+	fn eq(&self, other: &Self) -> bool {
+		self.header_path == other.header_path
+			&& self.header_info == other.header_info
+			&& self.modified == other.modified
+			&& self.accessed == other.accessed
+			&& self.fallback_ttl == other.fallback_ttl
+			&& self.suffixes == other.suffixes
+	}
+}
+
+impl Eq for CacheFileInfo {}
+
 #[allow(dead_code)]
 impl CacheFileInfo {
 	#[inline]
 	pub fn new(header_entry: &DirEntry) -> Result<Self, IOError> {
-		let metadata = header_entry.metadata()?;
+		Self::with_options(header_entry, None, &CacheSuffixes::shared_default())
+	}
+
+	/// Reads a cache entry with non-default options
+	///
+	/// With `fallback_ttl` set, a header file that isn't in a known format is
+	/// treated as a plain (non-vary) entry expiring `fallback_ttl` after its
+	/// modification time instead of failing. The paths of the data file and
+	/// vary directory are derived with `suffixes`.
+	#[inline]
+	pub fn with_options(
+		header_entry: &DirEntry,
+		fallback_ttl: Option<Duration>,
+		suffixes: &Arc<CacheSuffixes>,
+	) -> Result<Self, IOError> {
+		Self::from_path(
+			header_entry.path(),
+			timed(Syscall::Metadata, || header_entry.metadata())?,
+			fallback_ttl,
+			Arc::clone(suffixes),
+		)
+	}
+
+	fn from_path(
+		header_path: PathBuf,
+		metadata: Metadata,
+		fallback_ttl: Option<Duration>,
+		suffixes: Arc<CacheSuffixes>,
+	) -> Result<Self, IOError> {
 		let modified = metadata.modified()?;
 		let accessed = metadata.accessed().unwrap_or(modified);
-		let header_path = header_entry.path();
+		let header_size = allocated_size(&header_path, &metadata);
 
-		let mut options = OpenOptions::new();
-		options.read(true);
-		options.custom_flags(libc::O_NOATIME | libc::O_NOCTTY | libc::O_CLOEXEC);
-		let mut file = options.open(&header_path)?;
-		let header_info = apache_cache::parse(&mut file)?;
+		let mut file = open_header(&header_path)?;
+		let header_info = match (
+			apache_cache::parse(&mut file, Some(metadata.len())),
+			fallback_ttl,
+		) {
+			(Ok(header_info), _) => header_info,
+			(Err(e), Some(ttl))
+				if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) =>
+			{
+				debug!(
+					path=?header_path,
+					"Using modification time plus {}s as expiry of {:?}: {}",
+					ttl.as_secs(), header_path, e
+				);
+				apache_cache::Header {
+					format: apache_cache::Format::Disk,
+					expiry: modified + ttl,
+				}
+			}
+			(Err(e), _) => return Err(e),
+		};
 
 		Ok(Self {
 			header_path,
 			header_info,
 			modified,
 			accessed,
+			fallback_ttl,
+			suffixes,
+			header_size: OnceLock::from(header_size),
+			data_size: OnceLock::new(),
 		})
 	}
 
+	/// Creates an entry from information read earlier, without accessing the files
+	pub(crate) fn from_parts(
+		header_path: PathBuf,
+		header_info: apache_cache::Header,
+		modified: SystemTime,
+		accessed: SystemTime,
+		fallback_ttl: Option<Duration>,
+		suffixes: Arc<CacheSuffixes>,
+	) -> Self {
+		Self {
+			header_path,
+			header_info,
+			modified,
+			accessed,
+			fallback_ttl,
+			suffixes,
+			header_size: OnceLock::new(),
+			data_size: OnceLock::new(),
+		}
+	}
+
+	/// Re-reads the header file and returns the current information
+	pub fn reparse(&self) -> Result<Self, IOError> {
+		Self::from_path(
+			self.header_path.clone(),
+			symlink_metadata(&self.header_path)?,
+			self.fallback_ttl,
+			Arc::clone(&self.suffixes),
+		)
+	}
+
+	/// Checks if the entry was refreshed by Apache since it was read
+	///
+	/// Returns `true` if the header file now sorts later (was modified, accessed or
+	/// got a new expiry) or can't be read anymore.
+	pub fn is_refreshed(&self) -> bool {
+		match self.reparse() {
+			Ok(current) => current > *self,
+			Err(_) => true,
+		}
+	}
+
+	/// Checks if the stored response headers contain a validator (`ETag` or `Last-Modified`)
+	///
+	/// Returns `false` if the headers can't be read.
+	pub fn has_validators(&self) -> bool {
+		open_header(&self.header_path)
+			.and_then(|file| apache_cache::read_response_headers(BufReader::new(file)))
+			.map(|headers| apache_cache::has_validators(&headers))
+			.unwrap_or(false)
+	}
+
 	/// Path to the `.header` file
 	#[inline]
 	pub fn header_path(&self) -> &Path {
@@ -53,17 +242,46 @@ impl CacheFileInfo {
 	/// Path to the associated `.data` file
 	#[inline]
 	pub fn data_path(&self) -> PathBuf {
-		let mut data_path = self.header_path.clone();
-		data_path.set_extension(&CACHE_DATA_SUFFIX[1..]);
-		data_path
+		let name = self.header_path.file_name().unwrap_or_default().as_bytes();
+		let stem = name
+			.strip_suffix(self.suffixes.header.as_bytes())
+			.unwrap_or(name);
+		let mut data_name = OsString::from(OsStr::from_bytes(stem));
+		data_name.push(&self.suffixes.data);
+		self.header_path.with_file_name(data_name)
+	}
+
+	/// Disk space allocated to the header and data file in bytes
+	///
+	/// Uses the allocated blocks instead of the file size, as that is what
+	/// deleting the files frees. Files that don't exist (anymore) count as zero.
+	/// See `header_size` and `data_size` for when the files are read.
+	pub fn disk_usage(&self) -> u64 {
+		self.header_size() + self.data_size()
+	}
+
+	/// Disk space allocated to the header file in bytes
+	///
+	/// Known from reading the header file, otherwise only read on the first call.
+	pub fn header_size(&self) -> u64 {
+		*self
+			.header_size
+			.get_or_init(|| file_size(&self.header_path))
+	}
+
+	/// Disk space allocated to the data file in bytes
+	///
+	/// Only read on the first call, later calls return the same size.
+	pub fn data_size(&self) -> u64 {
+		*self.data_size.get_or_init(|| file_size(&self.data_path()))
 	}
 
 	/// Path to the associated `.vary` directory
 	#[inline]
 	pub fn vary_path(&self) -> PathBuf {
-		let mut vary_path = self.header_path.clone();
-		vary_path.set_extension(CACHE_HEADER_VDIR_EXTENSION);
-		vary_path
+		let mut vary_path = self.header_path.clone().into_os_string();
+		vary_path.push(&self.suffixes.vary);
+		vary_path.into()
 	}
 
 	#[inline]
@@ -85,6 +303,15 @@ impl CacheFileInfo {
 	pub const fn is_vary(&self) -> bool {
 		matches!(self.header_info.format, apache_cache::Format::Vary)
 	}
+
+	/// Ordering only by access time, for pure LRU eviction
+	///
+	/// Ignores expiry and modification time. Tie breaking is done by comparing the path.
+	pub(crate) fn cmp_by_access_time(&self, other: &Self) -> Ordering {
+		self.accessed
+			.cmp(&other.accessed)
+			.then_with(|| self.header_path.cmp(&other.header_path))
+	}
 }
 
 impl PartialOrd<Self> for CacheFileInfo {
@@ -118,3 +345,63 @@ impl Ord for CacheFileInfo {
 		self.header_path.cmp(&other.header_path)
 	}
 }
+
+/// Order in which cache entries are considered for eviction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionStrategy {
+	/// Chronologically, see `CacheFileInfo::cmp`
+	#[default]
+	Lru,
+	/// Largest first
+	Size,
+	/// Largest product of size and idle time first
+	SizeAge,
+}
+
+/// Cache entry ordered by a size based score, highest first
+///
+/// Tie breaking is done chronologically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BySize {
+	score: u128,
+	pub fileinfo: CacheFileInfo,
+}
+
+impl BySize {
+	/// Scores the entry by its disk usage
+	pub fn new(fileinfo: CacheFileInfo) -> Self {
+		Self {
+			score: fileinfo.disk_usage().into(),
+			fileinfo,
+		}
+	}
+
+	/// Scores the entry by its disk usage times the seconds since its last access at `now`
+	pub fn weighted_by_age(fileinfo: CacheFileInfo, now: &SystemTime) -> Self {
+		let idle = now
+			.duration_since(*max(&fileinfo.accessed, &fileinfo.modified))
+			.unwrap_or_default()
+			.as_secs();
+		Self {
+			score: u128::from(fileinfo.disk_usage()) * (u128::from(idle) + 1),
+			fileinfo,
+		}
+	}
+}
+
+impl PartialOrd<Self> for BySize {
+	#[inline]
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for BySize {
+	#[inline]
+	fn cmp(&self, other: &Self) -> Ordering {
+		other
+			.score
+			.cmp(&self.score)
+			.then_with(|| self.fileinfo.cmp(&other.fileinfo))
+	}
+}
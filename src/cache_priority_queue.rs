@@ -1,17 +1,21 @@
 // Copyright (c) 2022 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
-use std::cmp::Ord;
-use std::collections::BinaryHeap;
+use std::cmp::{Ord, Ordering};
+
+/// Comparator of a `CachePriorityQueue` ordering by the [`Ord`] trait
+pub type NaturalOrder<T> = fn(&T, &T) -> Ordering;
 
 #[derive(Debug, Clone)]
-pub struct CachePriorityQueue<T> {
-	heap: BinaryHeap<T>,
+pub struct CachePriorityQueue<T, F = NaturalOrder<T>> {
+	/// Binary max-heap according to `cmp`
+	heap: Vec<T>,
 	limit: usize,
+	cmp: F,
 }
 
 #[allow(dead_code)]
-impl<T> CachePriorityQueue<T> {
+impl<T, F> CachePriorityQueue<T, F> {
 	#[inline]
 	pub fn len(&self) -> usize {
 		self.heap.len()
@@ -32,16 +36,13 @@ impl<T> CachePriorityQueue<T> {
 ///
 /// Discards the largest (according to the [`Ord`] trait) items if the limit is reached.
 ///
-/// Implemented with a [`BinaryHeap`].
+/// Implemented as a binary heap, see `with_comparator` for other orderings.
 #[allow(dead_code)]
 impl<T: Ord> CachePriorityQueue<T> {
 	/// Creates an empty queue that keeps at most `limit` items.
 	#[must_use]
 	pub fn new(limit: usize) -> Self {
-		Self {
-			heap: BinaryHeap::new(),
-			limit,
-		}
+		Self::with_comparator(limit, T::cmp)
 	}
 
 	/// Creates an empty queue with a specific capacity.
@@ -52,39 +53,114 @@ impl<T: Ord> CachePriorityQueue<T> {
 	/// Panics if `capacity` is larger than `limit`.
 	#[must_use]
 	pub fn with_capacity(capacity: usize, limit: usize) -> Self {
-		assert!(capacity <= limit);
+		let mut queue = Self::new(limit);
+		queue.reserve(capacity);
+		queue
+	}
+}
+
+/// A priority queue that keeps a limited amount of items, ordered by `cmp`.
+///
+/// Discards the largest items according to `cmp` if the limit is reached.
+#[allow(dead_code)]
+impl<T, F: Fn(&T, &T) -> Ordering> CachePriorityQueue<T, F> {
+	/// Creates an empty queue that keeps at most `limit` items, ordered by `cmp`.
+	#[must_use]
+	pub fn with_comparator(limit: usize, cmp: F) -> Self {
 		Self {
-			heap: BinaryHeap::with_capacity(capacity),
+			heap: Vec::new(),
 			limit,
+			cmp,
 		}
 	}
 
+	/// Preallocates memory for `capacity` elements.
+	///
+	/// # Panics
+	/// Panics if `capacity` is larger than `limit`.
+	pub fn reserve(&mut self, capacity: usize) {
+		assert!(capacity <= self.limit);
+		self.heap.reserve(capacity);
+	}
+
 	/// Pushes an item into the queue.
 	///
 	/// If the limit is reached, the smallest item is removed from the queue
 	/// before pushing the new `item`. If the new item is larger than any
 	/// item in the queue, it is dropped instead.
+	///
+	/// Returns the removed or dropped item, if any.
 	#[inline]
-	pub fn push(&mut self, item: T) {
+	pub fn push(&mut self, item: T) -> Option<T> {
+		let mut discarded = None;
 		// If the limit is reached
 		if self.heap.len() >= self.limit {
 			// If the new element would be the one we would pop(), don't insert
-			if let Some(element) = self.heap.peek() {
-				if &item > element {
-					return;
+			if let Some(element) = self.heap.first() {
+				if (self.cmp)(&item, element) == Ordering::Greater {
+					return Some(item);
 				}
 			}
 			// Otherwise pop() one before inserting
-			self.heap.pop();
+			discarded = self.pop();
 		}
 		// Insert new element
 		self.heap.push(item);
+		self.sift_up(self.heap.len() - 1);
+		discarded
+	}
+
+	/// Removes the largest item from the queue and returns it.
+	pub fn pop(&mut self) -> Option<T> {
+		if self.heap.is_empty() {
+			return None;
+		}
+		let item = self.heap.swap_remove(0);
+		self.sift_down(0);
+		Some(item)
 	}
 
 	/// Consumes the `CachePriorityQueue` and returns a vector in sorted (ascending) order.
 	#[inline]
 	pub fn into_sorted_vec(self) -> Vec<T> {
-		self.heap.into_sorted_vec()
+		let mut items = self.heap;
+		items.sort_unstable_by(&self.cmp);
+		items
+	}
+
+	/// Moves the item at `index` up until its parent isn't smaller
+	fn sift_up(&mut self, mut index: usize) {
+		while index > 0 {
+			let parent = (index - 1) / 2;
+			if (self.cmp)(&self.heap[index], &self.heap[parent]) != Ordering::Greater {
+				break;
+			}
+			self.heap.swap(index, parent);
+			index = parent;
+		}
+	}
+
+	/// Moves the item at `index` down until none of its children is larger
+	fn sift_down(&mut self, mut index: usize) {
+		loop {
+			let left = 2 * index + 1;
+			if left >= self.heap.len() {
+				break;
+			}
+			let right = left + 1;
+			let larger = if right < self.heap.len()
+				&& (self.cmp)(&self.heap[right], &self.heap[left]) == Ordering::Greater
+			{
+				right
+			} else {
+				left
+			};
+			if (self.cmp)(&self.heap[larger], &self.heap[index]) != Ordering::Greater {
+				break;
+			}
+			self.heap.swap(index, larger);
+			index = larger;
+		}
 	}
 }
 
@@ -101,18 +177,55 @@ mod tests {
 		assert_eq!(h.len(), 3);
 		assert_eq!(&h.into_sorted_vec(), &[1, 5, 7]);
 
+		let mut h = CachePriorityQueue::new(2);
+		assert_eq!(h.push(5), None);
+		assert_eq!(h.push(7), None);
+		assert_eq!(h.push(1), Some(7));
+		assert_eq!(h.push(9), Some(9));
+		assert_eq!(h.len(), 2);
+		assert_eq!(&h.into_sorted_vec(), &[1, 5]);
+
 		let mut h = CachePriorityQueue::new(2);
 		h.push(5);
+		h.clear();
+		assert_eq!(h.len(), 0);
+		assert!(h.into_sorted_vec().is_empty());
+	}
+
+	#[test]
+	fn test_queue_with_comparator() {
+		let reversed = |a: &i32, b: &i32| b.cmp(a);
+		let mut h = CachePriorityQueue::with_comparator(4, reversed);
+		h.push(5);
 		h.push(7);
 		h.push(1);
-		h.push(9);
+		assert_eq!(h.len(), 3);
+		assert_eq!(&h.into_sorted_vec(), &[7, 5, 1]);
+
+		// The smallest numbers are the largest items and dropped first
+		let mut h = CachePriorityQueue::with_comparator(2, reversed);
+		assert_eq!(h.push(5), None);
+		assert_eq!(h.push(1), None);
+		assert_eq!(h.push(7), Some(1));
+		assert_eq!(h.push(0), Some(0));
 		assert_eq!(h.len(), 2);
-		assert_eq!(&h.into_sorted_vec(), &[1, 5]);
+		assert_eq!(&h.into_sorted_vec(), &[7, 5]);
 
-		let mut h = CachePriorityQueue::new(2);
+		let mut h = CachePriorityQueue::with_comparator(2, reversed);
 		h.push(5);
 		h.clear();
 		assert_eq!(h.len(), 0);
-		assert_eq!(&h.into_sorted_vec(), &[]);
+		assert!(h.into_sorted_vec().is_empty());
+	}
+
+	#[test]
+	fn test_queue_keeps_smallest() {
+		let mut h = CachePriorityQueue::new(10);
+		let mut items: Vec<u32> = (0..100).map(|i| i * 37 % 101).collect();
+		for &item in &items {
+			h.push(item);
+		}
+		items.sort_unstable();
+		assert_eq!(h.into_sorted_vec(), items[..10]);
 	}
 }
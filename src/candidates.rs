@@ -0,0 +1,276 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::apache_cache::{Format, Header};
+use crate::{CacheFileInfo, CacheSuffixes};
+
+/// Converts a time to (possibly negative) nanoseconds since the Unix epoch
+fn unix_nanos(time: &SystemTime) -> i128 {
+	match time.duration_since(UNIX_EPOCH) {
+		Ok(duration) => duration.as_nanos() as i128,
+		Err(e) => -(e.duration().as_nanos() as i128),
+	}
+}
+
+/// Converts nanoseconds since the Unix epoch back to a time
+fn from_unix_nanos(nanos: i128) -> Option<SystemTime> {
+	let duration = |nanos: u128| {
+		let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+		Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+	};
+	if nanos >= 0 {
+		UNIX_EPOCH.checked_add(duration(nanos.unsigned_abs())?)
+	} else {
+		UNIX_EPOCH.checked_sub(duration(nanos.unsigned_abs())?)
+	}
+}
+
+/// Writes the cache entries to delete, in order, for a later `read_candidates`
+///
+/// Each line contains the expiry, modification and access time in nanoseconds
+/// since the Unix epoch, the format (`disk` or `vary`) and the header path
+/// relative to `root`, separated by tabs. Returns the number of written entries.
+pub fn write_candidates<'a>(
+	root: &Path,
+	entries: impl IntoIterator<Item = &'a CacheFileInfo>,
+	mut writer: impl Write,
+) -> Result<u64, io::Error> {
+	let mut count = 0;
+	for fileinfo in entries {
+		let path = fileinfo.header_path().strip_prefix(root).map_err(|_| {
+			io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!(
+					"cache entry {:?} is outside of {:?}",
+					fileinfo.header_path(),
+					root
+				),
+			)
+		})?;
+		write!(
+			writer,
+			"{}\t{}\t{}\t{}\t",
+			unix_nanos(fileinfo.expires()),
+			unix_nanos(fileinfo.modified()),
+			unix_nanos(fileinfo.accessed()),
+			if fileinfo.is_vary() { "vary" } else { "disk" }
+		)?;
+		writer.write_all(path.as_os_str().as_bytes())?;
+		writer.write_all(b"\n")?;
+		count += 1;
+	}
+	writer.flush()?;
+	Ok(count)
+}
+
+/// Reads cache entries written by `write_candidates`
+///
+/// The entries keep the information from when they were written. Entries
+/// changed since then are detected by re-reading them before deleting, see
+/// `Config::revalidate`. The paths are resolved relative to `root`; absolute
+/// paths and paths leaving `root` are rejected, as the entries get deleted.
+pub fn read_candidates(
+	reader: impl BufRead,
+	root: &Path,
+	fallback_ttl: Option<Duration>,
+	suffixes: &Arc<CacheSuffixes>,
+) -> Result<Vec<CacheFileInfo>, io::Error> {
+	let mut entries = Vec::new();
+	for (index, line) in reader.split(b'\n').enumerate() {
+		let line = line?;
+		let invalid = || {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("invalid candidate in line {}", index + 1),
+			)
+		};
+		let mut fields = line.splitn(5, |&b| b == b'\t');
+		let mut time = || {
+			let field = std::str::from_utf8(fields.next()?).ok()?;
+			from_unix_nanos(field.parse().ok()?)
+		};
+		let (expiry, modified, accessed) = (
+			time().ok_or_else(invalid)?,
+			time().ok_or_else(invalid)?,
+			time().ok_or_else(invalid)?,
+		);
+		let format = match fields.next() {
+			Some(b"disk") => Format::Disk,
+			Some(b"vary") => Format::Vary,
+			_ => return Err(invalid()),
+		};
+		let path = match fields.next() {
+			Some(path) if !path.is_empty() => Path::new(OsStr::from_bytes(path)),
+			_ => return Err(invalid()),
+		};
+		if !path
+			.components()
+			.all(|c| matches!(c, Component::CurDir | Component::Normal(_)))
+		{
+			return Err(invalid());
+		}
+		let path: PathBuf = root.join(path);
+		if !path.starts_with(root) {
+			return Err(invalid());
+		}
+		entries.push(CacheFileInfo::from_parts(
+			path,
+			Header { format, expiry },
+			modified,
+			accessed,
+			fallback_ttl,
+			Arc::clone(suffixes),
+		));
+	}
+	Ok(entries)
+}
+
+/// Difference between two candidate lists by header path
+#[derive(Debug, Default)]
+pub struct CandidateDiff<'a> {
+	/// Entries only in the current list, i.e. newly eligible for deletion
+	pub added: Vec<&'a CacheFileInfo>,
+	/// Entries only in the previous list, e.g. deleted in the meantime
+	pub removed: Vec<&'a CacheFileInfo>,
+}
+
+/// Lists the added entries prefixed with `+ `, then the removed ones with `- `
+impl fmt::Display for CandidateDiff<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (prefix, entries) in [('+', &self.added), ('-', &self.removed)] {
+			for fileinfo in entries {
+				writeln!(f, "{} {}", prefix, fileinfo.header_path().display())?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Compares the candidate list of an earlier scan with the current one
+///
+/// Both diffs keep the order of their list.
+pub fn diff_candidates<'a>(
+	previous: &'a [CacheFileInfo],
+	current: &'a [CacheFileInfo],
+) -> CandidateDiff<'a> {
+	let paths = |entries: &'a [CacheFileInfo]| -> HashSet<&'a Path> {
+		entries.iter().map(|e| e.header_path()).collect()
+	};
+	let (previous_paths, current_paths) = (paths(previous), paths(current));
+	CandidateDiff {
+		added: current
+			.iter()
+			.filter(|e| !previous_paths.contains(e.header_path()))
+			.collect(),
+		removed: previous
+			.iter()
+			.filter(|e| !current_paths.contains(e.header_path()))
+			.collect(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::iter_cache_entries;
+	use std::fs::{copy, create_dir_all};
+	use tempfile::TempDir;
+
+	#[test]
+	fn test_candidates_round_trip() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		copy("testcases/disk.header", sub.join("disk.header")).unwrap();
+		copy("testcases/vary.header", sub.join("with\ttab.header")).unwrap();
+		let mut entries: Vec<_> = iter_cache_entries(dir.path()).unwrap().flatten().collect();
+		entries.sort();
+
+		let mut output = Vec::new();
+		assert_eq!(
+			write_candidates(dir.path(), &entries, &mut output).unwrap(),
+			2
+		);
+		assert!(String::from_utf8_lossy(&output).contains("\tab/disk.header\n"));
+		let read = read_candidates(
+			&output[..],
+			dir.path(),
+			None,
+			&CacheSuffixes::shared_default(),
+		)
+		.unwrap();
+		assert_eq!(read, entries);
+		let error = write_candidates(Path::new("/other"), &entries, &mut Vec::new()).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+		for invalid in [
+			&b"1\t2\tdisk\t/a.header\n"[..],
+			b"1\t2\t3\tother\t/a.header\n",
+			b"1\t2\t3\tdisk\t\n",
+			b"x\t2\t3\tdisk\t/a.header\n",
+			// Paths outside of the cache root
+			b"1\t2\t3\tdisk\t/a.header\n",
+			b"1\t2\t3\tdisk\t../a.header\n",
+			b"1\t2\t3\tdisk\tab/../../a.header\n",
+		] {
+			let error =
+				read_candidates(invalid, dir.path(), None, &CacheSuffixes::shared_default())
+					.unwrap_err();
+			assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+		}
+	}
+
+	#[test]
+	fn test_diff_candidates() {
+		let list = |paths: &[&str]| {
+			let lines: String = paths
+				.iter()
+				.map(|path| format!("1\t2\t3\tdisk\t{}\n", path))
+				.collect();
+			read_candidates(
+				lines.as_bytes(),
+				Path::new("/"),
+				None,
+				&CacheSuffixes::shared_default(),
+			)
+			.unwrap()
+		};
+		let previous = list(&["a.header", "b.header", "./c.header"]);
+		let current = list(&["b.header", "d.header", "c.header", "e.header"]);
+		let names = |entries: Vec<&CacheFileInfo>| -> Vec<_> {
+			entries.iter().map(|e| e.header_path().to_owned()).collect()
+		};
+
+		let diff = diff_candidates(&previous, &current);
+		assert_eq!(diff.to_string(), "+ /d.header\n+ /e.header\n- /a.header\n");
+		assert_eq!(
+			names(diff.added),
+			[Path::new("/d.header"), Path::new("/e.header")]
+		);
+		assert_eq!(names(diff.removed), [Path::new("/a.header")]);
+
+		let diff = diff_candidates(&current, &current);
+		assert!(diff.added.is_empty() && diff.removed.is_empty());
+		assert_eq!(diff.to_string(), "");
+	}
+
+	#[test]
+	fn test_unix_nanos() {
+		for time in [
+			UNIX_EPOCH,
+			UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+			UNIX_EPOCH - Duration::new(3600, 1),
+		] {
+			assert_eq!(from_unix_nanos(unix_nanos(&time)), Some(time));
+		}
+	}
+}
@@ -0,0 +1,112 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of the current time
+pub trait Clock: Debug + Send + Sync {
+	/// Returns the current wall clock time
+	fn now(&self) -> SystemTime;
+
+	/// Returns the current monotonic time for measuring durations
+	fn instant(&self) -> Instant;
+}
+
+/// `Clock` using the system clocks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	#[inline]
+	fn now(&self) -> SystemTime {
+		SystemTime::now()
+	}
+
+	#[inline]
+	fn instant(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// `Clock` with a fixed wall clock time, for evaluating the cache "as of" another time
+///
+/// Durations are still measured with the monotonic system clock.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+	#[inline]
+	fn now(&self) -> SystemTime {
+		self.0
+	}
+
+	#[inline]
+	fn instant(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// `Clock` that only advances when told to, for deterministic tests
+#[derive(Debug)]
+pub struct MockClock {
+	epoch: SystemTime,
+	start: Instant,
+	elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+	/// Creates a clock that starts at `epoch`
+	pub fn new(epoch: SystemTime) -> Self {
+		Self {
+			epoch,
+			start: Instant::now(),
+			elapsed: Mutex::new(Duration::ZERO),
+		}
+	}
+
+	/// Advances the clock by `duration`
+	pub fn advance(&self, duration: Duration) {
+		*self.elapsed.lock().unwrap() += duration;
+	}
+}
+
+impl Clock for MockClock {
+	fn now(&self) -> SystemTime {
+		self.epoch + *self.elapsed.lock().unwrap()
+	}
+
+	fn instant(&self) -> Instant {
+		self.start + *self.elapsed.lock().unwrap()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::UNIX_EPOCH;
+
+	#[test]
+	fn test_mock_clock() {
+		let epoch = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let clock = MockClock::new(epoch);
+		let start = clock.instant();
+		assert_eq!(clock.now(), epoch);
+		assert_eq!(clock.instant(), start);
+
+		clock.advance(Duration::from_secs(90));
+		assert_eq!(clock.now(), epoch + Duration::from_secs(90));
+		assert_eq!(clock.instant() - start, Duration::from_secs(90));
+	}
+
+	#[test]
+	fn test_fixed_clock() {
+		let epoch = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let clock = FixedClock(epoch);
+		let start = clock.instant();
+		std::thread::sleep(Duration::from_millis(10));
+		assert_eq!(clock.now(), epoch);
+		assert!(clock.instant() - start >= Duration::from_millis(10));
+	}
+}
@@ -1,14 +1,24 @@
 // Copyright (c) 2022 Papoo Software & Media GmbH <info@papoo.de>
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use crate::AutoSizeSpec;
 use crate::SizeSpec;
+use crate::Units;
+use crate::duration_spec::DurationSpec;
 use crate::job_count::JobCount;
+use crate::length_range::LengthRange;
+use crate::timestamp::Timestamp;
 use clap::Parser;
+use clap::builder::NonEmptyStringValueParser;
+use glob::Pattern;
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 /// Program for cleaning the Apache disk cache.
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
+#[clap(group(clap::ArgGroup::new("time_report").multiple(true)))]
 pub struct Args {
 	/// Root directory of the disk cache.
 	#[clap(short, long)]
@@ -16,22 +26,593 @@ pub struct Args {
 	/// Minimum free disk space to keep. Attach 'K', 'M', 'G', 'T' or '%' to
 	/// specify Kilobytes, Megabytes, Gigabytes, Terabytes or a percentage
 	/// of the total disk size. Attach `Ki`, `Mi`, etc. to specify Kibibytes, Mebibytes, etc.
-	#[clap(short='f', long, value_name="BYTES|PERCENT", default_value_t=SizeSpec::Percentage(10.0))]
-	pub min_free_space: SizeSpec,
+	/// Combine a percentage and an absolute value (e.g. `10%,20G`) to keep whichever is larger.
+	/// Use `auto` to keep twice the largest growth between two runs recorded in --usage-history
+	/// (at least 1%, 10% until two runs are recorded).
+	#[clap(short='f', long, value_name="BYTES|PERCENT|auto", default_value_t=AutoSizeSpec::Fixed(SizeSpec::Percentage(10.0)))]
+	pub min_free_space: AutoSizeSpec,
 
 	/// Minimum free inodes to keep. Attach 'K', 'M', 'G', 'T' or '%' to
 	/// specify thousands, millions, billions, trillions or a percentage
-	/// of the total disk inodes.
+	/// of the total disk inodes. Combine a percentage and an absolute value
+	/// (e.g. `5%,1M`) to keep whichever is larger.
 	#[clap(short='F', long, value_name="COUNT|PERCENT", default_value_t=SizeSpec::Percentage(5.0))]
 	pub min_free_inodes: SizeSpec,
 
+	/// Ignore the inode limit.
+	///
+	/// Useful for filesystems without meaningful inode counts. Btrfs is detected automatically.
+	#[clap(long)]
+	pub no_inode_check: bool,
+
 	/// Jobs to run simultaneously. ('auto' for automatic selection based on available CPUs)
 	///
 	/// Use `-j1` for slow storage devices where parallel accesses slow down too much.
 	#[clap(short, long, default_value_t = JobCount::Auto)]
 	pub jobs: JobCount,
 
+	/// Jobs to run simultaneously while scanning. (defaults to --jobs)
+	#[clap(long, value_name = "JOBS")]
+	pub scan_jobs: Option<JobCount>,
+
+	/// Jobs to run simultaneously while deleting cache entries. (defaults to --jobs)
+	#[clap(long, value_name = "JOBS")]
+	pub delete_jobs: Option<JobCount>,
+
+	/// Maximum number of cache entries to delete between usage checks.
+	///
+	/// Larger batches need fewer `statfs` calls, but may delete more than necessary.
+	#[clap(long, value_name = "COUNT", default_value_t = 10)]
+	pub delete_batch_size: usize,
+
+	/// Pin each scanning thread to its own CPU.
+	///
+	/// Improves cache locality on servers with many cores or NUMA nodes.
+	/// Ignored where thread affinity isn't supported.
+	#[clap(long)]
+	pub pin_threads: bool,
+
+	/// Maximum number of header files opened and parsed at once across all scanning threads.
+	///
+	/// Throttles the metadata-heavy part of the scan on storage suffering from many
+	/// concurrent reads, independently of the number of scanning jobs.
+	#[clap(long, value_name = "COUNT")]
+	pub parse_concurrency: Option<NonZeroUsize>,
+
+	/// Limit the number of header files parsed at once to stay within the open files limit.
+	///
+	/// Avoids failures from running out of file descriptors with many jobs and a low
+	/// `ulimit -n`. Warns if the limit is too low to parse in all jobs at once.
+	#[clap(long)]
+	pub limit_open_files: bool,
+
+	/// Treat vary directories as plain directories.
+	///
+	/// Disables the special handling of content negotiation entries. Only use
+	/// this if the cache doesn't contain any vary entries.
+	#[clap(long)]
+	pub ignore_vary: bool,
+
+	/// Never evict cache entries matching this glob pattern (relative to the cache root).
+	///
+	/// Orphaned and temporary files are still cleaned up. Can be given multiple times.
+	#[clap(long, value_name = "GLOB")]
+	pub preserve: Vec<Pattern>,
+
+	/// Treat directories with names matching this glob pattern as temporary directories, e.g. `*.tmp`.
+	///
+	/// They aren't scanned, but skipped while recently modified and deleted with their
+	/// contents once stale, unless they contain files matching --preserve or
+	/// --max-delete-bytes is given. Can be given multiple times.
+	#[clap(long, value_name = "GLOB")]
+	pub temp_dir_pattern: Vec<Pattern>,
+
+	/// Lengths of the random suffix after `aptmp` in names of Apache's temporary files.
+	///
+	/// Given as `MIN-MAX` or a single length.
+	#[clap(long, value_name = "MIN-MAX", default_value = "6-12")]
+	pub tempfile_suffix_lengths: LengthRange,
+
+	/// Skip hidden files and directories (names starting with a dot).
+	///
+	/// Avoids recursing into foreign directories like `.snapshot` or `.git`.
+	#[clap(long)]
+	pub no_recurse_hidden: bool,
+
+	/// Re-read each cache entry before deleting it and skip it if Apache refreshed it in the meantime.
+	#[clap(long)]
+	pub revalidate: bool,
+
+	/// Log the first bytes of unparseable header files as hex (at debug level).
+	#[clap(long)]
+	pub dump_header_hex: bool,
+
+	/// Only report percentiles of the cache entry age and time to expiry, don't delete anything.
+	#[clap(long, group = "time_report")]
+	pub report_age_distribution: bool,
+
+	/// Also report histograms with these bucket boundaries for --report-age-distribution (e.g. 1h,6h,1d,7d,30d).
+	///
+	/// Durations are whole numbers with an optional unit s, m, h, d or w (seconds by default).
+	#[clap(long, alias = "buckets", value_name = "DURATIONS", value_delimiter = ',')]
+	pub histogram_buckets: Vec<DurationSpec>,
+
+	/// Only report cache entries that expired more than SECONDS ago (default one week), don't delete anything.
+	///
+	/// Many long expired entries indicate that runs don't keep up or that parts of
+	/// the cache are never reached.
+	#[clap(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "604800", group = "time_report")]
+	pub report_expiry_gaps: Option<u64>,
+
+	/// Only report the usage and the disk space per cache entry and inode, don't delete anything.
+	///
+	/// Helps to judge whether `CacheDirLevels` and `CacheDirLength` waste inodes.
+	#[clap(long)]
+	pub report_inode_efficiency: bool,
+
+	/// Only report temporary directories (see --temp-dir-pattern) and hidden directories, don't delete anything.
+	///
+	/// Lists Apache's rehash directories and the like, which aren't scanned as
+	/// cache directories, and whether they are still active.
+	#[clap(long, group = "time_report")]
+	pub scan_hidden_report: bool,
+
+	/// Only print the raw `statfs` figures of the cache filesystem and the computed targets, don't delete anything.
+	///
+	/// Helps to debug unexpected usage percentages on exotic filesystems.
+	#[clap(long)]
+	pub dump_fs_info: bool,
+
+	/// Only write one JSON object per cache entry to FILE ('-' for stdout), don't delete anything.
+	///
+	/// Each object contains the header path, format, expiry, modification and access
+	/// time (in Unix seconds) and the allocated size in bytes.
+	#[cfg(feature = "serde")]
+	#[clap(long, value_name = "FILE")]
+	pub export_entries: Option<PathBuf>,
+
+	/// Only clean up and scan the cache, writing the cache entries to delete to FILE.
+	///
+	/// The entries can be deleted later with --delete-phase-only, e.g. to scan
+	/// during low-traffic hours. The scan runs regardless of the current usage.
+	#[clap(long, value_name = "FILE", conflicts_with = "delete_phase_only")]
+	pub scan_phase_only: Option<PathBuf>,
+
+	/// Compare the candidate list of --scan-phase-only with the one in FILE from an earlier scan.
+	///
+	/// Prints the cache entries that became eligible for deletion prefixed with `+ `
+	/// and those no longer present prefixed with `- `, to follow the churn of the
+	/// cache. FILE may be the same as the
+	/// one given to --scan-phase-only, as it's read before being overwritten.
+	#[clap(long, value_name = "FILE", requires = "scan_phase_only")]
+	pub diff_candidates: Option<PathBuf>,
+
+	/// Only delete the cache entries listed in FILE by --scan-phase-only.
+	///
+	/// Entries changed or deleted since the scan are skipped (implies --revalidate).
+	/// Paths outside of the cache directory are rejected, and --min-entry-count
+	/// applies to the number of listed entries.
+	#[clap(long, value_name = "FILE")]
+	pub delete_phase_only: Option<PathBuf>,
+
+	/// Only parse cache entries in directories modified since the last run recorded in STATE_FILE.
+	///
+	/// Temporary and orphaned files are still cleaned up everywhere. Entries in unchanged
+	/// directories are taken from the list of entries in STATE_FILE instead, with the access
+	/// times of that run. Everything is scanned if STATE_FILE doesn't exist yet. STATE_FILE
+	/// is only updated by runs that scan the cache.
+	#[clap(long, value_name = "STATE_FILE")]
+	pub scan_only_changed: Option<PathBuf>,
+
+	/// Skip directories modified within the last SECONDS, as Apache is probably writing to them.
+	#[clap(long, value_name = "SECONDS")]
+	pub exclude_recent_dirs: Option<u64>,
+
+	/// Never delete cache entries written within DURATION (e.g. 90, 30m, 6h), even in desperate mode.
+	///
+	/// Avoids cache stampedes from evicting entries Apache just stored.
+	#[clap(long, value_name = "DURATION")]
+	pub min_lifetime: Option<DurationSpec>,
+
+	/// Protect the cache entries listed in FILE from eviction, unless in desperate mode.
+	///
+	/// FILE contains one cache key (e.g. `http://example.com:80/index.html?`) or hashed
+	/// cache file path per line.
+	#[clap(long, value_name = "FILE")]
+	pub hot_list: Option<PathBuf>,
+
+	/// Limit the size of top-level cache directories as listed in FILE.
+	///
+	/// FILE contains a directory name prefix and a size (like `--min-free-space`) per
+	/// line, e.g. `tenant-a 10G`. The oldest cache entries of each matching directory
+	/// over its quota are deleted, regardless of the free space. Entries kept by
+	/// --entry-filter or --protect-largest-percentile aren't deleted, but count
+	/// towards the quota. Deleted entries count towards --max-delete-bytes.
+	#[clap(long, value_name = "FILE")]
+	pub quota_file: Option<PathBuf>,
+
+	/// Keep cache entries whose stored response headers contain an `ETag` or `Last-Modified` validator.
+	///
+	/// Such entries can be cheaply revalidated by Apache instead of being fetched again.
+	/// They are still deleted in desperate mode.
+	#[clap(long)]
+	pub exclude_if_revalidatable: bool,
+
+	/// Let an external command decide which cache entries may be deleted.
+	///
+	/// COMMAND is run with `sh -c` in the cache directory for batches of entries. It gets
+	/// one line per entry on stdin with the header path, the expiry as Unix time and the
+	/// disk usage in bytes, separated by tabs, and has to answer with one line per entry,
+	/// either `keep` or `delete`. The run fails if the command fails or answers invalidly.
+	#[clap(long, alias = "filter-cmd", value_name = "COMMAND")]
+	pub entry_filter_script: Option<String>,
+
+	/// Check that the usage is at most PERCENT of the limits after the run and exit with an error if not.
+	///
+	/// For automated tests of the cleanup, uses the same usage percentage as the logged usage.
+	#[clap(long, value_name = "PERCENT")]
+	pub verify_free_space_after: Option<f64>,
+
+	/// Stop at the first error when deleting files or folders and exit with an error.
+	///
+	/// Same as `--on-error abort`. Unparsable header files are only counted.
+	#[clap(long, conflicts_with = "on_error")]
+	pub fail_fast: bool,
+
+	/// What to do on errors deleting files or folders or scanning subdirectories.
+	#[clap(long, value_enum, value_name = "POLICY", default_value_t)]
+	pub on_error: ErrorPolicy,
+
+	/// Log the statistics and summaries of the work done even if the run fails.
+	#[clap(long)]
+	pub output_summary_on_failure: bool,
+
+	/// Keep deleting until the usage is comfortably below the limits.
+	///
+	/// Normally deletion stops as soon as the usage drops just below the limits, so
+	/// the next run may have to delete again soon. This mode frees some headroom.
+	#[clap(long)]
+	pub aggressive: bool,
+
+	/// Keep empty cache directories instead of deleting them.
+	#[clap(long)]
+	pub no_delete_folders: bool,
+
+	/// Remove all empty cache directories bottom-up after deleting.
+	///
+	/// Directories emptied by deleting cache entries are otherwise only removed by
+	/// a later run. Reclaims their inodes right away.
+	#[clap(long, conflicts_with = "no_delete_folders")]
+	pub remove_empty_dirs: bool,
+
+	/// Scan the cache even if its filesystem is mounted read-only.
+	///
+	/// Normally the run is aborted before scanning, as all deletions would fail.
+	#[clap(long)]
+	pub ignore_readonly: bool,
+
+	/// Clean up old temporary files in the cache root once more at the end of the run.
+	///
+	/// Catches temporary files Apache created before or while deleting that are
+	/// old enough by then, without scanning the cache again.
+	#[clap(long)]
+	pub follow_up_run: bool,
+
+	/// Allocate memory for the deletion queue upfront, estimating the number of cache
+	/// entries from the used inodes of the filesystem.
+	///
+	/// Avoids reallocations while scanning large caches, at the cost of overestimating
+	/// on filesystems shared with other data.
+	#[clap(long)]
+	pub preallocate_queue_from_statfs: bool,
+
+	/// Stop scanning after this number of cache entries.
+	///
+	/// Bounds the runtime and memory use on very large caches. Only the entries
+	/// found until then are considered for deletion.
+	#[clap(long, value_name = "COUNT")]
+	pub max_scan_entries: Option<u64>,
+
+	/// Stop scanning once the resident memory exceeds SIZE (or % of the physical memory).
+	///
+	/// Like --max-scan-entries, only the entries found until then are considered for
+	/// deletion. The memory usage is checked after the first and then every 1000 cache entries.
+	#[clap(long, value_name = "SIZE")]
+	pub self_limit_memory: Option<SizeSpec>,
+
+	/// Only scan the top-level directories whose name sorts at or after DIR.
+	///
+	/// Allows resuming an interrupted run on a huge cache from where it stopped.
+	/// Temporary files in the cache root are still cleaned up.
+	#[clap(long, value_name = "DIR")]
+	pub resume_from: Option<OsString>,
+
+	/// Fail without deleting anything if more than PERCENT (default 10) of the cache headers can't be parsed.
+	///
+	/// Normally unparsable headers are only counted as failures, so a corrupt cache
+	/// would still be cleaned "successfully".
+	#[clap(long, value_name = "PERCENT", num_args = 0..=1, default_missing_value = "10")]
+	pub parse_errors_fatal: Option<f64>,
+
+	/// Don't delete any cache entries if fewer than COUNT are found.
+	///
+	/// Avoids pruning a freshly started cache under transient pressure. Temporary
+	/// and orphaned files are still cleaned up.
+	#[clap(long, value_name = "COUNT")]
+	pub min_entry_count: Option<u64>,
+
+	/// Stop deleting once this much disk space was freed in this run.
+	///
+	/// Attach 'K', 'M', 'G', 'T' or '%' as for --min-free-space. Limits the impact
+	/// of a single run, e.g. on replicated or backed up filesystems.
+	#[clap(long, value_name = "BYTES|PERCENT")]
+	pub max_delete_bytes: Option<SizeSpec>,
+
+	/// Report the number and disk space of the cache entries kept after the run.
+	///
+	/// Requires an additional stat call per kept cache entry.
+	#[clap(long)]
+	pub retained_stats: bool,
+
+	/// Don't delete the given percentage of largest cache entries.
+	///
+	/// Protects content that is expensive to regenerate. The protection is lifted
+	/// if the usage is over 105% of the limits.
+	#[clap(long, value_name = "PERCENT")]
+	pub exclude_by_size_percentile: Option<f64>,
+
+	/// Always delete expired cache entries before fresh ones.
+	///
+	/// Normally entries are ordered by the later of expiry and modification time,
+	/// so expired entries modified in the future (e.g. due to clock skew) may be
+	/// kept longer than fresh ones.
+	#[clap(long)]
+	pub priority_boost_expired: bool,
+
+	/// Delete in tiers by idle time, e.g. `2592000,604800,86400` (30, 7 and 1 days).
+	///
+	/// Expired cache entries are deleted first, then entries not accessed for longer
+	/// than each of the given SECONDS, longest first, and the remaining entries last.
+	/// Deletion stops as soon as the usage is below the target, so later tiers are
+	/// only touched if the earlier ones didn't free enough space.
+	#[clap(long, value_name = "SECONDS", value_delimiter = ',')]
+	pub eviction_tiers: Vec<u64>,
+
+	/// Evict cache entries strictly least recently accessed first.
+	///
+	/// Ignores expiry and modification time entirely, treating the cache as a store
+	/// of warm data. Requires a filesystem that records access times.
+	#[clap(long, conflicts_with_all = ["priority_boost_expired", "eviction_tiers"])]
+	pub cache_age_from_atime_only: bool,
+
+	/// Which cache entries to evict first.
+	///
+	/// `lru` deletes the least recently used and expired entries first, `size` the
+	/// largest and `size-age` those with the largest product of size and idle time.
+	/// The size based strategies free space with fewer deletions, but need an
+	/// additional `stat` per cache entry while scanning.
+	#[clap(long, value_enum, default_value_t, conflicts_with = "cache_age_from_atime_only")]
+	pub strategy: Strategy,
+
+	/// Delete all expired cache entries, even if there is enough free space, but no fresh ones.
+	///
+	/// Like `htcacheclean -t`. Temporary files and broken entries are still cleaned up as usual.
+	#[clap(long, conflicts_with = "cache_age_from_atime_only")]
+	pub expired_only: bool,
+
+	/// Relieve space and inode pressure in two separate phases.
+	///
+	/// First the largest cache entries are deleted until the space usage is below the
+	/// target, then the smallest until the inode usage is. Within each phase, entries
+	/// of the same size are deleted oldest first.
+	#[clap(long)]
+	pub inode_target_separate_from_space: bool,
+
+	/// Record the used space before and after each run in FILE (for `--min-free-space auto`).
+	#[clap(long, value_name = "FILE")]
+	pub usage_history: Option<PathBuf>,
+
+	/// Evaluate the cache as of TIME (RFC 3339, e.g. 2023-05-01T12:00:00Z) instead of now.
+	///
+	/// Only with --report-age-distribution, --report-expiry-gaps or --scan-hidden-report
+	/// to see what would be expired at that time. Nothing is deleted based on it, as
+	/// files still being written could look old enough to delete.
+	#[clap(long, value_name = "TIME", requires = "time_report")]
+	pub as_of: Option<Timestamp>,
+
+	/// Order in which expired cache entries are deleted.
+	///
+	/// With `random`, expired entries are deleted in random order to avoid always
+	/// evicting the same cold entries. Fresh entries are always deleted oldest first.
+	#[clap(long, value_enum, default_value_t)]
+	pub delete_order: DeleteOrder,
+
+	/// Give up on filesystem usage queries after SECONDS.
+	///
+	/// Protects against hanging on stale network filesystems. If a re-check while
+	/// deleting times out, the last known usage is used instead.
+	#[clap(long, value_name = "SECONDS")]
+	pub scan_timeout_per_statfs: Option<u64>,
+
+	/// Don't delete cache entries Apache is currently updating (see `CacheLock`).
+	#[clap(long)]
+	pub respect_cache_lock: bool,
+
+	/// Directory of Apache's cache lock files (`CacheLockPath`)
+	#[clap(long, value_name = "PATH", default_value = "/tmp/mod_cache-lock")]
+	pub cache_lock_path: PathBuf,
+
+	/// Where to take the expiry time of cache entries from.
+	///
+	/// With `mtime`, entries with corrupt or unknown headers expire --expiry-ttl
+	/// seconds after their modification time instead of being kept forever.
+	#[clap(long, value_enum, default_value_t)]
+	pub expiry_source: ExpirySource,
+
+	/// Lifetime of cache entries in seconds for `--expiry-source mtime`
+	/// (like Apache's `CacheDefaultExpire`)
+	#[clap(long, value_name = "SECONDS", default_value_t = 3600)]
+	pub expiry_ttl: u64,
+
+	/// Print a single summary line to stdout and only log warnings and errors.
+	///
+	/// Meant for cron jobs mailing their output, e.g.
+	/// `fasthtcacheclean: freed 3.2G in 1240 files, usage 88%→72%, 0 failures`.
+	#[clap(long, alias = "compact-output")]
+	pub compact: bool,
+
+	/// Write a report with all metrics of the run in FORMAT to stdout or the --report-file.
+	///
+	/// Includes phase timings, deletions per format, freed bytes, usage before and after,
+	/// the binding limit and the number of skipped entries per reason. Log messages
+	/// go to stderr while the report is written to stdout. Also the format of the
+	/// reports of --dump-fs-info, --report-age-distribution, --report-expiry-gaps and
+	/// --report-inode-efficiency, which are written as text by default.
+	#[clap(long, alias = "format", value_name = "FORMAT", value_enum)]
+	pub report_format: Option<ReportFormat>,
+
+	/// Write the report of --report-format to FILE instead of stdout.
+	#[clap(long, value_name = "FILE", requires = "report_format")]
+	pub report_file: Option<PathBuf>,
+
+	/// Print a JSON object with all metrics of the run to stdout (same as `--report-format json`).
+	#[cfg(feature = "serde")]
+	#[clap(long, conflicts_with = "report_format")]
+	pub verbose_json_summary: bool,
+
+	/// Print histograms of the durations of directory reads, metadata reads, opens and
+	/// deletions at the end of the run.
+	#[cfg(feature = "syscall-timing")]
+	#[clap(long)]
+	pub verbose_timing: bool,
+
+	/// Log the usage after every recheck while deleting, with the number of deleted entries.
+	///
+	/// Shows how the batch size adapts and where deleting stops, for tuning
+	/// --delete-batch-size. Logged at trace level, so visible without -vvv.
+	#[clap(long)]
+	pub verbose_usage_trace: bool,
+
+	/// Print the deletion statistics per top-level directory, sorted by deleted files.
+	#[clap(long)]
+	pub tree_summary: bool,
+
+	/// How to report the usage: relative to the limits or as absolute figures
+	#[clap(long, value_enum, value_name = "FORMAT", default_value_t = UsageFormat::Percent)]
+	pub usage_format: UsageFormat,
+
+	/// Units for byte counts in the output ('si', 'binary' or 'raw')
+	#[clap(long, value_name = "UNITS", default_value_t = Units::Raw)]
+	pub units: Units,
+
+	/// Don't ask for confirmation of options that may delete more than a normal cleanup.
+	///
+	/// Without this, such options are refused unless confirmed interactively. These are
+	/// --aggressive, --expired-only, --delete-phase-only, --quota-file,
+	/// --expiry-source mtime, --remove-empty-dirs and --temp-dir-pattern.
+	#[clap(short='y', long)]
+	pub assume_yes: bool,
+
+	/// File name suffix of cache header files.
+	#[clap(long, value_name = "SUFFIX", default_value = ".header", value_parser = NonEmptyStringValueParser::new())]
+	pub header_suffix: String,
+
+	/// File name suffix of cache data files, replacing the header suffix.
+	#[clap(long, value_name = "SUFFIX", default_value = ".data", value_parser = NonEmptyStringValueParser::new())]
+	pub data_suffix: String,
+
+	/// File name suffix of vary directories, appended to the header file name.
+	#[clap(long, value_name = "SUFFIX", default_value = ".vary", value_parser = NonEmptyStringValueParser::new())]
+	pub vary_suffix: String,
+
+	/// Warn about files in the cache directory that don't belong to the cache
+	/// (e.g. logs, backups or editor swap files) and list some of them.
+	#[clap(long)]
+	pub warn_on_foreign_files: bool,
+
+	/// Log the progress of the run when receiving SIGUSR1, without stopping it.
+	#[clap(long)]
+	pub dump_stats_on_signal: bool,
+
+	/// Write a profile of all phases and scanned directories in folded stack format
+	/// (e.g. for `inferno-flamegraph`) to FILE.
+	#[clap(long, value_name = "FILE")]
+	pub profile: Option<PathBuf>,
+
+	/// Write the cumulative freed space over time while deleting to FILE
+	/// (CSV with seconds since start of deletion and bytes freed).
+	#[clap(long, value_name = "FILE")]
+	pub freed_space_samples: Option<PathBuf>,
+
 	/// Increase verbosity
 	#[clap(short, long, action = clap::ArgAction::Count)]
 	pub verbose: u8,
 }
+
+/// Format of the reported usage
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UsageFormat {
+	/// Percentage of the space/inode limit
+	#[default]
+	Percent,
+	/// Total, used and free space and inodes and the limits
+	Absolute,
+}
+
+/// Source of the expiry time of cache entries
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpirySource {
+	/// Only the header file
+	#[default]
+	Header,
+	/// The header file, or the modification time plus a fixed TTL if it is unparseable
+	Mtime,
+}
+
+/// Reaction to errors deleting files or folders
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+	/// Count the error and continue
+	#[default]
+	Continue,
+	/// Stop the whole run and exit with an error
+	Abort,
+	/// Count the error and skip the rest of the current directory
+	SkipDir,
+}
+
+/// Output format of the run report
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+	/// Human readable text
+	Text,
+	/// A single JSON object
+	#[cfg(feature = "serde")]
+	Json,
+	/// A header line and a line of values
+	Csv,
+	/// Prometheus text format, e.g. for the node exporter's textfile collector
+	Prometheus,
+}
+
+/// Eviction strategy
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+	/// Least recently used first
+	#[default]
+	Lru,
+	/// Largest first
+	Size,
+	/// Largest and longest unused first
+	SizeAge,
+}
+
+/// Order of deleting expired cache entries
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeleteOrder {
+	/// Oldest first
+	#[default]
+	Oldest,
+	/// Random order
+	Random,
+}
@@ -1,13 +1,28 @@
 // Copyright (c) 2022 Papoo Software & Media GmbH <info@papoo.de>
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use crate::cache_file_info::{CacheSuffixes, EvictionStrategy};
+use crate::clock::{Clock, SystemClock};
+use crate::entry_filter::EntryFilter;
+use crate::hot_list::HotList;
+use crate::last_run::KnownEntries;
+use crate::memory::{MemoryUsage, ProcStatm};
+use crate::quota::Quotas;
+use crate::semaphore::Semaphore;
 use crate::size_spec::SizeSpec;
-use std::path::PathBuf;
+use crate::usage::{AbsoluteUsage, Constraint, Statfs, TimeoutUsage, UsageProvider};
+use crate::{AP_TEMPFILE_SUFFIX_LENGTHS, DELETE_BATCH_SIZE, ENTRY_CHANNEL_CAPACITY};
+use glob::Pattern;
+use std::ffi::OsString;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 
 /// Application configuration parameters
 #[derive(Debug, Clone)]
 pub struct Config {
-	/// Root directory of the disk cache
+	/// Root directory of the disk cache, also used to query the filesystem usage
 	pub path: PathBuf,
 
 	/// Minimum free disk space to keep
@@ -16,7 +31,364 @@ pub struct Config {
 	/// Minimum free inodes to keep
 	pub min_free_inodes: SizeSpec,
 
-	/// Jobs to run simultaneously
+	/// Jobs to run simultaneously while scanning
 	pub jobs: usize,
+
+	/// Jobs to run simultaneously while deleting cache entries
+	pub delete_jobs: usize,
+
+	/// Maximum number of cache entries deleted between usage checks
+	pub delete_batch_size: usize,
+
+	/// Capacity of the channel passing cache entries from the scanning threads to the queue
+	///
+	/// Scanning threads block while the channel is full. A larger capacity uses more
+	/// memory, a smaller one throttles scanning when the queue can't keep up.
+	pub channel_capacity: usize,
+
+	/// Treat vary directories as plain directories
+	pub ignore_vary: bool,
+
+	/// Glob patterns for cache entries that are never evicted
+	pub preserve: Vec<Pattern>,
+
+	/// Glob patterns for names of temporary directories, which are deleted when stale instead of scanned
+	pub temp_dir_patterns: Vec<Pattern>,
+
+	/// Skip hidden files and directories
+	pub no_recurse_hidden: bool,
+
+	/// Re-read cache entries before deleting them and skip refreshed ones
+	pub revalidate: bool,
+
+	/// Deleting the cache entries of a candidate list from an earlier scan (see `crate::delete_candidates`)
+	pub delete_phase_only: bool,
+
+	/// Log the start of unparseable header files as hex
+	pub dump_header_hex: bool,
+
+	/// Ignore the inode limit
+	pub no_inode_check: bool,
+
+	/// Record the freed space over time while deleting cache entries
+	pub sample_freed_space: bool,
+
+	/// Only parse header files in directories modified since this time
+	///
+	/// Entries in other directories are taken from `known_entries` instead, so
+	/// this has no effect without them.
+	pub changed_since: Option<SystemTime>,
+
+	/// Cache entries of the last run for `changed_since`, collecting the ones of this run
+	pub known_entries: Option<Arc<KnownEntries>>,
+
+	/// Skip directories modified within this many seconds
+	pub exclude_recent_dirs: Option<u64>,
+
+	/// Keep cache entries modified within this duration, even in desperate mode
+	pub min_lifetime: Option<Duration>,
+
+	/// Record statistics per top-level folder
+	pub tree_summary: bool,
+
+	/// Frequently accessed cache entries that are only evicted in desperate mode
+	pub hot_list: HotList,
+
+	/// Size limits of top-level folders, enforced regardless of the usage
+	pub quotas: Quotas,
+
+	/// Only delete cache entries over their quota, as the usage doesn't need pruning
+	pub quotas_only: bool,
+
+	/// Keep entries whose stored response headers contain a validator (`ETag` or `Last-Modified`)
+	pub exclude_revalidatable: bool,
+
+	/// External command deciding which cache entries may be deleted
+	pub entry_filter: Option<EntryFilter>,
+
+	/// Stop at the first error when deleting files or folders
+	pub fail_fast: bool,
+
+	/// Skip the rest of a directory after an error while scanning it
+	pub skip_dir_on_error: bool,
+
+	/// Keep deleting until the usage is well below the limits instead of just below them
+	pub aggressive: bool,
+
+	/// Accepted lengths of the random suffix of Apache temporary files (`aptmpXXXXXX`)
+	pub tempfile_suffix_lengths: RangeInclusive<usize>,
+
+	/// Never delete folders, only files
+	pub no_delete_folders: bool,
+
+	/// Scan even if the cache filesystem is mounted read-only
+	pub ignore_readonly: bool,
+
+	/// Remove all empty directories in the cache after deleting
+	pub remove_empty_dirs: bool,
+
+	/// Clean up old temporary files in the cache root once more after deleting
+	pub follow_up_run: bool,
+
+	/// Preallocate the deletion queue for the number of cache entries estimated from the used inodes
+	pub preallocate_queue: bool,
+
+	/// Stop scanning after this number of valid cache entries
+	pub max_scan_entries: Option<u64>,
+
+	/// Stop scanning once the resident memory of the process exceeds this many bytes
+	pub memory_limit: Option<u64>,
+
+	/// Source of the memory usage for `memory_limit`
+	pub memory_usage: Arc<dyn MemoryUsage>,
+
+	/// Only scan top-level directories named at or after this name
+	pub resume_from: Option<OsString>,
+
+	/// Fail the run if a higher percentage of cache headers can't be parsed
+	pub max_parse_error_rate: Option<f64>,
+
+	/// Skip deleting cache entries if fewer valid entries than this are found
+	pub min_entry_count: Option<u64>,
+
+	/// Stop deleting once this much disk space was freed (percentages refer to the filesystem size)
+	pub max_delete_bytes: Option<SizeSpec>,
+
+	/// Count the cache entries and disk space kept after the run
+	pub retained_stats: bool,
+
+	/// Percentage of the largest cache entries that are only evicted in desperate mode
+	pub protect_largest_percentile: Option<f64>,
+
+	/// Source of the current time
+	///
+	/// Also used for the age checks protecting files Apache may still write,
+	/// so runs that delete should use the real time.
+	pub clock: Arc<dyn Clock>,
+
+	/// Delete all expired cache entries before any fresh one
+	pub priority_boost_expired: bool,
+
+	/// Delete expired cache entries in random order instead of oldest first
+	pub random_delete_order: bool,
+
+	/// Idle times for tiered deletion (see `--eviction-tiers`), disabled if empty
+	pub eviction_tiers: Vec<Duration>,
+
+	/// Evict strictly by access time, ignoring expiry and modification time
+	pub atime_only: bool,
+
+	/// Order of the cache entries considered for eviction
+	pub strategy: EvictionStrategy,
+
+	/// Delete only expired cache entries, regardless of the usage
+	pub expired_only: bool,
+
+	/// Delete the largest entries for space, then the smallest for inodes
+	pub separate_inode_target: bool,
+
+	/// Maximum time to wait for filesystem usage queries
+	pub statfs_timeout: Option<Duration>,
+
+	/// `UsageProvider` built from `path`, `no_inode_check` and `statfs_timeout` on first use
+	///
+	/// Shared by all queries and clones of the config, so a timed out query can
+	/// fall back to the figures of an earlier one.
+	pub shared_usage_provider: Arc<OnceLock<TimeoutUsage<Statfs>>>,
+
+	/// Apache's `CacheLockPath`, to skip cache entries with a recent lock
+	pub cache_lock_path: Option<PathBuf>,
+
+	/// Pin each scanning thread to its own CPU
+	pub pin_threads: bool,
+
+	/// Limits how many header files are parsed at once across all scanning threads
+	pub parse_limit: Option<Arc<Semaphore>>,
+
+	/// Expiry of cache entries with unparseable headers, relative to their modification time
+	///
+	/// Without this, such entries are counted as failed and never evicted.
+	pub expiry_fallback_ttl: Option<Duration>,
+
+	/// Record sample paths of files that don't belong to the cache
+	pub warn_on_foreign_files: bool,
+
+	/// File name suffixes of header files, data files and vary directories
+	pub suffixes: Arc<CacheSuffixes>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			path: PathBuf::from("."),
+			min_free_space: SizeSpec::Percentage(10.0),
+			min_free_inodes: SizeSpec::Percentage(5.0),
+			jobs: 1,
+			delete_jobs: 1,
+			delete_batch_size: DELETE_BATCH_SIZE,
+			channel_capacity: ENTRY_CHANNEL_CAPACITY,
+			ignore_vary: false,
+			preserve: Vec::new(),
+			temp_dir_patterns: Vec::new(),
+			no_recurse_hidden: false,
+			revalidate: false,
+			delete_phase_only: false,
+			dump_header_hex: false,
+			no_inode_check: false,
+			sample_freed_space: false,
+			changed_since: None,
+			known_entries: None,
+			exclude_recent_dirs: None,
+			min_lifetime: None,
+			tree_summary: false,
+			hot_list: HotList::default(),
+			quotas: Quotas::default(),
+			quotas_only: false,
+			exclude_revalidatable: false,
+			entry_filter: None,
+			fail_fast: false,
+			skip_dir_on_error: false,
+			aggressive: false,
+			tempfile_suffix_lengths: AP_TEMPFILE_SUFFIX_LENGTHS,
+			no_delete_folders: false,
+			remove_empty_dirs: false,
+			ignore_readonly: false,
+			follow_up_run: false,
+			preallocate_queue: false,
+			max_scan_entries: None,
+			memory_limit: None,
+			memory_usage: Arc::new(ProcStatm),
+			resume_from: None,
+			max_parse_error_rate: None,
+			min_entry_count: None,
+			max_delete_bytes: None,
+			retained_stats: false,
+			protect_largest_percentile: None,
+			clock: Arc::new(SystemClock),
+			priority_boost_expired: false,
+			random_delete_order: false,
+			eviction_tiers: Vec::new(),
+			atime_only: false,
+			strategy: EvictionStrategy::Lru,
+			expired_only: false,
+			separate_inode_target: false,
+			statfs_timeout: None,
+			shared_usage_provider: Arc::default(),
+			cache_lock_path: None,
+			pin_threads: false,
+			parse_limit: None,
+			expiry_fallback_ttl: None,
+			warn_on_foreign_files: false,
+			suffixes: CacheSuffixes::shared_default(),
+		}
+	}
 }
 
+impl Config {
+	/// Returns the names of enabled options that may delete more than a normal cleanup
+	///
+	/// Running with any of these requires confirmation, see [`crate::confirm_destructive`].
+	pub fn destructive_modes(&self) -> Vec<&'static str> {
+		let mut modes = Vec::new();
+		if self.aggressive {
+			modes.push("--aggressive");
+		}
+		if self.expired_only {
+			modes.push("--expired-only");
+		}
+		if self.delete_phase_only {
+			modes.push("--delete-phase-only");
+		}
+		if !self.quotas.is_empty() {
+			modes.push("--quota-file");
+		}
+		if self.expiry_fallback_ttl.is_some() {
+			modes.push("--expiry-source mtime");
+		}
+		if self.remove_empty_dirs {
+			modes.push("--remove-empty-dirs");
+		}
+		if !self.temp_dir_patterns.is_empty() {
+			modes.push("--temp-dir-pattern");
+		}
+		modes
+	}
+
+	/// Returns the `UsageProvider` for the cache filesystem
+	pub fn usage_provider(&self) -> &TimeoutUsage<Statfs> {
+		self.shared_usage_provider.get_or_init(|| {
+			let statfs = Statfs {
+				path: self.path.clone(),
+				ignore_inodes: self.no_inode_check,
+			};
+			TimeoutUsage::new(statfs, self.statfs_timeout)
+		})
+	}
+
+	/// Returns which of the configured limits determines the usage percentage
+	pub fn binding_constraint(&self) -> nix::Result<Constraint> {
+		Ok(self
+			.usage_provider()
+			.fs_stats()?
+			.binding_constraint(self.min_free_space, self.min_free_inodes))
+	}
+
+	/// Calculates a percentage of how close the used space is to the configured limits
+	pub fn usage(&self) -> nix::Result<f64> {
+		self.usage_provider()
+			.usage(self.min_free_space, self.min_free_inodes)
+	}
+
+	/// Returns the absolute figures the usage percentage is calculated from
+	pub fn absolute_usage(&self) -> nix::Result<AbsoluteUsage> {
+		Ok(self
+			.usage_provider()
+			.fs_stats()?
+			.absolute_usage(self.min_free_space, self.min_free_inodes))
+	}
+
+	/// Checks if a path matches one of the `preserve` patterns
+	///
+	/// Paths are matched relative to the cache root, either `path` or the
+	/// current directory.
+	pub fn is_preserved(&self, path: &Path) -> bool {
+		let path = path
+			.strip_prefix(&self.path)
+			.or_else(|_| path.strip_prefix("."))
+			.unwrap_or(path);
+		self.preserve
+			.iter()
+			.any(|pattern| pattern.matches_path(path))
+	}
+
+	/// Checks if a directory name matches one of the `temp_dir_patterns`
+	pub fn is_temp_dir(&self, name: &str) -> bool {
+		self.temp_dir_patterns
+			.iter()
+			.any(|pattern| pattern.matches(name))
+	}
+
+	/// Checks if a directory or anything below it matches one of the `preserve` patterns
+	///
+	/// Unreadable directories count as preserved.
+	pub fn contains_preserved(&self, path: &Path) -> bool {
+		if self.preserve.is_empty() {
+			return false;
+		}
+		if self.is_preserved(path) {
+			return true;
+		}
+		let mut entries = match path.read_dir() {
+			Ok(entries) => entries,
+			Err(_) => return true,
+		};
+		entries.any(|entry| match entry {
+			Ok(entry) => match entry.file_type() {
+				Ok(t) if t.is_dir() => self.contains_preserved(&entry.path()),
+				Ok(_) => self.is_preserved(&entry.path()),
+				Err(_) => true,
+			},
+			Err(_) => true,
+		})
+	}
+}
@@ -0,0 +1,103 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::io::{BufRead, Write};
+
+/// Checks if the given destructive modes may run
+///
+/// Returns `true` if no destructive modes are enabled, if `assume_yes` is set
+/// or if the user confirms on `prompt`. `prompt` should be `None` if no
+/// interactive confirmation is possible (e.g. stdin is not a terminal), in
+/// which case destructive modes are refused.
+pub fn confirm_destructive(
+	modes: &[&str],
+	assume_yes: bool,
+	prompt: Option<(&mut dyn BufRead, &mut dyn Write)>,
+) -> bool {
+	if modes.is_empty() || assume_yes {
+		return true;
+	}
+
+	let (input, output) = match prompt {
+		Some(prompt) => prompt,
+		None => return false,
+	};
+
+	if write!(
+		output,
+		"The following options may delete more than a normal cleanup: {}\nContinue? [y/N] ",
+		modes.join(", ")
+	)
+	.and_then(|_| output.flush())
+	.is_err()
+	{
+		return false;
+	}
+
+	let mut answer = String::new();
+	if input.read_line(&mut answer).is_err() {
+		return false;
+	}
+	matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Config, Quotas};
+	use std::time::Duration;
+
+	fn ask(answer: &str) -> bool {
+		let mut input = answer.as_bytes();
+		let mut output = Vec::new();
+		confirm_destructive(&["--test"], false, Some((&mut input, &mut output)))
+	}
+
+	#[test]
+	fn test_confirm_destructive() {
+		assert!(confirm_destructive(&[], false, None));
+		assert!(!confirm_destructive(&["--test"], false, None));
+		assert!(confirm_destructive(&["--test"], true, None));
+
+		assert!(ask("y\n"));
+		assert!(ask("Yes\n"));
+		assert!(!ask("n\n"));
+		assert!(!ask("\n"));
+		assert!(!ask(""));
+	}
+
+	#[test]
+	fn test_destructive_modes() {
+		assert!(Config::default().destructive_modes().is_empty());
+		let both = Config {
+			aggressive: true,
+			expired_only: true,
+			..Default::default()
+		};
+		assert_eq!(both.destructive_modes(), ["--aggressive", "--expired-only"]);
+
+		let cases: [(fn(&mut Config), _); 7] = [
+			(|c| c.aggressive = true, "--aggressive"),
+			(|c| c.expired_only = true, "--expired-only"),
+			(|c| c.delete_phase_only = true, "--delete-phase-only"),
+			(
+				|c| c.quotas = Quotas::from_reader("tenant 1M\n".as_bytes()).unwrap(),
+				"--quota-file",
+			),
+			(
+				|c| c.expiry_fallback_ttl = Some(Duration::from_secs(3600)),
+				"--expiry-source mtime",
+			),
+			(|c| c.remove_empty_dirs = true, "--remove-empty-dirs"),
+			(
+				|c| c.temp_dir_patterns = vec![glob::Pattern::new("*.tmp").unwrap()],
+				"--temp-dir-pattern",
+			),
+		];
+		for (enable, mode) in cases {
+			let mut config = Config::default();
+			enable(&mut config);
+			assert_eq!(config.destructive_modes(), [mode]);
+		}
+	}
+}
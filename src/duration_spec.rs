@@ -0,0 +1,70 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Duration given as a number with an optional unit (e.g. `90`, `30m`, `6h`, `7d`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DurationSpec(pub Duration);
+
+/// Error type for parsing a `DurationSpec`
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("expected a whole number with an optional unit s, m, h, d or w, like 90, 30m or 7d")]
+pub struct ParseDurationError;
+
+/// Parsing a number of seconds, minutes, hours, days or weeks into a `DurationSpec`
+impl FromStr for DurationSpec {
+	type Err = ParseDurationError;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let (number, factor) = match s.char_indices().last() {
+			Some((i, 's')) => (&s[..i], 1),
+			Some((i, 'm')) => (&s[..i], 60),
+			Some((i, 'h')) => (&s[..i], 3600),
+			Some((i, 'd')) => (&s[..i], 86400),
+			Some((i, 'w')) => (&s[..i], 7 * 86400),
+			_ => (s, 1),
+		};
+		if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+			return Err(ParseDurationError);
+		}
+		let number: u64 = number.parse().map_err(|_| ParseDurationError)?;
+		let seconds = number.checked_mul(factor).ok_or(ParseDurationError)?;
+		Ok(Self(Duration::from_secs(seconds)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(s: &str) -> Result<u64, ParseDurationError> {
+		s.parse::<DurationSpec>().map(|d| d.0.as_secs())
+	}
+
+	#[test]
+	fn test_parse_duration() {
+		assert_eq!(parse("90"), Ok(90));
+		assert_eq!(parse("90s"), Ok(90));
+		assert_eq!(parse("30m"), Ok(1800));
+		assert_eq!(parse(" 6h "), Ok(21600));
+		assert_eq!(parse("7d"), Ok(604800));
+		assert_eq!(parse("2w"), Ok(1209600));
+		assert_eq!(parse("0"), Ok(0));
+
+		for invalid in [
+			"",
+			"h",
+			"-1h",
+			"1.5h",
+			"1H",
+			"1 h",
+			"1y",
+			"99999999999999999999d",
+		] {
+			assert_eq!(parse(invalid), Err(ParseDurationError), "{}", invalid);
+		}
+	}
+}
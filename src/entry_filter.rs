@@ -0,0 +1,171 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use crate::cache_file_info::CacheFileInfo;
+
+/// Number of cache entries passed to one invocation of the filter command
+const ENTRY_FILTER_BATCH_SIZE: usize = 1000;
+
+/// External command deciding which cache entries may be deleted
+///
+/// The command is run with `sh -c` in the cache directory, once per batch of
+/// entries. It gets one line per entry on stdin with the header path, the
+/// expiry as Unix time in seconds and the disk usage in bytes, separated by
+/// tabs. It has to answer with one line per entry in the same order, either
+/// `keep` or `delete`, and exit successfully.
+#[derive(Debug, Clone)]
+pub struct EntryFilter {
+	command: String,
+}
+
+impl EntryFilter {
+	/// Creates a filter running the shell command `command`
+	pub fn new(command: impl Into<String>) -> Self {
+		Self {
+			command: command.into(),
+		}
+	}
+
+	/// Splits cache entries into the ones to delete and the ones to keep
+	///
+	/// The order of the entries is kept in both parts. Fails if the command
+	/// can't be started, exits unsuccessfully or gives an invalid answer.
+	pub fn partition(
+		&self,
+		entries: Vec<CacheFileInfo>,
+	) -> Result<(Vec<CacheFileInfo>, Vec<CacheFileInfo>), io::Error> {
+		let mut delete = Vec::with_capacity(entries.len());
+		let mut keep = Vec::new();
+		let mut entries = entries.into_iter().peekable();
+		while entries.peek().is_some() {
+			let batch: Vec<_> = entries.by_ref().take(ENTRY_FILTER_BATCH_SIZE).collect();
+			let decisions = self.decide(&batch)?;
+			for (fileinfo, deletable) in batch.into_iter().zip(decisions) {
+				if deletable {
+					delete.push(fileinfo);
+				} else {
+					keep.push(fileinfo);
+				}
+			}
+		}
+		Ok((delete, keep))
+	}
+
+	/// Runs the command for one batch and returns if each entry may be deleted
+	fn decide(&self, batch: &[CacheFileInfo]) -> Result<Vec<bool>, io::Error> {
+		let error = |e: io::Error| {
+			io::Error::new(
+				e.kind(),
+				format!("entry filter `{}` failed: {}", self.command, e),
+			)
+		};
+		let mut child = Command::new("/bin/sh")
+			.arg("-c")
+			.arg(&self.command)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()
+			.map_err(error)?;
+		let stdin = child.stdin.take().expect("stdin is piped");
+		let stdout = child.stdout.take().expect("stdout is piped");
+
+		// Write in a separate thread, the command may answer before reading everything
+		let (written, answers) = std::thread::scope(|s| {
+			let writer = s.spawn(move || -> Result<(), io::Error> {
+				let mut stdin = BufWriter::new(stdin);
+				for fileinfo in batch {
+					let expires = fileinfo
+						.expires()
+						.duration_since(UNIX_EPOCH)
+						.unwrap_or_default()
+						.as_secs();
+					writeln!(
+						stdin,
+						"{}\t{}\t{}",
+						fileinfo.header_path().display(),
+						expires,
+						fileinfo.disk_usage()
+					)?;
+				}
+				// Closing stdin signals the end of the batch
+				stdin.flush()
+			});
+			let answers = BufReader::new(stdout)
+				.lines()
+				.map(|line| match line?.trim() {
+					"delete" => Ok(true),
+					"keep" => Ok(false),
+					other => Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						format!("expected `keep` or `delete`, got `{}`", other),
+					)),
+				})
+				.collect::<Result<Vec<_>, _>>();
+			(
+				writer.join().expect("entry filter writer panicked"),
+				answers,
+			)
+		});
+		let status = child.wait().map_err(error)?;
+		if !status.success() {
+			return Err(error(io::Error::other(format!("exited with {}", status))));
+		}
+		// A command exiting early makes writing fail with a broken pipe
+		written.map_err(error)?;
+		let answers = answers.map_err(error)?;
+		if answers.len() != batch.len() {
+			return Err(error(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("expected {} answers, got {}", batch.len(), answers.len()),
+			)));
+		}
+		Ok(answers)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{add_entry, read_entries};
+	use tempfile::TempDir;
+
+	#[test]
+	fn test_entry_filter() {
+		let dir = TempDir::new().unwrap();
+		for i in 0..(ENTRY_FILTER_BATCH_SIZE + 2) {
+			let name = if i % 2 == 0 { "keep" } else { "drop" };
+			add_entry(dir.path(), &format!("{}{}", name, i));
+		}
+		let entries = read_entries(dir.path());
+
+		let filter = EntryFilter::new(
+			"while IFS=\"$(printf '\\t')\" read -r path expires size; do \
+			 case \"$path\" in */keep*) echo keep ;; *) echo delete ;; esac; \
+			 done",
+		);
+		let (delete, keep) = filter.partition(entries.clone()).unwrap();
+		assert_eq!(delete.len(), ENTRY_FILTER_BATCH_SIZE / 2 + 1);
+		assert_eq!(keep.len(), ENTRY_FILTER_BATCH_SIZE / 2 + 1);
+		assert!(delete.iter().all(|e| e
+			.header_path()
+			.file_name()
+			.unwrap()
+			.to_string_lossy()
+			.starts_with("drop")));
+		assert!(keep.iter().all(|e| e
+			.header_path()
+			.file_name()
+			.unwrap()
+			.to_string_lossy()
+			.starts_with("keep")));
+
+		for command in ["exit 1", "echo maybe", "echo keep", "/nonexistent/filter"] {
+			let filter = EntryFilter::new(command);
+			assert!(filter.partition(entries.clone()).is_err(), "{}", command);
+		}
+	}
+}
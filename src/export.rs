@@ -0,0 +1,108 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::CacheFileInfo;
+
+/// Information about a cache entry for offline analysis
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct EntryRecord {
+	/// Path of the header file
+	pub path: String,
+	/// Header format (`disk` or `vary`)
+	pub format: &'static str,
+	/// Expiry time in Unix seconds
+	pub expiry: f64,
+	/// Modification time of the header file in Unix seconds
+	pub mtime: f64,
+	/// Access time of the header file in Unix seconds
+	pub atime: f64,
+	/// Disk space allocated to the header and data file in bytes
+	pub size: u64,
+}
+
+/// Converts a time to (possibly negative) Unix seconds
+fn unix_seconds(time: &SystemTime) -> f64 {
+	match time.duration_since(UNIX_EPOCH) {
+		Ok(duration) => duration.as_secs_f64(),
+		Err(e) => -e.duration().as_secs_f64(),
+	}
+}
+
+impl From<&CacheFileInfo> for EntryRecord {
+	fn from(fileinfo: &CacheFileInfo) -> Self {
+		Self {
+			path: fileinfo.header_path().to_string_lossy().into_owned(),
+			format: if fileinfo.is_vary() { "vary" } else { "disk" },
+			expiry: unix_seconds(fileinfo.expires()),
+			mtime: unix_seconds(fileinfo.modified()),
+			atime: unix_seconds(fileinfo.accessed()),
+			size: fileinfo.disk_usage(),
+		}
+	}
+}
+
+/// Writes one JSON object per cache entry to `writer`
+///
+/// Entries are written as they are read, so memory use doesn't grow with the
+/// number of entries. Returns the number of exported entries.
+pub fn export_entries(
+	entries: impl IntoIterator<Item = CacheFileInfo>,
+	mut writer: impl Write,
+) -> Result<u64, io::Error> {
+	let mut count = 0;
+	for fileinfo in entries {
+		serde_json::to_writer(&mut writer, &EntryRecord::from(&fileinfo))?;
+		writer.write_all(b"\n")?;
+		count += 1;
+	}
+	writer.flush()?;
+	Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::iter_cache_entries;
+	use std::fs::{copy, create_dir_all, write};
+	use tempfile::TempDir;
+
+	#[test]
+	fn test_export_entries() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		copy("testcases/disk.header", sub.join("disk.header")).unwrap();
+		write(sub.join("disk.data"), [0u8; 8192]).unwrap();
+		copy("testcases/vary.header", dir.path().join("vary.header")).unwrap();
+
+		let mut output = Vec::new();
+		let entries = iter_cache_entries(dir.path()).unwrap().flatten();
+		assert_eq!(export_entries(entries, &mut output).unwrap(), 2);
+
+		let mut records: Vec<serde_json::Value> = String::from_utf8(output)
+			.unwrap()
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		records.sort_by_key(|record| record["format"].as_str().unwrap().to_owned());
+
+		let disk = &records[0];
+		assert_eq!(disk["path"], sub.join("disk.header").to_str().unwrap());
+		assert_eq!(disk["format"], "disk");
+		assert_eq!(disk["expiry"], 1656657076.694344);
+		assert!(disk["size"].as_u64().unwrap() >= 8192);
+		assert!(disk["mtime"].as_f64().unwrap() > 0.0);
+		assert!(disk["atime"].as_f64().unwrap() > 0.0);
+
+		let vary = &records[1];
+		assert_eq!(
+			vary["path"],
+			dir.path().join("vary.header").to_str().unwrap()
+		);
+		assert_eq!(vary["format"], "vary");
+		assert_eq!(vary["expiry"], 1656536974.0);
+	}
+}
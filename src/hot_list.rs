@@ -0,0 +1,163 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Alphabet of the modified uuencoding Apache uses for cache file names
+const ENCODING_TABLE: &[u8; 64] =
+	b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_@";
+/// Length of an encoded cache key hash
+const HASH_LENGTH: usize = 22;
+
+/// Hashes a cache key like Apache's `mod_cache_disk` does for file names
+///
+/// Returns the 22 character hash without directory separators.
+pub fn cache_hash(key: &str) -> String {
+	let digest = md5::compute(key.as_bytes());
+	let encode = |x: u32| ENCODING_TABLE[(x & 0x3f) as usize] as char;
+
+	let mut hash = String::with_capacity(HASH_LENGTH);
+	for chunk in digest[..15].chunks(3) {
+		let x = (chunk[0] as u32) << 16 | (chunk[1] as u32) << 8 | chunk[2] as u32;
+		hash.extend([encode(x >> 18), encode(x >> 12), encode(x >> 6), encode(x)]);
+	}
+	let x = digest[15] as u32;
+	hash.extend([encode(x >> 2), encode(x << 4)]);
+	hash
+}
+
+/// Checks if `s` looks like an encoded cache key hash
+fn is_hash(s: &str) -> bool {
+	s.len() == HASH_LENGTH && s.bytes().all(|b| ENCODING_TABLE.contains(&b))
+}
+
+/// Set of frequently accessed cache entries that are protected from eviction
+#[derive(Debug, Clone, Default)]
+pub struct HotList(HashSet<String>);
+
+impl HotList {
+	/// Reads a hot list from a file
+	///
+	/// See `from_reader` for the format.
+	pub fn load(path: &Path, header_suffix: &str) -> Result<Self, io::Error> {
+		Self::from_reader(BufReader::new(File::open(path)?), header_suffix)
+	}
+
+	/// Reads a hot list with one entry per line
+	///
+	/// Each line is either a cache key (e.g. `http://example.com:80/index.html?`)
+	/// or the hashed path of a cache entry relative to the cache root, with or
+	/// without directory separators and `header_suffix`. Empty lines and lines
+	/// starting with `#` are ignored.
+	pub fn from_reader(reader: impl BufRead, header_suffix: &str) -> Result<Self, io::Error> {
+		let mut hashes = HashSet::new();
+		for line in reader.lines() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let path: String = line
+				.strip_suffix(header_suffix)
+				.unwrap_or(line)
+				.split('/')
+				.collect();
+			if is_hash(&path) {
+				hashes.insert(path);
+			} else {
+				hashes.insert(cache_hash(line));
+			}
+		}
+		Ok(Self(hashes))
+	}
+
+	/// Returns `true` if the hot list has no entries
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Checks if the cache entry with the given header file is on the hot list
+	pub fn contains(&self, header_path: &Path, header_suffix: &str) -> bool {
+		match header_hash(header_path, header_suffix) {
+			Some(hash) => self.0.contains(&hash),
+			None => false,
+		}
+	}
+}
+
+/// Returns the cache key hash of the cache entry with the given header file
+///
+/// The hash is reassembled from the file name without `header_suffix` and as
+/// many parent directories as needed for the configured directory levels.
+/// Returns `None` if the path doesn't contain a valid hash.
+pub(crate) fn header_hash(header_path: &Path, header_suffix: &str) -> Option<String> {
+	let mut hash = header_path
+		.file_name()
+		.and_then(|name| name.to_str())
+		.and_then(|name| name.strip_suffix(header_suffix))?
+		.to_owned();
+	for dir in header_path.ancestors().skip(1) {
+		if hash.len() >= HASH_LENGTH {
+			break;
+		}
+		match dir.file_name().and_then(|name| name.to_str()) {
+			Some(name) => hash.insert_str(0, name),
+			None => break,
+		}
+	}
+	is_hash(&hash).then_some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cache_hash() {
+		let hash = cache_hash("http://example.com:80/index.html?");
+		assert!(is_hash(&hash));
+		assert_eq!(hash, cache_hash("http://example.com:80/index.html?"));
+		assert_ne!(hash, cache_hash("http://example.com:80/other.html?"));
+		// MD5 of the empty string is d41d8cd98f00b204e9800998ecf8427e
+		assert_eq!(cache_hash(""), "1B2M2Y8AsgTpgAmY7PhCfg");
+	}
+
+	#[test]
+	fn test_hot_list() {
+		let key_hash = cache_hash("http://example.com:80/index.html?");
+		let input = format!(
+			"# comment\n\nhttp://example.com:80/index.html?\nAB/cd/{}.header\n",
+			"efghijklmnopqrstuv"
+		);
+		let list = HotList::from_reader(input.as_bytes(), ".header").unwrap();
+
+		let nested = format!(
+			"/cache/{}/{}/{}.header",
+			&key_hash[..2],
+			&key_hash[2..4],
+			&key_hash[4..]
+		);
+		assert!(list.contains(Path::new(&nested), ".header"));
+		assert!(list.contains(Path::new(&format!("{}.header", key_hash)), ".header"));
+		assert!(list.contains(
+			Path::new("/cache/AB/cd/efghijklmnopqrstuv.header"),
+			".header"
+		));
+		assert!(list.contains(Path::new("./A/Bcdefghijklmnopqrstuv.header"), ".header"));
+		assert!(!list.contains(
+			Path::new("/cache/AB/cd/efghijklmnopqrstuw.header"),
+			".header"
+		));
+		assert!(!list.contains(Path::new("/cache/AB/cd/efghijklmnopqrstuv.data"), ".header"));
+
+		// Custom header suffixes are stripped from the list and the paths
+		let list =
+			HotList::from_reader("AB/cd/efghijklmnopqrstuv.hdr\n".as_bytes(), ".hdr").unwrap();
+		assert!(list.contains(Path::new("/cache/AB/cd/efghijklmnopqrstuv.hdr"), ".hdr"));
+		assert!(!list.contains(Path::new("/cache/AB/cd/efghijklmnopqrstuv.header"), ".hdr"));
+	}
+}
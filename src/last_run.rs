@@ -0,0 +1,194 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{read, rename, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{read_candidates, unpoison, write_candidates, CacheFileInfo, CacheSuffixes};
+
+/// Cache entries of the last run, reused for directories that didn't change since
+///
+/// Also collects the entries of the current run for the next one.
+#[derive(Debug, Default)]
+pub struct KnownEntries {
+	previous: HashMap<PathBuf, CacheFileInfo>,
+	found: Mutex<Vec<CacheFileInfo>>,
+}
+
+impl KnownEntries {
+	/// Creates the known entries from the ones of the last run
+	pub fn new(previous: Vec<CacheFileInfo>) -> Self {
+		Self {
+			previous: previous
+				.into_iter()
+				.map(|fileinfo| (fileinfo.header_path().to_owned(), fileinfo))
+				.collect(),
+			found: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Returns the entry of the last run with the given header file
+	pub fn get(&self, header_path: &Path) -> Option<CacheFileInfo> {
+		self.previous.get(header_path).cloned()
+	}
+
+	/// Records an entry found in the current run
+	pub fn record(&self, fileinfo: &CacheFileInfo) {
+		unpoison(self.found.lock()).push(fileinfo.clone());
+	}
+
+	/// Returns the entries recorded in the current run
+	pub fn take_found(&self) -> Vec<CacheFileInfo> {
+		std::mem::take(&mut *unpoison(self.found.lock()))
+	}
+}
+
+/// Parses the timestamp in the first line of a state file
+fn parse_time(line: &[u8]) -> Result<SystemTime, io::Error> {
+	let seconds: u64 = std::str::from_utf8(line)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+		.trim()
+		.parse()
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Reads the state file, split into the first line and the rest
+///
+/// Returns `None` if the state file doesn't exist yet.
+fn read_state(path: &Path) -> Result<Option<(SystemTime, Vec<u8>)>, io::Error> {
+	let mut content = match read(path) {
+		Ok(content) => content,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e),
+	};
+	let rest = match content.iter().position(|&b| b == b'\n') {
+		Some(index) => content.split_off(index + 1),
+		None => Vec::new(),
+	};
+	Ok(Some((parse_time(&content)?, rest)))
+}
+
+/// Reads the timestamp of the last run from a state file
+///
+/// Returns `None` if the state file doesn't exist yet.
+pub fn read_last_run(path: &Path) -> Result<Option<SystemTime>, io::Error> {
+	Ok(read_state(path)?.map(|(time, _)| time))
+}
+
+/// Reads the timestamp and the cache entries of the last run from a state file
+///
+/// The entries are resolved relative to `root` like with `read_candidates`.
+/// Returns `None` if the state file doesn't exist yet.
+pub fn read_scan_state(
+	path: &Path,
+	root: &Path,
+	fallback_ttl: Option<Duration>,
+	suffixes: &Arc<CacheSuffixes>,
+) -> Result<Option<(SystemTime, KnownEntries)>, io::Error> {
+	match read_state(path)? {
+		Some((time, rest)) => {
+			let entries = read_candidates(&rest[..], root, fallback_ttl, suffixes)?;
+			Ok(Some((time, KnownEntries::new(entries))))
+		}
+		None => Ok(None),
+	}
+}
+
+/// Formats a timestamp for the first line of a state file
+fn format_time(time: SystemTime) -> Result<String, io::Error> {
+	let seconds = time
+		.duration_since(UNIX_EPOCH)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+		.as_secs();
+	Ok(format!("{}\n", seconds))
+}
+
+/// Writes the timestamp of the last run to a state file
+///
+/// The timestamp is stored as Unix time in whole seconds, rounded down.
+pub fn write_last_run(path: &Path, time: SystemTime) -> Result<(), io::Error> {
+	write_scan_state(path, time, Path::new(""), &[])
+}
+
+/// Writes the timestamp and the cache entries found by a run to a state file
+///
+/// The entries follow the timestamp in the format of `write_candidates`. The
+/// file is replaced atomically, so an interrupted write keeps the old state.
+pub fn write_scan_state(
+	path: &Path,
+	time: SystemTime,
+	root: &Path,
+	entries: &[CacheFileInfo],
+) -> Result<(), io::Error> {
+	let mut temp_path = OsString::from(path);
+	temp_path.push(".tmp");
+	let mut writer = BufWriter::new(File::create(&temp_path)?);
+	writer.write_all(format_time(time)?.as_bytes())?;
+	write_candidates(root, entries, &mut writer)?;
+	writer.into_inner()?.sync_all()?;
+	rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::TempDir;
+
+	#[test]
+	fn test_last_run() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("state");
+		assert_eq!(read_last_run(&path).unwrap(), None);
+
+		let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+		write_last_run(&path, time).unwrap();
+		assert_eq!(
+			read_last_run(&path).unwrap(),
+			Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+		);
+
+		std::fs::write(&path, "garbage").unwrap();
+		assert!(read_last_run(&path).is_err());
+	}
+
+	#[test]
+	fn test_scan_state() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("state");
+		let suffixes = CacheSuffixes::shared_default();
+		assert!(read_scan_state(&path, dir.path(), None, &suffixes)
+			.unwrap()
+			.is_none());
+
+		let sub = dir.path().join("ab");
+		std::fs::create_dir_all(&sub).unwrap();
+		std::fs::copy("testcases/disk.header", sub.join("entry.header")).unwrap();
+		let entries: Vec<_> = crate::iter_cache_entries(dir.path())
+			.unwrap()
+			.flatten()
+			.collect();
+		let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		write_scan_state(&path, time, dir.path(), &entries).unwrap();
+		assert_eq!(read_last_run(&path).unwrap(), Some(time));
+
+		let (read_time, known) = read_scan_state(&path, dir.path(), None, &suffixes)
+			.unwrap()
+			.unwrap();
+		assert_eq!(read_time, time);
+		assert_eq!(
+			known.get(&sub.join("entry.header")).as_ref(),
+			entries.first()
+		);
+		assert!(known.get(&sub.join("other.header")).is_none());
+
+		known.record(&entries[0]);
+		assert_eq!(known.take_found(), entries);
+		assert!(known.take_found().is_empty());
+	}
+}
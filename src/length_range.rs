@@ -0,0 +1,47 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Range of lengths given as `MIN-MAX` or a single length (e.g. `6-12`, `6`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LengthRange(pub RangeInclusive<usize>);
+
+/// Error type for parsing a `LengthRange`
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("expected a length or a range of lengths like 6-12")]
+pub struct ParseLengthRangeError;
+
+/// Parsing `MIN-MAX` or a single length into a `LengthRange`
+impl FromStr for LengthRange {
+	type Err = ParseLengthRangeError;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parse = |s: &str| s.trim().parse::<usize>().map_err(|_| ParseLengthRangeError);
+		let (min, max) = match s.split_once('-') {
+			Some((min, max)) => (parse(min)?, parse(max)?),
+			None => (parse(s)?, parse(s)?),
+		};
+		if min == 0 || min > max {
+			return Err(ParseLengthRangeError);
+		}
+		Ok(Self(min..=max))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_length_range() {
+		let parse = |s: &str| s.parse::<LengthRange>().map(|range| range.0);
+		assert_eq!(parse("6-12"), Ok(6..=12));
+		assert_eq!(parse(" 6 - 6 "), Ok(6..=6));
+		assert_eq!(parse("8"), Ok(8..=8));
+		for invalid in ["", "-", "0-6", "12-6", "6-", "-6", "a-b", "6-12-14"] {
+			assert_eq!(parse(invalid), Err(ParseLengthRangeError), "{}", invalid);
+		}
+	}
+}
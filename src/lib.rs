@@ -4,43 +4,155 @@
 #[macro_use]
 extern crate tracing;
 
-use crossbeam::{channel, thread};
-use nix::sys::statfs::statfs;
+use cache_file_info::BySize;
+use crossbeam::channel;
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
-use std::collections::HashSet;
-use std::convert::Infallible;
+use status::StatusRequests;
+use std::cmp::{max, Reverse};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::{remove_dir, remove_file, DirEntry, Metadata};
+use std::ffi::OsString;
+use std::fs::{remove_dir, remove_file, DirEntry, File, Metadata};
 use std::io;
-use std::mem::drop;
+use std::mem::{drop, take};
+use std::ops::RangeInclusive;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
-use std::sync::Mutex;
+use std::path::{Component, Path};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{LockResult, Mutex};
 use std::thread::yield_now;
-use std::time::{Instant, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+use syscall_timing::timed;
+use tracing::{dispatcher, Dispatch, Span};
 
+mod affinity;
 mod apache_cache;
+mod cache_entries;
 mod cache_file_info;
 mod cache_priority_queue;
+mod candidates;
+mod clock;
 mod config;
+mod confirm;
+mod entry_filter;
+#[cfg(feature = "serde")]
+mod export;
+mod hot_list;
+mod last_run;
+mod memory;
+mod open_files;
+mod quota;
+mod report;
+mod reporter;
+mod semaphore;
 mod size_spec;
 mod stats;
+mod status;
+mod summary;
+mod syscall_timing;
+mod usage;
+mod usage_history;
 
-pub use cache_file_info::CacheFileInfo;
+pub use cache_entries::{iter_cache_entries, iter_cache_entries_with_suffixes, CacheEntries};
+pub use cache_file_info::{CacheFileInfo, CacheSuffixes, EvictionStrategy};
 pub use cache_priority_queue::CachePriorityQueue;
+pub use candidates::{diff_candidates, read_candidates, write_candidates, CandidateDiff};
+pub use clock::{Clock, FixedClock, MockClock, SystemClock};
 pub use config::Config;
-pub use size_spec::SizeSpec;
-pub use stats::Stats;
+pub use confirm::confirm_destructive;
+pub use entry_filter::EntryFilter;
+#[cfg(feature = "serde")]
+pub use export::{export_entries, EntryRecord};
+pub use hot_list::{cache_hash, HotList};
+pub use last_run::{
+	read_last_run, read_scan_state, write_last_run, write_scan_state, KnownEntries,
+};
+pub use memory::{physical_memory, MemoryUsage, ProcStatm};
+pub use open_files::{open_files_limit, parse_budget};
+pub use quota::Quotas;
+pub use report::{
+	AgeDistribution, ExpiryGaps, HiddenDirKind, HiddenDirs, InodeEfficiency, Percentiles,
+	TreeSummary, EXPIRY_GAP_SAMPLES,
+};
+#[cfg(feature = "serde")]
+pub use reporter::JsonReporter;
+pub use reporter::{CsvReporter, PrometheusReporter, Report, Reporter, TextReporter};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use size_spec::{AutoSizeSpec, SizeSpec, Units};
+pub use stats::{
+	FreedSpaceSample, PhaseTimings, RunError, SkipCounts, Stats, FOREIGN_FILE_SAMPLES,
+};
+pub use status::install_status_handler;
+pub use summary::{CompactSummary, VerboseSummary};
+pub use syscall_timing::Syscall;
+#[cfg(feature = "syscall-timing")]
+pub use syscall_timing::{syscall_timings, SyscallHistogram, SyscallTimings};
+pub use usage::{
+	AbsoluteUsage, Constraint, FsInfo, FsStats, ResourceUsage, Statfs, TimeoutUsage,
+	UsageAboveLimit, UsageProvider,
+};
+pub use usage_history::{append_usage_history, auto_free_space, read_usage_history, UsageRecord};
 
 pub const MAX_DELETE_COUNT: usize = 1000000;
+/// Initial capacity of the deletion queue if it isn't estimated from the filesystem
+const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+/// Default maximum number of cache entries deleted between usage checks
+pub const DELETE_BATCH_SIZE: usize = 10;
+/// Number of cache entries found between checks of `Config::memory_limit`
+const MEMORY_CHECK_INTERVAL: u64 = 1000;
+/// Default capacity of the channel passing cache entries from the scanning threads
+pub const ENTRY_CHANNEL_CAPACITY: usize = 1000;
+/// Usage percentage at which deleting stops
+const TARGET_USAGE: f64 = 99.0;
+/// Usage percentage at which deleting stops in aggressive mode
+const AGGRESSIVE_TARGET_USAGE: f64 = 95.0;
+/// Maximum directory nesting below a scanned folder
+///
+/// Apache nests at most 20 directory levels (`CacheDirLevels` times `CacheDirLength`),
+/// plus the same again below vary directories.
+const MAX_SCAN_DEPTH: usize = 64;
+/// Usage percentage above which entries are deleted regardless of protections
+const DESPERATE_USAGE: f64 = 105.0;
+/// Log target of the usage rechecks while deleting, logged at trace level
+pub const USAGE_TRACE_TARGET: &str = "fasthtcacheclean::usage_trace";
 
 const CACHE_HEADER_SUFFIX: &str = ".header";
 const CACHE_DATA_SUFFIX: &str = ".data";
 const CACHE_VDIR_SUFFIX: &str = ".vary";
-const CACHE_HEADER_VDIR_EXTENSION: &str = "header.vary";
 const AP_TEMPFILE_BASE: &str = "aptmp";
-const AP_TEMPFILE_SUFFIX: &str = "XXXXXX";
+/// Default accepted lengths of the random suffix of Apache temporary files
+pub const AP_TEMPFILE_SUFFIX_LENGTHS: RangeInclusive<usize> = 6..=12;
+
+/// Minimum age in seconds of data files without header before they are deleted
+const ORPHAN_DATA_AGE: u64 = 120;
+/// Minimum age in seconds of temporary directories before they are deleted
+const STALE_TEMP_DIR_AGE: u64 = 600;
+/// Minimum assumed write rate for data files in bytes per second
+const MIN_WRITE_RATE: u64 = 1024 * 1024;
+/// Age in seconds after which Apache ignores cache locks (default of `CacheLockMaxAge`)
+const CACHE_LOCK_MAX_AGE: u64 = 5;
+/// Number of bytes logged of unparseable header files
+const HEADER_DUMP_LENGTH: usize = 64;
+/// Key for files directly in the cache root in per-folder statistics
+pub const ROOT_FOLDER: &str = ".";
+
+/// Checks if `name` is the name of an Apache temporary file
+///
+/// Apache creates temporary files with `mkstemp` and the template `aptmpXXXXXX`.
+/// Since the number of random characters may differ between platforms, any
+/// alphanumeric suffix with a length in `config.tempfile_suffix_lengths` matches.
+/// Cache files always contain a `.` and are never matched.
+fn is_ap_tempfile(name: &str, config: &Config) -> bool {
+	match name.strip_prefix(AP_TEMPFILE_BASE) {
+		Some(suffix) => {
+			config.tempfile_suffix_lengths.contains(&suffix.len())
+				&& suffix.bytes().all(|b| b.is_ascii_alphanumeric())
+		}
+		None => false,
+	}
+}
 
 /// Deletes a file, if it wasn't modified or accessed recently
 ///
@@ -50,7 +162,7 @@ pub fn delete_file_if_not_recent(
 	now: &SystemTime,
 	seconds: u64,
 ) -> Result<bool, io::Error> {
-	let metadata = entry.metadata()?;
+	let metadata = timed(Syscall::Metadata, || entry.metadata())?;
 	if !metadata.is_file() {
 		return Ok(false);
 	}
@@ -67,7 +179,7 @@ pub fn delete_file_if_not_recent(
 		}
 	}
 	let path = entry.path();
-	let result = remove_file(&path);
+	let result = timed(Syscall::Remove, || remove_file(&path));
 
 	debug!(
 		path=?&path,
@@ -78,6 +190,81 @@ pub fn delete_file_if_not_recent(
 	result.map(|_| true)
 }
 
+/// Logs the start of an unparseable header file as hex
+fn dump_header_hex(path: &Path) {
+	match File::open(path).and_then(|f| apache_cache::hex_prefix(f, HEADER_DUMP_LENGTH)) {
+		Ok(hex) => debug!(path=?path, "Unparseable header file {:?}: {}", path, hex),
+		Err(e) => {
+			debug!(path=?path, error=&e as &dyn Error, "Couldn't read header file {:?}", path)
+		}
+	}
+}
+
+/// Checks if a directory entry is hidden (its name starts with a dot)
+///
+/// Apache never creates hidden files in the cache.
+#[inline]
+fn is_hidden(entry: &DirEntry) -> bool {
+	entry.file_name().as_bytes().first() == Some(&b'.')
+}
+
+/// Checks if a data file without header might still be written by Apache
+///
+/// The header is only written after the data file is complete. As larger files
+/// take longer to write, the window after the last modification grows with the
+/// file size (assuming at least `MIN_WRITE_RATE`) on top of `ORPHAN_DATA_AGE`.
+fn is_write_in_progress(entry: &DirEntry, now: &SystemTime) -> bool {
+	let metadata = match entry.metadata() {
+		Ok(m) => m,
+		Err(_) => return false,
+	};
+	let window = ORPHAN_DATA_AGE + metadata.len() / MIN_WRITE_RATE;
+	match metadata
+		.modified()
+		.map(|modified| now.duration_since(modified))
+	{
+		Ok(Ok(duration)) => duration.as_secs() < window,
+		Ok(Err(_)) => true,
+		Err(_) => false,
+	}
+}
+
+/// Checks if a directory was modified in the last `seconds`
+///
+/// Modification times in the future count as recent.
+fn is_recently_modified(metadata: &Metadata, now: &SystemTime, seconds: u64) -> bool {
+	match metadata
+		.modified()
+		.map(|modified| now.duration_since(modified))
+	{
+		Ok(Ok(duration)) => duration.as_secs() < seconds,
+		Ok(Err(_)) => true,
+		Err(_) => false,
+	}
+}
+
+/// Checks if Apache holds a recent `CacheLock` lock for a cache entry
+///
+/// Apache names lock files like cache files, but always with two directory
+/// levels of length one below `CacheLockPath`. Locks older than
+/// `CACHE_LOCK_MAX_AGE` are ignored like Apache does.
+fn is_cache_locked(
+	lock_path: &Path,
+	header_path: &Path,
+	header_suffix: &str,
+	now: &SystemTime,
+) -> bool {
+	let hash = match hot_list::header_hash(header_path, header_suffix) {
+		Some(hash) => hash,
+		None => return false,
+	};
+	let path = lock_path.join(&hash[..1]).join(&hash[1..2]).join(&hash);
+	match path.metadata() {
+		Ok(metadata) => is_recently_modified(&metadata, now, CACHE_LOCK_MAX_AGE),
+		Err(_) => false,
+	}
+}
+
 /// Deletes an empty folder, if it wasn't modified or accessed recently
 fn delete_folder_if_not_recent(
 	entry: &DirEntry,
@@ -87,7 +274,7 @@ fn delete_folder_if_not_recent(
 ) -> Result<bool, io::Error> {
 	let metadata = match metadata {
 		Some(m) => m,
-		None => entry.metadata()?,
+		None => timed(Syscall::Metadata, || entry.metadata())?,
 	};
 
 	// Abort if it isn't a directory
@@ -118,17 +305,78 @@ fn delete_folder_if_not_recent(
 
 	// Try to remove it
 	let path = entry.path();
-	let result = remove_dir(&path);
+	let result = timed(Syscall::Remove, || remove_dir(&path));
 	if result.is_ok() {
 		debug!(
 			path=?&path,
 			"Deleting folder {:?}: ok", path
 		);
 	}
+	remove_dir_outcome(result)
+}
+
+/// Maps the result of `remove_dir` to whether the folder was deleted
+///
+/// Races with Apache or other cleaners are not treated as failures: a folder that
+/// became non-empty or is busy (e.g. a mountpoint) is skipped and a folder that
+/// is already gone doesn't need to be deleted anymore.
+fn remove_dir_outcome(result: Result<(), io::Error>) -> Result<bool, io::Error> {
+	match result {
+		Ok(()) => Ok(true),
+		Err(e) => match e.raw_os_error() {
+			Some(libc::ENOTEMPTY | libc::ENOENT | libc::EBUSY) => Ok(false),
+			_ => Err(e),
+		},
+	}
+}
+
+/// Passes the result of an operation through, unless it should stop the run
+///
+/// Returns the error as outer error if `config.fail_fast` is set, so it can be
+/// propagated with `?`. Files or folders that vanished in the meantime don't
+/// stop the run.
+fn check_fail_fast<T>(
+	config: &Config,
+	result: Result<T, io::Error>,
+) -> Result<Result<T, io::Error>, io::Error> {
 	match result {
+		Err(e) if config.fail_fast && e.kind() != io::ErrorKind::NotFound => Err(e),
+		result => Ok(result),
+	}
+}
+
+/// Deletes a stale temporary directory with all its contents
+///
+/// Symbolic links inside are deleted, not followed. Returns `false` if the
+/// directory vanished in the meantime.
+fn remove_temp_dir(entry: &DirEntry) -> Result<bool, io::Error> {
+	let path = entry.path();
+	let result = match timed(Syscall::Remove, || std::fs::remove_dir_all(&path)) {
 		Ok(()) => Ok(true),
-		Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTEMPTY)) => Ok(false),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
 		Err(e) => Err(e),
+	};
+
+	debug!(
+		path=?&path,
+		error=result.as_ref().err().map(|v| v as &dyn Error),
+		"Deleting temporary directory {:?}: {}", path, if result.is_ok() {"ok"} else {"failed"}
+	);
+
+	result
+}
+
+/// Checks if the rest of the directory at `path` should be skipped after `result`
+///
+/// Only with `config.skip_dir_on_error`. Like with `check_fail_fast`, files or
+/// folders that vanished in the meantime don't count as errors.
+fn skips_dir<T>(config: &Config, path: &Path, result: &Result<T, io::Error>) -> bool {
+	match result {
+		Err(e) if config.skip_dir_on_error && e.kind() != io::ErrorKind::NotFound => {
+			warn!(path=?path, "Skipping the rest of directory {:?} after an error: {}", path, e);
+			true
+		}
+		_ => false,
 	}
 }
 
@@ -137,8 +385,16 @@ fn delete_folder_if_not_recent(
 /// Returns `true` if the file and its associated data file were successfully deleted.
 #[inline]
 pub fn process_header_file(fileinfo: &CacheFileInfo) -> Result<bool, io::Error> {
+	remove_entry_files(fileinfo, &mut Stats::default())
+}
+
+/// Deletes the header and data file of a cache entry like `process_header_file`
+///
+/// The disk space and number of the files actually removed are added to `stats`,
+/// with the sizes known to `fileinfo`.
+fn remove_entry_files(fileinfo: &CacheFileInfo, stats: &mut Stats) -> Result<bool, io::Error> {
 	let data_path = fileinfo.data_path();
-	if remove_file(&data_path).is_ok() {
+	if remove_counted(&data_path, fileinfo.data_size(), stats).is_ok() {
 		debug!(
 			path=?data_path,
 			"Deleting data file {:?}: ok", data_path,
@@ -146,7 +402,7 @@ pub fn process_header_file(fileinfo: &CacheFileInfo) -> Result<bool, io::Error>
 	}
 
 	let path = fileinfo.header_path();
-	let result = remove_file(path);
+	let result = remove_counted(path, fileinfo.header_size(), stats);
 
 	debug!(
 		path=?path,
@@ -157,222 +413,4386 @@ pub fn process_header_file(fileinfo: &CacheFileInfo) -> Result<bool, io::Error>
 	result.map(|_| true)
 }
 
+/// Deletes a cache entry with its data file and, for vary entries, its `.vary` directory
+///
+/// Returns the statistics of the deleted entry, including its format and the freed
+/// space. The `.vary` directory is kept with `config.no_delete_folders` or if it still
+/// contains variants. Failing to delete it is counted in the statistics, unless
+/// `config.fail_fast` is set.
+pub fn process_cache_entry(fileinfo: &CacheFileInfo, config: &Config) -> Result<Stats, io::Error> {
+	let mut stats = Stats::default();
+	remove_entry_files(fileinfo, &mut stats)?;
+	stats.deleted += 1;
+	if fileinfo.is_vary() {
+		stats.deleted_vary += 1;
+		if !config.no_delete_folders {
+			stats.count_folder(check_fail_fast(config, remove_vary_folder(fileinfo))?);
+		}
+	} else {
+		stats.deleted_disk += 1;
+	}
+	Ok(stats)
+}
+
+/// Removes a file, adding `size` to `stats.freed_bytes` if successful
+#[inline]
+fn remove_counted(path: &Path, size: u64, stats: &mut Stats) -> Result<(), io::Error> {
+	timed(Syscall::Remove, || remove_file(path))?;
+	stats.freed_bytes += size;
+	stats.freed_inodes += 1;
+	Ok(())
+}
+
+/// Counts a valid cache entry that is kept after the run, if enabled in the configuration
+#[inline]
+fn count_retained(stats: &mut Stats, config: &Config, fileinfo: &CacheFileInfo) {
+	if config.retained_stats {
+		stats.retained += 1;
+		stats.retained_bytes += fileinfo.disk_usage();
+	}
+}
+
+/// Deletes the `.vary` directory of a deleted vary header, if it is empty
+///
+/// Returns `true` if the directory was deleted. A directory still containing
+/// variants is left alone, as these may still be valid.
+pub fn remove_vary_folder(fileinfo: &CacheFileInfo) -> Result<bool, io::Error> {
+	let path = fileinfo.vary_path();
+	let result = timed(Syscall::Remove, || remove_dir(&path));
+	if result.is_ok() {
+		debug!(
+			path=?&path,
+			"Deleting vary folder {:?}: ok", path
+		);
+	}
+	remove_dir_outcome(result)
+}
+
 /// Processes the subfolders of a folder in parallel
 ///
 /// Directly deletes definitely unneccessary files and folders, then collects
 /// information about all valid cache entries, prunes them and returns statistics.
-pub fn process_folder_parallel(path: &Path, config: &Config, now: &SystemTime) -> Result<Stats, io::Error> {
+///
+/// With `config.max_scan_entries`, scanning stops after that many cache entries
+/// and only these are considered for deletion. The same applies once the memory
+/// usage exceeds `config.memory_limit`, checked after the first and then every
+/// `MEMORY_CHECK_INTERVAL` cache entries.
+///
+/// With `config.preallocate_queue`, memory for the deletion queue is allocated
+/// upfront for the number of cache entries estimated from the used inodes.
+///
+/// With `config.priority_boost_expired`, all expired cache entries are deleted
+/// before any fresh one.
+///
+/// With `config.protect_largest_percentile`, the largest cache entries found are
+/// not deleted, unless the usage is over 105 % of the limits.
+///
+/// With `config.entry_filter`, only the cache entries the filter command
+/// allows are deleted. A failing filter command aborts the run.
+///
+/// With `config.follow_up_run`, old temporary files are cleaned up once more
+/// after deleting, using the current time of `config.clock`.
+///
+/// If the run is aborted by an error, the statistics up to then are returned
+/// with it.
+pub fn process_folder_parallel(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+) -> Result<Stats, RunError> {
+	let (mut stats, entries) = scan_phase(path, config, now)?;
+
+	if below_min_entry_count(config, stats.scanned) {
+		for fileinfo in &entries {
+			count_retained(&mut stats, config, fileinfo);
+		}
+		return Ok(stats);
+	}
+
+	match delete_phase(path, entries, config, now) {
+		Ok(delete_stats) => stats.merge(delete_stats),
+		Err(e) => return Err(e.after(stats)),
+	}
+	Ok(stats)
+}
+
+/// Checks if fewer than `config.min_entry_count` cache entries were found
+///
+/// Nothing is deleted then, as the cache is probably not mounted or broken.
+fn below_min_entry_count(config: &Config, count: u64) -> bool {
+	if config.min_entry_count.is_some_and(|min| count < min) {
+		info!("Found only {} cache entries, not deleting any.", count);
+		return true;
+	}
+	false
+}
+
+/// Deletes the cache entries of a candidate list from `read_candidates`
+///
+/// Checks that the cache is writable and that the list has at least
+/// `config.min_entry_count` entries like `process_folder_parallel` does
+/// before calling `delete_phase`.
+pub fn delete_candidates(
+	path: &Path,
+	entries: Vec<CacheFileInfo>,
+	config: &Config,
+	now: &SystemTime,
+) -> Result<Stats, RunError> {
+	check_writable(config, config.usage_provider())
+		.map_err(|e| RunError::new(Stats::default(), e))?;
+	if below_min_entry_count(config, entries.len() as u64) {
+		let mut stats = Stats::default();
+		for fileinfo in &entries {
+			count_retained(&mut stats, config, fileinfo);
+		}
+		return Ok(stats);
+	}
+	delete_phase(path, entries, config, now)
+}
+
+/// Cleans up temporary files and scans the cache, the first half of `process_folder_parallel`
+///
+/// Returns the statistics and the cache entries to consider for deletion, oldest
+/// first. These can be deleted with `delete_phase`, also in a later run.
+pub fn scan_phase(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+) -> Result<(Stats, Vec<CacheFileInfo>), RunError> {
 	let mut stats = Stats::default();
+	check_writable(config, config.usage_provider())
+		.map_err(|e| RunError::new(Stats::default(), e))?;
 
 	debug!("Cleaning up temporary files...");
-	let start = Instant::now();
+	let span = trace_span!("cleanup").entered();
+	let start = config.clock.instant();
 	// First clean old temporary files
-	for item in path.read_dir()?.flatten() {
-		if let Some(name) = item.file_name().to_str() {
-			// Temporary files -> only delete if old
-			if name.len() == AP_TEMPFILE_BASE.len() + AP_TEMPFILE_SUFFIX.len()
-				&& name.starts_with(AP_TEMPFILE_BASE)
-			{
-				stats.count(delete_file_if_not_recent(&item, now, 600));
-			}
-		}
-	}
-	debug!("Cleanup done ({:.2}s).", start.elapsed().as_secs_f64());
+	cleanup_tempfiles(path, config, now, &mut stats)
+		.map_err(|e| RunError::new(take(&mut stats), e))?;
+	stats.timings.cleanup = config.clock.instant() - start;
+	debug!(
+		"Cleanup done ({:.2}s).",
+		stats.timings.cleanup.as_secs_f64()
+	);
+	span.exit();
 
-	let mut folders = path.read_dir()?.collect::<Vec<_>>();
+	let mut folders = timed(Syscall::ReadDir, || path.read_dir())
+		.map_err(|e| RunError::new(take(&mut stats), e))?
+		.filter(|item| match item {
+			Ok(item) => {
+				!(config.no_recurse_hidden && is_hidden(item))
+					&& config
+						.resume_from
+						.as_ref()
+						.is_none_or(|resume_from| &item.file_name() >= resume_from)
+			}
+			Err(_) => true,
+		})
+		.collect::<Vec<_>>();
 	let chunk_size = (folders.len() / config.jobs) + 1;
 	let stats = Mutex::new(stats);
-	let mut queue = CachePriorityQueue::with_capacity(1000, MAX_DELETE_COUNT);
+	// Set to stop all workers on the first error with `config.fail_fast`
+	let shutdown = AtomicBool::new(false);
+	let first_error = Mutex::new(None);
+	let mut scanned = 0;
+	// Entries discarded from the queue as they won't be deleted anyway
+	let mut retained = Stats::default();
+	let capacity = if config.preallocate_queue {
+		estimate_queue_capacity(config.usage_provider())
+	} else {
+		DEFAULT_QUEUE_CAPACITY
+	};
+	let mut queue = EvictionQueue::new(config, capacity, now);
 
 	// Shuffle the subfolders to evenly distribute to the threads
 	let mut rng = thread_rng();
 	folders.shuffle(&mut rng);
 
 	debug!("Scanning directories... ({} threads)", config.jobs);
-	let start = Instant::now();
+	let span = trace_span!("scan").entered();
+	let start = config.clock.instant();
+	// Propagate the tracing context to the worker threads
+	let dispatch = dispatcher::get_default(Dispatch::clone);
 	// Run `process_folder` in parallel (in up to CPUs/2 threads)
-	thread::scope(|s| {
-		let (sender, receiver) = channel::bounded(1000);
+	std::thread::scope(|s| {
+		let (sender, receiver) = entry_channel(config);
+		let mut workers = Vec::new();
 
-		for chunk in folders.chunks(chunk_size) {
+		for (index, chunk) in folders.chunks(chunk_size).enumerate() {
 			let sender = sender.clone();
 			let stats = &stats;
-			s.spawn(move |_| {
-				for folder in chunk.iter().flatten() {
-					let result = process_folder(&folder.path(), config, now, &sender);
-					stats.lock().unwrap().merge_result(result);
-				}
-			});
+			let shutdown = &shutdown;
+			let first_error = &first_error;
+			let dispatch = &dispatch;
+			let parent = Span::current();
+			workers.push(s.spawn(move || {
+				dispatcher::with_default(dispatch, || {
+					let _guard = parent.enter();
+					if config.pin_threads {
+						affinity::pin_current_thread(index);
+					}
+					for folder in chunk.iter().flatten() {
+						if shutdown.load(atomic::Ordering::Relaxed) {
+							break;
+						}
+						let result = match check_fail_fast(
+							config,
+							process_folder(&folder.path(), config, now, &sender),
+						) {
+							Ok(result) => result,
+							Err(e) => {
+								shutdown.store(true, atomic::Ordering::Relaxed);
+								unpoison(first_error.lock()).get_or_insert(e);
+								break;
+							}
+						};
+						if config.tree_summary {
+							let mut folder_stats = Stats::default();
+							folder_stats.merge_result(result);
+							unpoison(stats.lock())
+								.merge_for_folder(folder.file_name(), folder_stats);
+						} else {
+							unpoison(stats.lock()).merge_result(result);
+						}
+					}
+				})
+			}));
 		}
 		drop(sender);
 
+		let mut status_requests = StatusRequests::new();
 		for fileinfo in receiver {
-			queue.push(fileinfo);
+			if let Some(discarded) = queue.push(fileinfo) {
+				count_retained(&mut retained, config, &discarded);
+			}
+			scanned += 1;
+			// Checked after queueing, so stopping always leaves something to delete
+			if let Some(limit) = config.memory_limit {
+				if (scanned - 1) % MEMORY_CHECK_INTERVAL == 0 {
+					if let Some(rss) = config.memory_usage.rss().filter(|&rss| rss > limit) {
+						warn!(
+							"Memory usage of {} bytes exceeds the limit, stopping scan with {} cache entries found",
+							rss, scanned
+						);
+						shutdown.store(true, atomic::Ordering::Relaxed);
+						break;
+					}
+				}
+			}
+			if status_requests.take() {
+				let stats = unpoison(stats.lock());
+				log_status(
+					&format!("scanning, {} cache entries found", scanned),
+					&stats,
+					config.usage(),
+				);
+			}
+			if config.max_scan_entries.is_some_and(|max| scanned >= max) {
+				debug!("Scanned {} cache entries, stopping scan.", scanned);
+				// Workers stop at the next folder or when sending to the dropped receiver fails
+				shutdown.store(true, atomic::Ordering::Relaxed);
+				break;
+			}
 		}
-	})
-	.unwrap();
-	debug!("Scanning done ({:.2}s).", start.elapsed().as_secs_f64());
-	let mut stats = stats.into_inner().unwrap();
-
-	debug!("Deleting cache entries...");
-	let start = Instant::now();
-	let results = queue.into_sorted_vec();
-	for chunk in results.chunks(10) {
-		for fileinfo in chunk {
-			stats.count(process_header_file(fileinfo));
-		}
-		let usage = calculate_usage(config.min_free_space, config.min_free_inodes);
-		if usage < 99.0 || (usage < 99.5 && rng.gen::<u8>() < 1) {
-			break;
+		join_workers(workers);
+	});
+	let mut stats = unpoison(stats.into_inner());
+	stats.scanned = scanned;
+	stats.merge(retained);
+	stats.timings.scan = config.clock.instant() - start;
+	debug!("Scanning done ({:.2}s).", stats.timings.scan.as_secs_f64());
+	span.exit();
+	if let Some(e) = unpoison(first_error.into_inner()) {
+		return Err(RunError::new(stats, e));
+	}
+	if let (Some(max_rate), Some(rate)) = (config.max_parse_error_rate, stats.parse_error_rate()) {
+		if rate > max_rate {
+			let e = io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!(
+					"{} of {} cache headers ({:.1}%) failed to parse, the cache may be corrupt",
+					stats.parse_errors,
+					stats.parsed + stats.parse_errors,
+					rate
+				),
+			);
+			return Err(RunError::new(stats, e));
 		}
-		yield_now();
 	}
-	debug!("Deleting done ({:.2}s).", start.elapsed().as_secs_f64());
 
-	Ok(stats)
+	Ok((stats, queue.into_sorted_vec()))
 }
 
-/// Processes one folder recursively
-///
-/// Directly deletes definitely unneccessary files and folders and
-/// sends information about all valid cache entries via `sender`.
+/// Deletes cache entries found by `scan_phase`, the second half of `process_folder_parallel`
 ///
-/// Activates `desperate` mode if usage is over 105 % of the limits
-/// in `config`.
-pub fn process_folder(
+/// The entries are ordered and filtered according to `config` first. For entries
+/// from an earlier run, `config.revalidate` should be set to skip entries
+/// changed in the meantime. With `config.quotas_only`, only entries over their
+/// quota are deleted.
+pub fn delete_phase(
 	path: &Path,
+	mut results: Vec<CacheFileInfo>,
 	config: &Config,
 	now: &SystemTime,
-	sender: &channel::Sender<CacheFileInfo>,
-) -> Result<Stats, io::Error> {
+) -> Result<Stats, RunError> {
 	let mut stats = Stats::default();
-	let usage = calculate_usage(config.min_free_space, config.min_free_inodes);
-	let desperate = usage > 105.0;
+	debug!("Deleting cache entries...");
+	let span = trace_span!("delete").entered();
+	let start = config.clock.instant();
+	// Expiry doesn't matter when evicting by access time only
+	if !config.atime_only {
+		if config.priority_boost_expired {
+			expired_first(&mut results, now);
+		}
+		if config.random_delete_order {
+			shuffle_expired(&mut results, now, &mut thread_rng());
+		}
+		if !config.eviction_tiers.is_empty() {
+			order_by_eviction_tier(&mut results, &config.eviction_tiers, now);
+		}
+	}
+	// Entries that are never deleted, still counting towards the quotas
+	let mut kept = Vec::new();
+	if let Some(percentile) = config.protect_largest_percentile {
+		let desperate = config.usage().is_ok_and(|usage| usage > DESPERATE_USAGE);
+		let protected;
+		(results, protected) = protect_largest(results, percentile, desperate);
+		stats.skipped.large += protected.len() as u64;
+		kept.extend(protected);
+	}
+	if let Some(filter) = &config.entry_filter {
+		let filtered;
+		(results, filtered) = filter
+			.partition(results)
+			.map_err(|e| RunError::new(take(&mut stats), e))?;
+		stats.skipped.filtered += filtered.len() as u64;
+		kept.extend(filtered);
+	}
+	for fileinfo in &kept {
+		count_retained(&mut stats, config, fileinfo);
+	}
+	let provider = config.usage_provider();
+	let mut progress =
+		PruneProgress::new(config, provider).map_err(|e| RunError::new(take(&mut stats), e))?;
+	if !config.quotas.is_empty() {
+		debug!("Deleting cache entries over their quota...");
+		results = enforce_quotas(
+			path,
+			results,
+			&kept,
+			config,
+			provider,
+			&mut stats,
+			&mut progress,
+		)
+		.map_err(|e| RunError::new(take(&mut stats), e))?;
+	}
+	let result = if config.quotas_only {
+		for fileinfo in &results {
+			count_retained(&mut stats, config, fileinfo);
+		}
+		Ok(Stats::default())
+	} else if config.expired_only {
+		prune_expired_with(path, &results, config, now, &mut progress)
+	} else {
+		prune_entries_with(path, &results, config, provider, &mut progress)
+	};
+	match result {
+		Ok(prune_stats) => stats.merge(prune_stats),
+		Err(e) => return Err(e.after(stats)),
+	}
+	if config.remove_empty_dirs {
+		debug!("Removing empty directories...");
+		let dir_stats = remove_empty_dirs(path, config, now, 0)
+			.map_err(|e| RunError::new(take(&mut stats), e))?;
+		stats.merge(dir_stats);
+	}
+	stats.timings.delete = config.clock.instant() - start;
+	debug!(
+		"Deleting done ({:.2}s).",
+		stats.timings.delete.as_secs_f64()
+	);
+	span.exit();
 
-	stats.merge(scan_folder(path, now, false, sender, desperate)?);
+	if config.follow_up_run {
+		debug!("Cleaning up new temporary files...");
+		let _span = trace_span!("follow_up").entered();
+		let start = config.clock.instant();
+		// Temporary files created while deleting may be old by now
+		cleanup_tempfiles(path, config, &config.clock.now(), &mut stats)
+			.map_err(|e| RunError::new(take(&mut stats), e))?;
+		stats.timings.cleanup += config.clock.instant() - start;
+	}
 
 	Ok(stats)
 }
 
-/// Scans one subfolder recursively
+/// Removes all empty directories below `path`, deepest first
 ///
-/// Directly deletes definitely unneccessary files and folders and
-/// sends information about all valid cache entries via `sender`.
+/// Deleting the cache entries from the queue leaves directories empty that the
+/// scan couldn't remove anymore. Unlike while scanning, recently modified
+/// directories are removed too, as deleting the entries modified them. Apache
+/// creates missing directories again when storing an entry. Temporary
+/// directories are left alone.
 ///
-/// If `desperate` is true, deleting happens more aggressively.
-#[instrument(level = "trace", skip(now, sender))]
-pub fn scan_folder(
+/// Nothing is removed with `config.no_delete_folders`. Like the scan, top-level
+/// directories before `config.resume_from` and directories modified within
+/// `config.exclude_recent_dirs` seconds before `now` are skipped.
+fn remove_empty_dirs(
 	path: &Path,
+	config: &Config,
 	now: &SystemTime,
-	in_vary: bool,
-	sender: &channel::Sender<CacheFileInfo>,
-	desperate: bool,
+	depth: usize,
 ) -> Result<Stats, io::Error> {
-	let mut known_headers = HashSet::new();
 	let mut stats = Stats::default();
-
-	for item in path.read_dir()?.flatten() {
-		let name = item.file_name();
-		if let Some(name) = name.to_str() {
-			// Temporary files -> only delete if old
-			if name.len() == AP_TEMPFILE_BASE.len() + AP_TEMPFILE_SUFFIX.len()
-				&& name.starts_with(AP_TEMPFILE_BASE)
-			{
-				stats.count(delete_file_if_not_recent(&item, now, 600));
+	if config.no_delete_folders {
+		return Ok(stats);
+	}
+	for item in timed(Syscall::ReadDir, || path.read_dir())?.flatten() {
+		if !item.file_type().is_ok_and(|t| t.is_dir())
+			|| (config.no_recurse_hidden && is_hidden(&item))
+			|| item
+				.file_name()
+				.to_str()
+				.is_some_and(|name| config.is_temp_dir(name))
+		{
+			continue;
+		}
+		if depth == 0
+			&& config
+				.resume_from
+				.as_ref()
+				.is_some_and(|resume_from| &item.file_name() < resume_from)
+		{
+			continue;
+		}
+		// Only the ones the scan skipped, deleting entries modified the others after `now`
+		if let Some(seconds) = config.exclude_recent_dirs {
+			let skipped_by_scan =
+				timed(Syscall::Metadata, || item.metadata()).is_ok_and(|metadata| {
+					metadata.modified().is_ok_and(|modified| modified <= *now)
+						&& is_recently_modified(&metadata, now, seconds)
+				});
+			if skipped_by_scan {
+				continue;
 			}
-			// Header files
-			else if let Some(stem) = name.strip_suffix(CACHE_HEADER_SUFFIX) {
-				known_headers.insert(stem.to_owned());
-				if let Ok(fileinfo) = CacheFileInfo::new(&item) {
-					if !in_vary && fileinfo.is_vary() {
-						// Delete orphaned data file if the header indicates a vary directory
-						let data_path = fileinfo.data_path();
-						let result = remove_file(&data_path);
-						stats.count::<Infallible>(Ok(result.is_ok()));
-
-						if result.is_ok() {
-							debug!(
-								path=?&data_path,
-								"Deleting orphaned data file {:?}: ok", &data_path,
-							);
-						}
+		}
+		if depth >= MAX_SCAN_DEPTH {
+			skip_deep_dir(&item, &mut stats);
+			continue;
+		}
+		let result = check_fail_fast(
+			config,
+			remove_empty_dirs(&item.path(), config, now, depth + 1),
+		)?;
+		stats.merge_result(result);
+		let path = item.path();
+		let result = remove_dir_outcome(timed(Syscall::Remove, || remove_dir(&path)));
+		if matches!(result, Ok(true)) {
+			debug!(path=?&path, "Deleting empty folder {:?}: ok", path);
+		}
+		stats.count_folder(check_fail_fast(config, result)?);
+	}
+	Ok(stats)
+}
 
-						// Don't delete main header as long as a vary directory exists (as long as not in desperate mode)
-						if !desperate {
-							let vdir_path = fileinfo.vary_path();
-							if vdir_path.exists() {
-								if let Ok(metadata) = vdir_path.metadata() {
-									if metadata.is_dir() && metadata.nlink() > 2 {
-										continue;
-									}
-								}
-							}
-						}
-					}
-					sender.send(fileinfo).unwrap();
+/// Deletes old Apache temporary files directly in `path`
+///
+/// Apache creates its temporary files in the cache root, temporary files in
+/// subfolders are deleted while scanning instead.
+fn cleanup_tempfiles(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+	stats: &mut Stats,
+) -> Result<(), io::Error> {
+	for item in timed(Syscall::ReadDir, || path.read_dir())?.flatten() {
+		if let Some(name) = item.file_name().to_str() {
+			// Temporary files -> only delete if old
+			if is_ap_tempfile(name, config) {
+				let result = check_fail_fast(config, delete_file_if_not_recent(&item, now, 600))?;
+				if config.tree_summary {
+					let mut file_stats = Stats::default();
+					file_stats.count(result);
+					stats.merge_for_folder(ROOT_FOLDER.into(), file_stats);
 				} else {
-					stats.add_failed();
-				}
-			}
-			// Data files
-			else if let Some(stem) = name.strip_suffix(CACHE_DATA_SUFFIX) {
-				if !known_headers.contains(stem) {
-					let mut header_path = item.path();
-					header_path.set_extension(&CACHE_HEADER_SUFFIX[1..]);
-					// If the header file is missing and the file is old, delete it.
-					if !header_path.exists() {
-						stats.count(delete_file_if_not_recent(&item, now, 120));
-						continue;
-					}
-				}
-			}
-			// Recurse into vary directories
-			else if name.ends_with(CACHE_VDIR_SUFFIX) {
-				stats.merge_result(scan_folder(&item.path(), now, true, sender, desperate));
-				stats.count_folder(delete_folder_if_not_recent(&item, None, now, 300));
-			}
-			// Recurse into other directories
-			else if let Ok(metadata) = item.metadata() {
-				if metadata.is_dir() {
-					stats.merge_result(scan_folder(&item.path(), now, in_vary, sender, desperate));
-					stats.count_folder(delete_folder_if_not_recent(
-						&item,
-						Some(metadata),
-						now,
-						300,
-					));
+					stats.count(result);
 				}
 			}
 		}
 	}
+	Ok(())
+}
 
-	// Be somewhat nice to other processes by yielding the CPU after each non-vary directory
-	if !in_vary {
-		yield_now();
+/// Fails if the cache filesystem is mounted read-only, unless `config.ignore_readonly` is set
+///
+/// Every deletion would fail anyway, so the scan would be wasted.
+fn check_writable(config: &Config, provider: &impl UsageProvider) -> Result<(), io::Error> {
+	if !config.ignore_readonly && provider.is_read_only()? {
+		return Err(io::Error::new(
+			io::ErrorKind::ReadOnlyFilesystem,
+			"cache filesystem is mounted read-only, pass --ignore-readonly to scan anyway",
+		));
 	}
-
-	Ok(stats)
+	Ok(())
 }
 
-/// Calculates a percentage of how close the used space is to the free space/inode limit
+/// Logs the progress of a run on request (see `install_status_handler`)
 ///
-/// Returns the maximum of space and inode percentage.
-pub fn calculate_usage(minspace: SizeSpec, mininodes: SizeSpec) -> f64 {
-	let fsstat = statfs(".").expect("Couldn't get free space information");
-	let block_size: u64 = fsstat.block_size().try_into().unwrap_or(4096);
-	let total_space = block_size * fsstat.blocks();
-	let used_space_target = total_space.saturating_sub(minspace.value(total_space));
-	let used_space = fsstat.blocks().saturating_sub(fsstat.blocks_available()) * block_size;
-
-	let total_inodes = fsstat.files();
-	let used_inodes_target = total_inodes.saturating_sub(mininodes.value(total_inodes));
-	let used_inodes = total_inodes.saturating_sub(fsstat.files_free());
-
-	let inode_usage = used_inodes as f64 * 100.0 / (used_inodes_target + 1) as f64;
-	let space_usage = used_space as f64 * 100.0 / (used_space_target + 1) as f64;
-	if inode_usage > space_usage {
-		inode_usage
-	} else {
-		space_usage
+/// Logged as a warning to be visible at the default log level.
+fn log_status(phase: &str, stats: &Stats, usage: nix::Result<f64>) {
+	let usage = match usage {
+		Ok(usage) => format!("{:.1}%", usage),
+		Err(e) => format!("unknown ({})", e),
+	};
+	warn!(
+		"Status: {}, {} deleted files, {} deleted folders, {} failed to delete, usage {}",
+		phase, stats.deleted, stats.deleted_folders, stats.failed, usage
+	);
+}
+
+/// Queue of the cache entries to delete first, in the eviction order of the `Config`
+enum EvictionQueue {
+	/// Ordered chronologically (see `CacheFileInfo::cmp`) or only by access time
+	/// with `config.atime_only`
+	Ordered(CachePriorityQueue<CacheFileInfo>),
+	/// Ordered by size, weighted by the idle time at the given time if set
+	Size(CachePriorityQueue<BySize>, Option<SystemTime>),
+}
+
+impl EvictionQueue {
+	fn new(config: &Config, capacity: usize, now: &SystemTime) -> Self {
+		fn queue<T: Ord>(capacity: usize) -> CachePriorityQueue<T> {
+			CachePriorityQueue::with_capacity(capacity, MAX_DELETE_COUNT)
+		}
+		if config.atime_only {
+			let mut queue = CachePriorityQueue::with_comparator(
+				MAX_DELETE_COUNT,
+				CacheFileInfo::cmp_by_access_time as _,
+			);
+			queue.reserve(capacity);
+			return Self::Ordered(queue);
+		}
+		match config.strategy {
+			EvictionStrategy::Lru => Self::Ordered(queue(capacity)),
+			EvictionStrategy::Size => Self::Size(queue(capacity), None),
+			EvictionStrategy::SizeAge => Self::Size(queue(capacity), Some(*now)),
+		}
+	}
+
+	/// Pushes a cache entry, returning the one discarded if the queue is full
+	fn push(&mut self, fileinfo: CacheFileInfo) -> Option<CacheFileInfo> {
+		match self {
+			Self::Ordered(queue) => queue.push(fileinfo),
+			Self::Size(queue, now) => {
+				let entry = match now {
+					Some(now) => BySize::weighted_by_age(fileinfo, now),
+					None => BySize::new(fileinfo),
+				};
+				queue.push(entry).map(|entry| entry.fileinfo)
+			}
+		}
+	}
+
+	/// Returns the cache entries, the ones to delete first at the start
+	fn into_sorted_vec(self) -> Vec<CacheFileInfo> {
+		match self {
+			Self::Ordered(queue) => queue.into_sorted_vec(),
+			Self::Size(queue, _) => queue
+				.into_sorted_vec()
+				.into_iter()
+				.map(|entry| entry.fileinfo)
+				.collect(),
+		}
+	}
+}
+
+/// Moves expired cache entries before all fresh ones
+///
+/// The order within both groups is kept. This only makes a difference for
+/// expired entries with a modification time in the future, which otherwise
+/// sort by their modification time.
+fn expired_first(entries: &mut [CacheFileInfo], now: &SystemTime) {
+	entries.sort_by_key(|fileinfo| fileinfo.expires() > now);
+}
+
+/// Shuffles the expired cache entries among their positions
+///
+/// Fresh entries stay where they are, so no fresh entry is deleted before an
+/// expired one that preceded it. Spreads the evictions over the cold entries
+/// instead of always hitting the oldest ones.
+fn shuffle_expired(entries: &mut [CacheFileInfo], now: &SystemTime, rng: &mut impl Rng) {
+	let positions: Vec<_> = (0..entries.len())
+		.filter(|&i| entries[i].expires() <= now)
+		.collect();
+	// Fisher-Yates shuffle over the positions of expired entries
+	for i in (1..positions.len()).rev() {
+		let j = rng.gen_range(0..=i);
+		entries.swap(positions[i], positions[j]);
+	}
+}
+
+/// Moves cache entries into eviction tiers by their idle time
+///
+/// Expired entries form the first tier, followed by one tier per age in `tiers`
+/// (longest first) with the entries not accessed or modified for longer than
+/// that, and the remaining entries last. The order within each tier is kept,
+/// so deleting until the usage target is met stops in the first tier that
+/// frees enough space.
+fn order_by_eviction_tier(entries: &mut [CacheFileInfo], tiers: &[Duration], now: &SystemTime) {
+	let mut tiers = tiers.to_vec();
+	tiers.sort_unstable_by_key(|&age| Reverse(age));
+	entries.sort_by_key(|fileinfo| {
+		if fileinfo.expires() <= now {
+			return 0;
+		}
+		let idle = now
+			.duration_since(*max(fileinfo.accessed(), fileinfo.modified()))
+			.unwrap_or_default();
+		tiers
+			.iter()
+			.position(|&age| idle > age)
+			.map_or(tiers.len() + 1, |tier| tier + 1)
+	});
+}
+
+/// Returns the size of the cache filesystem in bytes for resolving percentages of `purpose`
+fn filesystem_size(provider: &impl UsageProvider, purpose: &str) -> Result<u64, io::Error> {
+	let stats = provider.fs_stats().map_err(|e| {
+		io::Error::new(
+			io::Error::from(e).kind(),
+			format!("couldn't get the filesystem size for {}: {}", purpose, e),
+		)
+	})?;
+	Ok(stats.blocks * stats.block_size)
+}
+
+/// Deletes the oldest cache entries of top-level folders over their quota
+///
+/// The size of a folder is the disk usage of its cache entries in `entries` and
+/// `kept`, but only the ones in `entries` are deleted. Percentage quotas are
+/// relative to the size of the filesystem, failing if it can't be determined
+/// rather than deleting everything. Deleting stops once the budget of
+/// `progress` is exhausted. Returns the remaining entries in their original order.
+fn enforce_quotas(
+	root: &Path,
+	entries: Vec<CacheFileInfo>,
+	kept: &[CacheFileInfo],
+	config: &Config,
+	provider: &impl UsageProvider,
+	stats: &mut Stats,
+	progress: &mut PruneProgress,
+) -> Result<Vec<CacheFileInfo>, io::Error> {
+	// Only read when a percentage quota applies
+	let mut total = None;
+	// Size and limit of each folder with a quota
+	let mut folders: HashMap<OsString, (u64, u64)> = HashMap::new();
+	for fileinfo in entries.iter().chain(kept) {
+		let Some(folder) = top_level_folder(root, fileinfo.header_path()) else {
+			continue;
+		};
+		if !folders.contains_key(&folder) {
+			let limit = match config.quotas.limit_for(&folder) {
+				Some(SizeSpec::Absolute(bytes)) => bytes,
+				Some(spec) => {
+					let size = match total {
+						Some(size) => size,
+						None => *total.insert(filesystem_size(provider, "the quotas")?),
+					};
+					spec.value(size)
+				}
+				None => continue,
+			};
+			folders.insert(folder.clone(), (0, limit));
+		}
+		folders.get_mut(&folder).unwrap().0 += fileinfo.disk_usage();
+	}
+
+	let mut remaining = Vec::with_capacity(entries.len());
+	for fileinfo in entries {
+		let over_quota = top_level_folder(root, fileinfo.header_path())
+			.and_then(|folder| folders.get_mut(&folder))
+			.filter(|(size, limit)| size > limit);
+		match over_quota {
+			Some((size, _)) if !progress.budget_exhausted() => {
+				*size = size.saturating_sub(fileinfo.disk_usage());
+				let (entry_stats, freed) = prune_entry(root, &fileinfo, config)?;
+				progress.freed += freed;
+				stats.merge(entry_stats);
+			}
+			_ => remaining.push(fileinfo),
+		}
+	}
+	Ok(remaining)
+}
+
+/// Splits off the largest `percentile` % of cache entries by disk usage
+///
+/// Returns the remaining entries in their original order and the protected
+/// entries. Of entries with the same size, the ones earlier in the order are
+/// protected first. In `desperate` mode no entries are protected.
+fn protect_largest(
+	entries: Vec<CacheFileInfo>,
+	percentile: f64,
+	desperate: bool,
+) -> (Vec<CacheFileInfo>, Vec<CacheFileInfo>) {
+	let count = ((entries.len() as f64 * percentile / 100.0) as usize).min(entries.len());
+	if desperate || count == 0 {
+		return (entries, Vec::new());
+	}
+	let sizes: Vec<_> = entries.iter().map(CacheFileInfo::disk_usage).collect();
+	let mut sorted = sizes.clone();
+	sorted.sort_unstable_by_key(|&size| Reverse(size));
+	let threshold = sorted[count - 1];
+	let mut ties = count - sorted.iter().filter(|&&size| size > threshold).count();
+
+	let mut remaining = Vec::with_capacity(entries.len() - count);
+	let mut protected = Vec::with_capacity(count);
+	for (fileinfo, size) in entries.into_iter().zip(sizes) {
+		if size > threshold || (size == threshold && ties > 0) {
+			if size == threshold {
+				ties -= 1;
+			}
+			protected.push(fileinfo);
+		} else {
+			remaining.push(fileinfo);
+		}
+	}
+	(remaining, protected)
+}
+
+/// Returns the value of a mutex even if a worker panicked while holding it
+///
+/// The statistics are only merged under the lock, so they stay consistent and
+/// are used anyway instead of losing everything accumulated so far.
+fn unpoison<T>(result: LockResult<T>) -> T {
+	result.unwrap_or_else(|e| {
+		warn!("A worker panicked while holding a lock, statistics may be incomplete");
+		e.into_inner()
+	})
+}
+
+/// Waits for the scan workers, logging each one that panicked
+///
+/// The statistics a panicked worker merged before stay in the totals, only its
+/// remaining folders aren't scanned.
+fn join_workers(workers: Vec<std::thread::ScopedJoinHandle<'_, ()>>) {
+	for (index, worker) in workers.into_iter().enumerate() {
+		if let Err(payload) = worker.join() {
+			warn!(
+				"Scan worker {} panicked ({}), its remaining folders weren't scanned",
+				index,
+				panic_message(&*payload)
+			);
+		}
+	}
+}
+
+/// Extracts the message of a panic payload
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+	payload
+		.downcast_ref::<&str>()
+		.copied()
+		.or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+		.unwrap_or("unknown cause")
+}
+
+/// Estimates the number of cache entries from the used inodes of the filesystem
+///
+/// Assumes a header and a data file per entry and is capped at `MAX_DELETE_COUNT`.
+/// Falls back to `DEFAULT_QUEUE_CAPACITY` if the filesystem doesn't report inodes.
+fn estimate_queue_capacity(provider: &impl UsageProvider) -> usize {
+	match provider.fs_stats() {
+		Ok(stats) if stats.files > 0 => {
+			let used = stats.files.saturating_sub(stats.files_free);
+			usize::try_from(used / 2).map_or(MAX_DELETE_COUNT, |n| n.min(MAX_DELETE_COUNT))
+		}
+		_ => DEFAULT_QUEUE_CAPACITY,
+	}
+}
+
+/// Creates the channel passing cache entries from the scanning threads to the queue
+fn entry_channel(
+	config: &Config,
+) -> (
+	channel::Sender<CacheFileInfo>,
+	channel::Receiver<CacheFileInfo>,
+) {
+	channel::bounded(config.channel_capacity)
+}
+
+/// Deletes cache entries in the given order until the usage drops below the limits
+///
+/// The usage is re-checked after each batch of deletions. The batch size is
+/// derived from the usage decrease per entry observed in the previous batch,
+/// so that deletion slows down when approaching the target. While no decrease
+/// is observed the batch size doubles up to `config.delete_batch_size`.
+///
+/// If `config.sample_freed_space` is set, the cumulative freed space is
+/// recorded in the returned statistics at each usage re-check. If
+/// `config.tree_summary` is set, deletions are attributed to the top-level
+/// folder below `root` containing the entry.
+///
+/// With `config.fail_fast`, deleting stops at the first error, which is returned
+/// with the statistics up to the failed batch.
+///
+/// The `.vary` directory of a deleted vary header is deleted as well if it is
+/// empty, unless `config.no_delete_folders` is set.
+///
+/// With `config.cache_lock_path`, entries Apache is currently updating are skipped.
+///
+/// If `config.retained_stats` is set, entries that are skipped, fail to delete
+/// or aren't reached are counted as retained.
+///
+/// The entries of a batch are deleted in up to `config.delete_jobs` threads.
+///
+/// Normally deleting stops just below the limits, occasionally already up to
+/// 0.5 % above them. With `config.aggressive` it continues until the usage is
+/// below `AGGRESSIVE_TARGET_USAGE`, leaving headroom until the next run.
+///
+/// With `config.max_delete_bytes`, deleting also stops once that much disk
+/// space was freed. Batches are shortened to the entries fitting into the
+/// remaining budget, but always contain at least one entry.
+///
+/// With `config.separate_inode_target`, the largest entries are deleted until
+/// the space usage is below the target, then the smallest until the inode
+/// usage is. A phase is skipped if its usage already is below the target.
+pub fn prune_entries(
+	root: &Path,
+	entries: &[CacheFileInfo],
+	config: &Config,
+	provider: &impl UsageProvider,
+) -> Result<Stats, RunError> {
+	let mut progress =
+		PruneProgress::new(config, provider).map_err(|e| RunError::new(Stats::default(), e))?;
+	prune_entries_with(root, entries, config, provider, &mut progress)
+}
+
+/// `prune_entries` continuing with the budget left in `progress`
+fn prune_entries_with(
+	root: &Path,
+	entries: &[CacheFileInfo],
+	config: &Config,
+	provider: &impl UsageProvider,
+	progress: &mut PruneProgress,
+) -> Result<Stats, RunError> {
+	let mut stats = Stats::default();
+	if config.separate_inode_target {
+		let target = target_usage(config);
+		let mut remaining = entries.to_vec();
+		for constraint in [Constraint::Space, Constraint::Inodes] {
+			let usage = || {
+				provider.constraint_usage(constraint, config.min_free_space, config.min_free_inodes)
+			};
+			if usage().is_ok_and(|usage| usage < target) || progress.budget_exhausted() {
+				continue;
+			}
+			debug!(
+				"Deleting cache entries to relieve {} pressure...",
+				constraint
+			);
+			// Stable sorts keep entries of the same size oldest first
+			match constraint {
+				Constraint::Space => remaining.sort_by_cached_key(|f| Reverse(f.disk_usage())),
+				Constraint::Inodes => remaining.sort_by_cached_key(|f| f.disk_usage()),
+			}
+			let processed = prune_until(root, &remaining, config, &usage, &mut stats, progress)?;
+			remaining.drain(..processed);
+		}
+		for fileinfo in &remaining {
+			count_retained(&mut stats, config, fileinfo);
+		}
+	} else {
+		let usage = || provider.usage(config.min_free_space, config.min_free_inodes);
+		let processed = prune_until(root, entries, config, &usage, &mut stats, progress)?;
+		for fileinfo in &entries[processed..] {
+			count_retained(&mut stats, config, fileinfo);
+		}
+	}
+
+	Ok(stats)
+}
+
+/// Deletes the cache entries expired at `now`, regardless of the usage, like `htcacheclean -t`
+///
+/// The expired entries are deleted in the order of `entries`, which doesn't need to
+/// be chronological. `config.max_delete_bytes` is respected as with `prune_entries`.
+pub fn prune_expired(
+	root: &Path,
+	entries: &[CacheFileInfo],
+	config: &Config,
+	provider: &impl UsageProvider,
+	now: &SystemTime,
+) -> Result<Stats, RunError> {
+	let mut progress =
+		PruneProgress::new(config, provider).map_err(|e| RunError::new(Stats::default(), e))?;
+	prune_expired_with(root, entries, config, now, &mut progress)
+}
+
+/// `prune_expired` continuing with the budget left in `progress`
+fn prune_expired_with(
+	root: &Path,
+	entries: &[CacheFileInfo],
+	config: &Config,
+	now: &SystemTime,
+	progress: &mut PruneProgress,
+) -> Result<Stats, RunError> {
+	let mut stats = Stats::default();
+	let (expired, fresh): (Vec<_>, Vec<_>) = entries
+		.iter()
+		.cloned()
+		.partition(|fileinfo| fileinfo.expires() <= now);
+	debug!("Deleting {} expired cache entries...", expired.len());
+	// Never below the target, so only the end of the expired entries or the budget stops
+	let usage = || Ok(f64::INFINITY);
+	let processed = prune_until(root, &expired, config, &usage, &mut stats, progress)?;
+	for fileinfo in expired[processed..].iter().chain(&fresh) {
+		count_retained(&mut stats, config, fileinfo);
+	}
+	Ok(stats)
+}
+
+/// State of `prune_entries` shared between deletion phases
+struct PruneProgress {
+	/// Start of deleting, for `FreedSpaceSample::elapsed`
+	start: Instant,
+	/// Freed space in bytes so far
+	freed: u64,
+	/// Maximum number of bytes to free (see `config.max_delete_bytes`)
+	max_delete_bytes: Option<u64>,
+}
+
+impl PruneProgress {
+	/// Fails if `config.max_delete_bytes` is relative and the filesystem size can't be determined
+	fn new(config: &Config, provider: &impl UsageProvider) -> Result<Self, io::Error> {
+		let max_delete_bytes = match config.max_delete_bytes {
+			Some(SizeSpec::Absolute(bytes)) => Some(bytes),
+			Some(spec) => Some(spec.value(filesystem_size(provider, "the deletion limit")?)),
+			None => None,
+		};
+		Ok(Self {
+			start: config.clock.instant(),
+			freed: 0,
+			max_delete_bytes,
+		})
+	}
+
+	/// Checks if `max_delete_bytes` were freed
+	fn budget_exhausted(&self) -> bool {
+		self.max_delete_bytes
+			.is_some_and(|max_delete_bytes| self.freed >= max_delete_bytes)
+	}
+}
+
+/// Returns the usage `prune_entries` stops below
+fn target_usage(config: &Config) -> f64 {
+	if config.aggressive {
+		AGGRESSIVE_TARGET_USAGE
+	} else {
+		TARGET_USAGE
+	}
+}
+
+/// Deletes `entries` in order in batches until `usage()` is below the target
+///
+/// Stops early if the usage can't be determined. Returns the number of entries
+/// processed, the rest isn't counted in `stats`.
+fn prune_until(
+	root: &Path,
+	entries: &[CacheFileInfo],
+	config: &Config,
+	usage: &dyn Fn() -> nix::Result<f64>,
+	stats: &mut Stats,
+	progress: &mut PruneProgress,
+) -> Result<usize, RunError> {
+	let mut rng = thread_rng();
+	let mut current_usage = match usage() {
+		Ok(usage) => usage,
+		Err(e) => {
+			warn!("Couldn't get free space information, not deleting: {}", e);
+			return Ok(0);
+		}
+	};
+	trace!(
+		target: USAGE_TRACE_TARGET,
+		usage = current_usage,
+		"Usage {:.3}% before deleting", current_usage
+	);
+	let target = target_usage(config);
+	let max_batch_size = config.delete_batch_size.max(1);
+	let mut batch_size = max_batch_size;
+	let mut remaining = entries;
+	let mut status_requests = StatusRequests::new();
+
+	while !remaining.is_empty() {
+		// Nothing left of the budget, e.g. used up by the quotas
+		if progress.budget_exhausted() {
+			return Ok(0);
+		}
+		let mut batch_len = batch_size.min(remaining.len());
+		if let Some(max_delete_bytes) = progress.max_delete_bytes {
+			let mut budget = max_delete_bytes.saturating_sub(progress.freed);
+			batch_len = remaining[..batch_len]
+				.iter()
+				.take_while(|fileinfo| {
+					let size = fileinfo.disk_usage();
+					let fits = size <= budget;
+					budget = budget.saturating_sub(size);
+					fits
+				})
+				.count()
+				.max(1);
+		}
+		let (batch, rest) = remaining.split_at(batch_len);
+		let (results, outcome) = parallel_try_map(
+			batch,
+			config.delete_jobs,
+			|fileinfo| prune_entry(root, fileinfo, config),
+			|index, message| {
+				warn!(
+					"Delete worker {} panicked ({}), stopping deletion",
+					index, message
+				);
+				io::Error::other(format!("delete worker {} panicked ({})", index, message))
+			},
+		);
+		for (entry_stats, size) in results {
+			progress.freed += size;
+			stats.merge(entry_stats);
+		}
+		outcome.map_err(|e| RunError::new(take(stats), e))?;
+		remaining = rest;
+
+		let new_usage = usage();
+		if status_requests.take() {
+			log_status(
+				&format!(
+					"deleting, {} of {} cache entries processed",
+					entries.len() - remaining.len(),
+					entries.len()
+				),
+				stats,
+				new_usage,
+			);
+		}
+		if config.sample_freed_space {
+			stats.freed_space.push(FreedSpaceSample {
+				elapsed: config.clock.instant() - progress.start,
+				bytes: progress.freed,
+			});
+		}
+		if progress.budget_exhausted() {
+			debug!("Freed {} bytes, stopping deletion.", progress.freed);
+			break;
+		}
+		let new_usage = match new_usage {
+			Ok(usage) => usage,
+			Err(e) => {
+				warn!(
+					"Couldn't get free space information, stopping deletion: {}",
+					e
+				);
+				break;
+			}
+		};
+		trace!(
+			target: USAGE_TRACE_TARGET,
+			usage = new_usage,
+			deleted = stats.deleted,
+			batch = batch.len(),
+			"Usage {:.3}% after deleting {} cache entries (batch of {})",
+			new_usage, stats.deleted, batch.len()
+		);
+		if new_usage < target || (!config.aggressive && new_usage < 99.5 && rng.gen::<u8>() < 1) {
+			break;
+		}
+
+		let decrease_per_entry = (current_usage - new_usage) / batch.len() as f64;
+		batch_size = if decrease_per_entry > 0.0 {
+			((new_usage - target) / decrease_per_entry).clamp(1.0, max_batch_size as f64) as usize
+		} else {
+			(batch_size * 2).min(max_batch_size)
+		};
+		current_usage = new_usage;
+		yield_now();
+	}
+
+	Ok(entries.len() - remaining.len())
+}
+
+/// Deletes a single cache entry for `prune_entries`
+///
+/// Returns the statistics of the entry and the freed space in bytes.
+fn prune_entry(
+	root: &Path,
+	fileinfo: &CacheFileInfo,
+	config: &Config,
+) -> Result<(Stats, u64), io::Error> {
+	let mut entry_stats = Stats::default();
+	if config.revalidate && fileinfo.is_refreshed() {
+		debug!(
+			path=?fileinfo.header_path(),
+			"Skipping refreshed cache entry {:?}", fileinfo.header_path()
+		);
+		entry_stats.skipped.refreshed += 1;
+		count_retained(&mut entry_stats, config, fileinfo);
+		return Ok((entry_stats, 0));
+	}
+	if let Some(lock_path) = &config.cache_lock_path {
+		if is_cache_locked(
+			lock_path,
+			fileinfo.header_path(),
+			&config.suffixes.header,
+			&config.clock.now(),
+		) {
+			debug!(
+				path=?fileinfo.header_path(),
+				"Skipping locked cache entry {:?}", fileinfo.header_path()
+			);
+			entry_stats.skipped.locked += 1;
+			count_retained(&mut entry_stats, config, fileinfo);
+			return Ok((entry_stats, 0));
+		}
+	}
+	match check_fail_fast(config, process_cache_entry(fileinfo, config))? {
+		Ok(stats) => entry_stats.merge(stats),
+		Err(_) => {
+			count_retained(&mut entry_stats, config, fileinfo);
+			entry_stats.add_failed();
+		}
+	}
+	let size = entry_stats.freed_bytes;
+
+	let folder = if config.tree_summary {
+		top_level_folder(root, fileinfo.header_path())
+	} else {
+		None
+	};
+	match folder {
+		Some(folder) => {
+			let mut stats = Stats::default();
+			stats.merge_for_folder(folder, entry_stats);
+			Ok((stats, size))
+		}
+		None => Ok((entry_stats, size)),
+	}
+}
+
+/// Applies `f` to all items using up to `jobs` threads
+///
+/// The items are split into contiguous chunks, one per thread. With a single
+/// job or item, `f` runs on the current thread. Each chunk stops at its first
+/// error. Returns the results of all successful items in order together with
+/// the first error in item order. A worker that panicked loses the results of
+/// its chunk and reports the error created by `on_panic` from its index and
+/// panic message.
+fn parallel_try_map<T: Sync, R: Send, E: Send>(
+	items: &[T],
+	jobs: usize,
+	f: impl Fn(&T) -> Result<R, E> + Sync,
+	on_panic: impl Fn(usize, &str) -> E,
+) -> (Vec<R>, Result<(), E>) {
+	if jobs <= 1 || items.len() <= 1 {
+		return try_map_chunk(items, &f);
+	}
+	let chunk_size = items.len().div_ceil(jobs);
+	// Propagate the tracing context to the worker threads
+	let dispatch = dispatcher::get_default(Dispatch::clone);
+	let parent = Span::current();
+	std::thread::scope(|s| {
+		let handles: Vec<_> = items
+			.chunks(chunk_size)
+			.map(|chunk| {
+				let f = &f;
+				let dispatch = &dispatch;
+				let parent = &parent;
+				s.spawn(move || {
+					dispatcher::with_default(dispatch, || {
+						let _guard = parent.enter();
+						try_map_chunk(chunk, f)
+					})
+				})
+			})
+			.collect();
+		let mut results = Vec::with_capacity(items.len());
+		let mut outcome = Ok(());
+		for (index, handle) in handles.into_iter().enumerate() {
+			let (chunk_results, chunk_outcome) = handle.join().unwrap_or_else(|payload| {
+				(Vec::new(), Err(on_panic(index, panic_message(&*payload))))
+			});
+			results.extend(chunk_results);
+			if outcome.is_ok() {
+				outcome = chunk_outcome;
+			}
+		}
+		(results, outcome)
+	})
+}
+
+/// Applies `f` to the items until the first error, see `parallel_try_map`
+fn try_map_chunk<T, R, E>(items: &[T], f: &impl Fn(&T) -> Result<R, E>) -> (Vec<R>, Result<(), E>) {
+	let mut results = Vec::with_capacity(items.len());
+	for item in items {
+		match f(item) {
+			Ok(result) => results.push(result),
+			Err(e) => return (results, Err(e)),
+		}
+	}
+	(results, Ok(()))
+}
+
+/// Returns the name of the top-level folder below `root` containing `path`
+fn top_level_folder(root: &Path, path: &Path) -> Option<OsString> {
+	match path.strip_prefix(root).ok()?.components().next()? {
+		Component::Normal(name) => Some(name.to_owned()),
+		_ => None,
+	}
+}
+
+/// Processes one folder recursively
+///
+/// Directly deletes definitely unneccessary files and folders and
+/// sends information about all valid cache entries via `sender`.
+///
+/// Activates `desperate` mode if usage is over 105 % of the limits
+/// in `config`. If the usage can't be determined, it isn't activated.
+pub fn process_folder(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+	sender: &channel::Sender<CacheFileInfo>,
+) -> Result<Stats, io::Error> {
+	let mut stats = Stats::default();
+	let desperate = match config.usage() {
+		Ok(usage) => usage > DESPERATE_USAGE,
+		Err(e) => {
+			warn!(
+				"Couldn't get free space information, assuming normal usage: {}",
+				e
+			);
+			false
+		}
+	};
+
+	stats.merge(scan_folder(path, config, now, false, sender, desperate)?);
+
+	Ok(stats)
+}
+
+/// Scans one subfolder recursively
+///
+/// Directly deletes definitely unneccessary files and folders and
+/// sends information about all valid cache entries via `sender`.
+///
+/// If `desperate` is true, deleting happens more aggressively.
+/// If `config.ignore_vary` is set, vary directories are treated like plain directories.
+/// If `config.changed_since` and `config.known_entries` are set, header files in
+/// directories that weren't modified since then aren't parsed, but taken from the
+/// known entries if listed there. All entries are recorded in the known entries.
+/// If `config.exclude_recent_dirs` is set, subdirectories modified within that
+/// many seconds are skipped.
+///
+/// Symbolic links are never followed, as Apache doesn't create any: linked
+/// directories aren't scanned, linked header files fail to parse (and keep
+/// their data file) and deleting a linked file only removes the link.
+///
+/// If the receiving end of `sender` is disconnected, scanning of the current
+/// directory stops and the statistics collected so far are returned.
+pub fn scan_folder(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+	in_vary: bool,
+	sender: &channel::Sender<CacheFileInfo>,
+	desperate: bool,
+) -> Result<Stats, io::Error> {
+	// Read the sizes for the eviction order here instead of in the single receiving thread
+	let by_size = !config.atime_only && config.strategy != EvictionStrategy::Lru;
+	scan_folder_into(
+		path,
+		config,
+		now,
+		in_vary,
+		&mut |fileinfo| {
+			if by_size {
+				fileinfo.disk_usage();
+			}
+			sender.send(fileinfo).is_ok()
+		},
+		desperate,
+		0,
+	)
+}
+
+/// Result of scanning a folder with `scan_folder_collect`
+#[derive(Debug, Default)]
+pub struct ScanResult {
+	/// Statistics of the files and folders deleted while scanning
+	pub stats: Stats,
+	/// All valid cache entries found, in directory order
+	pub entries: Vec<CacheFileInfo>,
+}
+
+/// Scans one folder recursively and returns the found cache entries
+///
+/// Works like `scan_folder`, but collects the cache entries instead of
+/// sending them through a channel.
+pub fn scan_folder_collect(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+	desperate: bool,
+) -> Result<ScanResult, io::Error> {
+	let mut entries = Vec::new();
+	let stats = scan_folder_into(
+		path,
+		config,
+		now,
+		false,
+		&mut |fileinfo| {
+			entries.push(fileinfo);
+			true
+		},
+		desperate,
+		0,
+	)?;
+	Ok(ScanResult { stats, entries })
+}
+
+/// Logs and counts a directory that is nested too deeply to be scanned
+fn skip_deep_dir(item: &DirEntry, stats: &mut Stats) {
+	warn!(
+		path=?item.path(),
+		"Not scanning directory {:?}, it is nested more than {} levels deep", item.path(), MAX_SCAN_DEPTH
+	);
+	stats.skipped.deep_dirs += 1;
+}
+
+/// Shared implementation of `scan_folder` and `scan_folder_collect`
+///
+/// Passes all valid cache entries to `sink`, which returns `false` if
+/// no more entries can be accepted. Directories more than `MAX_SCAN_DEPTH`
+/// levels below the scanned folder are skipped to bound the stack usage.
+///
+/// Directories matching `config.temp_dir_patterns` aren't scanned. They are
+/// skipped while recently modified and deleted with their contents otherwise,
+/// unless they contain preserved files. With `config.max_delete_bytes`, they
+/// are kept, as the freed space can't be counted against the budget here.
+///
+/// With `config.skip_dir_on_error`, the rest of a directory is skipped after
+/// the first error deleting a file or folder or scanning a subdirectory in it.
+#[instrument(name = "scan_folder", level = "trace", skip(config, now, sink, depth))]
+fn scan_folder_into(
+	path: &Path,
+	config: &Config,
+	now: &SystemTime,
+	in_vary: bool,
+	sink: &mut dyn FnMut(CacheFileInfo) -> bool,
+	desperate: bool,
+	depth: usize,
+) -> Result<Stats, io::Error> {
+	let mut known_headers = HashSet::new();
+	let mut stats = Stats::default();
+	// Entries of unchanged directories are known from the last run
+	let known = match &config.known_entries {
+		Some(known) if !is_changed_since(path, config.changed_since) => {
+			stats.skipped.unchanged_dirs += 1;
+			Some(known)
+		}
+		_ => None,
+	};
+
+	for item in timed(Syscall::ReadDir, || path.read_dir())?.flatten() {
+		if config.no_recurse_hidden && is_hidden(&item) {
+			continue;
+		}
+		let name = item.file_name();
+		if let Some(name) = name.to_str() {
+			// Temporary files -> only delete if old
+			if is_ap_tempfile(name, config) {
+				let result = check_fail_fast(config, delete_file_if_not_recent(&item, now, 600))?;
+				let skip = skips_dir(config, path, &result);
+				stats.count(result);
+				if skip {
+					break;
+				}
+			}
+			// Header files
+			else if let Some(stem) = name.strip_suffix(config.suffixes.header.as_str()) {
+				known_headers.insert(stem.to_owned());
+				let result = match known.and_then(|known| known.get(&item.path())) {
+					Some(fileinfo) => Ok(fileinfo),
+					None => {
+						let _permit = config.parse_limit.as_ref().map(|limit| limit.acquire());
+						let result = CacheFileInfo::with_options(
+							&item,
+							config.expiry_fallback_ttl,
+							&config.suffixes,
+						);
+						if result.is_ok() {
+							stats.parsed += 1;
+						}
+						result
+					}
+				};
+				if let Ok(fileinfo) = result {
+					if let Some(known) = &config.known_entries {
+						known.record(&fileinfo);
+					}
+					if !in_vary && !config.ignore_vary && fileinfo.is_vary() {
+						// Delete orphaned data file if the header indicates a vary directory
+						let data_path = fileinfo.data_path();
+						let result = match timed(Syscall::Remove, || remove_file(&data_path)) {
+							Ok(()) => Ok(true),
+							// Usually there is no data file
+							Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+							Err(e) => Err(e),
+						};
+						if !matches!(result, Ok(false)) {
+							debug!(
+								path=?&data_path,
+								error=result.as_ref().err().map(|v| v as &dyn Error),
+								"Deleting orphaned data file {:?}: {}", &data_path, if result.is_ok() {"ok"} else {"failed"}
+							);
+						}
+						let result = check_fail_fast(config, result)?;
+						let skip = skips_dir(config, path, &result);
+						stats.count(result);
+						if skip {
+							break;
+						}
+
+						// Don't delete main header as long as a vary directory exists (as long as not in desperate mode)
+						if !desperate {
+							let vdir_path = fileinfo.vary_path();
+							if vdir_path.exists() {
+								if let Ok(metadata) = vdir_path.symlink_metadata() {
+									if metadata.is_dir() && metadata.nlink() > 2 {
+										count_retained(&mut stats, config, &fileinfo);
+										continue;
+									}
+								}
+							}
+						}
+					}
+					// Keep preserved entries out of the eviction queue
+					if !config.preserve.is_empty() && config.is_preserved(fileinfo.header_path()) {
+						stats.skipped.preserved += 1;
+						count_retained(&mut stats, config, &fileinfo);
+						continue;
+					}
+					// Keep freshly written entries out of the eviction queue, even in desperate mode
+					if config.min_lifetime.is_some_and(|min_lifetime| {
+						fileinfo
+							.modified()
+							.checked_add(min_lifetime)
+							.is_none_or(|t| t > *now)
+					}) {
+						stats.skipped.young += 1;
+						count_retained(&mut stats, config, &fileinfo);
+						continue;
+					}
+					// Keep hot entries out of the eviction queue (as long as not in desperate mode)
+					if !desperate
+						&& !config.hot_list.is_empty()
+						&& config
+							.hot_list
+							.contains(fileinfo.header_path(), &config.suffixes.header)
+					{
+						stats.skipped.hot += 1;
+						count_retained(&mut stats, config, &fileinfo);
+						continue;
+					}
+					// Keep revalidatable entries out of the eviction queue (as long as not in desperate mode)
+					if !desperate && config.exclude_revalidatable && fileinfo.has_validators() {
+						stats.skipped.revalidatable += 1;
+						count_retained(&mut stats, config, &fileinfo);
+						continue;
+					}
+					if !sink(fileinfo) {
+						debug!(path=?path, "Entry receiver disconnected, stopping scan of {:?}", path);
+						return Ok(stats);
+					}
+				} else {
+					if config.dump_header_hex {
+						dump_header_hex(&item.path());
+					}
+					stats.add_failed();
+					stats.parse_errors += 1;
+				}
+			}
+			// Data files
+			else if let Some(stem) = name.strip_suffix(config.suffixes.data.as_str()) {
+				if !known_headers.contains(stem) {
+					let header_path = item
+						.path()
+						.with_file_name(format!("{}{}", stem, config.suffixes.header));
+					// If the header file is missing and the file is old, delete it.
+					if header_path.symlink_metadata().is_err() {
+						if !is_write_in_progress(&item, now) {
+							let result = check_fail_fast(
+								config,
+								delete_file_if_not_recent(&item, now, ORPHAN_DATA_AGE),
+							)?;
+							let skip = skips_dir(config, path, &result);
+							stats.count(result);
+							if skip {
+								break;
+							}
+						}
+						continue;
+					}
+				}
+			}
+			// Recurse into vary directories
+			else if !config.ignore_vary && name.ends_with(config.suffixes.vary.as_str()) {
+				if depth >= MAX_SCAN_DEPTH {
+					skip_deep_dir(&item, &mut stats);
+					continue;
+				}
+				let result = check_fail_fast(
+					config,
+					scan_folder_into(&item.path(), config, now, true, sink, desperate, depth + 1),
+				)?;
+				let skip = skips_dir(config, path, &result);
+				stats.merge_result(result);
+				if skip {
+					break;
+				}
+				if !config.no_delete_folders {
+					let result = check_fail_fast(
+						config,
+						delete_folder_if_not_recent(&item, None, now, 300),
+					)?;
+					let skip = skips_dir(config, path, &result);
+					stats.count_folder(result);
+					if skip {
+						break;
+					}
+				}
+			}
+			// Recurse into other directories
+			else if let Ok(metadata) = timed(Syscall::Metadata, || item.metadata()) {
+				if metadata.is_dir() {
+					// Temporary directories -> skip while active, delete when stale
+					if config.is_temp_dir(name) {
+						if is_recently_modified(&metadata, now, STALE_TEMP_DIR_AGE) {
+							trace!(path=?item.path(), "Skipping active temporary directory {:?}", item.path());
+							stats.skipped.temp_dirs += 1;
+						} else if config.max_delete_bytes.is_some()
+							|| config.contains_preserved(&item.path())
+						{
+							trace!(path=?item.path(), "Keeping stale temporary directory {:?}", item.path());
+							stats.skipped.temp_dirs += 1;
+						} else if !config.no_delete_folders {
+							let result = check_fail_fast(config, remove_temp_dir(&item))?;
+							let skip = skips_dir(config, path, &result);
+							stats.count_folder(result);
+							if skip {
+								break;
+							}
+						}
+						continue;
+					}
+					// Skip directories Apache is probably writing to right now
+					if let Some(seconds) = config.exclude_recent_dirs {
+						if is_recently_modified(&metadata, now, seconds) {
+							trace!(path=?item.path(), "Skipping recently modified directory {:?}", item.path());
+							stats.skipped.recent_dirs += 1;
+							continue;
+						}
+					}
+					if depth >= MAX_SCAN_DEPTH {
+						skip_deep_dir(&item, &mut stats);
+						continue;
+					}
+					let result = check_fail_fast(
+						config,
+						scan_folder_into(
+							&item.path(),
+							config,
+							now,
+							in_vary,
+							sink,
+							desperate,
+							depth + 1,
+						),
+					)?;
+					let skip = skips_dir(config, path, &result);
+					stats.merge_result(result);
+					if skip {
+						break;
+					}
+					if !config.no_delete_folders {
+						let result = check_fail_fast(
+							config,
+							delete_folder_if_not_recent(&item, Some(metadata), now, 300),
+						)?;
+						let skip = skips_dir(config, path, &result);
+						stats.count_folder(result);
+						if skip {
+							break;
+						}
+					}
+				} else {
+					count_foreign(&mut stats, config, &item);
+				}
+			}
+		} else if !item.file_type().is_ok_and(|t| t.is_dir()) {
+			count_foreign(&mut stats, config, &item);
+		}
+	}
+
+	// Be somewhat nice to other processes by yielding the CPU after each non-vary directory
+	if !in_vary {
+		yield_now();
+	}
+
+	Ok(stats)
+}
+
+/// Counts a file that doesn't belong to the cache, keeping its path as a sample if enabled
+fn count_foreign(stats: &mut Stats, config: &Config, item: &DirEntry) {
+	trace!(path=?item.path(), "Found foreign file {:?}", item.path());
+	stats.foreign_files += 1;
+	if config.warn_on_foreign_files && stats.foreign_samples.len() < FOREIGN_FILE_SAMPLES {
+		stats.foreign_samples.push(item.path());
+	}
+}
+
+/// Checks if the directory at `path` was modified since `since`
+///
+/// Returns `true` if `since` is `None` or the modification time can't be read.
+fn is_changed_since(path: &Path, since: Option<SystemTime>) -> bool {
+	let since = match since {
+		Some(since) => since,
+		None => return true,
+	};
+	match path.metadata().and_then(|m| m.modified()) {
+		Ok(modified) => modified >= since,
+		Err(_) => true,
+	}
+}
+
+/// Calculates a percentage of how close the used space is to the free space/inode limit
+/// on the filesystem containing `path`
+///
+/// Returns the maximum of space and inode percentage.
+pub fn calculate_usage(path: &Path, minspace: SizeSpec, mininodes: SizeSpec) -> nix::Result<f64> {
+	statfs_for(path).usage(minspace, mininodes)
+}
+
+/// Returns the absolute figures `calculate_usage` calculates the percentage from
+pub fn calculate_absolute_usage(
+	path: &Path,
+	minspace: SizeSpec,
+	mininodes: SizeSpec,
+) -> nix::Result<AbsoluteUsage> {
+	Ok(statfs_for(path)
+		.fs_stats()?
+		.absolute_usage(minspace, mininodes))
+}
+
+/// Returns the `Statfs` provider for the filesystem containing `path`
+fn statfs_for(path: &Path) -> Statfs {
+	Statfs {
+		path: path.to_owned(),
+		..Default::default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+	use std::fs::{copy, create_dir_all, write, FileTimes};
+	use std::os::fd::AsRawFd;
+	use std::path::PathBuf;
+	use std::sync::atomic::AtomicU64;
+	use std::sync::Arc;
+	use std::time::{Duration, UNIX_EPOCH};
+	use tempfile::TempDir;
+	use tracing_flame::FlameLayer;
+	use tracing_subscriber::prelude::*;
+
+	/// Log output writer for `capture_logs`
+	struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+	impl io::Write for LogWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	/// Runs `f` with debug logging enabled and returns the log output
+	fn capture_logs(f: impl FnOnce()) -> String {
+		capture_logs_at(tracing::Level::DEBUG, f)
+	}
+
+	/// Runs `f` and returns the log output up to `level` it produced
+	fn capture_logs_at(level: tracing::Level, f: impl FnOnce()) -> String {
+		let buffer = Arc::new(Mutex::new(Vec::new()));
+		let writer = buffer.clone();
+		let subscriber = tracing_subscriber::fmt()
+			.with_max_level(level)
+			.with_ansi(false)
+			.with_writer(move || LogWriter(writer.clone()))
+			.finish();
+		tracing::subscriber::with_default(subscriber, f);
+		let output = buffer.lock().unwrap().clone();
+		String::from_utf8(output).unwrap()
+	}
+
+	/// Adds a cache entry with the test header and an empty data file, returns the header path
+	pub(crate) fn add_entry(dir: &Path, name: &str) -> PathBuf {
+		let header = dir.join(format!("{}.header", name));
+		copy("testcases/disk.header", &header).unwrap();
+		File::create(dir.join(format!("{}.data", name))).unwrap();
+		header
+	}
+
+	/// Adds a cache entry like `add_entry` with the given expiry and modification time
+	fn write_disk_header(
+		dir: &Path,
+		name: &str,
+		expiry: SystemTime,
+		modified: SystemTime,
+	) -> PathBuf {
+		let path = add_entry(dir, name);
+		let mut header = std::fs::read(&path).unwrap();
+		let micros = expiry.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+		header[32..40].copy_from_slice(&micros.to_ne_bytes());
+		write(&path, header).unwrap();
+		let times = FileTimes::new()
+			.set_accessed(modified)
+			.set_modified(modified);
+		File::options()
+			.write(true)
+			.open(&path)
+			.unwrap()
+			.set_times(times)
+			.unwrap();
+		path
+	}
+
+	/// Adds `count` cache entries named by their index and returns all entries in `dir`
+	pub(crate) fn add_entries(dir: &Path, count: usize) -> Vec<CacheFileInfo> {
+		for i in 0..count {
+			add_entry(dir, &i.to_string());
+		}
+		read_entries(dir)
+	}
+
+	/// Returns the cache entries directly in `dir`, sorted by access time
+	pub(crate) fn read_entries(dir: &Path) -> Vec<CacheFileInfo> {
+		let mut entries: Vec<_> = dir
+			.read_dir()
+			.unwrap()
+			.flatten()
+			.filter(|e| {
+				e.file_name()
+					.to_string_lossy()
+					.ends_with(CACHE_HEADER_SUFFIX)
+			})
+			.map(|e| CacheFileInfo::new(&e).unwrap())
+			.collect();
+		entries.sort();
+		entries
+	}
+
+	/// Sets the access and modification time of a file or directory to `seconds` ago
+	fn set_age(path: &Path, seconds: u64) {
+		let time = SystemTime::now() - Duration::from_secs(seconds);
+		let times = FileTimes::new().set_accessed(time).set_modified(time);
+		File::open(path).unwrap().set_times(times).unwrap();
+	}
+
+	/// Creates a cache directory with a vary entry and an orphaned data file for the main header
+	fn vary_fixture() -> TempDir {
+		let dir = TempDir::new().unwrap();
+		let vdir = dir.path().join("entry.header.vary/ab");
+		create_dir_all(&vdir).unwrap();
+		copy("testcases/vary.header", dir.path().join("entry.header")).unwrap();
+		File::create(dir.path().join("entry.data")).unwrap();
+		add_entry(&vdir, "variant");
+		dir
+	}
+
+	#[test]
+	fn test_process_cache_entry() {
+		let dir = TempDir::new().unwrap();
+		add_entry(dir.path(), "disk");
+		write(dir.path().join("disk.data"), [0u8; 8192]).unwrap();
+		copy("testcases/vary.header", dir.path().join("vary.header")).unwrap();
+		File::create(dir.path().join("vary.data")).unwrap();
+		create_dir_all(dir.path().join("vary.header.vary")).unwrap();
+		let entry = |name: &str| {
+			let entry = dir
+				.path()
+				.read_dir()
+				.unwrap()
+				.flatten()
+				.find(|e| e.file_name() == name)
+				.unwrap();
+			CacheFileInfo::new(&entry).unwrap()
+		};
+		let disk = entry("disk.header");
+		let vary = entry("vary.header");
+		// Sizes read before aren't read again when deleting
+		let disk_usage = disk.disk_usage();
+		write(dir.path().join("disk.data"), [0u8; 65536]).unwrap();
+
+		let stats = process_cache_entry(&disk, &Config::default()).unwrap();
+		assert_eq!(
+			(
+				stats.deleted,
+				stats.deleted_disk,
+				stats.deleted_vary,
+				stats.deleted_folders
+			),
+			(1, 1, 0, 0)
+		);
+		assert!(disk_usage >= 8192);
+		assert_eq!(stats.freed_bytes, disk_usage);
+		assert_eq!(stats.freed_inodes, 2);
+		assert!(!dir.path().join("disk.data").exists());
+
+		let stats = process_cache_entry(&vary, &Config::default()).unwrap();
+		assert_eq!(
+			(
+				stats.deleted,
+				stats.deleted_disk,
+				stats.deleted_vary,
+				stats.deleted_folders
+			),
+			(1, 0, 1, 1)
+		);
+		assert_eq!(stats.freed_inodes, 2);
+		assert!(!dir.path().join("vary.header.vary").exists());
+
+		let error = process_cache_entry(&vary, &Config::default()).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::NotFound);
+	}
+
+	/// Scans `path` and returns the statistics and the names of all found entries
+	fn scan(path: &Path, config: &Config) -> (Stats, Vec<String>) {
+		let (sender, receiver) = channel::unbounded();
+		let stats = scan_folder(path, config, &SystemTime::now(), false, &sender, false).unwrap();
+		drop(sender);
+		let mut names: Vec<_> = receiver
+			.iter()
+			.map(|f| {
+				f.header_path()
+					.file_name()
+					.unwrap()
+					.to_string_lossy()
+					.into_owned()
+			})
+			.collect();
+		names.sort();
+		(stats, names)
+	}
+
+	/// `UsageProvider` deriving the usage from the number of cache entries in a directory
+	///
+	/// Each entry accounts for 3 % of usage on top of a base usage of 80 %.
+	struct EntryCountUsage<'a>(&'a Path);
+
+	impl UsageProvider for EntryCountUsage<'_> {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(nix::errno::Errno::ENOSYS)
+		}
+
+		fn usage(&self, _minspace: SizeSpec, _mininodes: SizeSpec) -> nix::Result<f64> {
+			let count = self
+				.0
+				.read_dir()
+				.unwrap()
+				.flatten()
+				.filter(|e| {
+					e.file_name()
+						.to_string_lossy()
+						.ends_with(CACHE_HEADER_SUFFIX)
+				})
+				.count();
+			Ok(80.0 + 3.0 * count as f64)
+		}
+	}
+
+	#[test]
+	fn test_age_thresholds_mock_clock() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("aptmpAbC123");
+		File::create(&path).unwrap();
+		let modified = SystemTime::now() - Duration::from_secs(3600);
+		let times = FileTimes::new()
+			.set_accessed(modified)
+			.set_modified(modified);
+		File::open(&path).unwrap().set_times(times).unwrap();
+		let entry = || {
+			dir.path()
+				.read_dir()
+				.unwrap()
+				.flatten()
+				.find(|e| e.path() == path)
+				.unwrap()
+		};
+
+		let clock = MockClock::new(modified);
+		clock.advance(Duration::from_secs(599));
+		let metadata = entry().metadata().unwrap();
+		assert!(is_recently_modified(&metadata, &clock.now(), 600));
+		assert!(!delete_file_if_not_recent(&entry(), &clock.now(), 600).unwrap());
+		assert!(path.exists());
+
+		clock.advance(Duration::from_secs(1));
+		assert!(!is_recently_modified(&metadata, &clock.now(), 600));
+		assert!(delete_file_if_not_recent(&entry(), &clock.now(), 600).unwrap());
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn test_mock_clock_timings() {
+		let dir = TempDir::new().unwrap();
+		add_entry(dir.path(), "entry");
+		let clock = Arc::new(MockClock::new(SystemTime::now()));
+		let config = Config {
+			clock: clock.clone(),
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &clock.now()).unwrap();
+		assert_eq!(stats.timings, PhaseTimings::default());
+	}
+
+	#[test]
+	fn test_prune_overshoot() {
+		let dir = TempDir::new().unwrap();
+		let entries = add_entries(dir.path(), 10);
+
+		let provider = EntryCountUsage(dir.path());
+		assert_eq!(
+			provider.usage(SizeSpec::Absolute(0), SizeSpec::Absolute(0)),
+			Ok(110.0)
+		);
+		// The first batch measures the decrease, then single entries approach the target
+		let config = Config {
+			delete_batch_size: 2,
+			..Default::default()
+		};
+		let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+		assert_eq!(stats.deleted, 4);
+		assert_eq!(
+			provider.usage(SizeSpec::Absolute(0), SizeSpec::Absolute(0)),
+			Ok(98.0)
+		);
+	}
+
+	/// `UsageProvider` counting its calls, with a usage decreasing slightly per call
+	struct CountingUsage(std::cell::Cell<u32>);
+
+	impl UsageProvider for CountingUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(nix::errno::Errno::ENOSYS)
+		}
+
+		fn usage(&self, _minspace: SizeSpec, _mininodes: SizeSpec) -> nix::Result<f64> {
+			self.0.set(self.0.get() + 1);
+			Ok(500.0 - self.0.get() as f64 * 0.001)
+		}
+	}
+
+	/// `UsageProvider` raising `SIGUSR1` on the second call
+	struct SignallingUsage(std::cell::Cell<u32>);
+
+	impl UsageProvider for SignallingUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(nix::errno::Errno::ENOSYS)
+		}
+
+		fn usage(&self, _minspace: SizeSpec, _mininodes: SizeSpec) -> nix::Result<f64> {
+			self.0.set(self.0.get() + 1);
+			if self.0.get() == 2 {
+				nix::sys::signal::raise(nix::sys::signal::Signal::SIGUSR1).unwrap();
+			}
+			Ok(500.0)
+		}
+	}
+
+	#[test]
+	fn test_status_signal() {
+		install_status_handler().unwrap();
+		let dir = TempDir::new().unwrap();
+		let entries = add_entries(dir.path(), 5);
+
+		let provider = SignallingUsage(Default::default());
+		let config = Config {
+			delete_batch_size: 1,
+			..Default::default()
+		};
+		let logs = capture_logs(|| {
+			prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+		});
+		assert!(logs.contains("Status: deleting, 1 of 5 cache entries processed, 1 deleted files"));
+		assert!(logs.contains("usage 500.0%"));
+		assert_eq!(logs.matches("Status:").count(), 1);
+	}
+
+	/// `UsageProvider` returning a scripted sequence of usages
+	struct ScriptedUsage(std::cell::RefCell<Vec<f64>>);
+
+	impl UsageProvider for ScriptedUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(nix::errno::Errno::ENOSYS)
+		}
+
+		fn usage(&self, _minspace: SizeSpec, _mininodes: SizeSpec) -> nix::Result<f64> {
+			Ok(self.0.borrow_mut().remove(0))
+		}
+	}
+
+	#[test]
+	fn test_usage_trace() {
+		let dir = TempDir::new().unwrap();
+		let entries = add_entries(dir.path(), 10);
+
+		let provider = ScriptedUsage(vec![105.0, 101.0, 99.8, 98.5].into());
+		let config = Config {
+			delete_batch_size: 4,
+			..Default::default()
+		};
+		let logs = capture_logs_at(tracing::Level::TRACE, || {
+			let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+			assert_eq!(stats.deleted, 7);
+		});
+		let trace: Vec<_> = logs
+			.lines()
+			.filter(|line| line.contains("TRACE") && line.contains("Usage "))
+			.map(|line| &line[line.find("Usage ").unwrap()..])
+			.collect();
+		assert_eq!(
+			trace,
+			[
+				"Usage 105.000% before deleting usage=105.0",
+				"Usage 101.000% after deleting 4 cache entries (batch of 4) usage=101.0 deleted=4 batch=4",
+				"Usage 99.800% after deleting 6 cache entries (batch of 2) usage=99.8 deleted=6 batch=2",
+				"Usage 98.500% after deleting 7 cache entries (batch of 1) usage=98.5 deleted=7 batch=1",
+			]
+		);
+		assert!(provider.0.borrow().is_empty());
+
+		// Not logged at lower verbosity
+		let provider = ScriptedUsage(vec![105.0, 98.5].into());
+		let logs = capture_logs(|| {
+			prune_entries(dir.path(), &entries[7..], &Config::default(), &provider).unwrap();
+		});
+		assert!(!logs.contains("Usage "));
+	}
+
+	/// `UsageProvider` failing after the first call, like a vanished mount
+	struct FailingUsage(std::cell::Cell<u32>);
+
+	impl UsageProvider for FailingUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(nix::errno::Errno::EIO)
+		}
+
+		fn usage(&self, _minspace: SizeSpec, _mininodes: SizeSpec) -> nix::Result<f64> {
+			self.0.set(self.0.get() + 1);
+			if self.0.get() > 1 {
+				return Err(nix::errno::Errno::EIO);
+			}
+			Ok(500.0)
+		}
+	}
+
+	#[test]
+	fn test_usage_failure() {
+		let dir = TempDir::new().unwrap();
+		let entries = add_entries(dir.path(), 5);
+		let config = Config {
+			retained_stats: true,
+			delete_batch_size: 1,
+			..Default::default()
+		};
+
+		// Deleting stops after the first batch instead of panicking
+		let provider = FailingUsage(Default::default());
+		let logs = capture_logs(|| {
+			let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+			assert_eq!(stats.deleted, 1);
+			assert_eq!(stats.retained, 4);
+		});
+		assert!(logs.contains("Couldn't get free space information, stopping deletion"));
+
+		// Scanning continues without desperate mode
+		let config = Config {
+			path: dir.path().join("missing"),
+			..Default::default()
+		};
+		let (sender, receiver) = channel::unbounded();
+		process_folder(dir.path(), &config, &SystemTime::now(), &sender).unwrap();
+		drop(sender);
+		assert_eq!(receiver.iter().count(), 4);
+	}
+
+	#[test]
+	fn test_delete_batch_size() {
+		let dir = TempDir::new().unwrap();
+		let entries = add_entries(dir.path(), 25);
+
+		for batch_size in [1, 4, 10, 30] {
+			let config = Config {
+				delete_batch_size: batch_size,
+				..Default::default()
+			};
+			let provider = CountingUsage(Default::default());
+			let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+			assert_eq!(stats.failed + stats.deleted, 25);
+			// One initial check, then one per full batch
+			let rechecks = provider.0.get() as usize - 1;
+			assert_eq!(rechecks, 25usize.div_ceil(batch_size));
+		}
+	}
+
+	#[test]
+	fn test_retained_stats() {
+		let dir = TempDir::new().unwrap();
+		for i in 0..10 {
+			add_entry(dir.path(), &i.to_string());
+		}
+		add_entry(dir.path(), "kept");
+
+		let config = Config {
+			retained_stats: true,
+			preserve: vec![glob::Pattern::new("**/kept.header").unwrap()],
+			delete_batch_size: 2,
+			..Default::default()
+		};
+		let mut result =
+			scan_folder_collect(dir.path(), &config, &SystemTime::now(), false).unwrap();
+		assert_eq!(result.stats.retained, 1);
+		result.entries.sort();
+
+		let provider = EntryCountUsage(dir.path());
+		let mut stats = result.stats;
+		stats.merge(prune_entries(dir.path(), &result.entries, &config, &provider).unwrap());
+		assert_eq!(stats.deleted, 5);
+		assert_eq!(stats.retained + stats.deleted, 11);
+		assert!(stats.retained_bytes > 0);
+	}
+
+	#[test]
+	fn test_parallel_try_map() {
+		let items: Vec<_> = (0..10).collect();
+		for jobs in [1, 3, 4, 20] {
+			let (results, outcome) = parallel_try_map(
+				&items,
+				jobs,
+				|i| Ok::<_, ()>((i * 2, std::thread::current().id())),
+				|_, _| (),
+			);
+			assert_eq!(outcome, Ok(()));
+			let doubled: Vec<_> = results.iter().map(|(i, _)| *i).collect();
+			assert_eq!(doubled, (0..20).step_by(2).collect::<Vec<_>>());
+
+			let threads: HashSet<_> = results.iter().map(|(_, id)| *id).collect();
+			assert_eq!(threads.len(), jobs.min(items.len()));
+			assert_eq!(threads.contains(&std::thread::current().id()), jobs == 1);
+
+			let (results, outcome) = parallel_try_map(
+				&items,
+				jobs,
+				|&i| if i % 4 == 3 { Err(i) } else { Ok(i) },
+				|_, _| 0,
+			);
+			assert_eq!(outcome, Err(3));
+			assert!(results.starts_with(&[0, 1, 2]));
+			assert!(!results.contains(&3));
+		}
+	}
+
+	#[test]
+	fn test_expired_only() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("a");
+		create_dir_all(&sub).unwrap();
+		let now = SystemTime::now();
+		let hour = Duration::from_secs(3600);
+		for i in 0..3 {
+			write_disk_header(&sub, &format!("expired-{}", i), now - hour, now - 2 * hour);
+		}
+		for i in 0..2 {
+			write_disk_header(&sub, &format!("fresh-{}", i), now + hour, now - 3 * hour);
+		}
+		let config = Config {
+			expired_only: true,
+			retained_stats: true,
+			..Config::default()
+		};
+
+		// Deleted regardless of the usage of the test filesystem
+		let (_, entries) = scan_phase(dir.path(), &config, &now).unwrap();
+		let stats = delete_phase(dir.path(), entries, &config, &now).unwrap();
+		assert_eq!(stats.deleted, 3);
+		assert_eq!(stats.retained, 2);
+		for i in 0..3 {
+			assert!(!sub.join(format!("expired-{}.header", i)).exists());
+		}
+		for i in 0..2 {
+			assert!(sub.join(format!("fresh-{}.header", i)).exists());
+			assert!(sub.join(format!("fresh-{}.data", i)).exists());
+		}
+
+		// A large fresh entry sorts first by size, but doesn't stop the deletion
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("a");
+		create_dir_all(&sub).unwrap();
+		for i in 0..3 {
+			write_disk_header(&sub, &format!("expired-{}", i), now - hour, now - 2 * hour);
+		}
+		write_disk_header(&sub, "fresh", now + hour, now - 3 * hour);
+		std::fs::write(sub.join("fresh.data"), vec![1; 64 * 1024]).unwrap();
+		let config = Config {
+			strategy: EvictionStrategy::Size,
+			..config
+		};
+		let (_, entries) = scan_phase(dir.path(), &config, &now).unwrap();
+		assert_eq!(entries[0].header_path(), sub.join("fresh.header"));
+		let stats = delete_phase(dir.path(), entries, &config, &now).unwrap();
+		assert_eq!(stats.deleted, 3);
+		assert_eq!(stats.retained, 1);
+		assert!(sub.join("fresh.header").exists());
+	}
+
+	#[test]
+	fn test_shuffle_expired() {
+		let dir = TempDir::new().unwrap();
+		let now = SystemTime::now();
+		let hour = Duration::from_secs(3600);
+		for i in 0..20 {
+			write_disk_header(
+				dir.path(),
+				&format!("expired-{:02}", i),
+				now - hour,
+				now - hour * (i + 2),
+			);
+		}
+		for i in 0..5 {
+			write_disk_header(
+				dir.path(),
+				&format!("fresh-{}", i),
+				now + hour * (i * 10 + 1),
+				now - hour * (i * 10 + 1),
+			);
+		}
+		let mut entries = read_entries(dir.path());
+		// Move a fresh entry between the expired ones
+		entries.swap(3, 21);
+		let original = entries.clone();
+
+		shuffle_expired(&mut entries, &now, &mut StdRng::seed_from_u64(1));
+		assert_ne!(entries, original);
+		for (shuffled, original) in entries.iter().zip(&original) {
+			if original.expires() > &now {
+				assert_eq!(shuffled, original);
+			} else {
+				assert!(shuffled.expires() <= &now);
+			}
+		}
+		let mut sorted = entries.clone();
+		sorted.sort();
+		let mut original_sorted = original.clone();
+		original_sorted.sort();
+		assert_eq!(sorted, original_sorted);
+	}
+
+	#[test]
+	fn test_as_of_clock() {
+		let dir = TempDir::new().unwrap();
+		let now = SystemTime::now();
+		let hour = Duration::from_secs(3600);
+		write_disk_header(dir.path(), "fresh", now + hour, now - 3 * hour);
+		// Expired, but modified in the future (e.g. clock skew)
+		write_disk_header(dir.path(), "skewed", now - hour, now + 2 * hour);
+		let names = |config: &Config| -> Vec<_> {
+			let mut entries: Vec<_> = iter_cache_entries(dir.path()).unwrap().flatten().collect();
+			entries.sort();
+			expired_first(&mut entries, &config.clock.now());
+			entries
+				.iter()
+				.map(|e| e.header_path().file_stem().unwrap().to_owned())
+				.collect()
+		};
+
+		assert_eq!(names(&Config::default()), ["skewed", "fresh"]);
+		// Both are expired 90 minutes from now
+		let config = Config {
+			clock: Arc::new(FixedClock(now + 3 * hour / 2)),
+			..Config::default()
+		};
+		assert_eq!(names(&config), ["fresh", "skewed"]);
+	}
+
+	#[test]
+	fn test_expired_first() {
+		let dir = TempDir::new().unwrap();
+		let now = SystemTime::now();
+		let hour = Duration::from_secs(3600);
+		for (name, expiry, modified) in [
+			// Expired, but modified in the future (e.g. clock skew)
+			("expired-skewed", now - hour, now + hour),
+			("expired", now - hour, now - 2 * hour),
+			("fresh", now + hour / 6, now - 2 * hour),
+			("fresh-later", now + 2 * hour, now - 2 * hour),
+		] {
+			write_disk_header(dir.path(), name, expiry, modified);
+		}
+		let mut entries = read_entries(dir.path());
+		let names = |entries: &[CacheFileInfo]| -> Vec<_> {
+			entries
+				.iter()
+				.map(|e| e.header_path().file_stem().unwrap().to_owned())
+				.collect()
+		};
+		assert_eq!(
+			names(&entries),
+			["expired", "fresh", "expired-skewed", "fresh-later"]
+		);
+
+		expired_first(&mut entries, &now);
+		assert_eq!(
+			names(&entries),
+			["expired", "expired-skewed", "fresh", "fresh-later"]
+		);
+	}
+
+	#[test]
+	fn test_eviction_tiers() {
+		let dir = TempDir::new().unwrap();
+		let now = SystemTime::now();
+		let day = Duration::from_secs(86400);
+		for (name, expiry, modified) in [
+			("expired", now - day, now - day),
+			("idle-40d-a", now + 300 * day, now - 40 * day),
+			("idle-40d-b", now + 301 * day, now - 40 * day),
+			("idle-10d-a", now + 302 * day, now - 10 * day),
+			("idle-10d-b", now + 303 * day, now - 10 * day),
+			("recent-0", now + day, now - day / 24),
+			("recent-1", now + 2 * day, now - day / 24),
+			("recent-2", now + 3 * day, now - day / 24),
+			("recent-3", now + 4 * day, now - day / 24),
+			("recent-4", now + 5 * day, now - day / 24),
+		] {
+			write_disk_header(dir.path(), name, expiry, modified);
+		}
+		let mut entries: Vec<_> = iter_cache_entries(dir.path()).unwrap().flatten().collect();
+		entries.sort();
+		let names = |entries: &[CacheFileInfo]| -> Vec<_> {
+			entries
+				.iter()
+				.map(|e| {
+					e.header_path()
+						.file_stem()
+						.unwrap()
+						.to_string_lossy()
+						.into_owned()
+				})
+				.collect()
+		};
+		// Oldest first would delete the recently used entries expiring soon first
+		assert_eq!(names(&entries)[1], "recent-0");
+
+		let tiers = [7 * day, 30 * day];
+		order_by_eviction_tier(&mut entries, &tiers, &now);
+		assert_eq!(
+			names(&entries),
+			[
+				"expired",
+				"idle-40d-a",
+				"idle-40d-b",
+				"idle-10d-a",
+				"idle-10d-b",
+				"recent-0",
+				"recent-1",
+				"recent-2",
+				"recent-3",
+				"recent-4"
+			]
+		);
+
+		// 10 entries are 110 %, deleting 4 reaches the target
+		let provider = EntryCountUsage(dir.path());
+		let config = Config {
+			delete_batch_size: 2,
+			..Default::default()
+		};
+		let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+		assert_eq!(stats.deleted, 4);
+		let mut remaining: Vec<_> = iter_cache_entries(dir.path()).unwrap().flatten().collect();
+		remaining.sort();
+		assert_eq!(
+			names(&remaining),
+			[
+				"recent-0",
+				"recent-1",
+				"recent-2",
+				"recent-3",
+				"recent-4",
+				"idle-10d-b"
+			]
+		);
+	}
+
+	#[test]
+	fn test_protect_largest() {
+		let dir = TempDir::new().unwrap();
+		for (i, size) in [4, 64, 8, 32, 16, 16, 0, 128, 0, 0].into_iter().enumerate() {
+			add_entry(dir.path(), &i.to_string());
+			write(
+				dir.path().join(format!("{}.data", i)),
+				vec![1u8; size * 1024],
+			)
+			.unwrap();
+		}
+		let entries = read_entries(dir.path());
+		let name = |e: &CacheFileInfo| e.header_path().file_name().unwrap().to_owned();
+
+		let (remaining, protected) = protect_largest(entries.clone(), 30.0, false);
+		let mut protected: Vec<_> = protected.iter().map(name).collect();
+		protected.sort();
+		assert_eq!(protected, ["1.header", "3.header", "7.header"]);
+		assert_eq!(remaining.len(), 7);
+		assert!(remaining.windows(2).all(|pair| pair[0] <= pair[1]));
+
+		let (remaining, protected) = protect_largest(entries.clone(), 30.0, true);
+		assert_eq!(remaining, entries);
+		assert!(protected.is_empty());
+		let (remaining, protected) = protect_largest(entries.clone(), 0.0, false);
+		assert_eq!(remaining, entries);
+		assert!(protected.is_empty());
+		let (remaining, protected) = protect_largest(entries.clone(), 100.0, false);
+		assert!(remaining.is_empty());
+		assert_eq!(protected.len(), 10);
+	}
+
+	#[test]
+	fn test_prune_aggressive() {
+		let dir = TempDir::new().unwrap();
+		let entries = add_entries(dir.path(), 10);
+
+		let config = Config {
+			aggressive: true,
+			delete_jobs: 4,
+			delete_batch_size: 2,
+			..Default::default()
+		};
+		let provider = EntryCountUsage(dir.path());
+		let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+		assert_eq!(stats.deleted, 6);
+		let usage = provider
+			.usage(SizeSpec::Absolute(0), SizeSpec::Absolute(0))
+			.unwrap();
+		assert!(usage <= 100.0);
+		assert!(usage < AGGRESSIVE_TARGET_USAGE);
+	}
+
+	/// `UsageProvider` with one byte blocks used by the data files and one inode per
+	/// file in a directory, out of a million blocks and 100 inodes
+	struct DirUsage<'a>(&'a Path);
+
+	impl UsageProvider for DirUsage<'_> {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			let files: Vec<_> = self.0.read_dir().unwrap().flatten().collect();
+			let used: u64 = files
+				.iter()
+				.filter(|e| e.file_name().to_string_lossy().ends_with(".data"))
+				.map(|e| e.metadata().unwrap().len())
+				.sum();
+			Ok(FsStats {
+				block_size: 1,
+				blocks: 1_000_000,
+				blocks_available: 1_000_000 - used,
+				files: 100,
+				files_free: 100 - files.len() as u64,
+				fs_type: 0,
+			})
+		}
+	}
+
+	#[test]
+	fn test_enforce_quotas() {
+		let dir = TempDir::new().unwrap();
+		for (folder, count) in [("tenant-a", 4), ("tenant-big", 4), ("other", 4)] {
+			let sub = dir.path().join(folder);
+			create_dir_all(&sub).unwrap();
+			for i in 0..count {
+				let header = add_entry(&sub, &i.to_string());
+				write(sub.join(format!("{}.data", i)), vec![1u8; 65536]).unwrap();
+				set_age(&header, (count - i) * 3600);
+			}
+		}
+		let entry_size = read_entries(&dir.path().join("other"))[0].disk_usage();
+		let quotas = format!(
+			"tenant- {}\ntenant-big 1M\n",
+			entry_size * 2 + entry_size / 2
+		);
+		let config = Config {
+			quotas: Quotas::from_reader(quotas.as_bytes()).unwrap(),
+			quotas_only: true,
+			..Config::default()
+		};
+		let exists = |entry: &str| dir.path().join(format!("{}.header", entry)).exists();
+
+		let budget_config = Config {
+			max_delete_bytes: Some(SizeSpec::Absolute(entry_size)),
+			..config.clone()
+		};
+		let stats =
+			process_folder_parallel(dir.path(), &budget_config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert!(!exists("tenant-a/0") && exists("tenant-a/1"));
+
+		// Entries kept by the filter still count towards the quota
+		let filter_config = Config {
+			entry_filter: Some(EntryFilter::new(
+				"while IFS=\"$(printf '\\t')\" read -r path expires size; do \
+				 case \"$path\" in */tenant-a/1.header) echo keep ;; *) echo delete ;; esac; \
+				 done",
+			)),
+			..config
+		};
+		let stats =
+			process_folder_parallel(dir.path(), &filter_config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert_eq!(stats.skipped.filtered, 1);
+		assert!(exists("tenant-a/1") && !exists("tenant-a/2") && exists("tenant-a/3"));
+		for kept in ["tenant-big/0", "other/0"] {
+			assert!(exists(kept));
+		}
+
+		// Percentage quotas need the filesystem size
+		let entries = read_entries(&dir.path().join("tenant-big"));
+		let config = Config {
+			quotas: Quotas::from_reader("tenant- 5%\n".as_bytes()).unwrap(),
+			..Config::default()
+		};
+		let provider = FailingUsage(Default::default());
+		let mut progress = PruneProgress::new(&config, &provider).unwrap();
+		let mut stats = Stats::default();
+		let e = enforce_quotas(
+			dir.path(),
+			entries.clone(),
+			&[],
+			&config,
+			&provider,
+			&mut stats,
+			&mut progress,
+		)
+		.unwrap_err();
+		assert!(e.to_string().contains("quotas"), "{}", e);
+		assert_eq!(stats.deleted, 0);
+		assert!(entries.iter().all(|e| e.header_path().exists()));
+	}
+
+	#[test]
+	fn test_separate_inode_target() {
+		let dir = TempDir::new().unwrap();
+		for i in 0..24 {
+			add_entry(dir.path(), &format!("{:02}", i));
+			let size = if i % 6 == 0 { 65536 } else { 1 };
+			write(dir.path().join(format!("{:02}.data", i)), vec![0u8; size]).unwrap();
+		}
+		let entries = read_entries(dir.path());
+
+		// 262164 of 200000 bytes and 48 of 40 inodes used
+		let config = Config {
+			min_free_space: SizeSpec::Absolute(800_000),
+			min_free_inodes: SizeSpec::Absolute(60),
+			separate_inode_target: true,
+			retained_stats: true,
+			delete_batch_size: 1,
+			..Default::default()
+		};
+		let provider = DirUsage(dir.path());
+		let usage = |constraint| {
+			provider
+				.constraint_usage(constraint, config.min_free_space, config.min_free_inodes)
+				.unwrap()
+		};
+		assert!(usage(Constraint::Space) > 130.0);
+		assert!(usage(Constraint::Inodes) > 110.0);
+
+		let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+		assert!(usage(Constraint::Space) < TARGET_USAGE);
+		assert!(usage(Constraint::Inodes) < TARGET_USAGE);
+		// One large entry for space, then three small ones for inodes
+		assert_eq!(stats.deleted, 4);
+		assert_eq!(stats.retained, 20);
+		let large_left = ["06", "12", "18"]
+			.iter()
+			.filter(|i| dir.path().join(format!("{}.data", i)).exists())
+			.count();
+		assert_eq!(large_left, 3);
+		assert!(!dir.path().join("00.data").exists());
+		for i in ["01", "02", "03"] {
+			assert!(!dir.path().join(format!("{}.header", i)).exists());
+		}
+	}
+
+	#[test]
+	fn test_freed_space_samples() {
+		let dir = TempDir::new().unwrap();
+		for i in 0..10 {
+			add_entry(dir.path(), &i.to_string());
+			std::fs::write(dir.path().join(format!("{}.data", i)), [0u8; 8192]).unwrap();
+		}
+		let entries = read_entries(dir.path());
+
+		let config = Config {
+			sample_freed_space: true,
+			delete_batch_size: 2,
+			..Default::default()
+		};
+		let stats =
+			prune_entries(dir.path(), &entries, &config, &EntryCountUsage(dir.path())).unwrap();
+		assert!(stats.freed_space.len() > 1);
+		assert!(stats.freed_space[0].bytes > 0);
+		assert_eq!(stats.freed_space.last().unwrap().bytes, stats.freed_bytes);
+		assert_eq!(stats.freed_inodes, 2 * stats.deleted);
+		for pair in stats.freed_space.windows(2) {
+			assert!(pair[1].elapsed >= pair[0].elapsed);
+			assert!(pair[1].bytes > pair[0].bytes);
+		}
+
+		let stats = prune_entries(
+			dir.path(),
+			&entries[stats.deleted as usize..],
+			&Config::default(),
+			&EntryCountUsage(dir.path()),
+		)
+		.unwrap();
+		assert!(stats.freed_space.is_empty());
+	}
+
+	#[test]
+	fn test_max_delete_bytes() {
+		let dir = TempDir::new().unwrap();
+		for i in 0..10 {
+			add_entry(dir.path(), &i.to_string());
+			std::fs::write(dir.path().join(format!("{}.data", i)), [0u8; 8192]).unwrap();
+		}
+		let entries = read_entries(dir.path());
+		let budget: u64 = entries[..3].iter().map(CacheFileInfo::disk_usage).sum();
+
+		let config = Config {
+			max_delete_bytes: Some(SizeSpec::Absolute(budget)),
+			delete_batch_size: 10,
+			retained_stats: true,
+			..Default::default()
+		};
+		// The usage stays above the target until 7 entries are deleted
+		let stats =
+			prune_entries(dir.path(), &entries, &config, &EntryCountUsage(dir.path())).unwrap();
+		assert_eq!(stats.deleted, 3);
+		assert_eq!(stats.retained, 7);
+		assert!(entries[..3].iter().all(|e| !e.header_path().exists()));
+		assert!(entries[3..].iter().all(|e| e.header_path().exists()));
+
+		// A percentage needs the filesystem size, nothing is deleted without it
+		let config = Config {
+			max_delete_bytes: Some(SizeSpec::Percentage(1.0)),
+			..Default::default()
+		};
+		let error = prune_entries(
+			dir.path(),
+			&entries[3..],
+			&config,
+			&EntryCountUsage(dir.path()),
+		)
+		.unwrap_err();
+		assert_eq!(error.stats.deleted, 0);
+		assert!(entries[3..].iter().all(|e| e.header_path().exists()));
+
+		// No budget at all
+		let config = Config {
+			max_delete_bytes: Some(SizeSpec::Absolute(0)),
+			..Default::default()
+		};
+		let stats = prune_entries(
+			dir.path(),
+			&entries[3..],
+			&config,
+			&CountingUsage(Default::default()),
+		)
+		.unwrap();
+		assert_eq!(stats.deleted, 0);
+		assert!(entries[3..].iter().all(|e| e.header_path().exists()));
+
+		// A budget used up by the quotas, also for the separate inode target
+		let sub = dir.path().join("tenant");
+		create_dir_all(&sub).unwrap();
+		let entries = add_entries(&sub, 3);
+		let config = Config {
+			quotas: Quotas::from_reader("tenant 0\n".as_bytes()).unwrap(),
+			max_delete_bytes: Some(SizeSpec::Absolute(entries[0].disk_usage())),
+			separate_inode_target: true,
+			..Default::default()
+		};
+		let provider = CountingUsage(Default::default());
+		let mut progress = PruneProgress::new(&config, &provider).unwrap();
+		let mut stats = Stats::default();
+		let remaining = enforce_quotas(
+			dir.path(),
+			entries,
+			&[],
+			&config,
+			&provider,
+			&mut stats,
+			&mut progress,
+		)
+		.unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert_eq!(remaining.len(), 2);
+		let stats =
+			prune_entries_with(dir.path(), &remaining, &config, &provider, &mut progress).unwrap();
+		assert_eq!(stats.deleted, 0);
+		assert!(remaining.iter().all(|e| e.header_path().exists()));
+	}
+
+	#[test]
+	fn test_unpoison() {
+		let stats = Mutex::new(Stats::default());
+		let result = std::thread::scope(|s| {
+			s.spawn(|| {
+				let mut stats = stats.lock().unwrap();
+				stats.deleted += 3;
+				panic!("worker panic");
+			})
+			.join()
+		});
+		assert!(result.is_err());
+		assert!(stats.is_poisoned());
+
+		let logs = capture_logs(|| unpoison(stats.lock()).deleted += 1);
+		assert!(logs.contains("A worker panicked"));
+		assert_eq!(unpoison(stats.into_inner()).deleted, 4);
+	}
+
+	/// Log writer panicking in the thread logging `marker`, collecting everything else
+	struct PanickingWriter(&'static str, Arc<Mutex<Vec<u8>>>);
+
+	impl io::Write for PanickingWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if String::from_utf8_lossy(buf).contains(self.0) {
+				panic!("bad directory");
+			}
+			self.1.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_worker_panic() {
+		let dir = TempDir::new().unwrap();
+		for folder in ["a", "b", "c", "d"] {
+			let sub = dir.path().join(folder);
+			create_dir_all(&sub).unwrap();
+			if folder == "b" {
+				write(sub.join("broken.header"), b"broken").unwrap();
+			} else {
+				add_entries(&sub, 2);
+			}
+		}
+		let config = Config {
+			jobs: 8,
+			dump_header_hex: true,
+			..Config::default()
+		};
+
+		// The worker scanning "b" panics while logging the broken header
+		let buffer = Arc::new(Mutex::new(Vec::new()));
+		let writer = buffer.clone();
+		let subscriber = tracing_subscriber::fmt()
+			.with_max_level(tracing::Level::DEBUG)
+			.with_ansi(false)
+			.with_writer(move || PanickingWriter("broken.header", writer.clone()))
+			.finish();
+		let (stats, entries) = tracing::subscriber::with_default(subscriber, || {
+			scan_phase(dir.path(), &config, &SystemTime::now())
+		})
+		.unwrap();
+		let logs = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+		assert!(logs.contains("panicked (bad directory), its remaining folders weren't scanned"));
+		assert_eq!(entries.len(), 6);
+		assert_eq!(stats.scanned, 6);
+		assert_eq!(stats.parse_errors, 0);
+
+		// The delete worker of the third chunk panics while skipping the refreshed "4"
+		let dir = TempDir::new().unwrap();
+		for i in 0..8 {
+			set_age(&add_entry(dir.path(), &i.to_string()), 3600 - i);
+		}
+		let entries = read_entries(dir.path());
+		set_age(&dir.path().join("4.header"), 0);
+		let config = Config {
+			revalidate: true,
+			aggressive: true,
+			delete_jobs: 4,
+			delete_batch_size: 8,
+			..Config::default()
+		};
+		let buffer = Arc::new(Mutex::new(Vec::new()));
+		let writer = buffer.clone();
+		let subscriber = tracing_subscriber::fmt()
+			.with_max_level(tracing::Level::DEBUG)
+			.with_ansi(false)
+			.with_writer(move || PanickingWriter("4.header", writer.clone()))
+			.finish();
+		let error = tracing::subscriber::with_default(subscriber, || {
+			prune_entries(
+				dir.path(),
+				&entries,
+				&config,
+				&CountingUsage(Default::default()),
+			)
+		})
+		.unwrap_err();
+		let logs = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+		assert!(logs.contains("Delete worker 2 panicked (bad directory), stopping deletion"));
+		assert_eq!(
+			error.source.to_string(),
+			"delete worker 2 panicked (bad directory)"
+		);
+		assert_eq!(error.stats.deleted, 6);
+		for i in 0..8 {
+			let header = dir.path().join(format!("{}.header", i));
+			assert_eq!(header.exists(), i == 4 || i == 5);
+		}
+	}
+
+	#[test]
+	fn test_join_workers() {
+		let stats = Mutex::new(Stats::default());
+		let logs = capture_logs(|| {
+			std::thread::scope(|s| {
+				let workers = (0..3)
+					.map(|index| {
+						let stats = &stats;
+						s.spawn(move || {
+							unpoison(stats.lock()).deleted += 1;
+							if index == 1 {
+								panic!("bad directory");
+							}
+						})
+					})
+					.collect();
+				join_workers(workers);
+			});
+		});
+		assert!(logs.contains("Scan worker 1 panicked (bad directory)"));
+		assert!(!logs.contains("Scan worker 0"));
+		assert_eq!(unpoison(stats.into_inner()).deleted, 3);
+	}
+
+	#[test]
+	fn test_follow_up_run() {
+		let dir = TempDir::new().unwrap();
+		let tempfile = dir.path().join("aptmpAbC123");
+		let other = dir.path().join("other");
+		File::create(&tempfile).unwrap();
+		File::create(&other).unwrap();
+		set_age(&other, 3600);
+		let now = SystemTime::now();
+
+		let stats = process_folder_parallel(dir.path(), &Config::default(), &now).unwrap();
+		assert_eq!(stats.deleted, 0);
+		assert!(tempfile.exists());
+
+		// The temporary file is old by the end of the run
+		let config = Config {
+			clock: Arc::new(FixedClock(now + Duration::from_secs(3600))),
+			follow_up_run: true,
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &now).unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert!(!tempfile.exists());
+		assert!(other.exists());
+	}
+
+	#[test]
+	fn test_scan_only_changed() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		add_entry(&sub, "entry");
+		let state = dir.path().join("state");
+
+		let read_config =
+			|| match read_scan_state(&state, dir.path(), None, &CacheSuffixes::shared_default())
+				.unwrap()
+			{
+				Some((time, known)) => Config {
+					changed_since: Some(time),
+					known_entries: Some(Arc::new(known)),
+					..Default::default()
+				},
+				None => Config {
+					known_entries: Some(Arc::default()),
+					..Default::default()
+				},
+			};
+
+		// First run without state scans everything
+		let config = read_config();
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.parsed, 1);
+		let found = config.known_entries.unwrap().take_found();
+		write_scan_state(&state, SystemTime::now(), dir.path(), &found).unwrap();
+
+		// Second run takes the entries of the unchanged directory from the state, but still removes orphans
+		File::create(sub.join("orphan.data")).unwrap();
+		set_age(&sub.join("orphan.data"), 3600);
+		set_age(&sub, 3600);
+		set_age(dir.path(), 3600);
+		let config = read_config();
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.parsed, 0);
+		assert_eq!(stats.skipped.unchanged_dirs, 2);
+		assert_eq!(stats.deleted, 1);
+		assert!(!sub.join("orphan.data").exists());
+		assert_eq!(config.known_entries.as_ref().unwrap().take_found(), found);
+
+		// Entries missing from the state are parsed
+		let config = Config {
+			known_entries: Some(Arc::default()),
+			..read_config()
+		};
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.parsed, 1);
+
+		// Known entries can be evicted
+		set_age(&sub, 3600);
+		let stats =
+			process_folder_parallel(dir.path(), &read_config(), &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert!(!sub.join("entry.header").exists());
+	}
+
+	#[test]
+	fn test_orphaned_vary_data_failure() {
+		let dir = vary_fixture();
+		let (stats, _) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.deleted, 1);
+		assert_eq!(stats.failed, 0);
+		assert!(!dir.path().join("entry.data").exists());
+
+		// No data file isn't an error
+		let (stats, _) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.deleted, 0);
+		assert_eq!(stats.failed, 0);
+
+		// A directory in place of the data file can't be removed with `remove_file`
+		create_dir_all(dir.path().join("entry.data")).unwrap();
+		let (stats, _) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.deleted, 0);
+		assert_eq!(stats.failed, 1);
+	}
+
+	#[test]
+	fn test_temp_dirs() {
+		let dir = TempDir::new().unwrap();
+		let tmp = dir.path().join("ab.tmp");
+		let rehash = dir.path().join("rehash-1234");
+		for path in [&tmp, &rehash] {
+			create_dir_all(path).unwrap();
+			add_entry(path, "entry");
+		}
+
+		// Without patterns, they are scanned like any other directory
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert_eq!(names, ["entry.header", "entry.header"]);
+		assert_eq!(stats.skipped.temp_dirs, 0);
+
+		// Active temporary directories are skipped, not scanned
+		let config = Config {
+			temp_dir_patterns: vec![glob::Pattern::new("*.tmp").unwrap()],
+			..Default::default()
+		};
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.skipped.temp_dirs, 1);
+		assert!(tmp.join("entry.header").exists());
+
+		// Stale ones are kept with a deletion budget or preserved contents
+		set_age(&tmp, 3600);
+		set_age(&rehash, 3600);
+		let config = Config {
+			temp_dir_patterns: vec![
+				glob::Pattern::new("*.tmp").unwrap(),
+				glob::Pattern::new("rehash-*").unwrap(),
+			],
+			path: dir.path().to_owned(),
+			..Default::default()
+		};
+		let budgeted = Config {
+			max_delete_bytes: Some(SizeSpec::Absolute(1 << 30)),
+			..config.clone()
+		};
+		let (stats, _) = scan(dir.path(), &budgeted);
+		assert_eq!(stats.skipped.temp_dirs, 2);
+		assert_eq!(stats.deleted_folders, 0);
+		let preserving = Config {
+			preserve: vec![glob::Pattern::new("ab.tmp/*.data").unwrap()],
+			..config.clone()
+		};
+		let (stats, _) = scan(dir.path(), &preserving);
+		assert_eq!(stats.skipped.temp_dirs, 1);
+		assert_eq!(stats.deleted_folders, 1);
+		assert!(tmp.join("entry.data").exists());
+		assert!(!rehash.exists());
+
+		// Otherwise they are deleted with their contents
+		let (stats, names) = scan(dir.path(), &config);
+		assert!(names.is_empty());
+		assert_eq!(stats.skipped.temp_dirs, 0);
+		assert_eq!(stats.deleted_folders, 1);
+		assert!(!tmp.exists());
+	}
+
+	#[test]
+	fn test_scan_folder_collect() {
+		let dir = vary_fixture();
+		add_entry(dir.path(), "other");
+		let (_, names) = scan(dir.path(), &Config::default());
+
+		let dir = vary_fixture();
+		add_entry(dir.path(), "other");
+		let result =
+			scan_folder_collect(dir.path(), &Config::default(), &SystemTime::now(), false).unwrap();
+		let mut collected: Vec<_> = result
+			.entries
+			.iter()
+			.map(|f| {
+				f.header_path()
+					.file_name()
+					.unwrap()
+					.to_string_lossy()
+					.into_owned()
+			})
+			.collect();
+		collected.sort();
+		assert_eq!(collected, names);
+		assert_eq!(result.stats.deleted, 1);
+	}
+
+	#[test]
+	fn test_exclude_recent_dirs() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		add_entry(&sub, "entry");
+
+		let config = Config {
+			exclude_recent_dirs: Some(60),
+			..Default::default()
+		};
+		let (_, names) = scan(dir.path(), &config);
+		assert!(names.is_empty());
+
+		set_age(&sub, 3600);
+		let (_, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+	}
+
+	#[test]
+	fn test_ap_tempfile_suffix_lengths() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		let names = [
+			"aptmpAbC123",
+			"aptmpAbC12345",
+			"aptmpAbC1",
+			"aptmpAbC12_",
+			"aptmpAbC1234567890",
+		];
+		for folder in [dir.path(), &sub] {
+			for name in names {
+				let path = folder.join(name);
+				File::create(&path).unwrap();
+				set_age(&path, 3600);
+			}
+		}
+
+		let stats =
+			process_folder_parallel(dir.path(), &Config::default(), &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 4);
+		for folder in [dir.path(), &sub] {
+			assert!(!folder.join("aptmpAbC123").exists());
+			assert!(!folder.join("aptmpAbC12345").exists());
+			assert!(folder.join("aptmpAbC1").exists());
+			assert!(folder.join("aptmpAbC12_").exists());
+			assert!(folder.join("aptmpAbC1234567890").exists());
+		}
+	}
+
+	#[test]
+	fn test_no_delete_folders() {
+		let dir = TempDir::new().unwrap();
+		let folders = [dir.path().join("ab"), dir.path().join("cd")];
+		for folder in &folders {
+			create_dir_all(folder).unwrap();
+			set_age(folder, 3600);
+		}
+
+		let config = Config {
+			no_delete_folders: true,
+			..Default::default()
+		};
+		let (stats, _) = scan(dir.path(), &config);
+		assert_eq!(stats.deleted_folders, 0);
+		for folder in &folders {
+			assert!(folder.exists());
+			// Scanning may have updated the access time
+			set_age(folder, 3600);
+		}
+
+		let (stats, _) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.deleted_folders, 2);
+		for folder in &folders {
+			assert!(!folder.exists());
+		}
+	}
+
+	#[test]
+	fn test_max_scan_entries() {
+		let dir = TempDir::new().unwrap();
+		for folder in ["a", "b", "c"] {
+			let sub = dir.path().join(folder);
+			create_dir_all(&sub).unwrap();
+			for i in 0..5 {
+				add_entry(&sub, &i.to_string());
+			}
+		}
+
+		let config = Config {
+			max_scan_entries: Some(4),
+			jobs: 2,
+			..Default::default()
+		};
+		let limited = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(limited.scanned, 4);
+		assert!(limited.deleted <= 4);
+
+		let stats =
+			process_folder_parallel(dir.path(), &Config::default(), &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 15 - limited.deleted);
+	}
+
+	#[test]
+	fn test_split_phases() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("a");
+		create_dir_all(&sub).unwrap();
+		for i in 0..4 {
+			add_entry(&sub, &i.to_string());
+			set_age(&sub.join(format!("{}.header", i)), 3600);
+		}
+
+		let (stats, entries) =
+			scan_phase(dir.path(), &Config::default(), &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 4);
+		assert_eq!(stats.deleted, 0);
+		let mut candidates = Vec::new();
+		assert_eq!(
+			write_candidates(dir.path(), &entries, &mut candidates).unwrap(),
+			4
+		);
+
+		// Changed between the phases: one refreshed, one deleted by Apache
+		set_age(&sub.join("1.header"), 0);
+		remove_file(sub.join("2.header")).unwrap();
+
+		let config = Config {
+			revalidate: true,
+			..Default::default()
+		};
+		let entries = read_candidates(
+			&candidates[..],
+			dir.path(),
+			None,
+			&CacheSuffixes::shared_default(),
+		)
+		.unwrap();
+		assert_eq!(entries.len(), 4);
+
+		// The minimum entry count applies to the candidate list
+		let guarded = Config {
+			min_entry_count: Some(5),
+			..Default::default()
+		};
+		let stats =
+			delete_candidates(dir.path(), entries.clone(), &guarded, &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 0);
+		assert!(sub.join("0.header").exists());
+
+		let stats = delete_candidates(dir.path(), entries, &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 2);
+		assert_eq!(stats.skipped.refreshed, 2);
+		assert!(!sub.join("0.header").exists());
+		assert!(sub.join("1.header").exists());
+		assert!(!sub.join("3.header").exists());
+	}
+
+	#[test]
+	fn test_atime_only() {
+		let dir = TempDir::new().unwrap();
+		let now = SystemTime::now();
+		let hours = |h: u64| now - Duration::from_secs(h * 3600);
+		// (name, modified, accessed)
+		for (name, modified, accessed) in [
+			("a", hours(2), hours(0)),
+			("b", hours(1), hours(3)),
+			("c", hours(3), hours(2)),
+		] {
+			let sub = dir.path().join(name);
+			create_dir_all(&sub).unwrap();
+			add_entry(&sub, "entry");
+			let times = FileTimes::new()
+				.set_accessed(accessed)
+				.set_modified(modified);
+			File::open(sub.join("entry.header"))
+				.unwrap()
+				.set_times(times)
+				.unwrap();
+		}
+		let order = |config: &Config| -> Vec<_> {
+			let (_, entries) = scan_phase(dir.path(), config, &now).unwrap();
+			entries
+				.iter()
+				.map(|e| top_level_folder(dir.path(), e.header_path()).unwrap())
+				.collect()
+		};
+
+		assert_eq!(order(&Config::default()), ["c", "a", "b"]);
+		let config = Config {
+			atime_only: true,
+			..Config::default()
+		};
+		assert_eq!(order(&config), ["b", "c", "a"]);
+	}
+
+	#[test]
+	fn test_size_strategies() {
+		let dir = TempDir::new().unwrap();
+		// (name, size in KiB, idle hours)
+		for (name, size, idle) in [("a", 128, 1), ("b", 16, 8), ("c", 32, 2), ("d", 0, 9)] {
+			let sub = dir.path().join(name);
+			create_dir_all(&sub).unwrap();
+			let header = add_entry(&sub, "entry");
+			write(sub.join("entry.data"), vec![1u8; size * 1024]).unwrap();
+			set_age(&header, idle * 3600);
+		}
+		let order = |strategy| -> Vec<_> {
+			let config = Config {
+				strategy,
+				..Config::default()
+			};
+			let (_, entries) = scan_phase(dir.path(), &config, &SystemTime::now()).unwrap();
+			entries
+				.iter()
+				.map(|e| top_level_folder(dir.path(), e.header_path()).unwrap())
+				.collect()
+		};
+
+		assert_eq!(order(EvictionStrategy::Lru), ["d", "b", "c", "a"]);
+		assert_eq!(order(EvictionStrategy::Size), ["a", "c", "b", "d"]);
+		assert_eq!(order(EvictionStrategy::SizeAge), ["b", "a", "c", "d"]);
+	}
+
+	#[test]
+	fn test_min_entry_count() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("a");
+		create_dir_all(&sub).unwrap();
+		for i in 0..3 {
+			add_entry(&sub, &i.to_string());
+		}
+		let aptmp = dir.path().join("aptmpAbC123");
+		File::create(&aptmp).unwrap();
+		set_age(&aptmp, 3600);
+
+		let config = Config {
+			min_entry_count: Some(4),
+			retained_stats: true,
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 3);
+		assert_eq!(stats.retained, 3);
+		// Only the temporary file is deleted
+		assert_eq!(stats.deleted, 1);
+		assert!(!aptmp.exists());
+		assert_eq!(scan(&sub, &Config::default()).1.len(), 3);
+
+		let config = Config {
+			min_entry_count: Some(3),
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert!(stats.deleted > 0);
+	}
+
+	#[test]
+	fn test_resume_from() {
+		let dir = TempDir::new().unwrap();
+		for folder in ["a", "b", "c", "cd", "d"] {
+			let sub = dir.path().join(folder);
+			create_dir_all(&sub).unwrap();
+			add_entry(&sub, "0");
+		}
+
+		let config = Config {
+			resume_from: Some("c".into()),
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 3);
+		for folder in ["a", "b"] {
+			assert!(dir.path().join(folder).join("0.header").exists());
+		}
+	}
+
+	#[test]
+	fn test_parse_errors_fatal() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("a");
+		create_dir_all(&sub).unwrap();
+		add_entry(&sub, "valid");
+		for i in 0..3 {
+			write(sub.join(format!("corrupt{}.header", i)), b"garbage").unwrap();
+		}
+
+		let config = Config {
+			max_parse_error_rate: Some(50.0),
+			..Default::default()
+		};
+		let e = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap_err();
+		assert_eq!(e.source.kind(), io::ErrorKind::InvalidData);
+		assert_eq!(e.stats.parsed, 1);
+		assert_eq!(e.stats.parse_errors, 3);
+		assert_eq!(e.stats.parse_error_rate(), Some(75.0));
+		// Nothing is deleted from a corrupt cache
+		assert!(sub.join("valid.header").exists());
+
+		let config = Config {
+			max_parse_error_rate: Some(80.0),
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.failed, 3);
+	}
+
+	#[test]
+	fn test_remove_empty_dirs() {
+		let dir = TempDir::new().unwrap();
+		let deep = dir.path().join("a/b/c/d");
+		create_dir_all(&deep).unwrap();
+		create_dir_all(dir.path().join("a/empty")).unwrap();
+		add_entry(&deep, "0");
+		let kept = dir.path().join("e/f");
+		create_dir_all(&kept).unwrap();
+		File::create(kept.join("foreign")).unwrap();
+		let active = dir.path().join("g/upload.tmp");
+		create_dir_all(&active).unwrap();
+
+		let stats =
+			process_folder_parallel(dir.path(), &Config::default(), &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert!(deep.exists());
+
+		add_entry(&deep, "0");
+		let config = Config {
+			remove_empty_dirs: true,
+			temp_dir_patterns: vec![glob::Pattern::new("*.tmp").unwrap()],
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.deleted, 1);
+		// a/b/c/d, a/b/c, a/b, a/empty and a
+		assert_eq!(stats.deleted_folders, 5);
+		assert!(!dir.path().join("a").exists());
+		assert!(kept.join("foreign").exists());
+		assert!(active.exists());
+
+		// Library callers bypass the conflicts of the command line options
+		let dir = TempDir::new().unwrap();
+		for folder in ["a/x", "c/x", "d/x"] {
+			create_dir_all(dir.path().join(folder)).unwrap();
+		}
+		for (folder, age) in [("c/x", 3600), ("c", 3600)] {
+			set_age(&dir.path().join(folder), age);
+		}
+		let now = SystemTime::now();
+		let stats = remove_empty_dirs(
+			dir.path(),
+			&Config {
+				no_delete_folders: true,
+				..config.clone()
+			},
+			&now,
+			0,
+		)
+		.unwrap();
+		assert_eq!(stats.deleted_folders, 0);
+		let config = Config {
+			resume_from: Some("b".into()),
+			exclude_recent_dirs: Some(60),
+			..config
+		};
+		let stats = remove_empty_dirs(dir.path(), &config, &now, 0).unwrap();
+		// Only c/x and c, a is before "b" and d was modified recently
+		assert_eq!(stats.deleted_folders, 2);
+		assert!(dir.path().join("a/x").exists());
+		assert!(!dir.path().join("c").exists());
+		assert!(dir.path().join("d/x").exists());
+	}
+
+	/// `MemoryUsage` returning a fixed resident set size and counting its calls
+	#[derive(Debug)]
+	struct FixedMemoryUsage(u64, AtomicU64);
+
+	impl MemoryUsage for FixedMemoryUsage {
+		fn rss(&self) -> Option<u64> {
+			self.1.fetch_add(1, atomic::Ordering::Relaxed);
+			Some(self.0)
+		}
+	}
+
+	#[test]
+	fn test_memory_limit() {
+		let dir = TempDir::new().unwrap();
+		for folder in ["a", "b", "c"] {
+			let sub = dir.path().join(folder);
+			create_dir_all(&sub).unwrap();
+			for i in 0..5 {
+				add_entry(&sub, &i.to_string());
+			}
+		}
+
+		let memory_usage = Arc::new(FixedMemoryUsage(1000, AtomicU64::new(0)));
+		let config = Config {
+			memory_limit: Some(2000),
+			memory_usage: memory_usage.clone(),
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 15);
+		assert_eq!(memory_usage.1.load(atomic::Ordering::Relaxed), 1);
+
+		// Over the limit at the first check, the entry found is still considered
+		let memory_usage = Arc::new(FixedMemoryUsage(3000, AtomicU64::new(0)));
+		let config = Config {
+			memory_limit: Some(2000),
+			memory_usage: memory_usage.clone(),
+			..Default::default()
+		};
+		let (stats, entries) = scan_phase(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 1);
+		assert_eq!(entries.len(), 1);
+		assert_eq!(memory_usage.1.load(atomic::Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn test_deep_tree() {
+		let dir = TempDir::new().unwrap();
+		let mut deepest = dir.path().to_owned();
+		for _ in 0..1000 {
+			deepest.push("d");
+		}
+		create_dir_all(&deepest).unwrap();
+		let mut shallow = dir.path().to_owned();
+		for _ in 0..MAX_SCAN_DEPTH {
+			shallow.push("d");
+		}
+		add_entry(&shallow, "entry");
+
+		// Run with a small stack, which unbounded recursion would overflow
+		let root = dir.path().to_owned();
+		let (stats, names) = std::thread::Builder::new()
+			.stack_size(1024 * 1024)
+			.spawn(move || scan(&root, &Config::default()))
+			.unwrap()
+			.join()
+			.unwrap();
+		assert_eq!(stats.skipped.deep_dirs, 1);
+		assert_eq!(names, ["entry.header"]);
+		assert!(deepest.exists());
+	}
+
+	#[test]
+	fn test_channel_capacity() {
+		let config = Config {
+			channel_capacity: 3,
+			..Default::default()
+		};
+		assert_eq!(entry_channel(&config).0.capacity(), Some(3));
+		assert_eq!(
+			entry_channel(&Config::default()).0.capacity(),
+			Some(ENTRY_CHANNEL_CAPACITY)
+		);
+
+		let dir = TempDir::new().unwrap();
+		for folder in ["a", "b", "c"] {
+			let sub = dir.path().join(folder);
+			create_dir_all(&sub).unwrap();
+			for i in 0..20 {
+				add_entry(&sub, &i.to_string());
+			}
+		}
+		let config = Config {
+			channel_capacity: 1,
+			jobs: 3,
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(stats.scanned, 60);
+	}
+
+	#[test]
+	fn test_tree_summary() {
+		let dir = TempDir::new().unwrap();
+		for (folder, count) in [("a", 1), ("b", 2), ("c", 3)] {
+			let sub = dir.path().join(folder).join("xy");
+			create_dir_all(&sub).unwrap();
+			for i in 0..count {
+				add_entry(&sub, &i.to_string());
+			}
+			let orphan = sub.join("orphan.data");
+			File::create(&orphan).unwrap();
+			set_age(&orphan, 3600);
+		}
+
+		let config = Config {
+			tree_summary: true,
+			jobs: 2,
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert!(stats.deleted >= 3);
+		assert_eq!(stats.per_folder.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+		let sum: Stats = stats.per_folder.values().cloned().sum();
+		assert_eq!(sum.deleted, stats.deleted);
+		assert_eq!(sum.deleted_folders, stats.deleted_folders);
+		assert_eq!(sum.failed, stats.failed);
+
+		let summary = TreeSummary(&stats.per_folder).to_string();
+		assert_eq!(summary.lines().count(), 3);
+	}
+
+	#[test]
+	fn test_preallocated_data_file() {
+		let dir = TempDir::new().unwrap();
+		add_entry(dir.path(), "entry");
+		let data = File::create(dir.path().join("entry.data")).unwrap();
+		let size = 4 * 1024 * 1024;
+		let result =
+			unsafe { libc::fallocate(data.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, size) };
+		assert_eq!(result, 0);
+		assert_eq!(data.metadata().unwrap().len(), 0);
+
+		let entry = dir
+			.path()
+			.read_dir()
+			.unwrap()
+			.flatten()
+			.find(|e| e.file_name() == "entry.header")
+			.unwrap();
+		let fileinfo = CacheFileInfo::new(&entry).unwrap();
+		let mut usage = 0;
+		let logs = capture_logs(|| usage = fileinfo.disk_usage());
+		assert!(usage >= size as u64);
+		assert!(logs.contains("preallocated or sparse"));
+	}
+
+	#[test]
+	fn test_hot_list() {
+		let dir = TempDir::new().unwrap();
+		let hot = cache_hash("http://example.com:80/hot.html?");
+		let cold = cache_hash("http://example.com:80/cold.html?");
+		for hash in [&hot, &cold] {
+			let sub = dir.path().join(&hash[..2]);
+			create_dir_all(&sub).unwrap();
+			add_entry(&sub, &hash[2..]);
+		}
+		let config = Config {
+			hot_list: HotList::from_reader(
+				"http://example.com:80/hot.html?\n".as_bytes(),
+				".header",
+			)
+			.unwrap(),
+			..Default::default()
+		};
+
+		let (_, names) = scan(dir.path(), &config);
+		assert_eq!(names, [format!("{}.header", &cold[2..])]);
+
+		// Hot entries are only evicted in desperate mode
+		let (sender, receiver) = channel::unbounded();
+		scan_folder(
+			dir.path(),
+			&config,
+			&SystemTime::now(),
+			false,
+			&sender,
+			true,
+		)
+		.unwrap();
+		drop(sender);
+		assert_eq!(receiver.iter().count(), 2);
+	}
+
+	#[test]
+	fn test_exclude_revalidatable() {
+		let dir = TempDir::new().unwrap();
+		add_entry(dir.path(), "validated");
+		// Rename the validator headers so they aren't recognized anymore
+		let header = std::fs::read("testcases/disk.header").unwrap();
+		let mut plain = header.clone();
+		for (from, to) in [
+			(&b"Last-Modified:"[..], &b"Last-Xodified:"[..]),
+			(b"ETag:", b"XTag:"),
+		] {
+			if let Some(pos) = plain.windows(from.len()).position(|w| w == from) {
+				plain[pos..pos + to.len()].copy_from_slice(to);
+			}
+		}
+		assert_ne!(plain, header);
+		std::fs::write(dir.path().join("plain.header"), plain).unwrap();
+
+		let (_, names) = scan(dir.path(), &Config::default());
+		assert_eq!(names.len(), 2);
+
+		let config = Config {
+			exclude_revalidatable: true,
+			..Default::default()
+		};
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["plain.header"]);
+		assert_eq!(stats.skipped.revalidatable, 1);
+	}
+
+	#[test]
+	fn test_fail_fast() {
+		let dir = TempDir::new().unwrap();
+		let mut entries = add_entries(dir.path(), 3);
+		// A directory in place of the first header file can't be removed with `remove_file`
+		let broken = entries[0].header_path();
+		std::fs::remove_file(broken).unwrap();
+		create_dir_all(broken).unwrap();
+
+		let config = Config {
+			fail_fast: true,
+			..Default::default()
+		};
+		let provider = EntryCountUsage(dir.path());
+		assert!(prune_entries(dir.path(), &entries, &config, &provider).is_err());
+		assert!(entries[1..].iter().all(|e| e.header_path().exists()));
+
+		let stats = prune_entries(dir.path(), &entries, &Config::default(), &provider).unwrap();
+		assert_eq!(stats.failed, 1);
+		assert_eq!(stats.deleted, 2);
+
+		for fileinfo in &entries[1..] {
+			copy("testcases/disk.header", fileinfo.header_path()).unwrap();
+		}
+		// Statistics up to the failed batch are returned with the error
+		let config = Config {
+			fail_fast: true,
+			delete_batch_size: 1,
+			..Default::default()
+		};
+		entries.rotate_left(1);
+		let error = prune_entries(
+			dir.path(),
+			&entries,
+			&config,
+			&CountingUsage(Default::default()),
+		)
+		.unwrap_err();
+		assert_eq!(error.stats.deleted, 2);
+		assert!(!entries[0].header_path().exists());
+
+		// Unparsable header files don't stop the scan
+		let dir = TempDir::new().unwrap();
+		write(dir.path().join("corrupt.header"), b"garbage").unwrap();
+		add_entry(dir.path(), "valid");
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(stats.parse_errors, 1);
+		assert_eq!(names, ["valid.header"]);
+	}
+
+	#[test]
+	fn test_error_policy() {
+		let dir = TempDir::new().unwrap();
+		// Vary "directories" that are files can't be scanned
+		let broken = dir.path().join("a");
+		create_dir_all(&broken).unwrap();
+		for name in ["x.header.vary", "y.header.vary"] {
+			write(broken.join(name), "").unwrap();
+		}
+		let fine = dir.path().join("b");
+		create_dir_all(&fine).unwrap();
+		for i in 0..2 {
+			add_entry(&fine, &i.to_string());
+		}
+
+		// Continue: all errors are counted
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.failed, 2);
+		assert_eq!(names.len(), 2);
+
+		// Skip directory: the rest of the broken directory is skipped after the first error
+		let config = Config {
+			skip_dir_on_error: true,
+			..Default::default()
+		};
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(stats.failed, 1);
+		assert_eq!(names.len(), 2);
+
+		// Abort: the scan fails
+		let config = Config {
+			fail_fast: true,
+			..Default::default()
+		};
+		let (sender, _receiver) = channel::unbounded();
+		let result = scan_folder(
+			dir.path(),
+			&config,
+			&SystemTime::now(),
+			false,
+			&sender,
+			false,
+		);
+		assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotADirectory);
+	}
+
+	/// `UsageProvider` reporting a fixed inode count
+	struct InodeUsage(Option<(u64, u64)>);
+
+	impl UsageProvider for InodeUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			let (files, files_free) = self.0.ok_or(nix::Error::ENOSYS)?;
+			Ok(FsStats {
+				block_size: 4096,
+				blocks: 1000,
+				blocks_available: 500,
+				files,
+				files_free,
+				fs_type: 0,
+			})
+		}
+	}
+
+	/// `UsageProvider` of a filesystem mounted read-only
+	struct ReadOnlyUsage;
+
+	impl UsageProvider for ReadOnlyUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(nix::errno::Errno::ENOSYS)
+		}
+
+		fn is_read_only(&self) -> nix::Result<bool> {
+			Ok(true)
+		}
+	}
+
+	#[test]
+	fn test_shared_usage_provider() {
+		let config = Config::default();
+		let clone = config.clone();
+		assert!(std::ptr::eq(
+			config.usage_provider(),
+			clone.usage_provider()
+		));
+		assert!(!std::ptr::eq(
+			config.usage_provider(),
+			Config::default().usage_provider()
+		));
+	}
+
+	#[test]
+	fn test_read_only_filesystem() {
+		let e = check_writable(&Config::default(), &ReadOnlyUsage).unwrap_err();
+		assert_eq!(e.kind(), io::ErrorKind::ReadOnlyFilesystem);
+		let config = Config {
+			ignore_readonly: true,
+			..Default::default()
+		};
+		check_writable(&config, &ReadOnlyUsage).unwrap();
+		check_writable(&Config::default(), &EntryCountUsage(".".as_ref())).unwrap();
+	}
+
+	#[test]
+	fn test_estimate_queue_capacity() {
+		assert_eq!(
+			estimate_queue_capacity(&InodeUsage(Some((10_000, 4_000)))),
+			3_000
+		);
+		assert_eq!(
+			estimate_queue_capacity(&InodeUsage(Some((u64::MAX, 0)))),
+			MAX_DELETE_COUNT
+		);
+		// Filesystems without inode limits report zero inodes
+		assert_eq!(
+			estimate_queue_capacity(&InodeUsage(Some((0, 0)))),
+			DEFAULT_QUEUE_CAPACITY
+		);
+		assert_eq!(
+			estimate_queue_capacity(&InodeUsage(None)),
+			DEFAULT_QUEUE_CAPACITY
+		);
+	}
+
+	#[test]
+	fn test_receiver_disconnect() {
+		let dir = TempDir::new().unwrap();
+		for i in 0..5 {
+			add_entry(dir.path(), &i.to_string());
+		}
+
+		// The receiver accepts one entry and disconnects in the middle of the scan
+		let (sender, receiver) = channel::bounded(0);
+		let receiver = std::thread::spawn(move || receiver.recv().is_ok());
+		let stats = scan_folder(
+			dir.path(),
+			&Config::default(),
+			&SystemTime::now(),
+			false,
+			&sender,
+			false,
+		);
+		assert!(receiver.join().unwrap());
+		assert!(stats.is_ok());
+	}
+
+	#[test]
+	fn test_vary_handling() {
+		let dir = vary_fixture();
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert_eq!(names, ["variant.header"]);
+		assert_eq!(stats.deleted, 1);
+		assert!(!dir.path().join("entry.data").exists());
+	}
+
+	#[test]
+	fn test_cache_lock() {
+		let dir = TempDir::new().unwrap();
+		let cache = dir.path().join("cache");
+		let locks = dir.path().join("locks");
+		let hash = cache_hash("http://example.com:80/index.html?");
+		let entry_dir = cache.join(&hash[..2]).join(&hash[2..4]);
+		create_dir_all(&entry_dir).unwrap();
+		let header = add_entry(&entry_dir, &hash[4..]);
+		let lock_dir = locks.join(&hash[..1]).join(&hash[1..2]);
+		create_dir_all(&lock_dir).unwrap();
+		File::create(lock_dir.join(&hash)).unwrap();
+		let entries =
+			vec![
+				CacheFileInfo::new(&entry_dir.read_dir().unwrap().next().unwrap().unwrap())
+					.unwrap(),
+			];
+
+		let config = Config {
+			cache_lock_path: Some(locks.clone()),
+			..Default::default()
+		};
+		let provider = EntryCountUsage(&entry_dir);
+		let stats = prune_entries(&cache, &entries, &config, &provider).unwrap();
+		assert_eq!(stats.skipped.locked, 1);
+		assert_eq!(stats.deleted, 0);
+		assert!(header.exists());
+
+		// Stale locks are ignored
+		set_age(&lock_dir.join(&hash), 60);
+		let stats = prune_entries(&cache, &entries, &config, &provider).unwrap();
+		assert_eq!(stats.skipped.locked, 0);
+		assert_eq!(stats.deleted, 1);
+		assert!(!header.exists());
+	}
+
+	#[test]
+	fn test_custom_suffix_lookups() {
+		let dir = TempDir::new().unwrap();
+		let cache = dir.path().join("cache");
+		let locks = dir.path().join("locks");
+		let suffixes = Arc::new(CacheSuffixes {
+			header: ".hdr".to_owned(),
+			data: ".dat".to_owned(),
+			vary: ".vdir".to_owned(),
+		});
+		let [hot, locked, cold] = ["hot", "locked", "cold"]
+			.map(|name| cache_hash(&format!("http://example.com:80/{}.html?", name)));
+		for hash in [&hot, &locked, &cold] {
+			let sub = cache.join(&hash[..2]);
+			create_dir_all(&sub).unwrap();
+			copy(
+				"testcases/disk.header",
+				sub.join(format!("{}.hdr", &hash[2..])),
+			)
+			.unwrap();
+			File::create(sub.join(format!("{}.dat", &hash[2..]))).unwrap();
+		}
+		let lock_dir = locks.join(&locked[..1]).join(&locked[1..2]);
+		create_dir_all(&lock_dir).unwrap();
+		File::create(lock_dir.join(&locked)).unwrap();
+
+		// Entries are found by the configured header suffix
+		let mut entries: Vec<_> = iter_cache_entries_with_suffixes(&cache, &suffixes)
+			.unwrap()
+			.flatten()
+			.collect();
+		assert_eq!(entries.len(), 3);
+		assert_eq!(iter_cache_entries(&cache).unwrap().count(), 0);
+
+		let config = Config {
+			hot_list: HotList::from_reader(
+				format!("{}/{}.hdr\n", &hot[..2], &hot[2..]).as_bytes(),
+				&suffixes.header,
+			)
+			.unwrap(),
+			cache_lock_path: Some(locks.clone()),
+			suffixes: Arc::clone(&suffixes),
+			..Default::default()
+		};
+		let (_, names) = scan(&cache, &config);
+		assert_eq!(names.len(), 2);
+		assert!(!names.contains(&format!("{}.hdr", &hot[2..])));
+
+		entries.retain(|e| !e.header_path().ends_with(format!("{}.hdr", &hot[2..])));
+		let provider = CountingUsage(std::cell::Cell::new(0));
+		let stats = prune_entries(&cache, &entries, &config, &provider).unwrap();
+		assert_eq!(stats.skipped.locked, 1);
+		assert_eq!(stats.deleted, 1);
+	}
+
+	#[test]
+	fn test_prune_vary_folder() {
+		let dir = TempDir::new().unwrap();
+		for name in ["empty", "full"] {
+			copy(
+				"testcases/vary.header",
+				dir.path().join(format!("{}.header", name)),
+			)
+			.unwrap();
+			create_dir_all(dir.path().join(format!("{}.header.vary", name))).unwrap();
+		}
+		add_entry(&dir.path().join("full.header.vary"), "variant");
+		let entries = read_entries(dir.path());
+
+		let provider = EntryCountUsage(dir.path());
+		let stats = prune_entries(dir.path(), &entries, &Config::default(), &provider).unwrap();
+		assert_eq!(stats.deleted, 2);
+		assert_eq!(stats.deleted_vary, 2);
+		assert_eq!(stats.deleted_folders, 1);
+		assert!(!dir.path().join("empty.header.vary").exists());
+		assert!(dir.path().join("full.header.vary/variant.header").exists());
+	}
+
+	#[test]
+	fn test_ignore_vary() {
+		let dir = vary_fixture();
+		let config = Config {
+			ignore_vary: true,
+			..Config::default()
+		};
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header", "variant.header"]);
+		assert_eq!(stats.deleted, 0);
+		assert!(dir.path().join("entry.data").exists());
+	}
+
+	#[test]
+	fn test_preserve() {
+		let dir = TempDir::new().unwrap();
+		for subdir in ["keep", "other"] {
+			let path = dir.path().join(subdir);
+			create_dir_all(&path).unwrap();
+			add_entry(&path, "entry");
+			File::create(path.join("orphan.data")).unwrap();
+			set_age(&path.join("orphan.data"), 3600);
+		}
+		let config = Config {
+			preserve: vec!["*/keep/*".parse().unwrap()],
+			..Config::default()
+		};
+
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.deleted, 2);
+		assert!(!dir.path().join("keep/orphan.data").exists());
+		assert!(dir.path().join("keep/entry.data").exists());
+
+		// Patterns are relative to the cache root
+		let config = Config {
+			path: dir.path().to_owned(),
+			preserve: vec!["keep/*".parse().unwrap()],
+			..Config::default()
+		};
+		assert!(config.is_preserved(&dir.path().join("keep/entry.header")));
+		assert!(config.is_preserved(Path::new("./keep/entry.header")));
+		assert!(!config.is_preserved(&dir.path().join("other/entry.header")));
+		let (stats, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.skipped.preserved, 1);
+	}
+
+	#[test]
+	fn test_min_lifetime() {
+		let dir = TempDir::new().unwrap();
+		for (subdir, age) in [("old", 3600), ("young", 60)] {
+			let path = dir.path().join(subdir);
+			create_dir_all(&path).unwrap();
+			add_entry(&path, "entry");
+			set_age(&path.join("entry.header"), age);
+		}
+		let config = Config {
+			min_lifetime: Some(Duration::from_secs(600)),
+			..Config::default()
+		};
+
+		// Young entries are kept even in desperate mode
+		for desperate in [false, true] {
+			let result =
+				scan_folder_collect(dir.path(), &config, &SystemTime::now(), desperate).unwrap();
+			assert_eq!(result.entries.len(), 1);
+			assert!(result.entries[0]
+				.header_path()
+				.starts_with(dir.path().join("old")));
+			assert_eq!(result.stats.skipped.young, 1);
+		}
+
+		// A lifetime beyond the representable time keeps everything instead of panicking
+		let config = Config {
+			min_lifetime: Some(Duration::MAX),
+			..Config::default()
+		};
+		let result = scan_folder_collect(dir.path(), &config, &SystemTime::now(), false).unwrap();
+		assert!(result.entries.is_empty());
+		assert_eq!(result.stats.skipped.young, 2);
+	}
+
+	#[test]
+	fn test_orphan_data_in_progress() {
+		let dir = TempDir::new().unwrap();
+		File::create(dir.path().join("recent.data")).unwrap();
+		File::create(dir.path().join("old.data")).unwrap();
+		set_age(&dir.path().join("old.data"), 300);
+		// A large file is still within the extended window
+		File::create(dir.path().join("large.data"))
+			.unwrap()
+			.set_len(512 * MIN_WRITE_RATE)
+			.unwrap();
+		set_age(&dir.path().join("large.data"), 300);
+
+		let (stats, _) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.deleted, 1);
+		assert!(dir.path().join("recent.data").exists());
+		assert!(dir.path().join("large.data").exists());
+		assert!(!dir.path().join("old.data").exists());
+	}
+
+	#[test]
+	fn test_no_recurse_hidden() {
+		let dir = TempDir::new().unwrap();
+		for subdir in ["a", ".snapshot"] {
+			create_dir_all(dir.path().join(subdir)).unwrap();
+			add_entry(&dir.path().join(subdir), "entry");
+		}
+
+		let (_, names) = scan(dir.path(), &Config::default());
+		assert_eq!(names.len(), 2);
+
+		let config = Config {
+			no_recurse_hidden: true,
+			..Config::default()
+		};
+		let (_, names) = scan(dir.path(), &config);
+		assert_eq!(names, ["entry.header"]);
+	}
+
+	#[test]
+	fn test_revalidate() {
+		let dir = TempDir::new().unwrap();
+		let header_path = add_entry(dir.path(), "entry");
+		set_age(&header_path, 3600);
+		let entry = dir.path().read_dir().unwrap().next().unwrap().unwrap();
+		let entries = [CacheFileInfo::new(&entry).unwrap()];
+
+		// Apache refreshes the entry after the scan
+		set_age(&header_path, 0);
+
+		let provider = EntryCountUsage(dir.path());
+		let config = Config {
+			revalidate: true,
+			..Config::default()
+		};
+		let stats = prune_entries(dir.path(), &entries, &config, &provider).unwrap();
+		assert_eq!(stats.deleted, 0);
+		assert!(header_path.exists());
+
+		let stats = prune_entries(dir.path(), &entries, &Config::default(), &provider).unwrap();
+		assert_eq!(stats.deleted, 1);
+		assert!(!header_path.exists());
+	}
+
+	#[test]
+	fn test_dump_header_hex() {
+		let dir = TempDir::new().unwrap();
+		write(
+			dir.path().join("corrupt.header"),
+			b"\xde\xad\xbe\xef garbage",
+		)
+		.unwrap();
+		let config = Config {
+			dump_header_hex: true,
+			..Config::default()
+		};
+
+		let mut stats = Stats::default();
+		let logs = capture_logs(|| stats = scan(dir.path(), &config).0);
+		assert_eq!(stats.failed, 1);
+		assert!(logs.contains("de ad be ef 20 67 61 72 62 61 67 65"));
+
+		let logs = capture_logs(|| stats = scan(dir.path(), &Config::default()).0);
+		assert_eq!(stats.failed, 1);
+		assert!(!logs.contains("de ad be ef"));
+	}
+
+	#[test]
+	fn test_expiry_fallback_ttl() {
+		let dir = TempDir::new().unwrap();
+		let header_path = dir.path().join("corrupt.header");
+		write(&header_path, b"\xde\xad\xbe\xef garbage").unwrap();
+		let modified = header_path.metadata().unwrap().modified().unwrap();
+
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert_eq!(stats.failed, 1);
+		assert!(names.is_empty());
+
+		let config = Config {
+			expiry_fallback_ttl: Some(Duration::from_secs(3600)),
+			..Config::default()
+		};
+		let (sender, receiver) = channel::unbounded();
+		let stats = scan_folder(
+			dir.path(),
+			&config,
+			&SystemTime::now(),
+			false,
+			&sender,
+			false,
+		)
+		.unwrap();
+		drop(sender);
+		assert_eq!(stats.failed, 0);
+		let entries: Vec<_> = receiver.iter().collect();
+		assert_eq!(entries.len(), 1);
+		assert_eq!(*entries[0].expires(), modified + Duration::from_secs(3600));
+		assert!(!entries[0].is_vary());
+		assert!(!entries[0].is_refreshed());
+	}
+
+	#[test]
+	fn test_foreign_files() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		add_entry(&sub, "entry");
+		write(sub.join("entry.data"), b"data").unwrap();
+		write(sub.join("aptmpAbC123"), b"").unwrap();
+		write(dir.path().join("access.log"), b"log").unwrap();
+		write(sub.join(".entry.header.swp"), b"").unwrap();
+		for i in 0..FOREIGN_FILE_SAMPLES {
+			write(sub.join(format!("backup-{}.tar", i)), b"").unwrap();
+		}
+
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.foreign_files, 2 + FOREIGN_FILE_SAMPLES as u64);
+		assert!(stats.foreign_samples.is_empty());
+
+		let config = Config {
+			warn_on_foreign_files: true,
+			..Config::default()
+		};
+		let (stats, _) = scan(dir.path(), &config);
+		assert_eq!(stats.foreign_files, 2 + FOREIGN_FILE_SAMPLES as u64);
+		assert_eq!(stats.foreign_samples.len(), FOREIGN_FILE_SAMPLES);
+		assert!(stats
+			.foreign_samples
+			.contains(&dir.path().join("access.log")));
+		for cache_file in ["entry.header", "entry.data", "aptmpAbC123"] {
+			assert!(!stats.foreign_samples.contains(&sub.join(cache_file)));
+		}
+
+		let mut merged = stats.clone();
+		merged.merge(stats);
+		assert_eq!(merged.foreign_files, 2 * (2 + FOREIGN_FILE_SAMPLES as u64));
+		assert_eq!(merged.foreign_samples.len(), FOREIGN_FILE_SAMPLES);
+	}
+
+	#[test]
+	fn test_custom_suffixes() {
+		let dir = TempDir::new().unwrap();
+		let vdir = dir.path().join("entry.hdr.var/ab");
+		create_dir_all(&vdir).unwrap();
+		copy("testcases/vary.header", dir.path().join("entry.hdr")).unwrap();
+		copy("testcases/disk.header", vdir.join("variant.hdr")).unwrap();
+		write(vdir.join("variant.body"), b"data").unwrap();
+		write(dir.path().join("orphan.body"), b"data").unwrap();
+		set_age(&dir.path().join("orphan.body"), 3600);
+		copy("testcases/disk.header", dir.path().join("default.header")).unwrap();
+
+		let config = Config {
+			suffixes: Arc::new(CacheSuffixes {
+				header: ".hdr".to_owned(),
+				data: ".body".to_owned(),
+				vary: ".var".to_owned(),
+			}),
+			..Config::default()
+		};
+		let (sender, receiver) = channel::unbounded();
+		let stats = scan_folder(
+			dir.path(),
+			&config,
+			&SystemTime::now(),
+			false,
+			&sender,
+			false,
+		)
+		.unwrap();
+		drop(sender);
+		let mut entries: Vec<_> = receiver.iter().collect();
+		entries.sort_by_key(|fileinfo| fileinfo.header_path().to_owned());
+
+		// The orphaned data file is deleted, the vary header kept for its vary directory
+		assert_eq!(stats.deleted, 1);
+		assert!(!dir.path().join("orphan.body").exists());
+		assert_eq!(stats.foreign_files, 1);
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].header_path(), vdir.join("variant.hdr"));
+		assert_eq!(entries[0].data_path(), vdir.join("variant.body"));
+		assert_eq!(entries[0].vary_path(), vdir.join("variant.hdr.var"));
+	}
+
+	#[test]
+	fn test_symlinks_not_followed() {
+		let outside = TempDir::new().unwrap();
+		create_dir_all(outside.path().join("sub")).unwrap();
+		add_entry(outside.path(), "target");
+		add_entry(&outside.path().join("sub"), "entry");
+
+		let dir = TempDir::new().unwrap();
+		let link = dir.path().join("link.header");
+		std::os::unix::fs::symlink(outside.path().join("target.header"), &link).unwrap();
+		write(dir.path().join("link.data"), b"data").unwrap();
+		set_age(&dir.path().join("link.data"), 3600);
+		std::os::unix::fs::symlink(outside.path().join("sub"), dir.path().join("linkdir")).unwrap();
+
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert!(names.is_empty());
+		assert_eq!(stats.failed, 1);
+		assert_eq!(stats.deleted, 0);
+		assert!(dir.path().join("link.data").exists());
+		assert!(outside.path().join("target.header").exists());
+
+		let entry = dir
+			.path()
+			.read_dir()
+			.unwrap()
+			.flatten()
+			.find(|e| e.path() == link)
+			.unwrap();
+		let error = CacheFileInfo::new(&entry).unwrap_err();
+		assert_eq!(error.raw_os_error(), Some(libc::ELOOP));
+	}
+
+	#[test]
+	#[cfg(feature = "syscall-timing")]
+	fn test_syscall_timings() {
+		let dir = TempDir::new().unwrap();
+		add_entry(dir.path(), "entry");
+		write(dir.path().join("orphan.data"), b"data").unwrap();
+		set_age(&dir.path().join("orphan.data"), 3600);
+
+		// Other tests run concurrently, so only check for an increase
+		let before = syscall_timings();
+		let (stats, names) = scan(dir.path(), &Config::default());
+		assert_eq!(names, ["entry.header"]);
+		assert_eq!(stats.deleted, 1);
+		let after = syscall_timings();
+		for syscall in [
+			Syscall::ReadDir,
+			Syscall::Metadata,
+			Syscall::Open,
+			Syscall::Remove,
+		] {
+			assert!(after.get(syscall).calls() > before.get(syscall).calls());
+		}
+		assert!(after.to_string().contains("read_dir: "));
+	}
+
+	#[test]
+	fn test_remove_dir_outcome() {
+		let error = |errno| Err(io::Error::from_raw_os_error(errno));
+		assert!(remove_dir_outcome(Ok(())).unwrap());
+		assert!(!remove_dir_outcome(error(libc::ENOTEMPTY)).unwrap());
+		assert!(!remove_dir_outcome(error(libc::ENOENT)).unwrap());
+		assert!(!remove_dir_outcome(error(libc::EBUSY)).unwrap());
+		assert!(remove_dir_outcome(error(libc::EACCES)).is_err());
+	}
+
+	#[test]
+	fn test_profile_folded_stacks() {
+		let dir = TempDir::new().unwrap();
+		for subdir in ["a/b", "c"] {
+			create_dir_all(dir.path().join(subdir)).unwrap();
+			add_entry(&dir.path().join(subdir), "entry");
+		}
+
+		let buffer = Arc::new(Mutex::new(Vec::new()));
+		let layer = FlameLayer::new(LogWriter(buffer.clone()))
+			.with_threads_collapsed(true)
+			.with_file_and_line(false);
+		let guard = layer.flush_on_drop();
+		let subscriber = tracing_subscriber::registry().with(layer);
+		tracing::subscriber::with_default(subscriber, || {
+			process_folder_parallel(dir.path(), &Config::default(), &SystemTime::now()).unwrap();
+		});
+		drop(guard);
+
+		let output = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+		for line in output.lines() {
+			let (stack, value) = line.rsplit_once(' ').unwrap();
+			assert!(value.parse::<u64>().is_ok());
+			assert!(stack == "all-threads" || stack.starts_with("all-threads; fasthtcacheclean::"));
+		}
+		let scan_stack = "all-threads; fasthtcacheclean::scan; fasthtcacheclean::scan_folder";
+		assert!(output.lines().any(|line| line.starts_with(scan_stack)));
+		assert!(output.contains("all-threads; fasthtcacheclean::delete "));
 	}
 }
@@ -5,25 +5,112 @@
 extern crate tracing;
 
 mod cmdargs;
+mod duration_spec;
 mod job_count;
+mod length_range;
+mod timestamp;
 
-use fasthtcacheclean::{Config, SizeSpec, calculate_usage, process_folder_parallel};
+use fasthtcacheclean::{AgeDistribution, AutoSizeSpec, CacheSuffixes, CompactSummary, ENTRY_CHANNEL_CAPACITY, Config, CsvReporter, EntryFilter, EvictionStrategy, ExpiryGaps, FixedClock, FsInfo, HiddenDirs, HotList, InodeEfficiency, KnownEntries, ProcStatm, PrometheusReporter, Quotas, Report, Reporter, RunError, Semaphore, SizeSpec, Stats, SystemClock, TextReporter, TreeSummary, USAGE_TRACE_TARGET, Units, UsageProvider, UsageRecord, VerboseSummary, append_usage_history, auto_free_space, confirm_destructive, delete_candidates, diff_candidates, install_status_handler, iter_cache_entries_with_suffixes, parse_budget, physical_memory, process_folder_parallel, read_candidates, read_scan_state, read_usage_history, scan_phase, write_candidates, write_scan_state};
 use clap::Parser;
 use std::cmp::max;
 use std::env;
-use std::time::SystemTime;
-use cmdargs::Args;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use cmdargs::{Args, DeleteOrder, ErrorPolicy, ExpirySource, ReportFormat, Strategy, UsageFormat};
+#[cfg(feature = "serde")]
+use fasthtcacheclean::{JsonReporter, export_entries};
+#[cfg(feature = "syscall-timing")]
+use fasthtcacheclean::syscall_timings;
+use tracing_flame::{FlameLayer, FlushGuard};
+
+/// Free space to keep with `--min-free-space auto` until enough runs are recorded
+const DEFAULT_MIN_FREE_SPACE: SizeSpec = SizeSpec::Percentage(10.0);
+/// Minimum free space percentage to keep with `--min-free-space auto`
+const AUTO_MIN_FREE_PERCENTAGE: f64 = 1.0;
+
+/// Writes the report of a run to `file` or stdout
+fn write_report(reporter: &dyn Reporter, report: &dyn Report, file: Option<File>) {
+	let result = match file {
+		Some(file) => {
+			let mut writer = BufWriter::new(file);
+			reporter.write_report(report, &mut writer).and_then(|()| writer.flush())
+		}
+		None => reporter.write_report(report, &mut io::stdout().lock()),
+	};
+	result.expect("Couldn't write report.");
+}
+
+/// Prints human-readable output to stdout, or to stderr if stdout carries a report
+fn print_output(output: impl Display, to_stderr: bool) {
+	if to_stderr {
+		eprint!("{}", output);
+	} else {
+		print!("{}", output);
+	}
+}
+
+/// Checks if the report or the exported entries are written to stdout, keeping the logs and other output off it
+fn report_to_stdout(args: &Args) -> bool {
+	#[cfg(feature = "serde")]
+	let report = args.report_format.is_some() || args.verbose_json_summary;
+	#[cfg(not(feature = "serde"))]
+	let report = args.report_format.is_some();
+	#[cfg(feature = "serde")]
+	let export = args.export_entries.as_ref().is_some_and(|path| path.as_os_str() == "-");
+	#[cfg(not(feature = "serde"))]
+	let export = false;
+	(report && args.report_file.is_none()) || export
+}
+
+/// Scans the cache and writes the cache entries to delete to `file` for `--delete-phase-only`
+fn scan_to_candidates(config: &Config, now: &SystemTime, file: File, previous: Option<Vec<u8>>, units: Units, to_stderr: bool) -> Result<Stats, RunError> {
+	let (stats, entries) = scan_phase(".".as_ref(), config, now)?;
+	if let Some(previous) = previous {
+		let previous = match read_candidates(&previous[..], ".".as_ref(), config.expiry_fallback_ttl, &config.suffixes) {
+			Ok(previous) => previous,
+			Err(e) => return Err(RunError::new(stats, e)),
+		};
+		let diff = diff_candidates(&previous, &entries);
+		print_output(&diff, to_stderr);
+		let added_bytes: u64 = diff.added.iter().map(|fileinfo| fileinfo.disk_usage()).sum();
+		info!(
+			"Compared to the previous candidate list: {} new cache entries ({}B), {} no longer present.",
+			diff.added.len(), units.format(added_bytes), diff.removed.len()
+		);
+	}
+	match write_candidates(".".as_ref(), &entries, BufWriter::new(file)) {
+		Ok(count) => {
+			info!("Wrote {} cache entries to delete to the candidate list.", count);
+			Ok(stats)
+		}
+		Err(e) => Err(RunError::new(stats, e)),
+	}
+}
+
+/// Deletes the cache entries read from a candidate list written by `--scan-phase-only`
+fn delete_from_candidates(config: &Config, now: &SystemTime, file: File) -> Result<Stats, RunError> {
+	let entries = read_candidates(BufReader::new(file), ".".as_ref(), config.expiry_fallback_ttl, &config.suffixes)
+		.map_err(|e| RunError::new(Stats::default(), e))?;
+	delete_candidates(".".as_ref(), entries, config, now)
+}
 
 /// Initialize logging/tracing
-fn init_logging(args: &Args) {
+///
+/// Returns a guard that has to be kept alive until the end of the program if profiling is enabled.
+fn init_logging(args: &Args) -> Option<FlushGuard<BufWriter<File>>> {
 	use tracing_subscriber::filter::LevelFilter;
 	use tracing_subscriber::fmt::format::FmtSpan;
+	use tracing_subscriber::fmt::writer::BoxMakeWriter;
 	use tracing_subscriber::prelude::*;
 	use tracing_subscriber::{fmt, EnvFilter};
 
-	let filter_layer = EnvFilter::builder()
+	let mut filter_layer = EnvFilter::builder()
 		.with_default_directive(
 			match args.verbose {
+				_ if args.compact => LevelFilter::WARN,
 				0 => LevelFilter::WARN,
 				1 => LevelFilter::INFO,
 				2 => LevelFilter::DEBUG,
@@ -32,39 +119,161 @@ fn init_logging(args: &Args) {
 			.into(),
 		)
 		.from_env_lossy();
+	if args.verbose_usage_trace {
+		filter_layer = filter_layer.add_directive(format!("{}=trace", USAGE_TRACE_TARGET).parse().unwrap());
+	}
+
+	// Record all spans for profiling, independent of the log level
+	let (flame_layer, flame_guard) = match &args.profile {
+		Some(path) => {
+			let (layer, guard) = FlameLayer::with_file(path).expect("Couldn't create profile file.");
+			let layer = layer
+				.with_threads_collapsed(true)
+				.with_file_and_line(false)
+				.with_filter(LevelFilter::TRACE);
+			(Some(layer), Some(guard))
+		}
+		None => (None, None),
+	};
 
 	#[cfg(feature = "systemd")]
 	if env::var_os("JOURNAL_STREAM").is_some() {
 		if let Ok(journal_layer) = tracing_journald::layer() {
 			tracing_subscriber::registry()
-				.with(filter_layer)
-				.with(journal_layer)
+				.with(flame_layer)
+				.with(journal_layer.with_filter(filter_layer))
 				.init();
-			return;
+			return flame_guard;
 		}
 	}
 
+	// Keep stdout clean for piping the report into other tools
+	let writer = if report_to_stdout(args) {
+		BoxMakeWriter::new(io::stderr)
+	} else {
+		BoxMakeWriter::new(io::stdout)
+	};
 	let fmt_layer = fmt::layer()
+		.with_writer(writer)
 		.with_target(false)
 		.with_span_events(FmtSpan::NONE);
 
 	tracing_subscriber::registry()
-		.with(filter_layer)
-		.with(fmt_layer)
+		.with(flame_layer)
+		.with(fmt_layer.with_filter(filter_layer))
 		.init();
+	flame_guard
 }
 
 impl Args {
-	pub fn into_config<F: FnOnce() -> usize>(self, job_count_closure: F) -> Config {
+	pub fn into_config<F: Fn() -> usize>(self, job_count_closure: F) -> Config {
+		let jobs = self.jobs.unwrap_or_else(&job_count_closure);
+		let scan_jobs = self.scan_jobs.map_or(jobs, |n| n.unwrap_or_else(&job_count_closure));
+		let open_files_budget = if self.limit_open_files { parse_budget(scan_jobs) } else { None };
+		let parse_permits = match (self.parse_concurrency, open_files_budget) {
+			(Some(n), Some(budget)) => Some(n.get().min(budget)),
+			(Some(n), None) => Some(n.get()),
+			(None, budget) => budget,
+		};
 		Config {
-			path: self.path,
-			min_free_space: self.min_free_space,
+			path: std::path::absolute(&self.path).expect("Couldn't resolve cache directory."),
+			min_free_space: match self.min_free_space {
+				AutoSizeSpec::Fixed(spec) => spec,
+				AutoSizeSpec::Auto => DEFAULT_MIN_FREE_SPACE,
+			},
 			min_free_inodes: self.min_free_inodes,
-			jobs: self.jobs.unwrap_or_else(job_count_closure)
+			jobs: scan_jobs,
+			delete_jobs: self.delete_jobs.map_or(jobs, |n| n.unwrap_or_else(&job_count_closure)),
+			delete_batch_size: self.delete_batch_size,
+			channel_capacity: ENTRY_CHANNEL_CAPACITY,
+			ignore_vary: self.ignore_vary,
+			preserve: self.preserve,
+			temp_dir_patterns: self.temp_dir_pattern,
+			no_recurse_hidden: self.no_recurse_hidden,
+			// Entries may have changed since they were written to the candidate list
+			revalidate: self.revalidate || self.delete_phase_only.is_some(),
+			delete_phase_only: self.delete_phase_only.is_some(),
+			dump_header_hex: self.dump_header_hex,
+			no_inode_check: self.no_inode_check,
+			sample_freed_space: self.freed_space_samples.is_some(),
+			changed_since: None,
+			known_entries: None,
+			exclude_recent_dirs: self.exclude_recent_dirs,
+			min_lifetime: self.min_lifetime.map(|duration| duration.0),
+			tree_summary: self.tree_summary,
+			hot_list: HotList::default(),
+			quotas: Quotas::default(),
+			quotas_only: false,
+			exclude_revalidatable: self.exclude_if_revalidatable,
+			entry_filter: self.entry_filter_script.map(EntryFilter::new),
+			fail_fast: self.fail_fast || self.on_error == ErrorPolicy::Abort,
+			skip_dir_on_error: self.on_error == ErrorPolicy::SkipDir,
+			aggressive: self.aggressive,
+			tempfile_suffix_lengths: self.tempfile_suffix_lengths.0,
+			no_delete_folders: self.no_delete_folders,
+			remove_empty_dirs: self.remove_empty_dirs,
+			ignore_readonly: self.ignore_readonly,
+			follow_up_run: self.follow_up_run,
+			preallocate_queue: self.preallocate_queue_from_statfs,
+			max_scan_entries: self.max_scan_entries,
+			memory_limit: self.self_limit_memory.map(|limit| match limit {
+				SizeSpec::Absolute(bytes) => bytes,
+				_ => limit.value(physical_memory().expect("Couldn't determine the physical memory size.")),
+			}),
+			memory_usage: Arc::new(ProcStatm),
+			resume_from: self.resume_from,
+			max_parse_error_rate: self.parse_errors_fatal,
+			min_entry_count: self.min_entry_count,
+			max_delete_bytes: self.max_delete_bytes,
+			retained_stats: self.retained_stats,
+			protect_largest_percentile: self.exclude_by_size_percentile,
+			clock: match self.as_of {
+				Some(timestamp) => Arc::new(FixedClock(timestamp.0)),
+				None => Arc::new(SystemClock),
+			},
+			priority_boost_expired: self.priority_boost_expired,
+			random_delete_order: self.delete_order == DeleteOrder::Random,
+			atime_only: self.cache_age_from_atime_only,
+			strategy: match self.strategy {
+				Strategy::Lru => EvictionStrategy::Lru,
+				Strategy::Size => EvictionStrategy::Size,
+				Strategy::SizeAge => EvictionStrategy::SizeAge,
+			},
+			expired_only: self.expired_only,
+			eviction_tiers: self.eviction_tiers.into_iter().map(Duration::from_secs).collect(),
+			separate_inode_target: self.inode_target_separate_from_space,
+			statfs_timeout: self.scan_timeout_per_statfs.map(Duration::from_secs),
+			shared_usage_provider: Arc::default(),
+			cache_lock_path: self.respect_cache_lock.then(|| {
+				std::path::absolute(&self.cache_lock_path).expect("Couldn't resolve cache lock path.")
+			}),
+			pin_threads: self.pin_threads,
+			parse_limit: parse_permits.map(|permits| Arc::new(Semaphore::new(permits))),
+			expiry_fallback_ttl: match self.expiry_source {
+				ExpirySource::Header => None,
+				ExpirySource::Mtime => Some(Duration::from_secs(self.expiry_ttl)),
+			},
+			warn_on_foreign_files: self.warn_on_foreign_files,
+			suffixes: Arc::new(CacheSuffixes {
+				header: self.header_suffix,
+				data: self.data_suffix,
+				vary: self.vary_suffix,
+			}),
 		}
 	}
 }
 
+/// Logs the usage in the requested format
+fn log_usage(config: &Config, usage: f64, format: UsageFormat, units: Units) {
+	match format {
+		UsageFormat::Percent => info!("Usage: {:.1}% of target space/inode limit", usage),
+		UsageFormat::Absolute => match config.absolute_usage() {
+			Ok(absolute) => info!("Usage: {}", absolute.format(units)),
+			Err(e) => warn!("Couldn't get free space information: {}", e),
+		},
+	}
+}
+
 /// Main function
 ///
 /// Parses the arguments, initializes logging and runs the cleanup job
@@ -73,31 +282,344 @@ fn main() {
 	let args = Args::parse();
 
 	// Initialize logging
-	init_logging(&args);
+	let _flame_guard = init_logging(&args);
+	let output_to_stderr = report_to_stdout(&args);
+
+	if args.dump_stats_on_signal {
+		install_status_handler().expect("Couldn't install signal handler.");
+	}
+
+	let report_age_distribution = args.report_age_distribution;
+	let histogram_buckets: Vec<_> = args.histogram_buckets.iter().map(|bucket| bucket.0).collect();
+	let report_inode_efficiency = args.report_inode_efficiency;
+	let scan_hidden_report = args.scan_hidden_report;
+	let dump_fs_info = args.dump_fs_info;
+	let report_expiry_gaps = args.report_expiry_gaps.map(Duration::from_secs);
+	// Create the file before changing the directory so relative paths work as expected
+	#[cfg(feature = "serde")]
+	let export_file: Option<Box<dyn Write>> = args.export_entries.as_ref().map(|path| -> Box<dyn Write> {
+		if path.as_os_str() == "-" {
+			Box::new(io::stdout().lock())
+		} else {
+			Box::new(File::create(path).expect("Couldn't create entry export file."))
+		}
+	});
+	let assume_yes = args.assume_yes;
+	let output_summary_on_failure = args.output_summary_on_failure;
+	let compact = args.compact;
+	let verify_free_space_after = args.verify_free_space_after;
+	let units = args.units;
+	let usage_format = args.usage_format;
+	let report_format = args.report_format;
+	#[cfg(feature = "serde")]
+	let report_format = report_format.or(args.verbose_json_summary.then_some(ReportFormat::Json));
+	let reporter = report_format.map(|format| -> Box<dyn Reporter> {
+		match format {
+			ReportFormat::Text => Box::new(TextReporter { units }),
+			#[cfg(feature = "serde")]
+			ReportFormat::Json => Box::new(JsonReporter),
+			ReportFormat::Csv => Box::new(CsvReporter),
+			ReportFormat::Prometheus => Box::new(PrometheusReporter),
+		}
+	});
+	#[cfg(feature = "syscall-timing")]
+	let verbose_timing = args.verbose_timing;
+	// Create the file before changing the directory so relative paths work as expected
+	let freed_space_file = args
+		.freed_space_samples
+		.as_ref()
+		.map(|path| File::create(path).expect("Couldn't create freed space samples file."));
+	let report_file = args
+		.report_file
+		.as_ref()
+		.map(|path| File::create(path).expect("Couldn't create report file."));
+	// Read before the scan output is created, which may be the same file
+	let previous_candidates = args
+		.diff_candidates
+		.as_ref()
+		.map(|path| std::fs::read(path).expect("Couldn't read previous candidate list."));
+	let scan_output = args
+		.scan_phase_only
+		.as_ref()
+		.map(|path| File::create(path).expect("Couldn't create candidate list."));
+	let delete_input = args
+		.delete_phase_only
+		.as_ref()
+		.map(|path| File::open(path).expect("Couldn't open candidate list."));
+	let hot_list_path = args.hot_list.clone();
+	let quotas = args
+		.quota_file
+		.as_ref()
+		.map(|path| Quotas::load(path).expect("Couldn't read quota file."));
+	let auto_free_space_enabled = args.min_free_space == AutoSizeSpec::Auto;
+	let history_path = args
+		.usage_history
+		.as_ref()
+		.map(|path| std::path::absolute(path).expect("Couldn't resolve usage history path."));
+	let state_path = args
+		.scan_only_changed
+		.as_ref()
+		.map(|path| std::path::absolute(path).expect("Couldn't resolve state file path."));
 
 	// Create application configuration, calculating number of threads if set to "auto"
-	let config = args.into_config(|| max(1, num_cpus::get() / 2));
+	let mut config = args.into_config(|| max(1, num_cpus::get() / 2));
+	// Needs the configured header suffix to strip it from listed paths
+	if let Some(path) = hot_list_path {
+		config.hot_list = HotList::load(&path, &config.suffixes.header).expect("Couldn't read hot list.");
+	}
+	if let Some(quotas) = quotas {
+		config.quotas = quotas;
+	}
+	if let Some(state_path) = &state_path {
+		let state = read_scan_state(state_path, ".".as_ref(), config.expiry_fallback_ttl, &config.suffixes).expect("Couldn't read state file.");
+		let known = match state {
+			Some((time, known)) => {
+				config.changed_since = Some(time);
+				known
+			}
+			None => {
+				info!("No previous run recorded, scanning everything");
+				KnownEntries::default()
+			}
+		};
+		config.known_entries = Some(Arc::new(known));
+	}
+	if auto_free_space_enabled {
+		let history = match &history_path {
+			Some(path) => read_usage_history(path).expect("Couldn't read usage history."),
+			None => {
+				warn!("--min-free-space auto needs --usage-history, using the default");
+				Vec::new()
+			}
+		};
+		match auto_free_space(&history) {
+			Some(headroom) => config.min_free_space = SizeSpec::AtLeast(AUTO_MIN_FREE_PERCENTAGE, headroom),
+			None => info!("Not enough runs recorded, keeping the default free space"),
+		}
+		info!("Keeping {} free space", config.min_free_space);
+	}
 
 	std::env::set_current_dir(&config.path).expect("Couldn't change to cache directory.");
-	let now = SystemTime::now();
+	let now = config.clock.now();
+
+	#[cfg(feature = "serde")]
+	if let Some(file) = export_file {
+		let entries = iter_cache_entries_with_suffixes(".".as_ref(), &config.suffixes).expect("Couldn't read cache directory.").flatten();
+		let count = export_entries(entries, BufWriter::new(file)).expect("Couldn't write entry export.");
+		info!("Exported {} cache entries", count);
+		return;
+	}
+
+	// The reports of the report modes are written as text unless another format is given
+	let text_reporter = TextReporter { units };
+	let mode_reporter = reporter.as_deref().unwrap_or(&text_reporter);
+
+	if dump_fs_info {
+		match FsInfo::query(config.usage_provider(), config.min_free_space, config.min_free_inodes) {
+			Ok(info) => write_report(mode_reporter, &info, report_file),
+			Err(e) => {
+				error!("Couldn't get free space information: {}", e);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
 
-	let usage = calculate_usage(config.min_free_space, config.min_free_inodes);
-	info!("Usage: {:.1}% of target space/inode limit", usage);
+	if report_age_distribution {
+		let mut distribution = AgeDistribution::with_buckets(histogram_buckets);
+		for fileinfo in iter_cache_entries_with_suffixes(".".as_ref(), &config.suffixes).expect("Couldn't read cache directory.").flatten() {
+			distribution.add(&fileinfo, &now);
+		}
+		write_report(mode_reporter, &distribution, report_file);
+		return;
+	}
+
+	if let Some(threshold) = report_expiry_gaps {
+		let mut gaps = ExpiryGaps::new(threshold);
+		for fileinfo in iter_cache_entries_with_suffixes(".".as_ref(), &config.suffixes).expect("Couldn't read cache directory.").flatten() {
+			gaps.add(&fileinfo, &now);
+		}
+		write_report(mode_reporter, &gaps, report_file);
+		if gaps.count > 0 {
+			warn!("Found {} cache entries that expired long ago, parts of the cache may not be cleaned", gaps.count);
+		}
+		return;
+	}
+
+	if report_inode_efficiency {
+		let efficiency = InodeEfficiency::scan(".".as_ref(), &config.suffixes).expect("Couldn't read cache directory.");
+		if reporter.is_none() {
+			match config.absolute_usage() {
+				Ok(absolute) => println!("Usage: {}", absolute.format(units)),
+				Err(e) => warn!("Couldn't get free space information: {}", e),
+			}
+		}
+		write_report(mode_reporter, &efficiency, report_file);
+		return;
+	}
+
+	if scan_hidden_report {
+		let hidden = HiddenDirs::scan(".".as_ref(), &config, &now).expect("Couldn't read cache directory.");
+		write_report(mode_reporter, &hidden, report_file);
+		return;
+	}
 
-	if usage >= 90.0 {
+	// Refuse options deleting more than a normal cleanup unless confirmed, the report modes above don't delete anything
+	let stdin = io::stdin();
+	let mut input = stdin.lock();
+	let mut stderr = io::stderr();
+	let prompt: Option<(&mut dyn BufRead, &mut dyn Write)> = if stdin.is_terminal() {
+		Some((&mut input, &mut stderr))
+	} else {
+		None
+	};
+	if !confirm_destructive(&config.destructive_modes(), assume_yes, prompt) {
+		error!("Refusing to run without confirmation, pass --assume-yes to run anyway.");
+		std::process::exit(1);
+	}
+
+	let usage = match config.usage() {
+		Ok(usage) => usage,
+		Err(e) => {
+			error!("Couldn't get free space information: {}", e);
+			std::process::exit(1);
+		}
+	};
+	log_usage(&config, usage, usage_format, units);
+	let used_before = history_path.as_ref().and_then(|_| match config.absolute_usage() {
+		Ok(absolute) => Some(absolute.space.used),
+		Err(e) => {
+			warn!("Couldn't get free space information, not recording the usage history: {}", e);
+			None
+		}
+	});
+
+	if usage >= 90.0 || scan_output.is_some() || config.expired_only || !config.quotas.is_empty() {
+		// Quotas are enforced regardless of the usage, but nothing else is deleted below 90 %
+		config.quotas_only = usage < 90.0 && scan_output.is_none() && !config.expired_only;
 		info!("Pruning cache...");
 
-		let result = process_folder_parallel(".".as_ref(), &config, &now);
+		// Only runs that scan find the cache entries for the state file
+		let scans = delete_input.is_none();
+		let result = if let Some(file) = scan_output {
+			scan_to_candidates(&config, &now, file, previous_candidates, units, output_to_stderr)
+		} else if let Some(file) = delete_input {
+			delete_from_candidates(&config, &now, file)
+		} else {
+			process_folder_parallel(".".as_ref(), &config, &now)
+		};
 
-		if let Ok(stats) = result {
-			let usage = calculate_usage(config.min_free_space, config.min_free_inodes);
-			info!("Usage: {:.1}% of target space/inode limit", usage);
-			info!(
-				"Statistics: {} deleted files, {} deleted folders, {} failed to delete",
-				stats.deleted, stats.deleted_folders, stats.failed
+		let (stats, failed) = match result {
+			Ok(stats) => {
+				if let (Some(state_path), Some(known), true) = (&state_path, &config.known_entries, scans) {
+					write_scan_state(state_path, now, ".".as_ref(), &known.take_found()).expect("Couldn't write state file.");
+				}
+				(stats, false)
+			}
+			Err(e) => {
+				error!("Cleanup failed: {}", e);
+				if !output_summary_on_failure {
+					std::process::exit(1);
+				}
+				(*e.stats, true)
+			}
+		};
+
+		let usage_after = config.usage();
+		match usage_after {
+			Ok(usage_after) => log_usage(&config, usage_after, usage_format, units),
+			Err(e) => warn!("Couldn't get free space information after the cleanup: {}", e),
+		}
+		info!(
+			"Statistics: {} deleted files, {} deleted folders, {} failed to delete, {}B freed",
+			stats.deleted, stats.deleted_folders, stats.failed, units.format(stats.freed_bytes)
+		);
+		if config.warn_on_foreign_files && stats.foreign_files > 0 {
+			warn!(
+				"Found {} files not belonging to the cache, e.g. {:?}",
+				stats.foreign_files, stats.foreign_samples
 			);
 		}
+		if config.retained_stats {
+			info!("Retained: {} cache entries, {}B on disk", stats.retained, units.format(stats.retained_bytes));
+		}
+		if config.tree_summary {
+			print_output(TreeSummary(&stats.per_folder), output_to_stderr);
+		}
+		if let Ok(usage_after) = usage_after {
+			if compact {
+				print_output(format_args!("{}\n", CompactSummary::new(&stats, usage, usage_after, units)), output_to_stderr);
+			}
+			if let Some(reporter) = &reporter {
+				match config.binding_constraint() {
+					Ok(constraint) => {
+						let summary = VerboseSummary::new(&stats, usage, usage_after, constraint);
+						write_report(reporter.as_ref(), &summary, report_file);
+					}
+					Err(e) => warn!("Couldn't get free space information, not writing the report: {}", e),
+				}
+			}
+		} else if compact || reporter.is_some() {
+			warn!("Not writing the summary without the usage after the cleanup");
+		}
+		#[cfg(feature = "syscall-timing")]
+		if verbose_timing {
+			print_output(syscall_timings(), output_to_stderr);
+		}
+
+		if let Some(file) = freed_space_file {
+			let mut writer = BufWriter::new(file);
+			for sample in &stats.freed_space {
+				writeln!(writer, "{:.3},{}", sample.elapsed.as_secs_f64(), units.format(sample.bytes))
+					.expect("Couldn't write freed space samples.");
+			}
+			writer.flush().expect("Couldn't write freed space samples.");
+		}
+
+		if failed {
+			std::process::exit(1);
+		}
 	} else {
-		// do nothing
+		if compact {
+			print_output(format_args!("{}\n", CompactSummary::new(&Stats::default(), usage, usage, units)), output_to_stderr);
+		}
+		if let Some(reporter) = &reporter {
+			match config.binding_constraint() {
+				Ok(constraint) => {
+					let summary = VerboseSummary::new(&Stats::default(), usage, usage, constraint);
+					write_report(reporter.as_ref(), &summary, report_file);
+				}
+				Err(e) => warn!("Couldn't get free space information, not writing the report: {}", e),
+			}
+		}
+	}
+
+	if let (Some(path), Some(used_before)) = (&history_path, used_before) {
+		match config.absolute_usage() {
+			Ok(absolute) => {
+				let record = UsageRecord {
+					time: now,
+					used_before,
+					used_after: absolute.space.used,
+				};
+				append_usage_history(path, record).expect("Couldn't write usage history.");
+			}
+			Err(e) => warn!("Couldn't get free space information, not recording the usage history: {}", e),
+		}
+	}
+
+	if let Some(max_usage) = verify_free_space_after {
+		let provider = config.usage_provider();
+		match provider.verify_usage(config.min_free_space, config.min_free_inodes, max_usage) {
+			Ok(Ok(_)) => {}
+			Ok(Err(e)) => {
+				error!("Verification failed: {}", e);
+				std::process::exit(1);
+			}
+			Err(e) => {
+				error!("Verification failed, couldn't get free space information: {}", e);
+				std::process::exit(1);
+			}
+		}
 	}
 }
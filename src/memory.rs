@@ -0,0 +1,49 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::fmt::Debug;
+use std::fs::read_to_string;
+
+/// Source of the memory usage of the process
+pub trait MemoryUsage: Debug + Send + Sync {
+	/// Returns the resident set size in bytes, `None` if it can't be determined
+	fn rss(&self) -> Option<u64>;
+}
+
+/// `MemoryUsage` reading `/proc/self/statm`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcStatm;
+
+impl MemoryUsage for ProcStatm {
+	fn rss(&self) -> Option<u64> {
+		let statm = read_to_string("/proc/self/statm").ok()?;
+		// Second field is the number of resident pages
+		let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+		Some(pages * page_size()?)
+	}
+}
+
+/// Returns the memory page size in bytes
+fn page_size() -> Option<u64> {
+	// SAFETY: sysconf only reads a system setting, -1 for errors is rejected by try_from
+	u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).ok()
+}
+
+/// Returns the size of the physical memory in bytes, `None` if it can't be determined
+pub fn physical_memory() -> Option<u64> {
+	// SAFETY: sysconf only reads a system setting, -1 for errors is rejected by try_from
+	let pages = u64::try_from(unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) }).ok()?;
+	Some(pages * page_size()?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_proc_statm() {
+		let rss = ProcStatm.rss().unwrap();
+		assert!(rss > 0);
+		assert!(rss < physical_memory().unwrap());
+	}
+}
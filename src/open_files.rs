@@ -0,0 +1,130 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::io;
+
+/// File descriptors kept free for standard streams, log, report and candidate files
+const RESERVED_FILES: u64 = 16;
+/// File descriptors each scanning thread holds for the directories it descends into
+///
+/// Covers Apache's default of two directory levels plus a vary directory.
+const DIRS_PER_JOB: u64 = 4;
+
+/// Returns the soft limit of open files of the process, `None` if unlimited
+pub fn open_files_limit() -> Result<Option<u64>, io::Error> {
+	let mut limit = libc::rlimit {
+		rlim_cur: 0,
+		rlim_max: 0,
+	};
+	// SAFETY: `limit` is a valid `rlimit` for the call to write to
+	if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok((limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur))
+}
+
+/// Returns how many header files `jobs` scanning threads may open at once within `limit` open files
+///
+/// Returns 0 if the directories of the scanning threads alone may exhaust the limit.
+fn budget_within(limit: u64, jobs: usize) -> usize {
+	let budget = limit.saturating_sub(RESERVED_FILES + DIRS_PER_JOB * jobs as u64);
+	usize::try_from(budget).unwrap_or(usize::MAX)
+}
+
+/// Returns the number of header files to parse at once to stay within the open files limit
+///
+/// Warns if the limit is lower than needed for parsing in all `jobs` scanning
+/// threads at once, but always allows one. Returns `None` if the limit is
+/// unlimited or can't be determined.
+pub fn parse_budget(jobs: usize) -> Option<usize> {
+	let limit = match open_files_limit() {
+		Ok(limit) => limit?,
+		Err(e) => {
+			warn!("Couldn't get the open files limit: {}", e);
+			return None;
+		}
+	};
+	let budget = budget_within(limit, jobs);
+	if budget < jobs {
+		warn!(
+			"Open files limit of {} is low for {} jobs, parsing at most {} header files at once",
+			limit,
+			jobs,
+			budget.max(1)
+		);
+	}
+	Some(budget.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::add_entries;
+	use crate::{scan_phase, Config, Semaphore};
+	use std::fs::create_dir_all;
+	use std::process::Command;
+	use std::sync::Arc;
+	use std::time::SystemTime;
+	use tempfile::TempDir;
+
+	/// Set in the child process running the test with a lowered limit
+	const CHILD_ENV: &str = "FASTHTCACHECLEAN_TEST_OPEN_FILES_CHILD";
+
+	#[test]
+	fn test_budget_within() {
+		assert_eq!(budget_within(1024, 8), 1024 - 16 - 32);
+		assert_eq!(budget_within(64, 8), 16);
+		assert_eq!(budget_within(32, 8), 0);
+	}
+
+	#[test]
+	fn test_open_files_limit() {
+		// Lowering the limit affects the whole process, so run in a separate one
+		if std::env::var_os(CHILD_ENV).is_none() {
+			let output = Command::new(std::env::current_exe().unwrap())
+				.args(["--exact", "open_files::tests::test_open_files_limit"])
+				.env(CHILD_ENV, "1")
+				.output()
+				.unwrap();
+			assert!(
+				output.status.success(),
+				"{}",
+				String::from_utf8_lossy(&output.stdout)
+			);
+			return;
+		}
+
+		let dir = TempDir::new().unwrap();
+		for folder in 0..16 {
+			let sub = dir.path().join(format!("{:02}/ab", folder));
+			create_dir_all(&sub).unwrap();
+			add_entries(&sub, 8);
+		}
+		let mut limit = libc::rlimit {
+			rlim_cur: 0,
+			rlim_max: 0,
+		};
+		// SAFETY: `limit` is a valid `rlimit` for both calls, this process runs only this test
+		unsafe {
+			assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit), 0);
+			limit.rlim_cur = 50;
+			assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit), 0);
+		}
+		assert_eq!(open_files_limit().unwrap(), Some(50));
+
+		// Only 2 header files at once for 8 jobs
+		let budget = parse_budget(8).unwrap();
+		assert_eq!(budget, 2);
+		let parse_limit = Arc::new(Semaphore::new(budget));
+		let config = Config {
+			jobs: 8,
+			parse_limit: Some(Arc::clone(&parse_limit)),
+			..Config::default()
+		};
+		let (stats, entries) = scan_phase(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(entries.len(), 128);
+		assert_eq!(stats.parse_errors, 0);
+		assert_eq!(stats.failed, 0);
+		assert!((1..=budget).contains(&parse_limit.peak()));
+	}
+}
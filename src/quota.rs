@@ -0,0 +1,89 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::size_spec::SizeSpec;
+
+/// Size limits for top-level cache directories by name prefix
+#[derive(Debug, Clone, Default)]
+pub struct Quotas(Vec<(String, SizeSpec)>);
+
+impl Quotas {
+	/// Reads quotas from a file
+	///
+	/// See `from_reader` for the format.
+	pub fn load(path: &Path) -> Result<Self, io::Error> {
+		Self::from_reader(BufReader::new(File::open(path)?))
+	}
+
+	/// Reads quotas with one directory name prefix and size per line
+	///
+	/// The size is separated by whitespace and given like `--min-free-space`,
+	/// e.g. `tenant-a 10G` or `www. 5%`. Each top-level directory starting with
+	/// the prefix is limited to the size on its own. Empty lines and lines
+	/// starting with `#` are ignored.
+	pub fn from_reader(reader: impl BufRead) -> Result<Self, io::Error> {
+		let mut quotas = Vec::new();
+		for (index, line) in reader.lines().enumerate() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let invalid = |reason: String| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("invalid quota in line {}: {}", index + 1, reason),
+				)
+			};
+			let (prefix, size) = line
+				.rsplit_once(char::is_whitespace)
+				.ok_or_else(|| invalid("expected a prefix and a size".into()))?;
+			let size = size.parse().map_err(|e| invalid(format!("{}", e)))?;
+			quotas.push((prefix.trim_end().to_owned(), size));
+		}
+		Ok(Self(quotas))
+	}
+
+	/// Returns `true` if no quotas are configured
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Returns the quota of a top-level directory, from the longest matching prefix
+	pub fn limit_for(&self, folder: &OsStr) -> Option<SizeSpec> {
+		self.0
+			.iter()
+			.filter(|(prefix, _)| folder.as_bytes().starts_with(prefix.as_bytes()))
+			.max_by_key(|(prefix, _)| prefix.len())
+			.map(|(_, size)| *size)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_quotas() {
+		let input = "# tenants\n\ntenant- 10M\ntenant-big  1G\nwww. 5%\n";
+		let quotas = Quotas::from_reader(input.as_bytes()).unwrap();
+		assert!(!quotas.is_empty());
+		let limit = |folder: &str| quotas.limit_for(folder.as_ref());
+		assert_eq!(limit("tenant-a"), Some(SizeSpec::Absolute(10_000_000)));
+		assert_eq!(limit("tenant-big"), Some(SizeSpec::Absolute(1_000_000_000)));
+		assert_eq!(limit("www.example.com"), Some(SizeSpec::Percentage(5.0)));
+		assert_eq!(limit("other"), None);
+
+		for invalid in ["tenant-", "tenant- 10X"] {
+			let error = Quotas::from_reader(invalid.as_bytes()).unwrap_err();
+			assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+		}
+	}
+}
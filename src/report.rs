@@ -0,0 +1,750 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::reporter::Report;
+use crate::{is_hidden, is_recently_modified, STALE_TEMP_DIR_AGE};
+use crate::{CacheFileInfo, CacheSuffixes, Config, Stats, Units};
+
+/// Returns the signed difference `a - b` in seconds
+fn signed_secs(a: &SystemTime, b: &SystemTime) -> f64 {
+	match a.duration_since(*b) {
+		Ok(duration) => duration.as_secs_f64(),
+		Err(e) => -e.duration().as_secs_f64(),
+	}
+}
+
+/// Formats a (possibly negative) number of seconds in a compact human readable way
+struct HumanDuration(f64);
+
+impl fmt::Display for HumanDuration {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let secs = self.0;
+		let abs = secs.abs();
+		if abs < 120.0 {
+			write!(f, "{:.0}s", secs)
+		} else if abs < 7200.0 {
+			write!(f, "{:.1}m", secs / 60.0)
+		} else if abs < 172800.0 {
+			write!(f, "{:.1}h", secs / 3600.0)
+		} else {
+			write!(f, "{:.1}d", secs / 86400.0)
+		}
+	}
+}
+
+/// Formats a bucket boundary in the largest unit dividing it evenly
+struct BucketBound(Duration);
+
+impl fmt::Display for BucketBound {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let secs = self.0.as_secs();
+		match secs {
+			0 => f.write_str("0s"),
+			_ if secs.is_multiple_of(86400) => write!(f, "{}d", secs / 86400),
+			_ if secs.is_multiple_of(3600) => write!(f, "{}h", secs / 3600),
+			_ if secs.is_multiple_of(60) => write!(f, "{}m", secs / 60),
+			_ => write!(f, "{}s", secs),
+		}
+	}
+}
+
+/// Counts of values in seconds per bucket, bucket `i` ranging up to `bounds[i]` (exclusive)
+///
+/// The last bucket counts the values at or above the last boundary, negative
+/// values are counted in the first bucket.
+fn bucket_counts(values: impl Iterator<Item = f64>, bounds: &[Duration]) -> Vec<u64> {
+	let mut counts = vec![0; bounds.len() + 1];
+	for value in values {
+		counts[bounds.partition_point(|bound| bound.as_secs_f64() <= value)] += 1;
+	}
+	counts
+}
+
+/// Writes a histogram line with one `range: count` item per bucket
+fn write_histogram(f: &mut fmt::Formatter<'_>, bounds: &[Duration], counts: &[u64]) -> fmt::Result {
+	for (i, count) in counts.iter().enumerate() {
+		if i > 0 {
+			f.write_str(", ")?;
+		}
+		match (i.checked_sub(1).map(|i| bounds[i]), bounds.get(i)) {
+			(None, Some(&upper)) => write!(f, "<{}: {}", BucketBound(upper), count)?,
+			(Some(lower), Some(&upper)) => write!(
+				f,
+				"{}-{}: {}",
+				BucketBound(lower),
+				BucketBound(upper),
+				count
+			)?,
+			(Some(lower), None) => write!(f, ">={}: {}", BucketBound(lower), count)?,
+			(None, None) => write!(f, "all: {}", count)?,
+		}
+	}
+	Ok(())
+}
+
+/// Returns one metric per bucket, named by `prefix` and the bucket's boundary in seconds
+fn histogram_metrics(prefix: &str, bounds: &[Duration], counts: &[u64]) -> Vec<(String, f64)> {
+	counts
+		.iter()
+		.enumerate()
+		.map(|(i, &count)| {
+			let name = match bounds.get(i) {
+				Some(upper) => format!("{}_lt_{}", prefix, upper.as_secs()),
+				None => format!("{}_ge_{}", prefix, bounds[i - 1].as_secs()),
+			};
+			(name, count as f64)
+		})
+		.collect()
+}
+
+/// p50/p90/p99 summary of a set of values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles {
+	pub p50: f64,
+	pub p90: f64,
+	pub p99: f64,
+}
+
+impl Percentiles {
+	/// Computes nearest-rank percentiles of `values`
+	///
+	/// Returns `None` if `values` is empty.
+	pub fn from_values(mut values: Vec<f64>) -> Option<Self> {
+		if values.is_empty() {
+			return None;
+		}
+		values.sort_by(f64::total_cmp);
+		let percentile = |p: f64| {
+			let rank = (p / 100.0 * values.len() as f64).ceil() as usize;
+			values[rank.clamp(1, values.len()) - 1]
+		};
+		Some(Self {
+			p50: percentile(50.0),
+			p90: percentile(90.0),
+			p99: percentile(99.0),
+		})
+	}
+}
+
+impl fmt::Display for Percentiles {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"p50 {}, p90 {}, p99 {}",
+			HumanDuration(self.p50),
+			HumanDuration(self.p90),
+			HumanDuration(self.p99)
+		)
+	}
+}
+
+/// Distribution of cache entry ages and times to expiry
+#[derive(Debug, Clone, Default)]
+pub struct AgeDistribution {
+	ages: Vec<f64>,
+	expiries: Vec<f64>,
+	/// Sorted histogram bucket boundaries, no histograms if empty
+	buckets: Vec<Duration>,
+}
+
+impl AgeDistribution {
+	/// Creates a distribution also reporting histograms with the given bucket boundaries
+	pub fn with_buckets(mut buckets: Vec<Duration>) -> Self {
+		buckets.sort_unstable();
+		buckets.dedup();
+		Self {
+			buckets,
+			..Self::default()
+		}
+	}
+
+	/// Records the age and time-to-expiry of a cache entry relative to `now`
+	pub fn add(&mut self, fileinfo: &CacheFileInfo, now: &SystemTime) {
+		self.ages.push(signed_secs(now, fileinfo.modified()));
+		self.expiries.push(signed_secs(fileinfo.expires(), now));
+	}
+
+	/// Number of recorded entries
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.ages.len()
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.ages.is_empty()
+	}
+
+	/// Percentiles of the entry age (time since last modification)
+	pub fn age_percentiles(&self) -> Option<Percentiles> {
+		Percentiles::from_values(self.ages.clone())
+	}
+
+	/// Percentiles of the time to expiry (negative if already expired)
+	pub fn expiry_percentiles(&self) -> Option<Percentiles> {
+		Percentiles::from_values(self.expiries.clone())
+	}
+
+	/// Number of entries per age bucket (see `with_buckets`)
+	///
+	/// Bucket `i` counts the entries younger than the `i`-th boundary and not in
+	/// an earlier bucket, the last bucket the entries older than all boundaries.
+	pub fn age_histogram(&self) -> Vec<u64> {
+		bucket_counts(self.ages.iter().copied(), &self.buckets)
+	}
+
+	/// Number of entries already expired
+	fn expired(&self) -> usize {
+		self.expiries.iter().filter(|&&secs| secs < 0.0).count()
+	}
+
+	/// Number of entries not expired yet per time to expiry bucket, like `age_histogram`
+	pub fn expiry_histogram(&self) -> Vec<u64> {
+		let pending = self.expiries.iter().copied().filter(|&secs| secs >= 0.0);
+		bucket_counts(pending, &self.buckets)
+	}
+}
+
+impl fmt::Display for AgeDistribution {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (self.age_percentiles(), self.expiry_percentiles()) {
+			(Some(ages), Some(expiries)) => write!(
+				f,
+				"Entries: {}\nAge: {}\nTime to expiry: {}",
+				self.len(),
+				ages,
+				expiries
+			)?,
+			_ => f.write_str("Entries: 0")?,
+		}
+		if !self.buckets.is_empty() {
+			f.write_str("\nAge histogram: ")?;
+			write_histogram(f, &self.buckets, &self.age_histogram())?;
+			write!(
+				f,
+				"\nTime to expiry histogram: expired: {}, ",
+				self.expired()
+			)?;
+			write_histogram(f, &self.buckets, &self.expiry_histogram())?;
+		}
+		Ok(())
+	}
+}
+
+impl Report for AgeDistribution {
+	fn write_text(&self, _units: Units, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(out, "{}", self)
+	}
+
+	/// Percentiles in seconds and, with buckets, the entries per bucket
+	fn metrics(&self) -> Vec<(String, f64)> {
+		let mut metrics = vec![("entries".to_owned(), self.len() as f64)];
+		for (name, percentiles) in [
+			("age", self.age_percentiles()),
+			("expiry", self.expiry_percentiles()),
+		] {
+			if let Some(percentiles) = percentiles {
+				metrics.extend([
+					(format!("{}_p50_seconds", name), percentiles.p50),
+					(format!("{}_p90_seconds", name), percentiles.p90),
+					(format!("{}_p99_seconds", name), percentiles.p99),
+				]);
+			}
+		}
+		if !self.buckets.is_empty() {
+			metrics.extend(histogram_metrics(
+				"age_histogram",
+				&self.buckets,
+				&self.age_histogram(),
+			));
+			metrics.push(("expiry_histogram_expired".to_owned(), self.expired() as f64));
+			metrics.extend(histogram_metrics(
+				"expiry_histogram",
+				&self.buckets,
+				&self.expiry_histogram(),
+			));
+		}
+		metrics
+	}
+}
+
+/// Maximum number of long expired cache entries kept as a sample by `ExpiryGaps`
+pub const EXPIRY_GAP_SAMPLES: usize = 10;
+
+/// Cache entries that expired long ago, which indicate parts of the cache not being cleaned
+///
+/// Such entries accumulate if runs don't free enough space to reach them or
+/// if a subtree is excluded or unreachable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiryGaps {
+	/// Minimum time since expiry for an entry to be counted
+	pub threshold: Duration,
+	/// Number of counted entries
+	pub count: u64,
+	/// Longest time since expiry of the counted entries in seconds
+	pub max_overdue: f64,
+	/// Header paths of the first counted entries
+	pub samples: Vec<PathBuf>,
+}
+
+impl ExpiryGaps {
+	/// Creates an empty report counting entries expired more than `threshold` ago
+	pub fn new(threshold: Duration) -> Self {
+		Self {
+			threshold,
+			count: 0,
+			max_overdue: 0.0,
+			samples: Vec::new(),
+		}
+	}
+
+	/// Counts the cache entry if it expired more than `threshold` before `now`
+	pub fn add(&mut self, fileinfo: &CacheFileInfo, now: &SystemTime) {
+		let overdue = signed_secs(now, fileinfo.expires());
+		if overdue <= self.threshold.as_secs_f64() {
+			return;
+		}
+		self.count += 1;
+		self.max_overdue = self.max_overdue.max(overdue);
+		if self.samples.len() < EXPIRY_GAP_SAMPLES {
+			self.samples.push(fileinfo.header_path().to_owned());
+		}
+	}
+}
+
+impl fmt::Display for ExpiryGaps {
+	/// Writes the count and one line per sample
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"Entries expired more than {} ago: {}",
+			HumanDuration(self.threshold.as_secs_f64()),
+			self.count
+		)?;
+		if self.count > 0 {
+			write!(f, " (up to {} ago)", HumanDuration(self.max_overdue))?;
+		}
+		for path in &self.samples {
+			write!(f, "\n{}", path.display())?;
+		}
+		Ok(())
+	}
+}
+
+impl Report for ExpiryGaps {
+	fn write_text(&self, _units: Units, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(out, "{}", self)
+	}
+
+	/// Counts only, without the samples
+	fn metrics(&self) -> Vec<(String, f64)> {
+		vec![
+			("threshold_seconds".into(), self.threshold.as_secs_f64()),
+			("expired_entries".into(), self.count as f64),
+			("max_overdue_seconds".into(), self.max_overdue),
+		]
+	}
+}
+
+/// Disk space and inodes used by the cache, for judging the directory layout
+///
+/// Many inodes per entry or few bytes per inode indicate that `CacheDirLevels`
+/// and `CacheDirLength` create more directories than the cache needs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InodeEfficiency {
+	/// Cache entries (header files)
+	pub entries: u64,
+	/// Files and directories below the cache root
+	pub inodes: u64,
+	/// Disk space allocated to the files and directories in bytes
+	pub bytes: u64,
+}
+
+impl InodeEfficiency {
+	/// Walks the directory tree below `path` without following symlinks
+	pub fn scan(path: &Path, suffixes: &CacheSuffixes) -> Result<Self, io::Error> {
+		let mut result = Self::default();
+		let mut stack = vec![path.to_owned()];
+		while let Some(dir) = stack.pop() {
+			for item in dir.read_dir()? {
+				let item = item?;
+				let metadata = item.metadata()?;
+				result.inodes += 1;
+				result.bytes += metadata.blocks() * 512;
+				if metadata.is_dir() {
+					stack.push(item.path());
+				} else if item
+					.file_name()
+					.to_str()
+					.is_some_and(|name| name.ends_with(suffixes.header.as_str()))
+				{
+					result.entries += 1;
+				}
+			}
+		}
+		Ok(result)
+	}
+
+	/// Average disk space per cache entry in bytes
+	pub fn bytes_per_entry(&self) -> Option<f64> {
+		(self.entries > 0).then(|| self.bytes as f64 / self.entries as f64)
+	}
+
+	/// Average disk space per inode in bytes
+	pub fn bytes_per_inode(&self) -> Option<f64> {
+		(self.inodes > 0).then(|| self.bytes as f64 / self.inodes as f64)
+	}
+
+	/// Formats the figures for humans, with byte counts in the given units
+	pub fn format(&self, units: Units) -> String {
+		match (self.bytes_per_entry(), self.bytes_per_inode()) {
+			(Some(per_entry), Some(per_inode)) => format!(
+				"Entries: {}\nInodes: {} ({:.1} per entry)\nBytes per entry: {}B\nBytes per inode: {}B",
+				self.entries,
+				self.inodes,
+				self.inodes as f64 / self.entries as f64,
+				units.format(per_entry as u64),
+				units.format(per_inode as u64)
+			),
+			_ => format!("Entries: {}\nInodes: {}", self.entries, self.inodes),
+		}
+	}
+}
+
+impl Report for InodeEfficiency {
+	fn write_text(&self, units: Units, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(out, "{}", self.format(units))
+	}
+
+	fn metrics(&self) -> Vec<(String, f64)> {
+		let mut metrics = vec![
+			("entries".to_owned(), self.entries as f64),
+			("inodes".to_owned(), self.inodes as f64),
+			("bytes".to_owned(), self.bytes as f64),
+		];
+		metrics.extend(
+			self.bytes_per_entry()
+				.map(|value| ("bytes_per_entry".to_owned(), value)),
+		);
+		metrics.extend(
+			self.bytes_per_inode()
+				.map(|value| ("bytes_per_inode".to_owned(), value)),
+		);
+		metrics
+	}
+}
+
+/// Kind of a directory found by `HiddenDirs::scan`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HiddenDirKind {
+	/// Temporary directory modified recently, skipped by the scan
+	ActiveTemp,
+	/// Temporary directory deleted by the scan (unless kept for other options)
+	StaleTemp,
+	/// Directory with a name starting with a dot
+	Hidden,
+}
+
+impl fmt::Display for HiddenDirKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::ActiveTemp => "active temporary",
+			Self::StaleTemp => "stale temporary",
+			Self::Hidden => "hidden",
+		})
+	}
+}
+
+/// Directories below the cache root that aren't scanned like cache directories
+///
+/// Lists the temporary directories matching `Config::temp_dir_patterns`, like
+/// the ones Apache creates while rehashing, and hidden directories, which are
+/// skipped with `Config::no_recurse_hidden`. Neither is descended into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HiddenDirs {
+	/// Found directories with their kind, in the order they were found
+	pub dirs: Vec<(PathBuf, HiddenDirKind)>,
+}
+
+impl HiddenDirs {
+	/// Walks the directory tree below `path` without following symlinks
+	pub fn scan(path: &Path, config: &Config, now: &SystemTime) -> Result<Self, io::Error> {
+		let mut result = Self::default();
+		let mut stack = vec![path.to_owned()];
+		while let Some(dir) = stack.pop() {
+			for item in dir.read_dir()? {
+				let item = item?;
+				let metadata = item.metadata()?;
+				if !metadata.is_dir() {
+					continue;
+				}
+				let kind = if item
+					.file_name()
+					.to_str()
+					.is_some_and(|name| config.is_temp_dir(name))
+				{
+					if is_recently_modified(&metadata, now, STALE_TEMP_DIR_AGE) {
+						HiddenDirKind::ActiveTemp
+					} else {
+						HiddenDirKind::StaleTemp
+					}
+				} else if is_hidden(&item) {
+					HiddenDirKind::Hidden
+				} else {
+					stack.push(item.path());
+					continue;
+				};
+				result.dirs.push((item.path(), kind));
+			}
+		}
+		Ok(result)
+	}
+
+	/// Number of found directories of the given kind
+	pub fn count(&self, kind: HiddenDirKind) -> u64 {
+		self.dirs.iter().filter(|(_, k)| *k == kind).count() as u64
+	}
+}
+
+impl fmt::Display for HiddenDirs {
+	/// Writes the counts and one line per directory
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"Temporary directories: {} active, {} stale\nHidden directories: {}",
+			self.count(HiddenDirKind::ActiveTemp),
+			self.count(HiddenDirKind::StaleTemp),
+			self.count(HiddenDirKind::Hidden)
+		)?;
+		for (path, kind) in &self.dirs {
+			write!(f, "\n{}: {}", kind, path.display())?;
+		}
+		Ok(())
+	}
+}
+
+impl Report for HiddenDirs {
+	fn write_text(&self, _units: Units, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(out, "{}", self)
+	}
+
+	/// Counts only, without the paths
+	fn metrics(&self) -> Vec<(String, f64)> {
+		vec![
+			(
+				"active_temp_dirs".into(),
+				self.count(HiddenDirKind::ActiveTemp) as f64,
+			),
+			(
+				"stale_temp_dirs".into(),
+				self.count(HiddenDirKind::StaleTemp) as f64,
+			),
+			(
+				"hidden_dirs".into(),
+				self.count(HiddenDirKind::Hidden) as f64,
+			),
+		]
+	}
+}
+
+/// Per-folder deletion statistics, sorted by the number of deleted files
+///
+/// Writes one line per folder.
+pub struct TreeSummary<'a>(pub &'a BTreeMap<OsString, Stats>);
+
+impl fmt::Display for TreeSummary<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut folders: Vec<_> = self.0.iter().collect();
+		folders.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.deleted));
+		for (folder, stats) in folders {
+			writeln!(
+				f,
+				"{}: {} deleted files, {} deleted folders, {} failed to delete",
+				folder.to_string_lossy(),
+				stats.deleted,
+				stats.deleted_folders,
+				stats.failed
+			)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::reporter::{CsvReporter, Reporter};
+
+	#[test]
+	fn test_percentiles() {
+		let values = (1..=100).rev().map(f64::from).collect();
+		let result = Percentiles::from_values(values).unwrap();
+		assert_eq!(
+			result,
+			Percentiles {
+				p50: 50.0,
+				p90: 90.0,
+				p99: 99.0
+			}
+		);
+
+		let result = Percentiles::from_values(vec![-5.0, 10.0]).unwrap();
+		assert_eq!(result.p50, -5.0);
+		assert_eq!(result.p99, 10.0);
+
+		assert_eq!(Percentiles::from_values(Vec::new()), None);
+	}
+
+	#[test]
+	fn test_hidden_dirs() {
+		let dir = tempfile::TempDir::new().unwrap();
+		for path in ["a/rehash.tmp/b", "a/upload.tmp", "a/.git/c", "b/c"] {
+			std::fs::create_dir_all(dir.path().join(path)).unwrap();
+		}
+		let config = Config {
+			temp_dir_patterns: vec![glob::Pattern::new("*.tmp").unwrap()],
+			..Default::default()
+		};
+		let now = SystemTime::now() + Duration::from_secs(STALE_TEMP_DIR_AGE);
+
+		let mut result = HiddenDirs::scan(dir.path(), &config, &now).unwrap();
+		result.dirs.sort();
+		assert_eq!(
+			result.dirs,
+			[
+				(dir.path().join("a/.git"), HiddenDirKind::Hidden),
+				(dir.path().join("a/rehash.tmp"), HiddenDirKind::StaleTemp),
+				(dir.path().join("a/upload.tmp"), HiddenDirKind::StaleTemp),
+			]
+		);
+		let result = HiddenDirs::scan(dir.path(), &config, &SystemTime::now()).unwrap();
+		assert_eq!(result.count(HiddenDirKind::ActiveTemp), 2);
+		assert!(result
+			.to_string()
+			.starts_with("Temporary directories: 2 active, 0 stale\nHidden directories: 1\n"));
+		assert!(result.metrics().contains(&("hidden_dirs".into(), 1.0)));
+
+		// Without patterns, temporary directories are scanned like any other
+		let result = HiddenDirs::scan(dir.path(), &Config::default(), &now).unwrap();
+		assert_eq!(
+			result.dirs,
+			[(dir.path().join("a/.git"), HiddenDirKind::Hidden)]
+		);
+	}
+
+	#[test]
+	fn test_age_histogram() {
+		let dir = tempfile::TempDir::new().unwrap();
+		let path = dir.path().join("entry.header");
+		std::fs::copy("testcases/disk.header", &path).unwrap();
+		let entry = dir.path().read_dir().unwrap().next().unwrap().unwrap();
+		let fileinfo = CacheFileInfo::new(&entry).unwrap();
+		let hour = Duration::from_secs(3600);
+		let day = 24 * hour;
+
+		let mut distribution = AgeDistribution::with_buckets(vec![day, hour, 6 * hour, day]);
+		for age in [
+			Duration::ZERO,
+			hour / 2,
+			hour,
+			5 * hour,
+			6 * hour,
+			2 * day,
+			30 * day,
+		] {
+			distribution.add(&fileinfo, &(*fileinfo.modified() + age));
+		}
+		// Modified in the future
+		distribution.add(&fileinfo, &(*fileinfo.modified() - hour));
+		assert_eq!(distribution.age_histogram(), [3, 2, 1, 2]);
+		assert_eq!(distribution.expiry_histogram().len(), 4);
+		let metrics = distribution.metrics();
+		assert!(metrics.contains(&("entries".into(), 8.0)));
+		assert!(metrics.contains(&("age_histogram_lt_3600".into(), 3.0)));
+		assert!(metrics.contains(&("age_histogram_ge_86400".into(), 2.0)));
+
+		let text = distribution.to_string();
+		assert!(
+			text.contains("\nAge histogram: <1h: 3, 1h-6h: 2, 6h-1d: 1, >=1d: 2\n"),
+			"{}",
+			text
+		);
+		assert!(text.contains("\nTime to expiry histogram: expired: "));
+		assert!(!AgeDistribution::default().to_string().contains("histogram"));
+	}
+
+	#[test]
+	fn test_inode_efficiency() {
+		let dir = tempfile::TempDir::new().unwrap();
+		let sub = dir.path().join("ab/cd");
+		std::fs::create_dir_all(&sub).unwrap();
+		std::fs::copy("testcases/disk.header", sub.join("one.header")).unwrap();
+		std::fs::write(sub.join("one.data"), [1u8; 8192]).unwrap();
+		std::fs::copy("testcases/disk.header", sub.join("two.header")).unwrap();
+
+		let result = InodeEfficiency::scan(dir.path(), &CacheSuffixes::default()).unwrap();
+		assert_eq!(result.entries, 2);
+		// Two directories and three files
+		assert_eq!(result.inodes, 5);
+		assert!(result.bytes >= 8192);
+		assert_eq!(result.bytes_per_entry(), Some(result.bytes as f64 / 2.0));
+		assert_eq!(result.bytes_per_inode(), Some(result.bytes as f64 / 5.0));
+		assert!(result
+			.format(Units::Si)
+			.contains("Inodes: 5 (2.5 per entry)"));
+
+		let empty = InodeEfficiency::default();
+		assert_eq!(empty.bytes_per_entry(), None);
+		assert_eq!(empty.format(Units::Si), "Entries: 0\nInodes: 0");
+	}
+
+	#[test]
+	fn test_expiry_gaps() {
+		let dir = tempfile::TempDir::new().unwrap();
+		let path = dir.path().join("entry.header");
+		std::fs::copy("testcases/disk.header", &path).unwrap();
+		let entry = dir.path().read_dir().unwrap().next().unwrap().unwrap();
+		let fileinfo = CacheFileInfo::new(&entry).unwrap();
+		let day = Duration::from_secs(86400);
+
+		let mut gaps = ExpiryGaps::new(7 * day);
+		// Expired a day ago, within the threshold
+		gaps.add(&fileinfo, &(*fileinfo.expires() + day));
+		assert_eq!(gaps.count, 0);
+		assert_eq!(gaps.to_string(), "Entries expired more than 7.0d ago: 0");
+
+		// Expired 30 days ago
+		for _ in 0..(EXPIRY_GAP_SAMPLES + 1) {
+			gaps.add(&fileinfo, &(*fileinfo.expires() + 30 * day));
+		}
+		assert_eq!(gaps.count, EXPIRY_GAP_SAMPLES as u64 + 1);
+		assert_eq!(gaps.samples.len(), EXPIRY_GAP_SAMPLES);
+		assert_eq!(gaps.samples[0], path);
+		assert!(gaps
+			.to_string()
+			.starts_with("Entries expired more than 7.0d ago: 11 (up to 30.0d ago)\n"));
+
+		let mut csv = Vec::new();
+		CsvReporter.write_report(&gaps, &mut csv).unwrap();
+		assert_eq!(
+			String::from_utf8(csv).unwrap(),
+			"threshold_seconds,expired_entries,max_overdue_seconds\n604800,11,2592000\n"
+		);
+	}
+
+	#[test]
+	fn test_human_duration() {
+		assert_eq!(HumanDuration(30.0).to_string(), "30s");
+		assert_eq!(HumanDuration(-600.0).to_string(), "-10.0m");
+		assert_eq!(HumanDuration(3.0 * 3600.0).to_string(), "3.0h");
+		assert_eq!(HumanDuration(7.0 * 86400.0).to_string(), "7.0d");
+	}
+}
@@ -0,0 +1,216 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::size_spec::Units;
+use std::io::{self, Write};
+
+/// Prefix of the Prometheus metric names
+const METRIC_PREFIX: &str = "fasthtcacheclean_";
+
+/// Report that can be written in the format of any `Reporter`
+pub trait Report {
+	/// Writes the report for humans, with byte counts in the given units
+	fn write_text(&self, units: Units, out: &mut dyn Write) -> io::Result<()>;
+
+	/// Returns all numeric metrics with flat names, nested ones joined with `_`
+	fn metrics(&self) -> Vec<(String, f64)>;
+
+	/// Returns the non-numeric values with their name and the name of the Prometheus
+	/// label holding them
+	fn labels(&self) -> Vec<(&'static str, &'static str, String)> {
+		Vec::new()
+	}
+
+	/// Serializes the report as a single line of JSON, by default an object of
+	/// the metrics and labels
+	#[cfg(feature = "serde")]
+	fn to_json(&self) -> String {
+		let mut object = serde_json::Map::new();
+		for (name, value) in self.metrics() {
+			object.insert(name, value.into());
+		}
+		for (name, _, value) in self.labels() {
+			object.insert(name.into(), value.into());
+		}
+		serde_json::Value::Object(object).to_string()
+	}
+}
+
+/// Output format for reports, e.g. the summary of a run
+pub trait Reporter {
+	/// Writes `report` to `out`
+	fn write_report(&self, report: &dyn Report, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Human readable report with one line per group of metrics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextReporter {
+	/// Unit system for byte counts
+	pub units: Units,
+}
+
+/// Single line JSON object (see `Report::to_json`)
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonReporter;
+
+/// CSV with a header line and one line of values
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CsvReporter;
+
+/// Prometheus text exposition format, e.g. for the node exporter's textfile collector
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrometheusReporter;
+
+impl Reporter for TextReporter {
+	fn write_report(&self, report: &dyn Report, out: &mut dyn Write) -> io::Result<()> {
+		report.write_text(self.units, out)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl Reporter for JsonReporter {
+	fn write_report(&self, report: &dyn Report, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(out, "{}", report.to_json())
+	}
+}
+
+impl Reporter for CsvReporter {
+	fn write_report(&self, report: &dyn Report, out: &mut dyn Write) -> io::Result<()> {
+		let metrics = report.metrics();
+		let labels = report.labels();
+		let names: Vec<_> = metrics
+			.iter()
+			.map(|(name, _)| name.as_str())
+			.chain(labels.iter().map(|(name, _, _)| *name))
+			.collect();
+		let values: Vec<_> = metrics
+			.iter()
+			.map(|(_, value)| value.to_string())
+			.chain(labels.iter().map(|(_, _, value)| value.clone()))
+			.collect();
+		writeln!(out, "{}", names.join(","))?;
+		writeln!(out, "{}", values.join(","))
+	}
+}
+
+impl Reporter for PrometheusReporter {
+	fn write_report(&self, report: &dyn Report, out: &mut dyn Write) -> io::Result<()> {
+		for (name, value) in report.metrics() {
+			writeln!(out, "# TYPE {}{} gauge", METRIC_PREFIX, name)?;
+			writeln!(out, "{}{} {}", METRIC_PREFIX, name, value)?;
+		}
+		for (name, label, value) in report.labels() {
+			writeln!(out, "# TYPE {}{} gauge", METRIC_PREFIX, name)?;
+			writeln!(
+				out,
+				"{}{}{{{}=\"{}\"}} 1",
+				METRIC_PREFIX, name, label, value
+			)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::stats::Stats;
+	use crate::summary::VerboseSummary;
+	use crate::usage::Constraint;
+	use std::time::Duration;
+
+	fn summary() -> VerboseSummary {
+		let mut stats = Stats {
+			deleted: 3,
+			deleted_disk: 2,
+			deleted_vary: 1,
+			failed: 1,
+			freed_bytes: 2_500_000,
+			..Default::default()
+		};
+		stats.skipped.hot = 4;
+		stats.timings.scan = Duration::from_millis(1500);
+		VerboseSummary::new(&stats, 101.5, 98.24, Constraint::Inodes)
+	}
+
+	fn report(reporter: &dyn Reporter) -> String {
+		let mut out = Vec::new();
+		reporter.write_report(&summary(), &mut out).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+
+	#[test]
+	fn test_text_report() {
+		let text = report(&TextReporter { units: Units::Si });
+		let lines: Vec<_> = text.lines().collect();
+		assert_eq!(
+			lines[..3],
+			[
+				"Deleted: 3 files (2 disk, 1 vary), 0 folders, 1 failed",
+				"Freed: 2.5MB",
+				"Usage: 101.5% before, 98.2% after (limited by inodes)",
+			]
+		);
+		assert!(lines[3].starts_with("Skipped: 0 preserved, 4 hot, "));
+		assert_eq!(
+			lines[4],
+			"Phases: cleanup 0.000s, scan 1.500s, delete 0.000s"
+		);
+		assert_eq!(lines.len(), 5);
+	}
+
+	#[test]
+	fn test_csv_report() {
+		let csv = report(&CsvReporter);
+		let lines: Vec<_> = csv.lines().collect();
+		assert_eq!(lines.len(), 2);
+		let header: Vec<_> = lines[0].split(',').collect();
+		let values: Vec<_> = lines[1].split(',').collect();
+		assert_eq!(header.len(), values.len());
+		let value = |name| values[header.iter().position(|&h| h == name).unwrap()];
+		assert_eq!(value("deleted"), "3");
+		assert_eq!(value("freed_bytes"), "2500000");
+		assert_eq!(value("usage_after"), "98.24");
+		assert_eq!(value("skipped_hot"), "4");
+		assert_eq!(value("scan_seconds"), "1.5");
+		assert_eq!(value("binding_constraint"), "inodes");
+	}
+
+	#[test]
+	fn test_prometheus_report() {
+		let text = report(&PrometheusReporter);
+		let mut samples = 0;
+		for line in text.lines() {
+			if let Some(comment) = line.strip_prefix("# TYPE ") {
+				assert!(comment.starts_with(METRIC_PREFIX) && comment.ends_with(" gauge"));
+				continue;
+			}
+			let (name, value) = line.rsplit_once(' ').unwrap();
+			assert!(name.starts_with(METRIC_PREFIX), "{}", line);
+			let name = name.split('{').next().unwrap();
+			assert!(
+				name.bytes().all(|b| b.is_ascii_lowercase() || b == b'_'),
+				"{}",
+				line
+			);
+			value.parse::<f64>().unwrap();
+			// Each sample follows its type
+			assert!(text.contains(&format!("# TYPE {} gauge\n{}", name, line)));
+			samples += 1;
+		}
+		assert_eq!(samples, summary().metrics().len() + 1);
+		assert!(text.contains("fasthtcacheclean_deleted 3\n"));
+		assert!(text.contains("fasthtcacheclean_binding_constraint{constraint=\"inodes\"} 1\n"));
+	}
+
+	#[test]
+	#[cfg(feature = "serde")]
+	fn test_json_report() {
+		let json = report(&JsonReporter);
+		assert_eq!(json.lines().count(), 1);
+		let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+		assert_eq!(json["deleted"], 3);
+		assert_eq!(json["skipped"]["hot"], 4);
+	}
+}
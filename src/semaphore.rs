@@ -0,0 +1,87 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Counting semaphore limiting how many threads run a section at once
+#[derive(Debug)]
+pub struct Semaphore {
+	permits: usize,
+	available: Mutex<usize>,
+	released: Condvar,
+	/// Most permits held at once so far
+	peak: AtomicUsize,
+}
+
+/// Permit of a `Semaphore`, released when dropped
+#[derive(Debug)]
+pub struct SemaphorePermit<'a>(&'a Semaphore);
+
+impl Semaphore {
+	/// Creates a semaphore handing out up to `permits` permits at once
+	pub fn new(permits: usize) -> Self {
+		Self {
+			permits,
+			available: Mutex::new(permits),
+			released: Condvar::new(),
+			peak: AtomicUsize::new(0),
+		}
+	}
+
+	/// Waits until a permit is available and takes it
+	pub fn acquire(&self) -> SemaphorePermit<'_> {
+		let mut available = self
+			.released
+			.wait_while(self.lock(), |available| *available == 0)
+			.unwrap_or_else(|e| e.into_inner());
+		*available -= 1;
+		self.peak
+			.fetch_max(self.permits - *available, Ordering::Relaxed);
+		SemaphorePermit(self)
+	}
+
+	/// Returns the most permits held at once so far
+	pub fn peak(&self) -> usize {
+		self.peak.load(Ordering::Relaxed)
+	}
+
+	/// Locks the permit count, ignoring poisoning as it's always consistent
+	fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+		self.available.lock().unwrap_or_else(|e| e.into_inner())
+	}
+}
+
+impl Drop for SemaphorePermit<'_> {
+	fn drop(&mut self) {
+		*self.0.lock() += 1;
+		self.0.released.notify_one();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn test_semaphore() {
+		let semaphore = Semaphore::new(2);
+		let running = AtomicUsize::new(0);
+		let max_running = AtomicUsize::new(0);
+		std::thread::scope(|s| {
+			for _ in 0..8 {
+				s.spawn(|| {
+					let _permit = semaphore.acquire();
+					let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+					max_running.fetch_max(now_running, Ordering::SeqCst);
+					std::thread::sleep(Duration::from_millis(10));
+					running.fetch_sub(1, Ordering::SeqCst);
+				});
+			}
+		});
+		assert!(max_running.load(Ordering::SeqCst) <= 2);
+		assert_eq!(semaphore.peak(), 2);
+		assert_eq!(*semaphore.lock(), 2);
+	}
+}
@@ -11,6 +11,8 @@ use thiserror::Error;
 pub enum SizeSpec {
 	Percentage(f64),
 	Absolute(u64),
+	/// Percentage, but at least the absolute value
+	AtLeast(f64, u64),
 }
 
 impl fmt::Display for SizeSpec {
@@ -31,6 +33,9 @@ impl fmt::Display for SizeSpec {
 					write!(f, "{}T", (n as f64) / 1000000000000.0)
 				}
 			}
+			SizeSpec::AtLeast(p, n) => {
+				write!(f, "{},{}", SizeSpec::Percentage(*p), SizeSpec::Absolute(*n))
+			}
 		}
 	}
 }
@@ -42,6 +47,88 @@ impl SizeSpec {
 		match self {
 			SizeSpec::Percentage(n) => (*n / 100.0 * (total as f64)) as u64,
 			SizeSpec::Absolute(n) => *n,
+			SizeSpec::AtLeast(p, n) => SizeSpec::Percentage(*p).value(total).max(*n),
+		}
+	}
+}
+
+/// A size limit, or `auto` for a limit determined at runtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoSizeSpec {
+	Auto,
+	Fixed(SizeSpec),
+}
+
+impl fmt::Display for AutoSizeSpec {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Auto => f.write_str("auto"),
+			Self::Fixed(spec) => spec.fmt(f),
+		}
+	}
+}
+
+/// Unit system for displaying byte counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Units {
+	/// Decimal units (`K` = 1000)
+	Si,
+	/// Binary units (`Ki` = 1024)
+	Binary,
+	/// Plain number of bytes
+	#[default]
+	Raw,
+}
+
+impl fmt::Display for Units {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Si => f.write_str("si"),
+			Self::Binary => f.write_str("binary"),
+			Self::Raw => f.write_str("raw"),
+		}
+	}
+}
+
+impl Units {
+	/// Formats a byte count in this unit system
+	///
+	/// The output can be parsed as `SizeSpec::Absolute` again (up to rounding).
+	pub fn format(self, n: u64) -> String {
+		match self {
+			Self::Si => SizeSpec::Absolute(n).to_string(),
+			Self::Binary => {
+				if n < 1024 {
+					format!("{}", n)
+				} else if n < 1048576 {
+					format!("{}Ki", (n as f64) / 1024.0)
+				} else if n < 1073741824 {
+					format!("{}Mi", (n as f64) / 1048576.0)
+				} else if n < 1099511627776 {
+					format!("{}Gi", (n as f64) / 1073741824.0)
+				} else {
+					format!("{}Ti", (n as f64) / 1099511627776.0)
+				}
+			}
+			Self::Raw => n.to_string(),
+		}
+	}
+}
+
+/// Error type for parsing `Units`
+#[derive(Error, Debug)]
+#[error("expected 'si', 'binary' or 'raw'")]
+pub struct ParseUnitsError;
+
+/// Parsing a string into `Units`
+impl FromStr for Units {
+	type Err = ParseUnitsError;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"si" => Ok(Self::Si),
+			"binary" => Ok(Self::Binary),
+			"raw" => Ok(Self::Raw),
+			_ => Err(ParseUnitsError),
 		}
 	}
 }
@@ -57,12 +144,22 @@ pub enum ParseSizeSpecError {
 	InvalidFloat(#[from] ParseFloatError),
 	#[error("`{0}` is not a valid unit. Known units are `K`, `Ki`, `M`, `Mi`, `G`, `Gi`, `T`, `Ti`, `%`.")]
 	InvalidUnit(char),
+	#[error("expected a percentage and an absolute value separated by a comma")]
+	InvalidCombination,
 }
 
 /// Parsing a string into a `SizeSpec`
 impl FromStr for SizeSpec {
 	type Err = ParseSizeSpecError;
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some((first, second)) = s.split_once(',') {
+			return match (first.parse()?, second.parse()?) {
+				(SizeSpec::Percentage(p), SizeSpec::Absolute(n))
+				| (SizeSpec::Absolute(n), SizeSpec::Percentage(p)) => Ok(SizeSpec::AtLeast(p, n)),
+				_ => Err(ParseSizeSpecError::InvalidCombination),
+			};
+		}
+
 		let first_char = s.chars().next().ok_or(ParseSizeSpecError::EmptyString)?;
 		let (last_char, forelast_char) = {
 			let mut it = s.chars().rev();
@@ -108,6 +205,18 @@ impl FromStr for SizeSpec {
 	}
 }
 
+/// Parsing a string into an `AutoSizeSpec`
+impl FromStr for AutoSizeSpec {
+	type Err = ParseSizeSpecError;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.eq_ignore_ascii_case("auto") {
+			Ok(Self::Auto)
+		} else {
+			Ok(Self::Fixed(s.parse()?))
+		}
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -187,4 +296,55 @@ mod tests {
 		assert_eq!(b.value(1), 0);
 		assert_eq!(c.value(10000000), 0);
 	}
+
+	/// Tests combined percentage and absolute values
+	#[test]
+	fn test_at_least() {
+		let value: SizeSpec = "10%,20G".parse().unwrap();
+		assert_eq!(value, SizeSpec::AtLeast(10.0, 20000000000));
+		assert_eq!(value.to_string(), "10%,20G");
+		assert_eq!("20G,10%".parse::<SizeSpec>().unwrap(), value);
+
+		// Percentage is binding
+		assert_eq!(value.value(1000000000000), 100000000000);
+		// Absolute floor is binding
+		assert_eq!(value.value(100000000000), 20000000000);
+
+		for string in ["10%,20%", "1G,2G", "10%,1G,2G", "10%,", ",1G"] {
+			assert!(string.parse::<SizeSpec>().is_err());
+		}
+	}
+
+	/// Tests parsing `auto` or a fixed limit
+	#[test]
+	fn test_auto_size_spec() {
+		assert_eq!("auto".parse::<AutoSizeSpec>().unwrap(), AutoSizeSpec::Auto);
+		assert_eq!("AUTO".parse::<AutoSizeSpec>().unwrap(), AutoSizeSpec::Auto);
+		let fixed: AutoSizeSpec = "10%,20G".parse().unwrap();
+		assert_eq!(
+			fixed,
+			AutoSizeSpec::Fixed(SizeSpec::AtLeast(10.0, 20000000000))
+		);
+		assert_eq!(fixed.to_string(), "10%,20G");
+		assert_eq!(AutoSizeSpec::Auto.to_string(), "auto");
+		assert!("automatic".parse::<AutoSizeSpec>().is_err());
+	}
+
+	/// Tests byte count formatting in the different unit systems
+	#[test]
+	fn test_units() {
+		let n = 1536000;
+		assert_eq!(Units::Si.format(n), "1.536M");
+		assert_eq!(Units::Binary.format(n), "1.46484375Mi");
+		assert_eq!(Units::Raw.format(n), "1536000");
+		assert_eq!(Units::Binary.format(1536), "1.5Ki");
+		for units in [Units::Si, Units::Binary, Units::Raw] {
+			assert_eq!(units.to_string().parse::<Units>().unwrap(), units);
+			assert_eq!(
+				units.format(n).parse::<SizeSpec>().unwrap(),
+				SizeSpec::Absolute(n)
+			);
+		}
+		assert!("decimal".parse::<Units>().is_err());
+	}
 }
@@ -1,14 +1,139 @@
 // Copyright (c) 2022 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Maximum number of foreign file paths kept as a sample
+pub const FOREIGN_FILE_SAMPLES: usize = 10;
+
+/// Cumulative freed space at a point in time during deletion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FreedSpaceSample {
+	/// Time since deletion started
+	pub elapsed: Duration,
+	/// Bytes freed since deletion started
+	pub bytes: u64,
+}
+
+/// Number of cache entries skipped for deletion per reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SkipCounts {
+	/// Entries matching a `--preserve` pattern
+	pub preserved: u64,
+	/// Entries on the hot list
+	pub hot: u64,
+	/// Entries younger than the minimum lifetime
+	pub young: u64,
+	/// Entries with validators kept by `--exclude-if-revalidatable`
+	pub revalidatable: u64,
+	/// Entries kept by the `--entry-filter-script` command
+	pub filtered: u64,
+	/// Entries protected for their size
+	pub large: u64,
+	/// Entries refreshed by Apache before deletion
+	pub refreshed: u64,
+	/// Entries locked by Apache for an update
+	pub locked: u64,
+	/// Temporary directories recently modified or kept for `--preserve` or `--max-delete-bytes`
+	pub temp_dirs: u64,
+	/// Recently modified directories
+	pub recent_dirs: u64,
+	/// Directories unchanged since the last run
+	pub unchanged_dirs: u64,
+	/// Directories nested too deeply to be scanned
+	pub deep_dirs: u64,
+}
+
+impl SkipCounts {
+	/// Returns the counts with the name of their reason
+	pub fn by_reason(&self) -> [(&'static str, u64); 12] {
+		[
+			("preserved", self.preserved),
+			("hot", self.hot),
+			("young", self.young),
+			("revalidatable", self.revalidatable),
+			("filtered", self.filtered),
+			("large", self.large),
+			("refreshed", self.refreshed),
+			("locked", self.locked),
+			("temp_dirs", self.temp_dirs),
+			("recent_dirs", self.recent_dirs),
+			("unchanged_dirs", self.unchanged_dirs),
+			("deep_dirs", self.deep_dirs),
+		]
+	}
+
+	#[inline]
+	fn merge(&mut self, other: SkipCounts) {
+		self.preserved += other.preserved;
+		self.hot += other.hot;
+		self.young += other.young;
+		self.revalidatable += other.revalidatable;
+		self.filtered += other.filtered;
+		self.large += other.large;
+		self.refreshed += other.refreshed;
+		self.locked += other.locked;
+		self.temp_dirs += other.temp_dirs;
+		self.recent_dirs += other.recent_dirs;
+		self.unchanged_dirs += other.unchanged_dirs;
+		self.deep_dirs += other.deep_dirs;
+	}
+}
+
+/// Duration of the phases of a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PhaseTimings {
+	pub cleanup: Duration,
+	pub scan: Duration,
+	pub delete: Duration,
+}
 
 /// Statistic results
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Stats {
 	pub deleted: u64,
 	pub deleted_folders: u64,
 	pub failed: u64,
+	/// Deleted cache entries in the disk format (included in `deleted`)
+	pub deleted_disk: u64,
+	/// Deleted cache entries in the vary format (included in `deleted`)
+	pub deleted_vary: u64,
+	/// Disk space of the deleted header and data files in bytes
+	pub freed_bytes: u64,
+	/// Number of deleted header and data files
+	pub freed_inodes: u64,
+	/// Valid cache entries found while scanning (only set by `process_folder_parallel`)
+	pub scanned: u64,
+	/// Cache headers parsed while scanning
+	pub parsed: u64,
+	/// Cache headers that failed to parse while scanning (included in `failed`)
+	pub parse_errors: u64,
+	/// Valid cache entries kept after the run (only counted if enabled in the configuration)
+	pub retained: u64,
+	/// Disk space of the retained cache entries in bytes
+	pub retained_bytes: u64,
+	/// Cache entries and directories skipped
+	pub skipped: SkipCounts,
+	/// Files found while scanning that don't belong to the cache
+	pub foreign_files: u64,
+	/// Paths of up to `FOREIGN_FILE_SAMPLES` foreign files (only recorded if enabled in the configuration)
+	pub foreign_samples: Vec<PathBuf>,
+	/// Duration of the phases (only set by `process_folder_parallel`)
+	pub timings: PhaseTimings,
+	/// Freed space over time (only recorded if enabled in the configuration)
+	pub freed_space: Vec<FreedSpaceSample>,
+	/// Statistics per top-level folder (only recorded if enabled in the configuration)
+	pub per_folder: BTreeMap<OsString, Stats>,
 }
 
 impl Stats {
@@ -42,25 +167,63 @@ impl Stats {
 		}
 	}
 
+	/// Returns the percentage of cache headers that failed to parse, if any were found
+	pub fn parse_error_rate(&self) -> Option<f64> {
+		let total = self.parsed + self.parse_errors;
+		(total > 0).then(|| self.parse_errors as f64 * 100.0 / total as f64)
+	}
+
 	/// Merge the counts of the given stats-returning result into the statistics
 	#[inline]
 	pub fn merge_result<E: fmt::Debug>(&mut self, r: Result<Stats, E>) {
 		match r {
-			Ok(stats) => {
-				self.deleted += stats.deleted;
-				self.deleted_folders += stats.deleted_folders;
-				self.failed += stats.failed;
-			}
+			Ok(stats) => self.merge(stats),
 			Err(_) => self.failed += 1,
 		}
 	}
 
 	/// Merge the counts of the given stats into the statistics
+	///
+	/// Freed space samples are appended, per-folder statistics are merged by folder.
 	#[inline]
 	pub fn merge(&mut self, stats: Stats) {
 		self.deleted += stats.deleted;
 		self.deleted_folders += stats.deleted_folders;
 		self.failed += stats.failed;
+		self.deleted_disk += stats.deleted_disk;
+		self.deleted_vary += stats.deleted_vary;
+		self.freed_bytes += stats.freed_bytes;
+		self.freed_inodes += stats.freed_inodes;
+		self.scanned += stats.scanned;
+		self.parsed += stats.parsed;
+		self.parse_errors += stats.parse_errors;
+		self.retained += stats.retained;
+		self.retained_bytes += stats.retained_bytes;
+		self.skipped.merge(stats.skipped);
+		self.foreign_files += stats.foreign_files;
+		let free_samples = FOREIGN_FILE_SAMPLES.saturating_sub(self.foreign_samples.len());
+		self.foreign_samples
+			.extend(stats.foreign_samples.into_iter().take(free_samples));
+		self.timings.cleanup += stats.timings.cleanup;
+		self.timings.scan += stats.timings.scan;
+		self.timings.delete += stats.timings.delete;
+		self.freed_space.extend(stats.freed_space);
+		for (folder, folder_stats) in stats.per_folder {
+			self.per_folder
+				.entry(folder)
+				.or_default()
+				.merge(folder_stats);
+		}
+	}
+
+	/// Merge the given stats into the statistics, also attributing them to `folder`
+	#[inline]
+	pub fn merge_for_folder(&mut self, folder: OsString, stats: Stats) {
+		self.per_folder
+			.entry(folder)
+			.or_default()
+			.merge(stats.clone());
+		self.merge(stats);
 	}
 }
 
@@ -84,6 +247,35 @@ impl<E: fmt::Debug> std::iter::Sum<Result<Self, E>> for Stats {
 	}
 }
 
+/// Error aborting a run, with the statistics of the work done until then
+#[derive(Error, Debug)]
+#[error("{source}")]
+pub struct RunError {
+	/// Statistics up to the error (boxed to keep `Result`s small)
+	pub stats: Box<Stats>,
+	/// Cause of the abort
+	pub source: io::Error,
+}
+
+impl RunError {
+	/// Creates an error with the statistics of the work done before it
+	#[inline]
+	pub fn new(stats: Stats, source: io::Error) -> Self {
+		Self {
+			stats: Box::new(stats),
+			source,
+		}
+	}
+
+	/// Adds the statistics of the work done before the failed step
+	#[inline]
+	pub fn after(mut self, mut stats: Stats) -> Self {
+		stats.merge(*self.stats);
+		*self.stats = stats;
+		self
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -124,21 +316,29 @@ mod tests {
 				deleted: 50,
 				deleted_folders: 3,
 				failed: 12,
+				freed_bytes: 409600,
+				freed_inodes: 100,
+				..Default::default()
 			},
 			Stats {
 				deleted: 20,
 				deleted_folders: 2,
 				failed: 29,
+				freed_bytes: 81920,
+				freed_inodes: 39,
+				..Default::default()
 			},
 			Stats {
 				deleted: 0,
 				deleted_folders: 0,
 				failed: 0,
+				..Default::default()
 			},
 			Stats {
 				deleted: 0,
 				deleted_folders: 0,
 				failed: 1,
+				..Default::default()
 			},
 		];
 
@@ -146,6 +346,8 @@ mod tests {
 		assert_eq!(result.deleted, 70);
 		assert_eq!(result.deleted_folders, 5);
 		assert_eq!(result.failed, 42);
+		assert_eq!(result.freed_bytes, 491520);
+		assert_eq!(result.freed_inodes, 139);
 	}
 
 	/// Tests `Stats` summing
@@ -156,22 +358,28 @@ mod tests {
 				deleted: 50,
 				deleted_folders: 3,
 				failed: 12,
+				..Default::default()
 			}),
 			Ok(Stats {
 				deleted: 20,
 				deleted_folders: 2,
 				failed: 29,
+				freed_bytes: 81920,
+				freed_inodes: 39,
+				..Default::default()
 			}),
 			Ok(Stats {
 				deleted: 0,
 				deleted_folders: 0,
 				failed: 0,
+				..Default::default()
 			}),
 			Err(()),
 			Ok(Stats {
 				deleted: 0,
 				deleted_folders: 0,
 				failed: 1,
+				..Default::default()
 			}),
 		];
 
@@ -179,5 +387,7 @@ mod tests {
 		assert_eq!(result.deleted, 70);
 		assert_eq!(result.deleted_folders, 5);
 		assert_eq!(result.failed, 43);
+		assert_eq!(result.freed_bytes, 81920);
+		assert_eq!(result.freed_inodes, 39);
 	}
 }
@@ -0,0 +1,50 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of status requests received, incremented by the signal handler
+static STATUS_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn request_status(_signal: libc::c_int) {
+	STATUS_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Installs a `SIGUSR1` handler requesting a status log line from the running cleanup
+///
+/// Without this, `SIGUSR1` terminates the process.
+pub fn install_status_handler() -> Result<(), io::Error> {
+	let action = SigAction::new(
+		SigHandler::Handler(request_status),
+		SaFlags::SA_RESTART,
+		SigSet::empty(),
+	);
+	// SAFETY: the handler only stores to an atomic, which is async-signal-safe
+	unsafe { sigaction(Signal::SIGUSR1, &action) }.map_err(io::Error::from)?;
+	Ok(())
+}
+
+/// Status requests seen by one scan or deletion loop
+///
+/// Each loop keeps its own count, so concurrent loops (e.g. in tests) don't
+/// take each other's requests.
+pub(crate) struct StatusRequests(u64);
+
+impl StatusRequests {
+	/// Starts counting, ignoring requests received before
+	#[inline]
+	pub(crate) fn new() -> Self {
+		Self(STATUS_REQUESTS.load(Ordering::Relaxed))
+	}
+
+	/// Returns `true` once if status requests were received since the last call
+	#[inline]
+	pub(crate) fn take(&mut self) -> bool {
+		let requests = STATUS_REQUESTS.load(Ordering::Relaxed);
+		let requested = requests != self.0;
+		self.0 = requests;
+		requested
+	}
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::reporter::Report;
+use crate::size_spec::Units;
+use crate::stats::{SkipCounts, Stats};
+use crate::usage::Constraint;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Deleted cache entries per header format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FormatCounts {
+	pub disk: u64,
+	pub vary: u64,
+}
+
+/// Duration of the phases of a run in seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PhaseSeconds {
+	pub cleanup: f64,
+	pub scan: f64,
+	pub delete: f64,
+}
+
+/// All metrics of a run combined, e.g. for dashboards
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerboseSummary {
+	pub deleted: u64,
+	pub deleted_folders: u64,
+	pub failed: u64,
+	pub deleted_by_format: FormatCounts,
+	/// Freed disk space in bytes
+	pub freed_bytes: u64,
+	/// Number of deleted header and data files
+	pub freed_inodes: u64,
+	pub usage_before: f64,
+	pub usage_after: f64,
+	/// Limit determining the usage percentages
+	pub binding_constraint: Constraint,
+	pub skipped: SkipCounts,
+	pub phase_seconds: PhaseSeconds,
+	/// Total duration of all phases in seconds
+	pub elapsed_seconds: f64,
+}
+
+impl VerboseSummary {
+	/// Combines the statistics of a run with the usage before and after it
+	pub fn new(
+		stats: &Stats,
+		usage_before: f64,
+		usage_after: f64,
+		binding_constraint: Constraint,
+	) -> Self {
+		Self {
+			deleted: stats.deleted,
+			deleted_folders: stats.deleted_folders,
+			failed: stats.failed,
+			deleted_by_format: FormatCounts {
+				disk: stats.deleted_disk,
+				vary: stats.deleted_vary,
+			},
+			freed_bytes: stats.freed_bytes,
+			freed_inodes: stats.freed_inodes,
+			usage_before,
+			usage_after,
+			binding_constraint,
+			skipped: stats.skipped,
+			phase_seconds: PhaseSeconds {
+				cleanup: stats.timings.cleanup.as_secs_f64(),
+				scan: stats.timings.scan.as_secs_f64(),
+				delete: stats.timings.delete.as_secs_f64(),
+			},
+			elapsed_seconds: (stats.timings.cleanup + stats.timings.scan + stats.timings.delete)
+				.as_secs_f64(),
+		}
+	}
+}
+
+impl Report for VerboseSummary {
+	fn write_text(&self, units: Units, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(
+			out,
+			"Deleted: {} files ({} disk, {} vary), {} folders, {} failed",
+			self.deleted,
+			self.deleted_by_format.disk,
+			self.deleted_by_format.vary,
+			self.deleted_folders,
+			self.failed
+		)?;
+		writeln!(out, "Freed: {}B", units.format(self.freed_bytes))?;
+		writeln!(
+			out,
+			"Usage: {:.1}% before, {:.1}% after (limited by {})",
+			self.usage_before, self.usage_after, self.binding_constraint
+		)?;
+		let skipped: Vec<_> = self
+			.skipped
+			.by_reason()
+			.iter()
+			.map(|(reason, count)| format!("{} {}", count, reason.replace('_', " ")))
+			.collect();
+		writeln!(out, "Skipped: {}", skipped.join(", "))?;
+		writeln!(
+			out,
+			"Phases: cleanup {:.3}s, scan {:.3}s, delete {:.3}s",
+			self.phase_seconds.cleanup, self.phase_seconds.scan, self.phase_seconds.delete
+		)
+	}
+
+	fn metrics(&self) -> Vec<(String, f64)> {
+		let mut metrics: Vec<(String, f64)> = vec![
+			("deleted".into(), self.deleted as f64),
+			("deleted_folders".into(), self.deleted_folders as f64),
+			("failed".into(), self.failed as f64),
+			("deleted_disk".into(), self.deleted_by_format.disk as f64),
+			("deleted_vary".into(), self.deleted_by_format.vary as f64),
+			("freed_bytes".into(), self.freed_bytes as f64),
+			("freed_inodes".into(), self.freed_inodes as f64),
+			("usage_before".into(), self.usage_before),
+			("usage_after".into(), self.usage_after),
+		];
+		for (reason, count) in self.skipped.by_reason() {
+			metrics.push((format!("skipped_{}", reason), count as f64));
+		}
+		metrics.extend([
+			("cleanup_seconds".into(), self.phase_seconds.cleanup),
+			("scan_seconds".into(), self.phase_seconds.scan),
+			("delete_seconds".into(), self.phase_seconds.delete),
+			("elapsed_seconds".into(), self.elapsed_seconds),
+		]);
+		metrics
+	}
+
+	fn labels(&self) -> Vec<(&'static str, &'static str, String)> {
+		vec![(
+			"binding_constraint",
+			"constraint",
+			self.binding_constraint.to_string(),
+		)]
+	}
+
+	/// Serializes the summary with its nested structure
+	#[cfg(feature = "serde")]
+	fn to_json(&self) -> String {
+		serde_json::to_string(self).expect("Couldn't serialize summary")
+	}
+}
+
+/// Single line summary of a run, e.g. for cron mails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactSummary {
+	/// Freed disk space in bytes
+	pub freed_bytes: u64,
+	pub deleted: u64,
+	pub failed: u64,
+	pub usage_before: f64,
+	pub usage_after: f64,
+	/// Unit system for `freed_bytes`
+	pub units: Units,
+}
+
+impl CompactSummary {
+	/// Combines the statistics of a run with the usage before and after it
+	pub fn new(stats: &Stats, usage_before: f64, usage_after: f64, units: Units) -> Self {
+		Self {
+			freed_bytes: stats.freed_bytes,
+			deleted: stats.deleted,
+			failed: stats.failed,
+			usage_before,
+			usage_after,
+			units,
+		}
+	}
+}
+
+impl fmt::Display for CompactSummary {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"fasthtcacheclean: freed {} in {} files, usage {:.0}%\u{2192}{:.0}%, {} failures",
+			self.units.format(self.freed_bytes),
+			self.deleted,
+			self.usage_before,
+			self.usage_after,
+			self.failed
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[cfg(feature = "serde")]
+	use crate::{process_folder_parallel, Config};
+	#[cfg(feature = "serde")]
+	use std::fs::{copy, create_dir_all, write};
+	#[cfg(feature = "serde")]
+	use std::time::SystemTime;
+	#[cfg(feature = "serde")]
+	use tempfile::TempDir;
+
+	#[test]
+	fn test_compact_summary() {
+		let stats = Stats {
+			deleted: 1240,
+			freed_bytes: 3_200_000_000,
+			..Default::default()
+		};
+		let summary = CompactSummary::new(&stats, 88.2, 71.6, Units::Si);
+		assert_eq!(
+			summary.to_string(),
+			"fasthtcacheclean: freed 3.2G in 1240 files, usage 88%\u{2192}72%, 0 failures"
+		);
+
+		let summary = CompactSummary::new(&Stats::default(), 50.0, 50.0, Units::Raw);
+		assert_eq!(
+			summary.to_string(),
+			"fasthtcacheclean: freed 0 in 0 files, usage 50%\u{2192}50%, 0 failures"
+		);
+	}
+
+	#[test]
+	#[cfg(feature = "serde")]
+	fn test_verbose_summary_schema() {
+		let dir = TempDir::new().unwrap();
+		let sub = dir.path().join("ab");
+		create_dir_all(&sub).unwrap();
+		copy("testcases/disk.header", sub.join("disk.header")).unwrap();
+		write(sub.join("disk.data"), [0u8; 4096]).unwrap();
+		copy("testcases/disk.header", sub.join("kept.header")).unwrap();
+
+		let config = Config {
+			preserve: vec![glob::Pattern::new("**/kept.header").unwrap()],
+			..Default::default()
+		};
+		let stats = process_folder_parallel(dir.path(), &config, &SystemTime::now()).unwrap();
+		let summary = VerboseSummary::new(&stats, 100.5, 98.0, Constraint::Space);
+		let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+
+		let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+		keys.sort();
+		assert_eq!(
+			keys,
+			[
+				"binding_constraint",
+				"deleted",
+				"deleted_by_format",
+				"deleted_folders",
+				"elapsed_seconds",
+				"failed",
+				"freed_bytes",
+				"freed_inodes",
+				"phase_seconds",
+				"skipped",
+				"usage_after",
+				"usage_before",
+			]
+		);
+		assert_eq!(json["deleted"], 1);
+		assert_eq!(json["deleted_by_format"]["disk"], 1);
+		assert_eq!(json["deleted_by_format"]["vary"], 0);
+		assert!(json["freed_bytes"].as_u64().unwrap() >= 4096);
+		assert_eq!(json["freed_inodes"], 2);
+		assert_eq!(json["usage_before"], 100.5);
+		assert_eq!(json["binding_constraint"], "space");
+		assert_eq!(json["skipped"]["preserved"], 1);
+		for phase in ["cleanup", "scan", "delete"] {
+			assert!(json["phase_seconds"][phase].is_f64());
+		}
+		assert!(
+			json["elapsed_seconds"].as_f64().unwrap()
+				>= json["phase_seconds"]["scan"].as_f64().unwrap()
+		);
+	}
+}
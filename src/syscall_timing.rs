@@ -0,0 +1,205 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+#[cfg(feature = "syscall-timing")]
+use std::fmt;
+#[cfg(feature = "syscall-timing")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "syscall-timing")]
+use std::time::{Duration, Instant};
+
+/// Kind of a timed filesystem call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+	/// Opening a directory for reading
+	ReadDir,
+	/// Reading file metadata
+	Metadata,
+	/// Opening a file
+	Open,
+	/// Deleting a file or directory
+	Remove,
+}
+
+/// Runs `f`, recording its duration for `syscall` if enabled
+///
+/// With the `syscall-timing` feature, durations are recorded into global
+/// histograms (see `syscall_timings`). Without it, this just runs `f`.
+#[inline(always)]
+pub fn timed<T>(syscall: Syscall, f: impl FnOnce() -> T) -> T {
+	#[cfg(feature = "syscall-timing")]
+	{
+		let start = Instant::now();
+		let result = f();
+		HISTOGRAMS[syscall as usize].record(start.elapsed());
+		result
+	}
+	#[cfg(not(feature = "syscall-timing"))]
+	{
+		let _ = syscall;
+		f()
+	}
+}
+
+/// Number of histogram buckets, bucket `i` counts calls taking less than 2^i µs
+#[cfg(feature = "syscall-timing")]
+const BUCKETS: usize = 24;
+
+#[cfg(feature = "syscall-timing")]
+impl Syscall {
+	const ALL: [Syscall; 4] = [Self::ReadDir, Self::Metadata, Self::Open, Self::Remove];
+
+	fn name(self) -> &'static str {
+		match self {
+			Self::ReadDir => "read_dir",
+			Self::Metadata => "metadata",
+			Self::Open => "open",
+			Self::Remove => "remove",
+		}
+	}
+}
+
+/// Lock-free histogram of call durations
+#[cfg(feature = "syscall-timing")]
+struct Histogram {
+	buckets: [AtomicU64; BUCKETS],
+	total_nanos: AtomicU64,
+}
+
+#[cfg(feature = "syscall-timing")]
+impl Histogram {
+	const fn new() -> Self {
+		Self {
+			buckets: [const { AtomicU64::new(0) }; BUCKETS],
+			total_nanos: AtomicU64::new(0),
+		}
+	}
+
+	fn record(&self, duration: Duration) {
+		let micros = duration.as_micros() as u64;
+		let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		self.total_nanos
+			.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+	}
+}
+
+#[cfg(feature = "syscall-timing")]
+static HISTOGRAMS: [Histogram; 4] = [const { Histogram::new() }; 4];
+
+/// Snapshot of the recorded durations of one kind of call
+#[cfg(feature = "syscall-timing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallHistogram {
+	/// Number of calls per bucket, bucket `i` counts calls taking less than 2^i µs
+	pub buckets: [u64; BUCKETS],
+	/// Total duration of all calls
+	pub total: Duration,
+}
+
+#[cfg(feature = "syscall-timing")]
+impl SyscallHistogram {
+	/// Number of recorded calls
+	pub fn calls(&self) -> u64 {
+		self.buckets.iter().sum()
+	}
+
+	/// Upper bound of the bucket containing the `p`-th percentile
+	pub fn percentile(&self, p: f64) -> Option<Duration> {
+		let rank = (p / 100.0 * self.calls() as f64).ceil().max(1.0) as u64;
+		let mut seen = 0;
+		for (i, count) in self.buckets.iter().enumerate() {
+			seen += count;
+			if seen >= rank {
+				return Some(Duration::from_micros(1 << i));
+			}
+		}
+		None
+	}
+}
+
+/// Snapshot of the recorded durations of all kinds of calls
+#[cfg(feature = "syscall-timing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallTimings([SyscallHistogram; 4]);
+
+#[cfg(feature = "syscall-timing")]
+impl SyscallTimings {
+	/// Returns the recorded durations of `syscall`
+	pub fn get(&self, syscall: Syscall) -> &SyscallHistogram {
+		&self.0[syscall as usize]
+	}
+}
+
+/// Returns the durations recorded since the start of the program
+#[cfg(feature = "syscall-timing")]
+pub fn syscall_timings() -> SyscallTimings {
+	SyscallTimings(std::array::from_fn(|i| SyscallHistogram {
+		buckets: std::array::from_fn(|bucket| {
+			HISTOGRAMS[i].buckets[bucket].load(Ordering::Relaxed)
+		}),
+		total: Duration::from_nanos(HISTOGRAMS[i].total_nanos.load(Ordering::Relaxed)),
+	}))
+}
+
+#[cfg(feature = "syscall-timing")]
+impl fmt::Display for SyscallTimings {
+	/// Writes one line per kind of call
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for syscall in Syscall::ALL {
+			let histogram = self.get(syscall);
+			write!(
+				f,
+				"{}: {} calls, {:.3}s total",
+				syscall.name(),
+				histogram.calls(),
+				histogram.total.as_secs_f64()
+			)?;
+			if let (Some(p50), Some(p90), Some(p99)) = (
+				histogram.percentile(50.0),
+				histogram.percentile(90.0),
+				histogram.percentile(99.0),
+			) {
+				write!(
+					f,
+					", p50 <{}µs, p90 <{}µs, p99 <{}µs",
+					p50.as_micros(),
+					p90.as_micros(),
+					p99.as_micros()
+				)?;
+			}
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(all(test, feature = "syscall-timing"))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_histogram_percentiles() {
+		let mut buckets = [0; BUCKETS];
+		buckets[0] = 50;
+		buckets[3] = 40;
+		buckets[10] = 10;
+		let histogram = SyscallHistogram {
+			buckets,
+			total: Duration::ZERO,
+		};
+		assert_eq!(histogram.calls(), 100);
+		assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(1)));
+		assert_eq!(histogram.percentile(90.0), Some(Duration::from_micros(8)));
+		assert_eq!(
+			histogram.percentile(99.0),
+			Some(Duration::from_micros(1024))
+		);
+
+		let empty = SyscallHistogram {
+			buckets: [0; BUCKETS],
+			total: Duration::ZERO,
+		};
+		assert_eq!(empty.percentile(50.0), None);
+	}
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Point in time given as an RFC 3339 timestamp (e.g. `2023-05-01T12:00:00Z`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp(pub SystemTime);
+
+/// Error type for parsing a `Timestamp`
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("expected an RFC 3339 timestamp like 2023-05-01T12:00:00Z or 2023-05-01T14:00:00+02:00")]
+pub struct ParseTimestampError;
+
+/// Returns the number of days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+/// Parses exactly `len` ASCII digits at the start of `s`, returning the number and the rest
+fn parse_digits(s: &str, len: usize) -> Result<(i64, &str), ParseTimestampError> {
+	let digits = s.get(..len).ok_or(ParseTimestampError)?;
+	if !digits.bytes().all(|b| b.is_ascii_digit()) {
+		return Err(ParseTimestampError);
+	}
+	Ok((digits.parse().map_err(|_| ParseTimestampError)?, &s[len..]))
+}
+
+/// Strips one of the separators `c` from the start of `s`
+fn expect_char<'a>(s: &'a str, c: &[char]) -> Result<&'a str, ParseTimestampError> {
+	s.strip_prefix(c).ok_or(ParseTimestampError)
+}
+
+/// Parsing an RFC 3339 string into a `Timestamp`
+impl FromStr for Timestamp {
+	type Err = ParseTimestampError;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (year, s) = parse_digits(s, 4)?;
+		let (month, s) = parse_digits(expect_char(s, &['-'])?, 2)?;
+		let (day, s) = parse_digits(expect_char(s, &['-'])?, 2)?;
+		let (hour, s) = parse_digits(expect_char(s, &['T', 't', ' '])?, 2)?;
+		let (minute, s) = parse_digits(expect_char(s, &[':'])?, 2)?;
+		let (second, mut s) = parse_digits(expect_char(s, &[':'])?, 2)?;
+
+		let mut nanos = 0;
+		if let Some(rest) = s.strip_prefix('.') {
+			let len = rest.bytes().take_while(u8::is_ascii_digit).count();
+			if len == 0 {
+				return Err(ParseTimestampError);
+			}
+			// Only nanosecond precision is kept
+			let (fraction, _) = parse_digits(rest, len.min(9))?;
+			nanos = fraction * 10i64.pow(9 - len.min(9) as u32);
+			s = &rest[len..];
+		}
+
+		let offset = match s {
+			"Z" | "z" => 0,
+			_ => {
+				let sign = match s.chars().next() {
+					Some('+') => 1,
+					Some('-') => -1,
+					_ => return Err(ParseTimestampError),
+				};
+				let (offset_hour, rest) = parse_digits(&s[1..], 2)?;
+				let (offset_minute, rest) = parse_digits(expect_char(rest, &[':'])?, 2)?;
+				if !rest.is_empty() || offset_hour > 23 || offset_minute > 59 {
+					return Err(ParseTimestampError);
+				}
+				sign * (offset_hour * 3600 + offset_minute * 60)
+			}
+		};
+
+		let days_in_month = match month {
+			2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+			2 => 28,
+			4 | 6 | 9 | 11 => 30,
+			1..=12 => 31,
+			_ => return Err(ParseTimestampError),
+		};
+		// Leap seconds are accepted and treated like the following second
+		if day < 1 || day > days_in_month || hour > 23 || minute > 59 || second > 60 {
+			return Err(ParseTimestampError);
+		}
+
+		let seconds =
+			days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+		let time = if seconds >= 0 {
+			UNIX_EPOCH + Duration::new(seconds as u64, nanos as u32)
+		} else {
+			UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+				+ Duration::from_nanos(nanos as u64)
+		};
+		Ok(Self(time))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(s: &str) -> Result<SystemTime, ParseTimestampError> {
+		s.parse::<Timestamp>().map(|t| t.0)
+	}
+
+	#[test]
+	fn test_parse_timestamp() {
+		let expected = UNIX_EPOCH + Duration::from_secs(1_682_942_400);
+		assert_eq!(parse("2023-05-01T12:00:00Z"), Ok(expected));
+		assert_eq!(parse("2023-05-01 14:00:00+02:00"), Ok(expected));
+		assert_eq!(parse("2023-05-01T09:30:00-02:30"), Ok(expected));
+		assert_eq!(
+			parse("2023-05-01T12:00:00.25Z"),
+			Ok(expected + Duration::from_millis(250))
+		);
+		assert_eq!(parse("1970-01-01T00:00:00Z"), Ok(UNIX_EPOCH));
+		assert_eq!(
+			parse("1969-12-31T23:59:59.5Z"),
+			Ok(UNIX_EPOCH - Duration::from_millis(500))
+		);
+		assert_eq!(
+			parse("2024-02-29T00:00:00Z"),
+			Ok(UNIX_EPOCH + Duration::from_secs(1_709_164_800))
+		);
+
+		for invalid in [
+			"",
+			"2023-05-01",
+			"2023-05-01T12:00:00",
+			"2023-02-29T12:00:00Z",
+			"2023-13-01T12:00:00Z",
+			"2023-05-01T24:00:00Z",
+			"2023-05-01T12:00:00.Z",
+			"2023-05-01T12:00:00+0200",
+			"+023-05-01T12:00:00Z",
+		] {
+			assert_eq!(parse(invalid), Err(ParseTimestampError), "{}", invalid);
+		}
+	}
+}
@@ -0,0 +1,807 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::reporter::Report;
+use crate::{SizeSpec, Units};
+use nix::errno::Errno;
+use nix::sys::statfs::statfs;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+/// `statfs` magic number of btrfs
+const BTRFS_SUPER_MAGIC: i64 = 0x9123683e;
+/// Filesystems with dynamic inode allocation and meaningless inode counts
+const DYNAMIC_INODE_FILESYSTEMS: [i64; 1] = [BTRFS_SUPER_MAGIC];
+
+/// Raw filesystem figures as reported by `statfs`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsStats {
+	pub block_size: u64,
+	pub blocks: u64,
+	pub blocks_available: u64,
+	pub files: u64,
+	pub files_free: u64,
+	/// Filesystem type magic number
+	pub fs_type: i64,
+}
+
+/// Limit that is closest to being reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+	feature = "serde",
+	derive(serde::Serialize),
+	serde(rename_all = "lowercase")
+)]
+pub enum Constraint {
+	Space,
+	Inodes,
+}
+
+impl fmt::Display for Constraint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Space => "space",
+			Self::Inodes => "inodes",
+		})
+	}
+}
+
+impl FsStats {
+	/// Calculates a percentage of how close the used space is to the free space/inode limit
+	///
+	/// Returns the maximum of space and inode percentage. Filesystems reporting
+	/// zero total blocks are treated as having no pressure at all. The inode
+	/// percentage is ignored if the filesystem has no meaningful inode counts.
+	pub fn usage(&self, minspace: SizeSpec, mininodes: SizeSpec) -> f64 {
+		let (space_usage, inode_usage) = self.usages(minspace, mininodes);
+		if inode_usage > space_usage {
+			inode_usage
+		} else {
+			space_usage
+		}
+	}
+
+	/// Returns which limit determines the usage percentage
+	pub fn binding_constraint(&self, minspace: SizeSpec, mininodes: SizeSpec) -> Constraint {
+		let (space_usage, inode_usage) = self.usages(minspace, mininodes);
+		if inode_usage > space_usage {
+			Constraint::Inodes
+		} else {
+			Constraint::Space
+		}
+	}
+
+	/// Calculates the percentage of a single limit, see [`FsStats::usage`]
+	pub fn constraint_usage(
+		&self,
+		constraint: Constraint,
+		minspace: SizeSpec,
+		mininodes: SizeSpec,
+	) -> f64 {
+		let (space_usage, inode_usage) = self.usages(minspace, mininodes);
+		match constraint {
+			Constraint::Space => space_usage,
+			Constraint::Inodes => inode_usage,
+		}
+	}
+
+	/// Calculates the space and inode percentages separately
+	///
+	/// A filesystem without blocks has no usage pressure, a warning is logged once.
+	fn usages(&self, minspace: SizeSpec, mininodes: SizeSpec) -> (f64, f64) {
+		static WARNING: Once = Once::new();
+
+		if self.blocks == 0 {
+			WARNING.call_once(|| {
+				warn!("Filesystem reports zero total blocks, assuming no usage pressure");
+			});
+			return (0.0, 0.0);
+		}
+
+		let figures = self.absolute_usage(minspace, mininodes);
+		let inode_usage = figures.inodes.map_or(0.0, |inodes| inodes.percentage());
+		(figures.space.percentage(), inode_usage)
+	}
+
+	/// Returns the absolute figures the usage percentages are calculated from
+	///
+	/// The inode figures are `None` if the filesystem has no meaningful inode counts.
+	pub fn absolute_usage(&self, minspace: SizeSpec, mininodes: SizeSpec) -> AbsoluteUsage {
+		let total_space = self.block_size * self.blocks;
+		let free_space = self.blocks_available.min(self.blocks) * self.block_size;
+		let space = ResourceUsage {
+			total: total_space,
+			used: total_space - free_space,
+			free: free_space,
+			target_free: minspace.value(total_space),
+		};
+
+		let inodes = self.has_inode_counts().then(|| {
+			let free_inodes = self.files_free.min(self.files);
+			ResourceUsage {
+				total: self.files,
+				used: self.files - free_inodes,
+				free: free_inodes,
+				target_free: mininodes.value(self.files),
+			}
+		});
+		AbsoluteUsage { space, inodes }
+	}
+}
+
+/// Absolute figures of a limited resource (bytes or inodes)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+	pub total: u64,
+	pub used: u64,
+	pub free: u64,
+	/// Amount to keep free
+	pub target_free: u64,
+}
+
+impl ResourceUsage {
+	/// Calculates a percentage of how close the used amount is to the limit
+	pub fn percentage(&self) -> f64 {
+		let used_target = self.total.saturating_sub(self.target_free);
+		self.used as f64 * 100.0 / (used_target + 1) as f64
+	}
+}
+
+/// Absolute figures of space and inode usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AbsoluteUsage {
+	pub space: ResourceUsage,
+	/// `None` if the filesystem has no meaningful inode counts
+	pub inodes: Option<ResourceUsage>,
+}
+
+impl AbsoluteUsage {
+	/// Formats the figures for humans, with byte counts in the given units
+	pub fn format(&self, units: Units) -> String {
+		let mut result = format!(
+			"{}B used, {}B free of {}B, target {}B free",
+			units.format(self.space.used),
+			units.format(self.space.free),
+			units.format(self.space.total),
+			units.format(self.space.target_free),
+		);
+		if let Some(inodes) = &self.inodes {
+			result += &format!(
+				"; {} inodes used, {} free of {}, target {} free",
+				inodes.used, inodes.free, inodes.total, inodes.target_free
+			);
+		}
+		result
+	}
+}
+
+impl FsStats {
+	/// Checks if the filesystem reports meaningful inode counts
+	///
+	/// Filesystems allocating inodes dynamically (like btrfs) report arbitrary
+	/// counts, so a warning is logged once and the inode limit is ignored.
+	pub fn has_inode_counts(&self) -> bool {
+		static WARNING: Once = Once::new();
+
+		if self.files == 0 {
+			return false;
+		}
+		if DYNAMIC_INODE_FILESYSTEMS.contains(&self.fs_type) {
+			WARNING.call_once(|| {
+				warn!("Filesystem has no meaningful inode counts, ignoring the inode limit");
+			});
+			return false;
+		}
+		true
+	}
+}
+
+/// Source for filesystem usage information
+pub trait UsageProvider {
+	/// Queries the current filesystem figures
+	fn fs_stats(&self) -> nix::Result<FsStats>;
+
+	/// Calculates a percentage of how close the used space is to the free space/inode limit
+	///
+	/// See [`FsStats::usage`].
+	fn usage(&self, minspace: SizeSpec, mininodes: SizeSpec) -> nix::Result<f64> {
+		Ok(self.fs_stats()?.usage(minspace, mininodes))
+	}
+
+	/// Calculates the percentage of a single limit
+	///
+	/// See [`FsStats::constraint_usage`].
+	fn constraint_usage(
+		&self,
+		constraint: Constraint,
+		minspace: SizeSpec,
+		mininodes: SizeSpec,
+	) -> nix::Result<f64> {
+		Ok(self
+			.fs_stats()?
+			.constraint_usage(constraint, minspace, mininodes))
+	}
+
+	/// Checks if the filesystem is mounted read-only
+	fn is_read_only(&self) -> nix::Result<bool> {
+		Ok(false)
+	}
+
+	/// Checks that the usage is at most `max_usage` percent, returning the usage
+	///
+	/// The outer error is returned if the usage can't be determined.
+	fn verify_usage(
+		&self,
+		minspace: SizeSpec,
+		mininodes: SizeSpec,
+		max_usage: f64,
+	) -> nix::Result<Result<f64, UsageAboveLimit>> {
+		let usage = self.usage(minspace, mininodes)?;
+		if usage <= max_usage {
+			Ok(Ok(usage))
+		} else {
+			Ok(Err(UsageAboveLimit { usage, max_usage }))
+		}
+	}
+}
+
+/// Raw filesystem figures together with the targets and usage computed from them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FsInfo {
+	pub stats: FsStats,
+	pub minspace: SizeSpec,
+	pub mininodes: SizeSpec,
+}
+
+impl FsInfo {
+	/// Queries the filesystem figures from `provider`
+	pub fn query(
+		provider: &impl UsageProvider,
+		minspace: SizeSpec,
+		mininodes: SizeSpec,
+	) -> nix::Result<Self> {
+		Ok(Self {
+			stats: provider.fs_stats()?,
+			minspace,
+			mininodes,
+		})
+	}
+}
+
+impl fmt::Display for FsInfo {
+	/// Writes one `name: value` line per figure, byte counts unformatted
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let stats = &self.stats;
+		writeln!(f, "block_size: {}", stats.block_size)?;
+		writeln!(f, "blocks: {}", stats.blocks)?;
+		writeln!(f, "blocks_available: {}", stats.blocks_available)?;
+		writeln!(f, "files: {}", stats.files)?;
+		writeln!(f, "files_free: {}", stats.files_free)?;
+		writeln!(f, "fs_type: {:#x}", stats.fs_type)?;
+
+		let figures = stats.absolute_usage(self.minspace, self.mininodes);
+		let (space_usage, inode_usage) = stats.usages(self.minspace, self.mininodes);
+		writeln!(f, "min_free_space: {}", self.minspace)?;
+		writeln!(f, "target_free_bytes: {}", figures.space.target_free)?;
+		writeln!(f, "space_usage: {:.1}%", space_usage)?;
+		writeln!(f, "min_free_inodes: {}", self.mininodes)?;
+		match figures.inodes {
+			Some(inodes) => {
+				writeln!(f, "target_free_inodes: {}", inodes.target_free)?;
+				writeln!(f, "inode_usage: {:.1}%", inode_usage)?;
+			}
+			None => writeln!(
+				f,
+				"target_free_inodes: ignored (no meaningful inode counts)"
+			)?,
+		}
+		writeln!(
+			f,
+			"usage: {:.1}% (limited by {})",
+			stats.usage(self.minspace, self.mininodes),
+			stats.binding_constraint(self.minspace, self.mininodes)
+		)
+	}
+}
+
+impl Report for FsInfo {
+	fn write_text(&self, _units: Units, out: &mut dyn Write) -> io::Result<()> {
+		write!(out, "{}", self)
+	}
+
+	fn metrics(&self) -> Vec<(String, f64)> {
+		let stats = &self.stats;
+		let figures = stats.absolute_usage(self.minspace, self.mininodes);
+		let (space_usage, inode_usage) = stats.usages(self.minspace, self.mininodes);
+		let mut metrics = vec![
+			("block_size".to_owned(), stats.block_size as f64),
+			("blocks".to_owned(), stats.blocks as f64),
+			("blocks_available".to_owned(), stats.blocks_available as f64),
+			("files".to_owned(), stats.files as f64),
+			("files_free".to_owned(), stats.files_free as f64),
+			("fs_type".to_owned(), stats.fs_type as f64),
+			(
+				"target_free_bytes".to_owned(),
+				figures.space.target_free as f64,
+			),
+			("space_usage".to_owned(), space_usage),
+		];
+		if let Some(inodes) = figures.inodes {
+			metrics.extend([
+				("target_free_inodes".to_owned(), inodes.target_free as f64),
+				("inode_usage".to_owned(), inode_usage),
+			]);
+		}
+		metrics.push((
+			"usage".to_owned(),
+			stats.usage(self.minspace, self.mininodes),
+		));
+		metrics
+	}
+
+	fn labels(&self) -> Vec<(&'static str, &'static str, String)> {
+		vec![
+			("min_free_space", "size", self.minspace.to_string()),
+			("min_free_inodes", "size", self.mininodes.to_string()),
+			(
+				"binding_constraint",
+				"constraint",
+				self.stats
+					.binding_constraint(self.minspace, self.mininodes)
+					.to_string(),
+			),
+		]
+	}
+}
+
+/// Error of a failed usage check with `UsageProvider::verify_usage`
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[error("usage {usage:.1}% is above the expected maximum of {max_usage:.1}%")]
+pub struct UsageAboveLimit {
+	/// Actual usage percentage
+	pub usage: f64,
+	/// Expected maximum usage percentage
+	pub max_usage: f64,
+}
+
+/// `UsageProvider` querying `statfs` for the filesystem containing a path
+#[derive(Debug, Clone)]
+pub struct Statfs {
+	/// Path on the filesystem, the current working directory by default
+	pub path: PathBuf,
+	/// Don't report inode counts, disabling the inode limit
+	pub ignore_inodes: bool,
+}
+
+impl Default for Statfs {
+	fn default() -> Self {
+		Self {
+			path: PathBuf::from("."),
+			ignore_inodes: false,
+		}
+	}
+}
+
+impl UsageProvider for Statfs {
+	fn is_read_only(&self) -> nix::Result<bool> {
+		Ok(statvfs(&self.path)?.flags().contains(FsFlags::ST_RDONLY))
+	}
+
+	fn fs_stats(&self) -> nix::Result<FsStats> {
+		let fsstat = statfs(&self.path)?;
+		let (files, files_free) = if self.ignore_inodes {
+			(0, 0)
+		} else {
+			(fsstat.files(), fsstat.files_free())
+		};
+		Ok(FsStats {
+			block_size: fsstat.block_size().try_into().unwrap_or(4096),
+			blocks: fsstat.blocks(),
+			blocks_available: fsstat.blocks_available(),
+			files,
+			files_free,
+			fs_type: fsstat.filesystem_type().0 as i64,
+		})
+	}
+}
+
+/// `UsageProvider` wrapper giving up on queries that take longer than a timeout
+///
+/// Protects against hanging on `statfs` of stale network filesystems. On timeout
+/// a warning is logged and the last successfully queried figures are returned,
+/// skipping the re-check, or `ETIMEDOUT` if there are none. A hanging query is
+/// left running in the background and awaited again by the next query.
+#[derive(Debug)]
+pub struct TimeoutUsage<P> {
+	inner: P,
+	timeout: Option<Duration>,
+	state: Mutex<TimeoutState>,
+}
+
+#[derive(Debug, Default)]
+struct TimeoutState {
+	/// Result of a query that timed out before
+	pending: Option<Receiver<nix::Result<FsStats>>>,
+	/// Result of a read-only check that timed out before
+	pending_read_only: Option<Receiver<nix::Result<bool>>>,
+	/// Figures of the last successful query
+	last: Option<FsStats>,
+}
+
+/// Waits up to `timeout` for the result of `pending`, or of `query` run in a new thread
+///
+/// On timeout the receiver is put back into `pending` to be awaited again.
+fn recv_with_timeout<T: Send + 'static>(
+	pending: &mut Option<Receiver<nix::Result<T>>>,
+	timeout: Duration,
+	query: impl FnOnce() -> nix::Result<T> + Send + 'static,
+) -> Result<nix::Result<T>, RecvTimeoutError> {
+	let receiver = pending.take().unwrap_or_else(|| {
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || sender.send(query()));
+		receiver
+	});
+	let result = receiver.recv_timeout(timeout);
+	if let Err(RecvTimeoutError::Timeout) = result {
+		*pending = Some(receiver);
+	}
+	result
+}
+
+impl<P> TimeoutUsage<P> {
+	/// Wraps `inner`, without a timeout if `timeout` is `None`
+	pub fn new(inner: P, timeout: Option<Duration>) -> Self {
+		Self {
+			inner,
+			timeout,
+			state: Mutex::default(),
+		}
+	}
+}
+
+impl<P: UsageProvider + Clone + Send + 'static> UsageProvider for TimeoutUsage<P> {
+	/// Gives up with `ETIMEDOUT` on timeout, there are no previous figures to fall back to
+	fn is_read_only(&self) -> nix::Result<bool> {
+		let timeout = match self.timeout {
+			Some(timeout) => timeout,
+			None => return self.inner.is_read_only(),
+		};
+
+		let mut state = self.state.lock().unwrap();
+		let inner = self.inner.clone();
+		match recv_with_timeout(&mut state.pending_read_only, timeout, move || {
+			inner.is_read_only()
+		}) {
+			Ok(result) => result,
+			Err(RecvTimeoutError::Timeout) => {
+				warn!(
+					"Checking for a read-only filesystem took longer than {:?}",
+					timeout
+				);
+				Err(Errno::ETIMEDOUT)
+			}
+			Err(RecvTimeoutError::Disconnected) => Err(Errno::EIO),
+		}
+	}
+
+	fn fs_stats(&self) -> nix::Result<FsStats> {
+		let timeout = match self.timeout {
+			Some(timeout) => timeout,
+			None => return self.inner.fs_stats(),
+		};
+
+		let mut state = self.state.lock().unwrap();
+		let inner = self.inner.clone();
+		match recv_with_timeout(&mut state.pending, timeout, move || inner.fs_stats()) {
+			Ok(result) => {
+				if let Ok(stats) = result {
+					state.last = Some(stats);
+				}
+				result
+			}
+			Err(RecvTimeoutError::Timeout) => {
+				warn!(
+					"Querying the filesystem usage took longer than {:?}, skipping the usage re-check",
+					timeout
+				);
+				state.last.ok_or(Errno::ETIMEDOUT)
+			}
+			Err(RecvTimeoutError::Disconnected) => Err(Errno::EIO),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// `UsageProvider` returning fixed figures
+	struct MockUsage(FsStats);
+
+	impl UsageProvider for MockUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Ok(self.0)
+		}
+	}
+
+	#[test]
+	fn test_usage() {
+		let provider = MockUsage(FsStats {
+			block_size: 4096,
+			blocks: 1000,
+			blocks_available: 550,
+			files: 1000,
+			files_free: 900,
+			fs_type: 0,
+		});
+		let usage = provider
+			.usage(SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0))
+			.unwrap();
+		assert!((usage - 50.0).abs() < 0.1);
+	}
+
+	#[test]
+	fn test_fs_info() {
+		let provider = MockUsage(FsStats {
+			block_size: 4096,
+			blocks: 1000,
+			blocks_available: 550,
+			files: 1000,
+			files_free: 900,
+			fs_type: 0xef53,
+		});
+		let info = FsInfo::query(
+			&provider,
+			SizeSpec::Percentage(10.0),
+			SizeSpec::Percentage(5.0),
+		)
+		.unwrap();
+		let output = info.to_string();
+		for line in [
+			"block_size: 4096\n",
+			"blocks: 1000\n",
+			"blocks_available: 550\n",
+			"files: 1000\n",
+			"files_free: 900\n",
+			"fs_type: 0xef53\n",
+			"target_free_bytes: 409600\n",
+			"target_free_inodes: 50\n",
+			"inode_usage: 10.5%\n",
+			"usage: 50.0% (limited by space)\n",
+		] {
+			assert!(output.contains(line), "{:?} missing in {}", line, output);
+		}
+
+		let info = FsInfo {
+			stats: FsStats {
+				fs_type: BTRFS_SUPER_MAGIC,
+				..info.stats
+			},
+			..info
+		};
+		assert!(info
+			.to_string()
+			.contains("target_free_inodes: ignored (no meaningful inode counts)\n"));
+	}
+
+	#[test]
+	fn test_absolute_usage() {
+		let stats = FsStats {
+			block_size: 4096,
+			blocks: 1000,
+			blocks_available: 550,
+			files: 1000,
+			files_free: 900,
+			fs_type: 0,
+		};
+		let minspace = SizeSpec::Percentage(10.0);
+		let mininodes = SizeSpec::Percentage(5.0);
+		let figures = stats.absolute_usage(minspace, mininodes);
+		assert_eq!(
+			figures.space,
+			ResourceUsage {
+				total: 4096000,
+				used: 1843200,
+				free: 2252800,
+				target_free: 409600,
+			}
+		);
+		let inodes = figures.inodes.unwrap();
+		assert_eq!(
+			(inodes.used, inodes.free, inodes.target_free),
+			(100, 900, 50)
+		);
+		assert_eq!(figures.space.percentage(), stats.usage(minspace, mininodes));
+		assert_eq!(
+			figures.format(Units::Raw),
+			"1843200B used, 2252800B free of 4096000B, target 409600B free; \
+			 100 inodes used, 900 free of 1000, target 50 free"
+		);
+
+		let btrfs = FsStats {
+			fs_type: BTRFS_SUPER_MAGIC,
+			..stats
+		};
+		assert_eq!(btrfs.absolute_usage(minspace, mininodes).inodes, None);
+	}
+
+	/// `UsageProvider` returning fixed figures after a configurable delay
+	#[derive(Clone)]
+	struct SlowUsage(FsStats, std::sync::Arc<Mutex<Duration>>);
+
+	impl UsageProvider for SlowUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			let delay = *self.1.lock().unwrap();
+			thread::sleep(delay);
+			Ok(self.0)
+		}
+
+		fn is_read_only(&self) -> nix::Result<bool> {
+			let delay = *self.1.lock().unwrap();
+			thread::sleep(delay);
+			Ok(true)
+		}
+	}
+
+	#[test]
+	fn test_timeout_usage() {
+		let stats = FsStats {
+			block_size: 4096,
+			blocks: 1000,
+			blocks_available: 550,
+			files: 1000,
+			files_free: 900,
+			fs_type: 0,
+		};
+		let delay = std::sync::Arc::new(Mutex::new(Duration::from_secs(2)));
+		let timeout = Duration::from_millis(50);
+
+		let provider = TimeoutUsage::new(SlowUsage(stats, delay.clone()), Some(timeout));
+		assert_eq!(provider.fs_stats(), Err(Errno::ETIMEDOUT));
+		assert_eq!(provider.is_read_only(), Err(Errno::ETIMEDOUT));
+
+		*delay.lock().unwrap() = Duration::ZERO;
+		let provider = TimeoutUsage::new(SlowUsage(stats, delay.clone()), Some(timeout));
+		let usage = provider
+			.usage(SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0))
+			.unwrap();
+		assert!((usage - 50.0).abs() < 0.1);
+		assert_eq!(provider.is_read_only(), Ok(true));
+
+		// A hanging re-check returns the last figures in time
+		*delay.lock().unwrap() = Duration::from_secs(2);
+		let start = std::time::Instant::now();
+		assert_eq!(provider.fs_stats(), Ok(stats));
+		assert!(start.elapsed() < Duration::from_secs(1));
+	}
+
+	#[test]
+	fn test_zero_blocks() {
+		let provider = MockUsage(FsStats {
+			block_size: 4096,
+			blocks: 0,
+			blocks_available: 0,
+			files: 0,
+			files_free: 0,
+			fs_type: 0,
+		});
+		assert_eq!(
+			provider.usage(SizeSpec::Absolute(1000), SizeSpec::Absolute(1000)),
+			Ok(0.0)
+		);
+	}
+
+	#[test]
+	fn test_verify_usage() {
+		// 50 % of the space used, 10 % has to be kept free
+		let provider = MockUsage(FsStats {
+			block_size: 4096,
+			blocks: 1000,
+			blocks_available: 500,
+			files: 0,
+			files_free: 0,
+			fs_type: 0,
+		});
+		let (minspace, mininodes) = (SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0));
+		let usage = provider.usage(minspace, mininodes).unwrap();
+		assert_eq!(
+			provider.verify_usage(minspace, mininodes, 100.0),
+			Ok(Ok(usage))
+		);
+		let error = provider
+			.verify_usage(minspace, mininodes, 50.0)
+			.unwrap()
+			.unwrap_err();
+		assert_eq!(error.usage, usage);
+		assert_eq!(
+			error.to_string(),
+			format!("usage {:.1}% is above the expected maximum of 50.0%", usage)
+		);
+	}
+
+	/// `UsageProvider` failing like a vanished mount
+	struct FailingUsage;
+
+	impl UsageProvider for FailingUsage {
+		fn fs_stats(&self) -> nix::Result<FsStats> {
+			Err(Errno::EIO)
+		}
+	}
+
+	#[test]
+	fn test_verify_usage_failure() {
+		let (minspace, mininodes) = (SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0));
+		assert_eq!(
+			FailingUsage.verify_usage(minspace, mininodes, 100.0),
+			Err(Errno::EIO)
+		);
+	}
+
+	#[test]
+	fn test_statfs_path() {
+		let dir = tempfile::TempDir::new().unwrap();
+		let statfs = Statfs {
+			path: dir.path().to_owned(),
+			ignore_inodes: true,
+		};
+		let stats = statfs.fs_stats().unwrap();
+		assert!(stats.blocks > 0);
+		assert_eq!(stats.files, 0);
+
+		let missing = Statfs {
+			path: dir.path().join("missing"),
+			..Default::default()
+		};
+		assert_eq!(missing.fs_stats(), Err(Errno::ENOENT));
+	}
+
+	#[test]
+	fn test_dynamic_inodes() {
+		let stats = FsStats {
+			block_size: 4096,
+			blocks: 1000,
+			blocks_available: 550,
+			files: 1000,
+			files_free: 0,
+			fs_type: 0,
+		};
+		let usage = stats.usage(SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0));
+		assert!(usage > 100.0);
+		let space_usage = stats.constraint_usage(
+			Constraint::Space,
+			SizeSpec::Percentage(10.0),
+			SizeSpec::Percentage(5.0),
+		);
+		assert!((space_usage - 50.0).abs() < 0.1);
+		assert_eq!(
+			stats.constraint_usage(
+				Constraint::Inodes,
+				SizeSpec::Percentage(10.0),
+				SizeSpec::Percentage(5.0)
+			),
+			usage
+		);
+		assert_eq!(
+			stats.binding_constraint(SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0)),
+			Constraint::Inodes
+		);
+
+		let btrfs = FsStats {
+			fs_type: BTRFS_SUPER_MAGIC,
+			..stats
+		};
+		let usage = btrfs.usage(SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0));
+		assert!((usage - 50.0).abs() < 0.1);
+		assert_eq!(
+			btrfs.binding_constraint(SizeSpec::Percentage(10.0), SizeSpec::Percentage(5.0)),
+			Constraint::Space
+		);
+	}
+}
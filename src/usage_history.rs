@@ -0,0 +1,139 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of runs kept in the usage history
+const HISTORY_LENGTH: usize = 100;
+/// Factor applied to the largest observed growth between two runs
+const HEADROOM_FACTOR: u64 = 2;
+
+/// Used disk space recorded for one run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageRecord {
+	/// Start of the run
+	pub time: SystemTime,
+	/// Used space in bytes before cleaning
+	pub used_before: u64,
+	/// Used space in bytes after cleaning
+	pub used_after: u64,
+}
+
+/// Reads the recorded runs from a usage history file, oldest first
+///
+/// Returns an empty history if the file doesn't exist yet.
+pub fn read_usage_history(path: &Path) -> Result<Vec<UsageRecord>, io::Error> {
+	let content = match read_to_string(path) {
+		Ok(content) => content,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e),
+	};
+	let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+	content
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| {
+			let fields = line
+				.split_whitespace()
+				.map(str::parse)
+				.collect::<Result<Vec<u64>, _>>()
+				.map_err(invalid)?;
+			match fields[..] {
+				[seconds, used_before, used_after] => Ok(UsageRecord {
+					time: UNIX_EPOCH + Duration::from_secs(seconds),
+					used_before,
+					used_after,
+				}),
+				_ => Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("expected 3 fields in usage history line `{}`", line),
+				)),
+			}
+		})
+		.collect()
+}
+
+/// Appends a run to a usage history file, dropping the oldest runs beyond `HISTORY_LENGTH`
+///
+/// Each run is stored as a line with the Unix time in whole seconds and the used
+/// space before and after cleaning in bytes.
+pub fn append_usage_history(path: &Path, record: UsageRecord) -> Result<(), io::Error> {
+	let mut history = read_usage_history(path)?;
+	history.push(record);
+	let start = history.len().saturating_sub(HISTORY_LENGTH);
+	let mut content = String::new();
+	for record in &history[start..] {
+		let seconds = record
+			.time
+			.duration_since(UNIX_EPOCH)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+			.as_secs();
+		content += &format!("{} {} {}\n", seconds, record.used_before, record.used_after);
+	}
+	write(path, content)
+}
+
+/// Derives the free space to keep from the growth observed between runs
+///
+/// Returns twice the largest growth of the used space from the end of one run
+/// to the start of the next, so the cache can grow as fast as ever before
+/// without filling up until the next run. Returns `None` with less than two
+/// recorded runs.
+pub fn auto_free_space(history: &[UsageRecord]) -> Option<u64> {
+	history
+		.windows(2)
+		.map(|pair| pair[1].used_before.saturating_sub(pair[0].used_after))
+		.max()
+		.map(|growth| growth * HEADROOM_FACTOR)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::TempDir;
+
+	#[test]
+	fn test_usage_history() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("history");
+		assert_eq!(read_usage_history(&path).unwrap(), []);
+		assert_eq!(auto_free_space(&[]), None);
+
+		let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let hour = Duration::from_secs(3600);
+		for (i, (used_before, used_after)) in [(900, 800), (1000, 850), (870, 870), (1070, 900)]
+			.into_iter()
+			.enumerate()
+		{
+			let record = UsageRecord {
+				time: start + hour * i as u32,
+				used_before,
+				used_after,
+			};
+			append_usage_history(&path, record).unwrap();
+			if i == 0 {
+				// Cold start: no growth observed yet
+				assert_eq!(auto_free_space(&read_usage_history(&path).unwrap()), None);
+			}
+		}
+
+		let history = read_usage_history(&path).unwrap();
+		assert_eq!(history.len(), 4);
+		assert_eq!(history[3].time, start + hour * 3);
+		// Largest growth is 1070 - 870 = 200
+		assert_eq!(auto_free_space(&history), Some(400));
+
+		for _ in 0..HISTORY_LENGTH {
+			append_usage_history(&path, history[0]).unwrap();
+		}
+		assert_eq!(read_usage_history(&path).unwrap().len(), HISTORY_LENGTH);
+
+		write(&path, "1700000000 100\n").unwrap();
+		assert!(read_usage_history(&path).is_err());
+		write(&path, "1700000000 100 abc\n").unwrap();
+		assert!(read_usage_history(&path).is_err());
+	}
+}
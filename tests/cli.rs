@@ -0,0 +1,133 @@
+// Copyright (c) 2023 Christoph Grenz (Papoo Software & Media GmbH) <info@papoo.de>
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output};
+use tempfile::TempDir;
+
+/// Runs the program on the cache at `path` with `args`, returning its output
+fn output(path: &Path, args: &[&str]) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_fasthtcacheclean"))
+		.arg("--path")
+		.arg(path)
+		.args(args)
+		.output()
+		.unwrap()
+}
+
+/// Runs the program on the cache at `path` with `args`
+fn run(path: &Path, args: &[&str]) -> ExitStatus {
+	output(path, args).status
+}
+
+#[test]
+fn test_verify_free_space_after() {
+	let dir = TempDir::new().unwrap();
+	assert!(run(dir.path(), &["--verify-free-space-after", "1000"]).success());
+	// Any filesystem has some space used
+	assert_eq!(
+		run(dir.path(), &["--verify-free-space-after", "0"]).code(),
+		Some(1)
+	);
+}
+
+#[test]
+fn test_report_mode_format() {
+	let dir = TempDir::new().unwrap();
+	let result = output(dir.path(), &["--dump-fs-info", "--report-format", "csv"]);
+	assert!(result.status.success());
+	let stdout = String::from_utf8(result.stdout).unwrap();
+	let lines: Vec<_> = stdout.lines().collect();
+	assert_eq!(lines.len(), 2);
+	assert!(lines[0].starts_with("block_size,blocks,"));
+	assert!(lines[0].ends_with(",binding_constraint"));
+	assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+
+	let result = output(
+		dir.path(),
+		&["--report-expiry-gaps", "1", "--report-format", "prometheus"],
+	);
+	assert!(result.status.success());
+	let stdout = String::from_utf8(result.stdout).unwrap();
+	assert!(stdout.contains("\nfasthtcacheclean_expired_entries 0\n"));
+}
+
+#[test]
+fn test_as_of() {
+	let dir = TempDir::new().unwrap();
+	let entry = dir.path().join("a/entry.header");
+	std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+	std::fs::copy("testcases/disk.header", &entry).unwrap();
+	let expired = |as_of: &str| {
+		let result = output(
+			dir.path(),
+			&[
+				"--report-expiry-gaps",
+				"0",
+				"--report-format",
+				"prometheus",
+				"--as-of",
+				as_of,
+			],
+		);
+		assert!(result.status.success());
+		let stdout = String::from_utf8(result.stdout).unwrap();
+		stdout.contains("\nfasthtcacheclean_expired_entries 1\n")
+	};
+	assert!(!expired("1970-01-01T00:00:00Z"));
+	assert!(expired("2999-01-01T00:00:00Z"));
+
+	// Not allowed for runs that delete
+	for args in [
+		&["--as-of", "2999-01-01T00:00:00Z"][..],
+		&["--as-of", "2999-01-01T00:00:00Z", "--expired-only"],
+	] {
+		assert_eq!(run(dir.path(), args).code(), Some(2));
+	}
+	assert!(entry.exists());
+}
+
+#[test]
+fn test_report_to_stdout() {
+	let dir = TempDir::new().unwrap();
+	let entry = dir.path().join("a/entry.header");
+	std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+	std::fs::copy("testcases/disk.header", &entry).unwrap();
+	let result = output(
+		dir.path(),
+		&[
+			"--expired-only",
+			"--assume-yes",
+			"--report-format",
+			"csv",
+			"--tree-summary",
+			"--compact",
+		],
+	);
+	assert!(result.status.success());
+	// Only the report goes to stdout, the other output to stderr
+	let stdout = String::from_utf8(result.stdout).unwrap();
+	let lines: Vec<_> = stdout.lines().collect();
+	assert_eq!(lines.len(), 2);
+	assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+	let stderr = String::from_utf8(result.stderr).unwrap();
+	assert!(stderr.contains("fasthtcacheclean: freed "));
+	assert!(stderr
+		.lines()
+		.any(|line| line.starts_with("a: 1 deleted files, ")));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_export_entries_to_stdout() {
+	let dir = TempDir::new().unwrap();
+	std::fs::copy("testcases/disk.header", dir.path().join("entry.header")).unwrap();
+	let result = output(dir.path(), &["-v", "--export-entries", "-"]);
+	assert!(result.status.success());
+	let stdout = String::from_utf8(result.stdout).unwrap();
+	let lines: Vec<_> = stdout.lines().collect();
+	assert_eq!(lines.len(), 1);
+	assert!(lines[0].starts_with('{') && lines[0].ends_with('}'));
+	let stderr = String::from_utf8(result.stderr).unwrap();
+	assert!(stderr.contains("Exported 1 cache entries"));
+}